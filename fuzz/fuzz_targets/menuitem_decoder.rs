@@ -0,0 +1,34 @@
+//! Feeds arbitrary bytes to `MenuItemDecoder`, as the contents of a `!menu` file, to check it
+//! never panics. Menu files can come from anyone who can write to the document root.
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+#[allow(dead_code)]
+#[path = "../../src/menu.rs"]
+mod menu;
+#[allow(dead_code)]
+#[path = "../../src/types.rs"]
+mod types;
+
+use menu::MenuItemDecoder;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte picks strict or lenient parsing.
+    let Some((&mode, data)) = data.split_first() else { return };
+    let mut decoder = MenuItemDecoder { strict: mode & 1 == 1 };
+    let mut buf = BytesMut::from(data);
+    // Errors only spoil their own line, so keep going after them, like a menu file being read.
+    loop {
+        match decoder.decode_eof(&mut buf) {
+            Ok(Some(item)) => {
+                let _ = item.validate();
+            }
+            Ok(None) => break,
+            Err(_) => (),
+        }
+    }
+});
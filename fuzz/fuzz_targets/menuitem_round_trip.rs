@@ -0,0 +1,72 @@
+//! Encodes arbitrary menu items with `MenuItemEncoder`, and checks that `MenuItemDecoder` reads
+//! back what was sent: either the same item, or the encoder refused to send it.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::{Decoder, Encoder};
+
+#[allow(dead_code)]
+#[path = "../../src/menu.rs"]
+mod menu;
+#[allow(dead_code)]
+#[path = "../../src/types.rs"]
+mod types;
+
+use menu::{MenuItem, MenuItemDecoder, MenuItemEncoder};
+use types::ItemType;
+
+#[derive(Arbitrary, Debug)]
+struct Item {
+    typ: u8,
+    text: String,
+    selector: String,
+    host: Option<String>,
+    port: Option<String>,
+    gopher_plus: Option<String>,
+}
+
+fuzz_target!(|item: Item| {
+    // Types that the decoder reads as something else: unprintable ones, comments, and the start
+    // of "GOPHER:" and "URL:" links.
+    if item.typ <= b' ' || matches!(item.typ, b'#' | b'G' | b'U') {
+        return;
+    }
+    let sent = MenuItem {
+        typ: ItemType::from_u8(item.typ),
+        text: item.text,
+        selector: item.selector,
+        host: item.host,
+        port: item.port,
+        gopher_plus: item.gopher_plus,
+    };
+
+    let mut buf = BytesMut::new();
+    let valid = sent.validate().is_ok();
+    let encoded = MenuItemEncoder::default().encode(sent.clone(), &mut buf);
+    assert_eq!(valid, encoded.is_ok(), "{sent:?}");
+    if !valid {
+        assert!(buf.is_empty());
+        return;
+    }
+
+    let mut decoder = MenuItemDecoder::default();
+    let got = decoder.decode_eof(&mut buf).unwrap().expect("no item decoded");
+    assert!(decoder.decode_eof(&mut buf).unwrap().is_none(), "more than one item decoded");
+
+    // Info and error lines with extra fields are read back with them as part of the text.
+    if matches!(sent.typ, ItemType::Info | ItemType::Error)
+        && sent.gopher_plus.as_ref().is_some_and(|extra| !extra.is_empty())
+    {
+        return;
+    }
+    let msg = format!("sent {sent:?}, got {got:?}");
+    assert_eq!(sent.typ.into_u8(), got.typ.into_u8(), "{msg}");
+    assert_eq!(sent.text.replace(['\t', '\r', '\n'], " "), got.text, "{msg}");
+    assert_eq!(sent.selector, got.selector, "{msg}");
+    assert_eq!(sent.host.as_deref().or(Some("error.host")), got.host.as_deref(), "{msg}");
+    assert_eq!(sent.port.as_deref().or(Some("1")), got.port.as_deref(), "{msg}");
+    assert_eq!(sent.gopher_plus.filter(|extra| !extra.is_empty()), got.gopher_plus, "{msg}");
+});
@@ -0,0 +1,28 @@
+//! Feeds arbitrary bytes to `RequestDecoder`, a piece at a time like they'd come off the network,
+//! to check it never panics.
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+#[allow(dead_code)]
+#[path = "../../src/request.rs"]
+mod request;
+
+use request::RequestDecoder;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte picks how big the pieces are.
+    let Some((&piece_len, data)) = data.split_first() else { return };
+    let mut decoder = RequestDecoder::with_max_length(256);
+    let mut buf = BytesMut::new();
+    for piece in data.chunks(usize::from(piece_len).max(1)) {
+        buf.extend_from_slice(piece);
+        // Once there's a request or an error, the connection's done with the decoder.
+        if !matches!(decoder.decode(&mut buf), Ok(None)) {
+            return;
+        }
+    }
+});
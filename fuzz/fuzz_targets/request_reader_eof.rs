@@ -0,0 +1,23 @@
+//! Feeds arbitrary bytes to `RequestDecoder` and then ends the input, as when a client closes the
+//! connection, to check `decode_eof` never panics either.
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+#[allow(dead_code)]
+#[path = "../../src/request.rs"]
+mod request;
+
+use request::RequestDecoder;
+
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = RequestDecoder::with_max_length(256);
+    let mut buf = BytesMut::from(data);
+    // A complete request or an error ends the connection before it gets to EOF.
+    if let Ok(None) = decoder.decode(&mut buf) {
+        let _ = decoder.decode_eof(&mut buf);
+    }
+});
@@ -0,0 +1,183 @@
+use crate::request_stream::Peer;
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use tokio::fs::OpenOptions;
+use tokio::io::{self, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+/// One line of the access log, describing a finished request.
+#[derive(Debug)]
+pub struct Entry {
+    pub time: SystemTime,
+    pub peer: Peer,
+    /// `None` if no valid request was received.
+    pub selector: Option<String>,
+    /// What kind of response was sent; see `Response::kind`.
+    pub kind: &'static str,
+    pub bytes: u64,
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+/// Formats as space-separated fields: Unix timestamp, remote address, quoted selector (or '-'),
+/// response kind, bytes sent, milliseconds taken, and quoted error message (or '-'). Quoted
+/// fields are escaped like Rust string literals, so they never contain a bare space or quote.
+impl Display for Entry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let time = self.time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        write!(f, "{}.{:03} {} ", time.as_secs(), time.subsec_millis(), self.peer)?;
+        match &self.selector {
+            Some(selector) => write!(f, "{}", escape(selector))?,
+            None => f.write_str("-")?,
+        }
+        write!(f, " {} {} {} ", self.kind, self.bytes, self.duration.as_millis())?;
+        match &self.error {
+            Some(error) => write!(f, "{}", escape(error)),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    format!("\"{}\"", s.escape_default())
+}
+
+/// Handle for sending entries to the access log. Entries are written by a background task, so
+/// logging never holds up request handling.
+#[derive(Clone)]
+pub struct AccessLog {
+    tx: mpsc::UnboundedSender<Entry>,
+}
+
+impl AccessLog {
+    /// Start writing the log, appending to the given file, or to stderr if there isn't one.
+    pub async fn start(path: Option<&Path>) -> io::Result<Self> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        match path {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path).await?;
+                tokio::spawn(write_entries(rx, file));
+            }
+            None => {
+                tokio::spawn(write_entries(rx, io::stderr()));
+            }
+        }
+        Ok(Self { tx })
+    }
+
+    pub fn log(&self, entry: Entry) {
+        // This only fails if the writer task has died, which it already complained about.
+        let _ = self.tx.send(entry);
+    }
+}
+
+async fn write_entries(mut rx: mpsc::UnboundedReceiver<Entry>, mut w: impl AsyncWrite + Unpin) {
+    while let Some(entry) = rx.recv().await {
+        let line = format!("{entry}\n");
+        if let Err(e) = w.write_all(line.as_bytes()).await.and(w.flush().await) {
+            tracing::error!("error writing access log: {e}");
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Split a line back into its fields, unescaping the quoted ones.
+    fn parse(line: &str) -> Vec<String> {
+        let mut fields = vec![];
+        let mut rest = line.trim_end_matches('\n');
+        while !rest.is_empty() {
+            let (field, next) = match rest.strip_prefix('"') {
+                Some(quoted) => {
+                    let mut field = String::new();
+                    let mut chars = quoted.char_indices();
+                    let end = loop {
+                        match chars.next().expect("unterminated quote") {
+                            (_, '\\') => field.push(match chars.next().unwrap().1 {
+                                't' => '\t',
+                                'r' => '\r',
+                                'n' => '\n',
+                                c => c,
+                            }),
+                            (i, '"') => break i,
+                            (_, c) => field.push(c),
+                        }
+                    };
+                    (field, &quoted[end + 1 ..])
+                }
+                None => {
+                    let end = rest.find(' ').unwrap_or(rest.len());
+                    (rest[..end].to_owned(), &rest[end..])
+                }
+            };
+            fields.push(field);
+            rest = next.strip_prefix(' ').unwrap_or(next);
+        }
+        fields
+    }
+
+    fn entry() -> Entry {
+        Entry {
+            time: SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            peer: Peer::Tcp("[::1]:4567".parse().unwrap()),
+            selector: Some("/some dir/\"quoted\"\tfile".to_owned()),
+            kind: "text",
+            bytes: 1234,
+            duration: Duration::from_micros(56_789),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn format() {
+        let line = entry().to_string();
+        assert_eq!(parse(&line), [
+            "1700000000.123",
+            "[::1]:4567",
+            "/some dir/\"quoted\"\tfile",
+            "text",
+            "1234",
+            "56",
+            "-",
+        ]);
+    }
+
+    #[test]
+    fn format_error() {
+        let line = Entry {
+            selector: None,
+            kind: "error",
+            error: Some("timed out \"waiting\"".to_owned()),
+            ..entry()
+        }.to_string();
+        assert_eq!(parse(&line)[2..], ["-", "error", "1234", "56", "timed out \"waiting\""]);
+    }
+
+    #[tokio::test]
+    async fn writes_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let log = AccessLog::start(Some(&path)).await.unwrap();
+        log.log(entry());
+        log.log(Entry { bytes: 1, ..entry() });
+        drop(log);
+
+        // Wait for the writer task to catch up.
+        let mut contents = String::new();
+        for _ in 0 .. 100 {
+            contents = std::fs::read_to_string(&path).unwrap();
+            if contents.lines().count() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let lines = contents.lines().map(parse).collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0][4], "1234");
+        assert_eq!(lines[1][4], "1");
+    }
+}
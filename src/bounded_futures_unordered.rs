@@ -1,41 +1,88 @@
-use futures::stream::{FuturesUnordered, Stream};
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
 use pin_project_lite::pin_project;
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
+/// What to do when a future is pushed into a collection that's already full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvictionStrategy {
+    /// Remove the oldest future to make room.
+    DropOldest,
+    /// Don't add the new future.
+    DropNewest,
+    /// Wait for one of the futures already in the collection to finish.
+    Block,
+}
+
+/// What happened to make room, when a future is pushed into a full collection.
+pub enum Overflow<F: Future> {
+    /// The oldest future was removed.
+    Evicted(F),
+    /// The new future wasn't added.
+    Rejected(F),
+    /// One of the others finished, with this output.
+    Finished(F::Output),
+}
+
 pin_project! {
     pub struct BoundedFuturesUnordered<F> {
         #[pin]
         pending: FuturesUnordered<F>,
 
         max: usize,
+
+        strategy: EvictionStrategy,
     }
 }
 
 impl<F: Future + Unpin> BoundedFuturesUnordered<F> {
     pub fn new(max: usize) -> Self {
+        Self::with_strategy(max, EvictionStrategy::DropOldest)
+    }
+
+    pub fn with_strategy(max: usize, strategy: EvictionStrategy) -> Self {
         Self {
             pending: FuturesUnordered::new(),
             max,
+            strategy,
         }
     }
 
-    pub fn push(&mut self, item: F) {
-        if self.pending.len() == self.max {
-            // Remove the oldest pending request.
-            // Unfortunately, FuturesUnordered stores them as a linked list with the newest one at
-            // the head, so this requires walking the whole list; and preserving the order requires
-            // buffering them all so they can be inserted in reverse again.
-            let old = std::mem::take(&mut self.pending);
-            #[allow(clippy::needless_collect)] // needed to iterate in reverse
-            let fs = old.into_iter().collect::<Vec<_>>();
-            for f in fs.into_iter().rev().skip(1) {
-                self.pending.push(f);
-            }
-            assert_eq!(self.pending.len(), self.max - 1);
+    /// Add a future to the collection. If it was already full, room is made according to the
+    /// collection's strategy, and what that took is returned. Only the `Block` strategy ever has
+    /// to wait; if it's cancelled while waiting, the new future is dropped.
+    pub async fn push(&mut self, item: F) -> Option<Overflow<F>> {
+        if self.pending.len() < self.max {
+            self.pending.push(item);
+            return None;
         }
+        let overflow = match self.strategy {
+            EvictionStrategy::DropOldest => Overflow::Evicted(self.evict_oldest()),
+            EvictionStrategy::DropNewest => return Some(Overflow::Rejected(item)),
+            EvictionStrategy::Block => match self.pending.next().await {
+                Some(output) => Overflow::Finished(output),
+                None => unreachable!("a full collection can't be empty"),
+            },
+        };
         self.pending.push(item);
+        Some(overflow)
+    }
+
+    fn evict_oldest(&mut self) -> F {
+        // Unfortunately, FuturesUnordered stores them as a linked list with the newest one at the
+        // head, so this requires walking the whole list; and preserving the order requires
+        // buffering them all so they can be inserted in reverse again.
+        let old = std::mem::take(&mut self.pending);
+        #[allow(clippy::needless_collect)] // needed to iterate in reverse
+        let fs = old.into_iter().collect::<Vec<_>>();
+        let mut fs = fs.into_iter().rev();
+        let evicted = fs.next().expect("a full collection can't be empty");
+        for f in fs {
+            self.pending.push(f);
+        }
+        assert_eq!(self.pending.len(), self.max - 1);
+        evicted
     }
 
     pub fn len(&self) -> usize {
@@ -70,11 +117,11 @@ mod test {
         let (e_tx, e_rx) = oneshot::channel();
 
         let mut bfu = BoundedFuturesUnordered::new(2);
-        bfu.push(a_rx);
-        bfu.push(b_rx);
+        assert!(bfu.push(a_rx).await.is_none());
+        assert!(bfu.push(b_rx).await.is_none());
 
         // Pushing C should drop A.
-        bfu.push(c_rx);
+        assert!(matches!(bfu.push(c_rx).await, Some(Overflow::Evicted(_))));
         assert!(a_tx.is_closed());
         assert!(!b_tx.is_closed());
         assert!(!c_tx.is_closed());
@@ -85,8 +132,8 @@ mod test {
         assert!(!b_tx.is_closed());
 
         // Pushing two more should drop B.
-        bfu.push(d_rx);
-        bfu.push(e_rx);
+        bfu.push(d_rx).await;
+        bfu.push(e_rx).await;
         assert!(b_tx.is_closed());
         assert!(!d_tx.is_closed());
         assert!(!e_tx.is_closed());
@@ -101,4 +148,58 @@ mod test {
         assert_eq!(&res, &['D', 'E']);
         assert_eq!(None, bfu.next().await);
     }
+
+    #[tokio::test]
+    async fn drop_newest() {
+        use tokio::sync::oneshot;
+
+        let (a_tx, a_rx) = oneshot::channel::<char>();
+        let (b_tx, b_rx) = oneshot::channel();
+        let (c_tx, c_rx) = oneshot::channel();
+
+        let mut bfu = BoundedFuturesUnordered::with_strategy(2, EvictionStrategy::DropNewest);
+        assert!(bfu.push(a_rx).await.is_none());
+        assert!(bfu.push(b_rx).await.is_none());
+
+        // C is handed back instead of going in, and the others are untouched.
+        match bfu.push(c_rx).await {
+            Some(Overflow::Rejected(c)) => drop(c),
+            _ => panic!("C should have been rejected"),
+        }
+        assert!(c_tx.is_closed());
+        assert!(!a_tx.is_closed());
+        assert!(!b_tx.is_closed());
+        assert_eq!(bfu.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn block() {
+        use futures::FutureExt;
+        use tokio::sync::oneshot;
+
+        let (a_tx, a_rx) = oneshot::channel();
+        let (b_tx, b_rx) = oneshot::channel();
+        let (c_tx, c_rx) = oneshot::channel::<char>();
+
+        let mut bfu = BoundedFuturesUnordered::with_strategy(2, EvictionStrategy::Block);
+        assert!(bfu.push(a_rx).await.is_none());
+        assert!(bfu.push(b_rx).await.is_none());
+
+        // It's full, so pushing C has to wait, without dropping anything.
+        let mut push = Box::pin(bfu.push(c_rx));
+        assert!((&mut push).now_or_never().is_none());
+        assert!(!a_tx.is_closed());
+        assert!(!b_tx.is_closed());
+        assert!(!c_tx.is_closed());
+
+        // Once B finishes, C goes in, and B's output comes back.
+        b_tx.send('b').unwrap();
+        match push.await {
+            Some(Overflow::Finished(output)) => assert_eq!(output, Ok('b')),
+            _ => panic!("B should have finished"),
+        }
+        assert_eq!(bfu.len(), 2);
+        assert!(!a_tx.is_closed());
+        assert!(!c_tx.is_closed());
+    }
 }
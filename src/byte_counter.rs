@@ -0,0 +1,44 @@
+use pin_project_lite::pin_project;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::AsyncWrite;
+
+pin_project! {
+    /// A writer which keeps count of how many bytes have been written through it.
+    pub struct ByteCounter<W> {
+        #[pin]
+        inner: W,
+
+        count: u64,
+    }
+}
+
+impl<W> ByteCounter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for ByteCounter<W> {
+    fn poll_write(self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        let this = self.project();
+        let n = ready!(this.inner.poll_write(ctx, buf))?;
+        *this.count += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(ctx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(ctx)
+    }
+}
@@ -0,0 +1,79 @@
+use crate::config::Config;
+use crate::request_stream::Peer;
+use crate::response::Response;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+/// Whether the file at `path` is a script to run, rather than serve: it's somewhere under
+/// `cgi_dir`, once any symlinks are resolved.
+pub async fn is_script(path: &Path, config: &Config) -> bool {
+    let Some(cgi_dir) = &config.cgi_dir else { return false };
+    match tokio::fs::canonicalize(path).await {
+        Ok(canonical) => canonical.starts_with(cgi_dir),
+        Err(e) => {
+            warn!("can't resolve {path:?} to check if it's a script: {e}");
+            false
+        }
+    }
+}
+
+/// Run a script, and respond with whatever it writes to stdout. Anything it writes to stderr is
+/// logged. It's killed if it takes longer than `cgi_timeout_secs`.
+pub async fn run(script: &Path, selector: &str, query: Option<&str>, peer: Option<&Peer>,
+    config: &Config) -> Response
+{
+    debug!("running script {script:?}");
+    let mut command = Command::new(script);
+    command.env_clear()
+        .env("SELECTOR", selector)
+        .env("QUERY_STRING", query.unwrap_or_default())
+        .env("SERVER_NAME", &config.hostname)
+        .env("SERVER_PORT", config.port.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(path) = std::env::var_os("PATH") {
+        command.env("PATH", path);
+    }
+    if let Some(Peer::Tcp(addr)) = peer {
+        command.env("REMOTE_ADDR", addr.ip().to_string());
+    }
+    if let Some(dir) = script.parent() {
+        command.current_dir(dir);
+    }
+    let child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("error running script {script:?}: {e}");
+            return Response::Error("script failed".into());
+        }
+    };
+
+    let timeout = Duration::from_secs(config.cgi_timeout_secs);
+    let output = match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => {
+            warn!("error running script {script:?}: {e}");
+            return Response::Error("script failed".into());
+        }
+        Err(_) => {
+            // Dropping the child kills it.
+            warn!("script {script:?} timed out");
+            return Response::Error("script timed out".into());
+        }
+    };
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        warn!("script {script:?}: {line}");
+    }
+    if !output.status.success() {
+        warn!("script {script:?} failed: {}", output.status);
+        if output.stdout.is_empty() {
+            return Response::Error("script failed".into());
+        }
+    }
+    Response::Raw(output.stdout)
+}
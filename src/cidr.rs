@@ -0,0 +1,167 @@
+use serde::Deserialize;
+use std::fmt::{self, Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// A range of IP addresses, like `192.168.1.0/24` or `fd00::/8`. A bare address is a range of
+/// just that one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum CidrError {
+    #[error("invalid address {0:?} in {1:?}")]
+    Address(String, String),
+
+    #[error("invalid prefix length {0:?} in {1:?}; must be 0 to {2}")]
+    PrefixLength(String, String, u8),
+}
+
+impl Cidr {
+    /// Whether the address is in the range. IPv4 addresses mapped into IPv6 count as IPv4.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                mask(u32::from(addr).into(), 32, self.prefix_len)
+                    == mask(u32::from(net).into(), 32, self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                mask(addr.into(), 128, self.prefix_len) == mask(net.into(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Keep the top `prefix_len` bits of an address `bits` long.
+fn mask(addr: u128, bits: u8, prefix_len: u8) -> u128 {
+    match bits - prefix_len {
+        128 => 0,
+        host_bits => addr >> host_bits,
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr_str, len_str) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr = addr_str.parse::<IpAddr>()
+            .map_err(|_| CidrError::Address(addr_str.to_owned(), s.to_owned()))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match len_str {
+            None => max,
+            Some(len) => len.parse::<u8>()
+                .ok()
+                .filter(|&len| len <= max)
+                .ok_or_else(|| CidrError::PrefixLength(len.to_owned(), s.to_owned(), max))?,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = CidrError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Which client addresses may connect.
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    /// If not empty, only these are allowed.
+    pub allow: Vec<Cidr>,
+    /// These are never allowed, even if they're in `allow` too.
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(addr)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn contains(cidr: &str, addr: &str) -> bool {
+        cidr.parse::<Cidr>().unwrap().contains(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("10.0.0.0/8".parse::<Cidr>().unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!("10.1.2.3".parse::<Cidr>().unwrap().to_string(), "10.1.2.3/32");
+        assert_eq!("fd00::/8".parse::<Cidr>().unwrap().to_string(), "fd00::/8");
+        assert_eq!("::1".parse::<Cidr>().unwrap().to_string(), "::1/128");
+        assert_eq!("10.0.0/8".parse::<Cidr>(),
+            Err(CidrError::Address("10.0.0".to_owned(), "10.0.0/8".to_owned())));
+        assert_eq!("10.0.0.0/33".parse::<Cidr>(),
+            Err(CidrError::PrefixLength("33".to_owned(), "10.0.0.0/33".to_owned(), 32)));
+        assert_eq!("::/129".parse::<Cidr>(),
+            Err(CidrError::PrefixLength("129".to_owned(), "::/129".to_owned(), 128)));
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/-1".parse::<Cidr>().is_err());
+        assert!("".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn matching() {
+        assert!(contains("192.168.1.0/24", "192.168.1.255"));
+        assert!(!contains("192.168.1.0/24", "192.168.2.0"));
+        assert!(contains("192.168.1.7/24", "192.168.1.1"));
+        assert!(contains("0.0.0.0/0", "203.0.113.9"));
+        assert!(!contains("0.0.0.0/0", "2001:db8::1"));
+        assert!(contains("10.0.0.1/32", "10.0.0.1"));
+        assert!(!contains("10.0.0.1/32", "10.0.0.2"));
+        assert!(contains("10.0.0.0/8", "::ffff:10.9.8.7"));
+
+        assert!(contains("::/0", "2001:db8::1"));
+        assert!(!contains("::/0", "10.0.0.1"));
+        assert!(contains("2001:db8::/32", "2001:db8:ffff::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+        assert!(contains("2001:db8::1/128", "2001:db8::1"));
+        assert!(!contains("2001:db8::1/128", "2001:db8::2"));
+    }
+
+    #[test]
+    fn access_list() {
+        let cidrs = |list: &[&str]| list.iter().map(|s| s.parse().unwrap()).collect::<Vec<_>>();
+        let addr = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        assert!(AccessList::default().permits(addr("203.0.113.9")));
+
+        let list = AccessList {
+            allow: cidrs(&["192.168.0.0/16", "fd00::/8"]),
+            deny: cidrs(&["192.168.66.0/24"]),
+        };
+        assert!(list.permits(addr("192.168.1.1")));
+        assert!(list.permits(addr("fd12::1")));
+        assert!(!list.permits(addr("192.168.66.1")));
+        assert!(!list.permits(addr("203.0.113.9")));
+
+        let list = AccessList { allow: vec![], deny: cidrs(&["127.0.0.1"]) };
+        assert!(!list.permits(addr("127.0.0.1")));
+        assert!(list.permits(addr("127.0.0.2")));
+    }
+}
@@ -1,10 +1,1022 @@
-use serde::Deserialize;
-use std::path::PathBuf;
+use anyhow::{bail, Context, Result};
+use crate::cidr::Cidr;
+use crate::menu::MenuItemEncoder;
+use crate::selector;
+use serde::{Deserialize, Deserializer};
+use std::path::{Component, Path, PathBuf};
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
-    pub server_address: String,
+    /// Addresses to listen on. In the config file this can be a single string or a list.
+    /// Defaults to all IPv4 addresses on `port`; see `fill_defaults`.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub server_address: Vec<String>,
+
+    /// Listen on both IPv4 and IPv6, on the ports from `server_address`, ignoring their IP
+    /// addresses.
+    #[serde(default)]
+    pub bind_both: bool,
+
+    /// Set `SO_REUSEPORT` on listening TCP sockets, so several server processes can share a port.
+    #[serde(default)]
+    pub reuse_port: bool,
+
     pub document_root: PathBuf,
+
+    /// Defaults to the machine's hostname; see `fill_defaults`.
+    #[serde(default)]
     pub hostname: String,
-    pub port: u16
+
+    #[serde(default = "default_port")]
+    pub port: u16,
+
+    /// Host and port put on info and error lines, which don't point anywhere but still need them.
+    #[serde(default = "default_info_host")]
+    pub info_host: String,
+    #[serde(default = "default_info_port")]
+    pub info_port: u16,
+
+    /// Server administrator, for Gopher+ clients asking for an item's attributes.
+    pub admin_name: Option<String>,
+    pub admin_email: Option<String>,
+
+    #[serde(default)]
+    pub dir_sort: SortOrder,
+
+    #[serde(default)]
+    pub dirs_first: bool,
+
+    /// Glob patterns for file names to leave out of generated directory menus.
+    #[serde(default)]
+    pub hide_patterns: Vec<String>,
+
+    /// Info lines to put at the top of generated directory menus, in place of the default.
+    /// `{hostname}`, `{port}`, and `{selector}` in them are filled in.
+    pub menu_header: Option<Vec<String>>,
+
+    /// Info lines to put at the bottom of generated directory menus, filled in like the header.
+    pub menu_footer: Option<Vec<String>>,
+
+    /// Maximum number of entries to list in generated directory menus.
+    pub max_dir_entries: Option<usize>,
+
+    /// Longest menu item text, in characters, that old clients can be expected to show well.
+    /// Longer items are handled according to `long_item_text`.
+    pub max_item_text: Option<usize>,
+
+    #[serde(default)]
+    pub long_item_text: LongTextPolicy,
+
+    /// Show lines of menu files that can't be parsed as error items, instead of just logging
+    /// them.
+    #[serde(default)]
+    pub show_menu_errors: bool,
+
+    /// Tell GIFs from other images in generated directory menus by what's in them rather than
+    /// just their extensions. Only files with image extensions are looked at.
+    #[serde(default)]
+    pub detect_image_type: bool,
+
+    /// Show each entry's size and modification date in generated directory menus, using
+    /// `dir_listing_meta_format`.
+    #[serde(default)]
+    pub dir_listing_show_meta: bool,
+
+    /// Text for entries in generated directory menus, when `dir_listing_show_meta` is on.
+    /// `{name}`, `{size}`, and `{mtime}` in it are filled in. Directories show how many entries
+    /// they have for their size.
+    #[serde(default = "default_dir_listing_meta_format")]
+    pub dir_listing_meta_format: String,
+
+    /// How many posts to list on each page of a phlog index.
+    #[serde(default = "default_phlog_entries_per_page")]
+    pub phlog_entries_per_page: usize,
+
+    #[serde(default)]
+    pub symlink_policy: SymlinkPolicy,
+
+    /// Convert bare LF line endings in text files to CRLF when serving them.
+    #[serde(default)]
+    pub crlf_convert: bool,
+
+    /// Accepted connections waiting on reading a full request.
+    #[serde(default = "default_max_queued_requests")]
+    pub max_queued_requests: usize,
+
+    /// What to do with new connections while `max_queued_requests` are already waiting.
+    #[serde(default)]
+    pub when_queue_full: QueuePolicy,
+
+    #[serde(default = "default_max_selector_length")]
+    pub max_selector_length: usize,
+
+    /// Connections each client address can have open at once, counting IPv6 addresses in the same
+    /// /64 as one. Unlimited if unset.
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Client addresses allowed to connect. If empty, any can, unless they're in `deny_from`.
+    #[serde(default)]
+    pub allow_from: Vec<Cidr>,
+
+    /// Client addresses never allowed to connect.
+    #[serde(default)]
+    pub deny_from: Vec<Cidr>,
+
+    /// Error to send to clients that aren't allowed to connect. If unset, they're just
+    /// disconnected.
+    pub deny_message: Option<String>,
+
+    /// Expect a PROXY protocol header at the start of each non-TLS connection, giving the real
+    /// client's address, for running behind a load balancer.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+
+    /// How long a client gets to send its whole request before the connection is dropped.
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+
+    /// How long a client can go without reading any of its response before it's disconnected.
+    #[serde(default = "default_response_idle_timeout_secs")]
+    pub response_idle_timeout_secs: u64,
+
+    /// Limit on how long sending an entire response can take.
+    pub response_timeout_secs: Option<u64>,
+
+    /// Limit on how fast each response is sent, in bytes per second. Unlimited if unset.
+    pub max_bytes_per_sec: Option<usize>,
+
+    /// Gopher server (`host:port`) to pass requests on to when there's nothing local for them.
+    pub upstream: Option<String>,
+
+    /// How long to wait for the upstream server to accept the request, or to send more of its
+    /// response.
+    #[serde(default = "default_upstream_timeout_secs")]
+    pub upstream_timeout_secs: u64,
+
+    /// Directory of scripts to run, instead of serving them as files, when they're requested.
+    pub cgi_dir: Option<PathBuf>,
+
+    /// How long a script can run before it's killed.
+    #[serde(default = "default_cgi_timeout_secs")]
+    pub cgi_timeout_secs: u64,
+
+    /// User to switch to after binding the listening socket.
+    pub user: Option<String>,
+
+    /// What to log to stderr: a level like "info", or a filter like "gofer=debug".
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+
+    /// How many parsed menu files to keep around. 0 turns caching off.
+    #[serde(default = "default_menu_cache_max_entries")]
+    pub menu_cache_max_entries: u64,
+
+    /// How long to keep a parsed menu file around, even if it hasn't changed.
+    #[serde(default = "default_menu_cache_ttl_secs")]
+    pub menu_cache_ttl_secs: u64,
+
+    /// How many bytes of small files to keep in memory. 0 (the default) turns caching off.
+    #[serde(default)]
+    pub file_cache_max_bytes: u64,
+
+    /// Largest file to keep in memory, in bytes.
+    #[serde(default = "default_file_cache_max_file_size")]
+    pub file_cache_max_file_size: u64,
+
+    /// How many generated directory listings to keep around. 0 (the default) turns caching off.
+    #[serde(default)]
+    pub dir_cache_max_entries: u64,
+
+    /// How long to keep a generated directory listing around.
+    #[serde(default = "default_dir_cache_ttl_secs")]
+    pub dir_cache_ttl_secs: u64,
+
+    /// Address to serve Prometheus metrics from, over HTTP.
+    pub metrics_address: Option<String>,
+
+    /// File to append a line to for every request. If unset, these go to stderr.
+    pub access_log: Option<PathBuf>,
+
+    /// File to write the server's process ID to at startup, and remove on exit.
+    pub pid_file: Option<PathBuf>,
+
+    /// Group to switch to after binding the listening socket. Defaults to the user's primary group.
+    pub group: Option<String>,
+
+    /// Confine the server to the document root with chroot(2), after binding the listening socket
+    /// and before switching users.
+    #[serde(default)]
+    pub chroot: bool,
+
+    /// Additional directories to serve under particular selector prefixes.
+    #[serde(default)]
+    pub mounts: Vec<Mount>,
+
+    /// How long to wait for requests in progress to finish when shutting down.
+    #[serde(default = "default_shutdown_grace_secs")]
+    pub shutdown_grace_secs: u64,
+
+    /// Number of threads to handle requests on. Defaults to one per CPU.
+    pub worker_threads: Option<usize>,
+
+    /// How many responses can be in progress at once. Unlimited if unset.
+    pub max_concurrent_responses: Option<usize>,
+
+    /// What to do with requests that come in while `max_concurrent_responses` are in progress.
+    #[serde(default)]
+    pub when_busy: BusyPolicy,
+
+    /// Serve gopher over TLS as well, on a separate address.
+    pub tls: Option<TlsConfig>,
+
+    /// Limit how often each client address can make requests.
+    pub rate_limit: Option<RateLimit>,
+
+    /// TCP options for client connections.
+    #[serde(default)]
+    pub socket: SocketOptions,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// Steady rate a client can keep up.
+    pub requests_per_minute: u32,
+    /// How many requests a client can make at once, before the rate applies.
+    pub burst: u32,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct SocketOptions {
+    /// Send small writes straight away, instead of waiting to batch them up (`TCP_NODELAY`).
+    pub nodelay: bool,
+    /// How long a connection can be idle before keepalive probes are sent. Zero turns them off.
+    pub keepalive_secs: u64,
+    /// How long to wait between keepalive probes.
+    pub keepalive_interval_secs: u64,
+    /// How many keepalive probes can go unanswered before the connection is dropped. The
+    /// system's default if unset.
+    pub keepalive_retries: Option<u32>,
+    /// Size of each connection's send buffer, in bytes. The system's default if unset.
+    pub send_buffer_size: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive_secs: 60,
+            keepalive_interval_secs: 15,
+            keepalive_retries: None,
+            send_buffer_size: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub address: String,
+    /// PEM file with the certificate chain, server certificate first.
+    pub cert_path: PathBuf,
+    /// PEM file with the private key.
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Mount {
+    pub prefix: String,
+    pub document_root: PathBuf,
+}
+
+/// Overrides for directory listing settings, read from `.gofer` files in the document tree. These
+/// apply to the directory they're in, and any subdirectories without one of their own.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DirConfig {
+    pub hide_patterns: Option<Vec<String>>,
+    pub dir_sort: Option<SortOrder>,
+    pub dirs_first: Option<bool>,
+    pub menu_header: Option<Vec<String>>,
+    pub menu_footer: Option<Vec<String>>,
+    #[serde(alias = "max_dir_entries")]
+    pub max_entries: Option<usize>,
+}
+
+impl Config {
+    /// Fill in the settings whose defaults depend on other settings or the system, if they weren't
+    /// given. `system_hostname` is only called if it's needed.
+    pub fn fill_defaults(&mut self, system_hostname: impl FnOnce() -> Result<String>)
+        -> Result<()>
+    {
+        if self.server_address.is_empty() {
+            self.server_address.push(format!("0.0.0.0:{}", self.port));
+        }
+        if self.hostname.is_empty() {
+            self.hostname = system_hostname()
+                .context("couldn't look up the hostname; set it in the config instead")?;
+        }
+        Ok(())
+    }
+
+    /// Check for settings that can't work. All the problems found are reported together in the
+    /// error. If there are none, returns warnings about settings that look like mistakes.
+    pub fn validate(&self) -> Result<Vec<String>> {
+        let mut errors = vec![];
+        let mut warnings = vec![];
+
+        if !self.document_root.is_dir() {
+            errors.push(format!("document_root: {:?} is not a directory", self.document_root));
+        }
+        if self.hostname.is_empty() {
+            errors.push("hostname: must not be empty".to_owned());
+        } else if self.hostname.contains(char::is_whitespace) {
+            errors.push(format!("hostname: {:?} must not contain whitespace", self.hostname));
+        }
+        if self.port == 0 {
+            errors.push("port: must be nonzero".to_owned());
+        }
+        if self.info_host.contains(['\t', '\r', '\n']) {
+            errors.push(format!("info_host: {:?} must not contain tabs or line breaks",
+                self.info_host));
+        }
+        if self.server_address.is_empty() {
+            errors.push("server_address: must not be empty".to_owned());
+        }
+        let mut ports = vec![];
+        for address in &self.server_address {
+            if address.starts_with("unix:") {
+                continue;
+            }
+            match address.rsplit_once(':').and_then(|(_, p)| p.parse::<u16>().ok()) {
+                Some(port) => ports.push(port),
+                None => errors.push(format!("server_address: {address:?} must be an address and \
+                    port, or \"unix:\" and a path")),
+            }
+        }
+        if !ports.is_empty() && !ports.contains(&self.port) && self.port != 0 {
+            warnings.push(format!("port: {} doesn't match the port in server_address {:?}; menus \
+                will point clients at {}", self.port, self.server_address, self.port));
+        }
+        if let Some(upstream) = &self.upstream {
+            if upstream.rsplit_once(':').and_then(|(_, p)| p.parse::<u16>().ok()).is_none() {
+                errors.push(format!("upstream: {upstream:?} must be a host and port"));
+            }
+        }
+        if let Some(tls) = &self.tls {
+            if tls.address.rsplit_once(':').and_then(|(_, p)| p.parse::<u16>().ok()).is_none() {
+                errors.push(format!("tls: address {:?} must be an address and port",
+                    tls.address));
+            }
+        }
+        for mount in &self.mounts {
+            if !mount.prefix.starts_with('/') {
+                errors.push(format!("mounts: prefix {:?} must start with '/'", mount.prefix));
+            }
+            if !mount.document_root.is_dir() {
+                errors.push(format!("mounts: document_root {:?} for {:?} is not a directory",
+                    mount.document_root, mount.prefix));
+            }
+        }
+        for (name, value) in [
+            ("max_queued_requests", self.max_queued_requests as u64),
+            ("max_selector_length", self.max_selector_length as u64),
+            ("phlog_entries_per_page", self.phlog_entries_per_page as u64),
+            ("request_timeout_secs", self.request_timeout_secs),
+            ("cgi_timeout_secs", self.cgi_timeout_secs),
+            ("response_idle_timeout_secs", self.response_idle_timeout_secs),
+            ("upstream_timeout_secs", self.upstream_timeout_secs),
+            ("rate_limit.requests_per_minute",
+                self.rate_limit.map_or(1, |limit| limit.requests_per_minute.into())),
+            ("rate_limit.burst", self.rate_limit.map_or(1, |limit| limit.burst.into())),
+            ("socket.keepalive_interval_secs", match self.socket.keepalive_secs {
+                0 => 1,
+                _ => self.socket.keepalive_interval_secs,
+            }),
+        ] {
+            if value == 0 {
+                errors.push(format!("{name}: must be nonzero"));
+            }
+        }
+        for (name, value) in [
+            ("max_connections_per_ip", self.max_connections_per_ip),
+            ("worker_threads", self.worker_threads),
+            ("max_concurrent_responses", self.max_concurrent_responses),
+            ("socket.send_buffer_size", self.socket.send_buffer_size),
+            ("max_bytes_per_sec", self.max_bytes_per_sec),
+            ("max_item_text", self.max_item_text),
+        ] {
+            if value == Some(0) {
+                errors.push(format!("{name}: must be nonzero"));
+            }
+        }
+        if cfg!(not(unix)) && self.chroot {
+            errors.push("chroot: only supported on Unix".to_owned());
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log_level) {
+            errors.push(format!("log_level: {:?} is invalid: {e}", self.log_level));
+        }
+
+        if !errors.is_empty() {
+            bail!("{}", errors.join("\n"));
+        }
+        Ok(warnings)
+    }
+
+    /// Copy over settings from the running config which only take effect at startup, so a reload
+    /// doesn't pretend to change them. Returns the names of any that were different.
+    pub fn keep_startup_settings(&mut self, running: &Config) -> Vec<&'static str> {
+        let mut changed = vec![];
+        macro_rules! keep {
+            ($($field:ident),*) => {
+                $(
+                    if self.$field != running.$field {
+                        changed.push(stringify!($field));
+                        self.$field = running.$field.clone();
+                    }
+                )*
+            }
+        }
+        keep!(server_address, bind_both, reuse_port, log_level, metrics_address, access_log,
+            pid_file, user, group, chroot, menu_cache_max_entries, menu_cache_ttl_secs,
+            file_cache_max_bytes, file_cache_max_file_size, dir_cache_max_entries,
+            dir_cache_ttl_secs, cgi_dir, tls, worker_threads, rate_limit, max_concurrent_responses,
+            when_busy, when_queue_full, max_queued_requests, max_selector_length,
+            request_timeout_secs, max_connections_per_ip, socket, allow_from, deny_from,
+            deny_message, proxy_protocol, info_host, info_port);
+        changed
+    }
+
+    /// A copy of this config with a directory's overrides applied.
+    pub fn with_overrides(&self, dir: DirConfig) -> Self {
+        let mut config = self.clone();
+        if let Some(hide_patterns) = dir.hide_patterns {
+            config.hide_patterns = hide_patterns;
+        }
+        if let Some(dir_sort) = dir.dir_sort {
+            config.dir_sort = dir_sort;
+        }
+        if let Some(dirs_first) = dir.dirs_first {
+            config.dirs_first = dirs_first;
+        }
+        if let Some(menu_header) = dir.menu_header {
+            config.menu_header = Some(menu_header);
+        }
+        if let Some(menu_footer) = dir.menu_footer {
+            config.menu_footer = Some(menu_footer);
+        }
+        if let Some(max_entries) = dir.max_entries {
+            config.max_dir_entries = Some(max_entries);
+        }
+        config
+    }
+
+    /// Sort mounts longest prefix first, so the most specific one matches.
+    pub fn sort_mounts(&mut self) {
+        self.mounts.sort_by_key(|mount| std::cmp::Reverse(mount.prefix.trim_end_matches('/').len()));
+    }
+
+    /// Find the document root a selector should be resolved against, and the remainder of the
+    /// selector after stripping the mount prefix (if any) off of it.
+    ///
+    /// Mounts must already be sorted longest prefix first.
+    pub fn mount_for<'a>(&'a self, selector: &'a str) -> (&'a Path, &'a str) {
+        self.mounts.iter()
+            .find_map(|mount| {
+                let rest = selector.strip_prefix(mount.prefix.trim_end_matches('/'))?;
+                if rest.is_empty() || rest.starts_with('/') {
+                    Some((mount.document_root.as_path(), rest))
+                } else {
+                    None
+                }
+            })
+            .unwrap_or((&self.document_root, selector))
+    }
+
+    /// Find the document root a selector for a local file is resolved against, and the path it
+    /// refers to. Errors are messages for the client.
+    pub fn local_path<'a>(&'a self, selector: &'a str)
+        -> std::result::Result<(&'a Path, PathBuf), &'static str>
+    {
+        if selector.is_empty() {
+            return Ok((&self.document_root, self.document_root.clone()));
+        }
+        let (root, rest) = self.mount_for(selector);
+        let decoded = selector::decode(rest.strip_prefix('/').unwrap_or(rest))
+            .map_err(|_| "invalid selector")?;
+        let relative = Path::new(&decoded);
+        if escapes_root(relative) {
+            return Err("directory traversal denied");
+        }
+        Ok((root, root.join(relative)))
+    }
+
+    /// The encoder for menu items, with the placeholders for info and error lines.
+    pub fn menu_encoder(&self) -> MenuItemEncoder {
+        MenuItemEncoder {
+            info_host: self.info_host.clone(),
+            info_port: self.info_port,
+        }
+    }
+
+    /// Change the document roots to where they'll be after chrooting into the current one: it
+    /// becomes `/`, and mounts have to be inside it so they can be found under that.
+    ///
+    /// Paths must already be canonicalized.
+    pub fn chroot_paths(&mut self) -> Result<()> {
+        let jail = std::mem::replace(&mut self.document_root, PathBuf::from("/"));
+        for mount in &mut self.mounts {
+            let Ok(rest) = mount.document_root.strip_prefix(&jail) else {
+                bail!("document root {:?} for mount {:?} is outside of document_root {:?}, so it \
+                    can't be reached after chrooting", mount.document_root, mount.prefix, jail);
+            };
+            mount.document_root = Path::new("/").join(rest);
+        }
+        if let Some(cgi_dir) = &mut self.cgi_dir {
+            let Ok(rest) = cgi_dir.strip_prefix(&jail) else {
+                bail!("cgi_dir {cgi_dir:?} is outside of document_root {jail:?}, so it can't be \
+                    reached after chrooting");
+            };
+            *cgi_dir = Path::new("/").join(rest);
+        }
+        Ok(())
+    }
+}
+
+/// Parse the config file, without checking it.
+pub fn read(path: &Path) -> Result<Config> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file {path:?}"))?;
+    toml::from_str(&text)
+        .with_context(|| format!("error parsing config file {path:?}"))
+}
+
+/// The machine's hostname, for when the config doesn't give one.
+fn system_hostname() -> Result<String> {
+    #[cfg(unix)]
+    {
+        nix::unistd::gethostname()?
+            .into_string()
+            .map_err(|name| anyhow::anyhow!("hostname {name:?} isn't valid UTF-8"))
+    }
+    #[cfg(not(unix))]
+    {
+        std::env::var("COMPUTERNAME").context("COMPUTERNAME isn't set")
+    }
+}
+
+/// Validate a freshly read config, logging any warnings, and get it ready for use.
+pub fn prepare(mut config: Config, path: &Path) -> Result<Config> {
+    config.fill_defaults(system_hostname)?;
+    let warnings = config.validate()
+        .with_context(|| format!("invalid config file {path:?}"))?;
+    for warning in warnings {
+        warn!("{warning}");
+    }
+    // Canonicalize once up front, so symlink checks can compare against it cheaply.
+    config.document_root = config.document_root.canonicalize()
+        .with_context(|| format!("invalid document root {:?}", config.document_root))?;
+    for mount in &mut config.mounts {
+        mount.document_root = mount.document_root.canonicalize()
+            .with_context(|| format!("invalid document root {:?} for mount {:?}",
+                mount.document_root, mount.prefix))?;
+    }
+    if let Some(cgi_dir) = &mut config.cgi_dir {
+        *cgi_dir = cgi_dir.canonicalize()
+            .with_context(|| format!("invalid cgi_dir {cgi_dir:?}"))?;
+    }
+    config.sort_mounts();
+    Ok(config)
+}
+
+pub fn load(path: &Path) -> Result<Config> {
+    prepare(read(path)?, path)
+}
+
+/// Whether a path, relative to the document root, could refer to something outside of it.
+fn escapes_root(path: &Path) -> bool {
+    path.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Accept either a single string or a list of them.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D)
+    -> std::result::Result<Vec<String>, D::Error>
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+fn default_menu_cache_max_entries() -> u64 {
+    1000
+}
+
+fn default_menu_cache_ttl_secs() -> u64 {
+    300
+}
+
+fn default_file_cache_max_file_size() -> u64 {
+    64 * 1024
+}
+
+fn default_dir_cache_ttl_secs() -> u64 {
+    60
+}
+
+fn default_port() -> u16 {
+    70
+}
+
+fn default_info_host() -> String {
+    MenuItemEncoder::default().info_host
+}
+
+fn default_info_port() -> u16 {
+    MenuItemEncoder::default().info_port
+}
+
+fn default_log_level() -> String {
+    "info".to_owned()
+}
+
+fn default_max_queued_requests() -> usize {
+    50
+}
+
+fn default_dir_listing_meta_format() -> String {
+    "{name} {size} {mtime}".to_owned()
+}
+
+fn default_phlog_entries_per_page() -> usize {
+    20
+}
+
+fn default_max_selector_length() -> usize {
+    1024
+}
+
+fn default_request_timeout_secs() -> u64 {
+    30
+}
+
+fn default_cgi_timeout_secs() -> u64 {
+    30
+}
+
+fn default_upstream_timeout_secs() -> u64 {
+    10
+}
+
+fn default_shutdown_grace_secs() -> u64 {
+    30
+}
+
+fn default_response_idle_timeout_secs() -> u64 {
+    60
+}
+
+/// Order of entries in generated directory menus.
+#[derive(Debug, Deserialize, Copy, Clone, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    /// By name, case-insensitively.
+    #[default]
+    Name,
+    NameReverse,
+    /// By modification time, oldest first.
+    Modified,
+    ModifiedReverse,
+    /// By size, smallest first.
+    Size,
+    SizeReverse,
+}
+
+impl SortOrder {
+    /// Whether sorting in this order requires stat-ing each entry.
+    pub fn needs_metadata(self) -> bool {
+        !matches!(self, SortOrder::Name | SortOrder::NameReverse)
+    }
+}
+
+/// How to treat symbolic links encountered when resolving a selector.
+#[derive(Debug, Deserialize, Copy, Clone, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    /// Follow all symlinks, even ones leading outside the document root.
+    Follow,
+    /// Treat any symlink as if it doesn't exist.
+    Reject,
+    /// Follow symlinks, but only if they resolve to somewhere inside the document root.
+    #[default]
+    RejectOutsideRoot,
+}
+
+/// What to do with menu items whose text is longer than `max_item_text`.
+#[derive(Debug, Deserialize, Copy, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LongTextPolicy {
+    /// Send them as they are, and log a warning.
+    #[default]
+    Warn,
+    /// Cut them short, ending with an ellipsis.
+    Truncate,
+    /// Carry the rest of the text over onto info lines after them.
+    Wrap,
+}
+
+/// What to do with a request when the server is already sending as many responses as it can.
+#[derive(Debug, Deserialize, Copy, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BusyPolicy {
+    /// Hold off on taking requests until a response finishes. Clients wait, and new connections
+    /// queue up.
+    #[default]
+    Wait,
+    /// Tell the client the server is busy.
+    Reject,
+}
+
+/// What to do with a new connection when too many are already waiting to send their request.
+#[derive(Debug, Deserialize, Copy, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuePolicy {
+    /// Make room by telling the client that's been waiting the longest that the server is busy,
+    /// and dropping its connection.
+    #[default]
+    DropOldest,
+    /// Tell the new client that the server is busy, and drop its connection.
+    DropNewest,
+    /// Stop accepting connections until one of the waiting ones has sent its request.
+    Wait,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(root: &Path) -> Config {
+        toml::from_str(&format!(r#"
+            server_address = "127.0.0.1:7070"
+            document_root = {root:?}
+            hostname = "localhost"
+            port = 7070
+            "#)).unwrap()
+    }
+
+    fn errors(config: &Config) -> Vec<String> {
+        config.validate().unwrap_err().to_string().lines().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn minimal() {
+        let mut config: Config = toml::from_str(r#"document_root = "/srv/gopher""#).unwrap();
+        config.fill_defaults(|| Ok("gopher.example".to_owned())).unwrap();
+        assert_eq!(config.port, 70);
+        assert_eq!(config.server_address, ["0.0.0.0:70"]);
+        assert_eq!(config.hostname, "gopher.example");
+    }
+
+    #[test]
+    fn defaults_follow_port() {
+        let mut config: Config = toml::from_str(r#"
+            document_root = "/srv/gopher"
+            hostname = "localhost"
+            port = 7070
+            "#).unwrap();
+        config.fill_defaults(|| panic!("hostname was given")).unwrap();
+        assert_eq!(config.server_address, ["0.0.0.0:7070"]);
+        assert_eq!(config.hostname, "localhost");
+    }
+
+    #[test]
+    fn hostname_lookup_fails() {
+        let mut config: Config = toml::from_str(r#"document_root = "/srv/gopher""#).unwrap();
+        config.fill_defaults(|| bail!("no hostname")).unwrap_err();
+    }
+
+    #[test]
+    fn valid() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(config(dir.path()).validate().unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_document_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        assert!(errors(&config(&dir.path().join("missing")))[0].starts_with("document_root: "));
+        assert!(errors(&config(&file))[0].starts_with("document_root: "));
+    }
+
+    #[test]
+    fn bad_hostname() {
+        let dir = tempfile::tempdir().unwrap();
+        for hostname in ["", "local host", "local\thost"] {
+            let config = Config { hostname: hostname.to_owned(), ..config(dir.path()) };
+            assert!(errors(&config)[0].starts_with("hostname: "), "{hostname:?}");
+        }
+    }
+
+    #[test]
+    fn bad_info_host() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { info_host: "fake\thost".to_owned(), ..config(dir.path()) };
+        assert!(errors(&config)[0].starts_with("info_host: "));
+    }
+
+    #[test]
+    fn zero_port() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { port: 0, ..config(dir.path()) };
+        assert_eq!(errors(&config), ["port: must be nonzero"]);
+    }
+
+    #[test]
+    fn mismatched_port() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { port: 70, ..config(dir.path()) };
+        let warnings = config.validate().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("port: 70 doesn't match"));
+
+        // Nothing to compare against for a Unix socket.
+        let config = Config { server_address: vec!["unix:/tmp/gofer.sock".to_owned()], ..config };
+        assert!(config.validate().unwrap().is_empty());
+
+        // Listening on an extra port is fine, as long as one of them matches.
+        let config = Config {
+            server_address: vec!["0.0.0.0:70".to_owned(), "127.0.0.1:7070".to_owned()],
+            ..config
+        };
+        assert!(config.validate().unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_server_address() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { server_address: vec!["localhost".to_owned()], ..config(dir.path()) };
+        assert!(errors(&config)[0].starts_with("server_address: "));
+        let config = Config { server_address: vec![], ..config };
+        assert_eq!(errors(&config), ["server_address: must not be empty"]);
+    }
+
+    #[test]
+    fn server_address_list() {
+        let config: Config = toml::from_str(r#"
+            document_root = "/srv/gopher"
+            server_address = ["0.0.0.0:70", "[::]:70"]
+            "#).unwrap();
+        assert_eq!(config.server_address, ["0.0.0.0:70", "[::]:70"]);
+    }
+
+    #[test]
+    fn zero_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_queued_requests: 0,
+            max_connections_per_ip: Some(0),
+            request_timeout_secs: 0,
+            cgi_timeout_secs: 0,
+            worker_threads: Some(0),
+            max_concurrent_responses: Some(0),
+            max_bytes_per_sec: Some(0),
+            max_item_text: Some(0),
+            socket: SocketOptions { keepalive_interval_secs: 0, ..SocketOptions::default() },
+            ..config(dir.path())
+        };
+        assert_eq!(errors(&config), [
+            "max_queued_requests: must be nonzero",
+            "request_timeout_secs: must be nonzero",
+            "cgi_timeout_secs: must be nonzero",
+            "socket.keepalive_interval_secs: must be nonzero",
+            "max_connections_per_ip: must be nonzero",
+            "worker_threads: must be nonzero",
+            "max_concurrent_responses: must be nonzero",
+            "max_bytes_per_sec: must be nonzero",
+            "max_item_text: must be nonzero",
+        ]);
+    }
+
+    #[test]
+    fn bad_mount() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config(dir.path());
+        config.mounts.push(Mount {
+            prefix: "docs".to_owned(),
+            document_root: dir.path().join("missing"),
+        });
+        let errors = errors(&config);
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|e| e.starts_with("mounts: ")));
+    }
+
+    #[test]
+    fn chroot_paths() {
+        let mut inside = config(Path::new("/srv/gopher"));
+        inside.mounts.push(Mount {
+            prefix: "/docs".to_owned(),
+            document_root: "/srv/gopher/shared/docs".into(),
+        });
+        inside.mounts.push(Mount {
+            prefix: "/all".to_owned(),
+            document_root: "/srv/gopher".into(),
+        });
+        inside.cgi_dir = Some("/srv/gopher/cgi-bin".into());
+        inside.chroot_paths().unwrap();
+        assert_eq!(inside.document_root, Path::new("/"));
+        assert_eq!(inside.cgi_dir.as_deref(), Some(Path::new("/cgi-bin")));
+        assert_eq!(inside.mounts[0].document_root, Path::new("/shared/docs"));
+        assert_eq!(inside.mounts[1].document_root, Path::new("/"));
+        assert_eq!(inside.mount_for("/docs/a.txt"), (Path::new("/shared/docs"), "/a.txt"));
+
+        let mut outside = config(Path::new("/srv/gopher"));
+        outside.mounts.push(Mount { prefix: "/other".to_owned(), document_root: "/srv/x".into() });
+        let e = outside.chroot_paths().unwrap_err();
+        assert!(e.to_string().contains("is outside of document_root"), "{e}");
+
+        let mut outside = config(Path::new("/srv/gopher"));
+        outside.cgi_dir = Some("/usr/lib/cgi-bin".into());
+        let e = outside.chroot_paths().unwrap_err();
+        assert!(e.to_string().contains("is outside of document_root"), "{e}");
+    }
+
+    #[test]
+    fn bad_cidr() {
+        let parse = |list: &str| toml::from_str::<Config>(&format!(r#"
+            document_root = "/srv/gopher"
+            allow_from = {list}
+            "#));
+        let config = parse(r#"["10.0.0.0/8", "fd00::/8", "192.0.2.1"]"#).unwrap();
+        assert_eq!(config.allow_from.len(), 3);
+        let e = parse(r#"["10.0.0.0/8", "10.0.0/8"]"#).unwrap_err();
+        assert!(e.to_string().contains(r#"invalid address "10.0.0" in "10.0.0/8""#), "{e}");
+        let e = parse(r#"["fd00::/200"]"#).unwrap_err();
+        assert!(e.to_string().contains("invalid prefix length"), "{e}");
+    }
+
+    #[test]
+    fn bad_log_level() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { log_level: "gofer=loud".to_owned(), ..config(dir.path()) };
+        assert!(errors(&config)[0].starts_with("log_level: "));
+    }
+
+    #[test]
+    fn all_errors_reported() {
+        let config = Config {
+            hostname: String::new(),
+            port: 0,
+            ..config(Path::new("/nonexistent"))
+        };
+        let errors = errors(&config);
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].starts_with("document_root: "));
+        assert!(errors[1].starts_with("hostname: "));
+        assert!(errors[2].starts_with("port: "));
+    }
+
+    #[test]
+    fn startup_settings_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let running = config(dir.path());
+        let mut new = Config {
+            max_queued_requests: running.max_queued_requests + 1,
+            request_timeout_secs: running.request_timeout_secs + 1,
+            max_connections_per_ip: Some(1),
+            info_port: running.info_port + 1,
+            show_menu_errors: !running.show_menu_errors,
+            ..config(dir.path())
+        };
+        assert_eq!(new.keep_startup_settings(&running),
+            ["max_queued_requests", "request_timeout_secs", "max_connections_per_ip", "info_port"]);
+        assert_eq!(new.max_queued_requests, running.max_queued_requests);
+        assert_eq!(new.request_timeout_secs, running.request_timeout_secs);
+        assert_eq!(new.max_connections_per_ip, running.max_connections_per_ip);
+        assert_eq!(new.info_port, running.info_port);
+        assert_eq!(new.show_menu_errors, !running.show_menu_errors);
+    }
+
+    #[test]
+    fn traversal() {
+        assert!(escapes_root(Path::new("..")));
+        assert!(escapes_root(Path::new("../etc/passwd")));
+        assert!(escapes_root(Path::new("foo/../../etc/passwd")));
+        assert!(escapes_root(Path::new("foo/bar/..")));
+        assert!(escapes_root(Path::new("/etc/passwd")));
+        assert!(!escapes_root(Path::new("")));
+        assert!(!escapes_root(Path::new("foo/bar")));
+        assert!(!escapes_root(Path::new("foo//./bar/")));
+        assert!(!escapes_root(Path::new("foo/..bar")));
+    }
 }
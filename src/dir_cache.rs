@@ -0,0 +1,332 @@
+use crate::config::{Config, SortOrder};
+use crate::menu::MenuItem;
+use crate::menu_cache::Dependency;
+use futures::stream::{self, StreamExt};
+use moka::future::Cache;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+
+/// Generated directory listings, so big directories don't have to be read, and every entry in
+/// them looked at, for every request. Listings expire after a while, or, with the `notify`
+/// feature, as soon as anything in their directory changes. They're also made again if a file
+/// outside the directory they were made from, like a subdirectory's menu file giving its title,
+/// has changed. Clones share the same cache.
+#[derive(Clone, Default)]
+pub struct DirCache {
+    /// `None` if caching is turned off.
+    cache: Option<Cache<Key, Arc<Listing>>>,
+    #[cfg(feature = "notify")]
+    watcher: Option<Arc<watch::Watcher>>,
+}
+
+/// Everything a listing depends on besides what's in the directory: where it is, and the settings
+/// that go into generating it, so a reloaded config is never served stale listings.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+struct Key {
+    path: PathBuf,
+    selector: String,
+    hostname: String,
+    port: u16,
+    hide_patterns: Vec<String>,
+    dir_sort: SortOrder,
+    dirs_first: bool,
+    menu_header: Option<Vec<String>>,
+    menu_footer: Option<Vec<String>>,
+    max_dir_entries: Option<usize>,
+    show_meta: bool,
+    meta_format: String,
+    detect_image_type: bool,
+}
+
+/// A generated listing, and the files outside its directory it was made from.
+struct Listing {
+    items: Arc<Vec<MenuItem>>,
+    dependencies: Vec<Dependency>,
+}
+
+impl Key {
+    fn new(path: &Path, selector: &str, config: &Config) -> Self {
+        Self {
+            path: path.to_owned(),
+            selector: selector.to_owned(),
+            hostname: config.hostname.clone(),
+            port: config.port,
+            hide_patterns: config.hide_patterns.clone(),
+            dir_sort: config.dir_sort,
+            dirs_first: config.dirs_first,
+            menu_header: config.menu_header.clone(),
+            menu_footer: config.menu_footer.clone(),
+            max_dir_entries: config.max_dir_entries,
+            show_meta: config.dir_listing_show_meta,
+            meta_format: config.dir_listing_meta_format.clone(),
+            detect_image_type: config.detect_image_type,
+        }
+    }
+}
+
+impl DirCache {
+    /// A cache holding up to `max_entries` listings, each for up to `ttl`. If `max_entries` is
+    /// 0, nothing is cached.
+    pub fn new(max_entries: u64, ttl: Duration) -> Self {
+        #[cfg(feature = "notify")]
+        if max_entries > 0 {
+            return watch::new_cache(max_entries, ttl);
+        }
+        let cache = (max_entries > 0).then(|| Cache::builder()
+            .max_capacity(max_entries)
+            .time_to_live(ttl)
+            .build());
+        Self {
+            cache,
+            #[cfg(feature = "notify")]
+            watcher: None,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.dir_cache_max_entries, Duration::from_secs(config.dir_cache_ttl_secs))
+    }
+
+    /// Get the listing of the directory at `path`, requested as `selector`, from the cache or
+    /// else from `load`, which also returns the files outside the directory that the listing was
+    /// made from. It's made again if any of them have changed. Requests for a listing that's
+    /// being loaded wait for it, rather than loading it again. Errors aren't cached.
+    pub async fn get<F: Future<Output = io::Result<(Vec<MenuItem>, Vec<Dependency>)>>>(
+        &self,
+        path: &Path,
+        selector: &str,
+        config: &Config,
+        load: impl FnOnce() -> F,
+    ) -> io::Result<Arc<Vec<MenuItem>>> {
+        let Some(cache) = &self.cache else {
+            return load().await.map(|(items, _)| Arc::new(items));
+        };
+        let key = Key::new(path, selector, config);
+        if let Some(listing) = cache.get(&key).await {
+            if !stream::iter(&listing.dependencies).any(Dependency::changed).await {
+                return Ok(listing.items.clone());
+            }
+            debug!("not using cached listing of {path:?}: a file it was made from has changed");
+            cache.invalidate(&key).await;
+        }
+        let init = async {
+            // Watch first, so changes made while it's loading aren't missed.
+            #[cfg(feature = "notify")]
+            if let Some(watcher) = &self.watcher {
+                watcher.watch(path);
+            }
+            let result = load().await;
+            #[cfg(feature = "notify")]
+            if let (Err(_), Some(watcher)) = (&result, &self.watcher) {
+                watcher.unwatch(path);
+            }
+            result.map(|(items, dependencies)| {
+                Arc::new(Listing { items: Arc::new(items), dependencies })
+            })
+        };
+        cache.entry(key)
+            .or_try_insert_with(init)
+            .await
+            .map(|entry| entry.into_value().items.clone())
+            .map_err(|e| Arc::try_unwrap(e)
+                .unwrap_or_else(|e| io::Error::new(e.kind(), e.to_string())))
+    }
+}
+
+#[cfg(feature = "notify")]
+mod watch {
+    use super::{DirCache, Key};
+    use moka::future::Cache;
+    use moka::notification::RemovalCause;
+    use notify::{EventKind, RecursiveMode, Watcher as _};
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex, Weak};
+    use std::time::Duration;
+    use tracing::{debug, warn};
+
+    /// Watches the directories with cached listings, and drops their listings when they change.
+    pub struct Watcher {
+        state: Mutex<State>,
+    }
+
+    struct State {
+        /// `None` if the watcher couldn't be started, in which case listings just expire.
+        inner: Option<notify::RecommendedWatcher>,
+        /// How many cached listings there are of each watched directory.
+        watched: HashMap<PathBuf, usize>,
+    }
+
+    pub fn new_cache(max_entries: u64, ttl: Duration) -> DirCache {
+        let watcher = Arc::new(Watcher {
+            state: Mutex::new(State { inner: None, watched: HashMap::new() }),
+        });
+        // The watcher holds on to the cache, to drop listings from it, so the cache only gets a
+        // weak reference back, to stop watching directories once their listings are gone.
+        let weak = Arc::downgrade(&watcher);
+        let cache = Cache::builder()
+            .max_capacity(max_entries)
+            .time_to_live(ttl)
+            .support_invalidation_closures()
+            .eviction_listener(move |key: Arc<Key>, _, cause| {
+                if cause != RemovalCause::Replaced {
+                    if let Some(watcher) = Weak::upgrade(&weak) {
+                        watcher.unwatch(&key.path);
+                    }
+                }
+            })
+            .build();
+
+        let listings = cache.clone();
+        let result = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+            let event = match result {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("error watching directories: {e}");
+                    return;
+                }
+            };
+            if matches!(event.kind, EventKind::Access(_)) {
+                return;
+            }
+            // Anything changing in a directory changes its listing, and a directory changing
+            // itself can change its parent's.
+            let dirs = event.paths.iter()
+                .flat_map(|path| [Some(path.as_path()), path.parent()])
+                .flatten()
+                .map(Path::to_owned)
+                .collect::<Vec<_>>();
+            debug!("dropping cached listings of {dirs:?}");
+            if let Err(e) = listings.invalidate_entries_if(move |key, _| dirs.contains(&key.path)) {
+                warn!("error dropping cached listings: {e}");
+            }
+        });
+        match result {
+            Ok(inner) => watcher.state.lock().unwrap().inner = Some(inner),
+            Err(e) => warn!("can't watch directories for changes; listings will just expire: {e}"),
+        }
+        DirCache { cache: Some(cache), watcher: Some(watcher) }
+    }
+
+    impl Watcher {
+        pub fn watch(&self, path: &Path) {
+            let mut state = self.state.lock().unwrap();
+            let State { inner: Some(inner), watched } = &mut *state else { return };
+            let count = watched.entry(path.to_owned()).or_default();
+            *count += 1;
+            if *count == 1 {
+                if let Err(e) = inner.watch(path, RecursiveMode::NonRecursive) {
+                    warn!("can't watch {path:?} for changes: {e}");
+                }
+            }
+        }
+
+        pub fn unwatch(&self, path: &Path) {
+            let mut state = self.state.lock().unwrap();
+            let State { inner: Some(inner), watched } = &mut *state else { return };
+            let Some(count) = watched.get_mut(path) else { return };
+            *count -= 1;
+            if *count == 0 {
+                watched.remove(path);
+                // This fails if the directory is gone, which stops it being watched anyway.
+                let _ = inner.unwatch(path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn config(root: &Path) -> Config {
+        toml::from_str(&format!("document_root = {root:?}\nhostname = \"localhost\"")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn expires() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let cache = DirCache::new(10, Duration::from_millis(200));
+        let loads = AtomicUsize::new(0);
+        let get = |config| cache.get(dir.path(), "/", config, || async {
+            loads.fetch_add(1, Ordering::SeqCst);
+            Ok((vec![MenuItem::info("hi")], vec![]))
+        });
+
+        assert_eq!(get(&config).await.unwrap()[0].text, "hi");
+        get(&config).await.unwrap();
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        // A different config needs its own listing.
+        let other = Config { port: 7071, ..config.clone() };
+        get(&other).await.unwrap();
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        get(&config).await.unwrap();
+        assert_eq!(loads.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn coalesces() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let cache = DirCache::new(10, Duration::from_secs(60));
+        let loads = AtomicUsize::new(0);
+        let get = || cache.get(dir.path(), "/", &config, || async {
+            loads.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok((vec![], vec![]))
+        });
+        futures::future::join_all((0 .. 10).map(|_| get())).await;
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn errors_not_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let cache = DirCache::new(10, Duration::from_secs(60));
+        let err = cache.get(dir.path(), "/", &config, || async {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        }).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let items = cache.get(dir.path(), "/", &config, || async { Ok((vec![], vec![])) }).await
+            .unwrap();
+        assert!(items.is_empty());
+    }
+
+    #[cfg(feature = "notify")]
+    #[tokio::test]
+    async fn dropped_on_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().canonicalize().unwrap();
+        let config = config(&path);
+        let cache = DirCache::new(10, Duration::from_secs(60));
+        let loads = AtomicUsize::new(0);
+        let get = || cache.get(&path, "/", &config, || async {
+            loads.fetch_add(1, Ordering::SeqCst);
+            Ok((vec![], vec![]))
+        });
+        get().await.unwrap();
+        get().await.unwrap();
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        std::fs::write(path.join("new"), "").unwrap();
+        // The watcher sees the change on its own thread.
+        for _ in 0 .. 100 {
+            get().await.unwrap();
+            if loads.load(Ordering::SeqCst) == 2 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("listing wasn't dropped after the directory changed");
+    }
+}
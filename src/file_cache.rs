@@ -0,0 +1,206 @@
+use crate::config::{Config, SymlinkPolicy};
+use crate::text::TextEncoder;
+use bytes::{Bytes, BytesMut};
+use moka::future::Cache;
+use moka::policy::EvictionPolicy;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::fs::File;
+use tokio::io::{self, AsyncReadExt};
+use tokio_util::codec::Encoder;
+use tracing::debug;
+
+/// The contents of small files, ready to send, so popular ones don't have to be opened and read
+/// for every request. Clones share the same cache.
+#[derive(Clone, Default)]
+pub struct FileCache {
+    /// `None` if caching is turned off.
+    cache: Option<Cache<Key, Entry>>,
+    max_file_size: u64,
+}
+
+/// Everything a cached response depends on, besides the file itself.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+struct Key {
+    path: PathBuf,
+    /// A file that was found under one policy might not be under another.
+    symlinks: SymlinkPolicy,
+    /// Whether the file is sent as text, with CRLF line endings if this is set.
+    text: Option<bool>,
+}
+
+/// A cached response, and what the file looked like when it was read, to tell if it's changed.
+#[derive(Clone)]
+struct Entry {
+    modified: SystemTime,
+    size: u64,
+    bytes: Bytes,
+}
+
+impl FileCache {
+    /// A cache holding up to `max_bytes` of responses, for files up to `max_file_size` each. The
+    /// least recently used ones are evicted first. If `max_bytes` is 0, nothing is cached.
+    pub fn new(max_bytes: u64, max_file_size: u64) -> Self {
+        let cache = (max_bytes > 0).then(|| Cache::builder()
+            .max_capacity(max_bytes)
+            .weigher(|_key, entry: &Entry| entry.bytes.len().try_into().unwrap_or(u32::MAX))
+            .eviction_policy(EvictionPolicy::lru())
+            .build());
+        Self { cache, max_file_size }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.file_cache_max_bytes, config.file_cache_max_file_size)
+    }
+
+    /// Get the response for the file at `path`, which is open as `file`, if it's cached and hasn't
+    /// changed since. `text` says whether it's a text file. It has to have been looked up and
+    /// found to be a file to serve first, so ones that have since been hidden, made unreadable, or
+    /// turned into scripts aren't served from here.
+    pub async fn get(&self, path: &Path, file: &File, text: bool, config: &Config)
+        -> Option<Bytes>
+    {
+        let cache = self.cache.as_ref()?;
+        let key = key(path, text, config);
+        let entry = cache.get(&key).await?;
+        let meta = file.metadata().await.ok()?;
+        if meta.modified().ok() == Some(entry.modified) && meta.len() == entry.size {
+            return Some(entry.bytes);
+        }
+        debug!("{path:?} changed; dropping it from the cache");
+        cache.invalidate(&key).await;
+        None
+    }
+
+    /// Read the file at `path`, which is open as `file`, and cache the response, if it's small
+    /// enough. Returns `None`, without reading anything, if it isn't.
+    pub async fn insert(&self, path: &Path, file: &mut File, text: bool, config: &Config)
+        -> io::Result<Option<Bytes>>
+    {
+        let Some(cache) = &self.cache else { return Ok(None) };
+        let meta = file.metadata().await?;
+        if meta.len() > self.max_file_size {
+            return Ok(None);
+        }
+        let Ok(modified) = meta.modified() else { return Ok(None) };
+        let mut contents = Vec::with_capacity(meta.len() as usize);
+        file.read_to_end(&mut contents).await?;
+        let bytes = if text {
+            let mut encoder = TextEncoder::new(config.crlf_convert);
+            let mut out = BytesMut::new();
+            encoder.encode(Bytes::from(contents), &mut out)?;
+            encoder.finish(&mut out);
+            out.freeze()
+        } else {
+            Bytes::from(contents)
+        };
+        let entry = Entry { modified, size: meta.len(), bytes: bytes.clone() };
+        cache.insert(key(path, text, config), entry).await;
+        Ok(Some(bytes))
+    }
+}
+
+fn key(path: &Path, text: bool, config: &Config) -> Key {
+    Key {
+        path: path.to_owned(),
+        symlinks: config.symlink_policy,
+        text: text.then_some(config.crlf_convert),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn config(root: &Path) -> Config {
+        toml::from_str(&format!("document_root = {root:?}")).unwrap()
+    }
+
+    async fn fetch(cache: &FileCache, path: &Path, config: &Config) -> (Bytes, bool) {
+        let mut file = File::open(path).await.unwrap();
+        if let Some(bytes) = cache.get(path, &file, false, config).await {
+            return (bytes, true);
+        }
+        (cache.insert(path, &mut file, false, config).await.unwrap().unwrap(), false)
+    }
+
+    #[tokio::test]
+    async fn cached_until_modified() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, "hello").unwrap();
+        let config = config(dir.path());
+        let cache = FileCache::new(1024, 100);
+
+        assert_eq!(fetch(&cache, &path, &config).await, (Bytes::from("hello"), false));
+        assert_eq!(fetch(&cache, &path, &config).await, (Bytes::from("hello"), true));
+
+        std::fs::write(&path, "changed").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(fetch(&cache, &path, &config).await, (Bytes::from("changed"), false));
+        assert_eq!(fetch(&cache, &path, &config).await, (Bytes::from("changed"), true));
+    }
+
+    #[tokio::test]
+    async fn text_is_encoded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        std::fs::write(&path, "one\n.two\n").unwrap();
+        let config = Config { crlf_convert: true, ..config(dir.path()) };
+        let cache = FileCache::new(1024, 100);
+
+        let mut file = File::open(&path).await.unwrap();
+        let bytes = cache.insert(&path, &mut file, true, &config).await.unwrap().unwrap();
+        assert_eq!(bytes, "one\r\n..two\r\n.\r\n");
+        assert_eq!(cache.get(&path, &file, true, &config).await.unwrap(), bytes);
+        // The same file sent as-is, or with other line endings, is cached separately.
+        assert!(cache.get(&path, &file, false, &config).await.is_none());
+        let config = Config { crlf_convert: false, ..config };
+        assert!(cache.get(&path, &file, true, &config).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let cache = FileCache::new(10, 10);
+        let path = |name: &str| dir.path().join(name);
+        for name in ["a", "b", "c"] {
+            std::fs::write(path(name), "12345").unwrap();
+        }
+        std::fs::write(path("big"), "12345678901").unwrap();
+
+        // Too big to cache at all.
+        let mut file = File::open(path("big")).await.unwrap();
+        assert!(cache.insert(&path("big"), &mut file, false, &config).await.unwrap().is_none());
+
+        // The cache applies reads and writes to its eviction order in batches, so this makes sure
+        // each one has been applied before going on.
+        let settle = || cache.cache.as_ref().unwrap().run_pending_tasks();
+        fetch(&cache, &path("a"), &config).await;
+        settle().await;
+        fetch(&cache, &path("b"), &config).await;
+        settle().await;
+        // Using A makes B the least recently used, so it's the one to go when C comes in.
+        assert!(fetch(&cache, &path("a"), &config).await.1);
+        settle().await;
+        fetch(&cache, &path("c"), &config).await;
+        settle().await;
+        assert!(fetch(&cache, &path("a"), &config).await.1);
+        assert!(!fetch(&cache, &path("b"), &config).await.1);
+    }
+
+    #[tokio::test]
+    async fn disabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, "hello").unwrap();
+        let config = config(dir.path());
+        let cache = FileCache::from_config(&config);
+        let mut file = File::open(&path).await.unwrap();
+        assert!(cache.insert(&path, &mut file, false, &config).await.unwrap().is_none());
+        assert!(cache.get(&path, &file, false, &config).await.is_none());
+    }
+}
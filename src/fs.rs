@@ -1,36 +1,530 @@
+use crate::config::{DirConfig, SymlinkPolicy};
+use futures::{future, StreamExt};
+use std::ffi::{OsStr, OsString};
+use std::future::Future;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use tokio::fs::{self, File};
 use tokio::io;
+use tokio_stream::wrappers::ReadDirStream;
+use tracing::{info, warn};
 
-pub use tokio::fs::{read_dir, DirEntry};
+/// Which dialect a menu file is written in. This is determined by the file's name.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MenuFormat {
+    /// Gofer's own `!menu` format.
+    Menu,
+    /// Bucktooth-style `gophermap`.
+    Gophermap,
+}
+
+/// Menu file names to look for in a directory, in order of preference.
+pub const MENU_FILES: &[(&str, MenuFormat)] = &[
+    ("!menu", MenuFormat::Menu),
+    ("gophermap", MenuFormat::Gophermap),
+];
+
+/// Optional menu files merged into the top and bottom of generated directory menus.
+pub const HEADER_FILE: &str = "!header";
+pub const FOOTER_FILE: &str = "!footer";
+
+/// Menu served in place of a plain error when a selector isn't found.
+pub const NOT_FOUND_FILE: &str = "!404";
+
+/// Marks a directory as a phlog, whose index is generated from its dated entries.
+pub const PHLOG_FILE: &str = "!phlog";
+
+/// Per-directory config overrides.
+pub const DIR_CONFIG_FILE: &str = ".gofer";
+
+/// How many levels up from a directory to look for a `.gofer` file.
+const MAX_DIR_CONFIG_DEPTH: usize = 32;
+
+/// Whether a file name is one of the special files above, which shouldn't be listed in generated
+/// menus.
+pub fn is_special_file(name: &OsStr) -> bool {
+    [HEADER_FILE, FOOTER_FILE, NOT_FOUND_FILE, PHLOG_FILE, DIR_CONFIG_FILE]
+        .iter()
+        .any(|special| name == *special)
+}
+
+/// Find the nearest `.gofer` file in `dir` or its parents, stopping at `root`, and parse it.
+/// Errors are logged, and treated as if there was no file.
+pub async fn load_dir_config(dir: &Path, root: &Path) -> Option<DirConfig> {
+    let mut dir = dir;
+    for _ in 0 .. MAX_DIR_CONFIG_DEPTH {
+        let path = dir.join(DIR_CONFIG_FILE);
+        match fs::read_to_string(&path).await {
+            Ok(text) => {
+                return match toml::from_str(&text) {
+                    Ok(config) => Some(config),
+                    Err(e) => {
+                        warn!("error parsing {path:?}: {e}");
+                        None
+                    }
+                };
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                warn!("error reading {path:?}: {e}");
+                return None;
+            }
+        }
+        if dir == root {
+            break;
+        }
+        dir = dir.parent()?;
+    }
+    None
+}
 
 #[derive(Debug)]
 pub enum FileType {
     Directory,
-    Menu { file: File, path: PathBuf },
+    Menu { file: File, path: PathBuf, format: MenuFormat },
+    /// A directory with a `!phlog` file; the file is the one given.
+    Phlog(File),
     File(File),
     NotFound,
+    /// Something at the path, or a menu file in it, can't be read by the server.
+    PermissionDenied,
+}
+
+/// Add the path to an error's message, keeping its kind.
+fn with_path(path: &Path) -> impl FnOnce(io::Error) -> io::Error + '_ {
+    move |e| io::Error::new(e.kind(), format!("{path:?}: {e}"))
+}
+
+/// Open a file, or return `None` if it doesn't exist.
+async fn open_if_exists(path: &Path) -> io::Result<Option<File>> {
+    match File::open(path).await {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Up to the first `len` bytes of an open file, leaving it to be read again from the start.
+pub async fn read_start(file: &mut File, len: usize) -> io::Result<Vec<u8>> {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+    let mut start = Vec::with_capacity(len);
+    (&mut *file).take(len as u64).read_to_end(&mut start).await?;
+    file.rewind().await?;
+    Ok(start)
 }
 
-fn map_not_found(r: io::Result<FileType>, not_found: FileType) -> io::Result<FileType> {
-    match r {
-        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(not_found),
-        _ => r,
+/// Open a special file, like a menu file or `!header`, at `path` inside `root`, or return `None`
+/// if it doesn't exist. The symlink policy applies to it like to any other file, and it counts as
+/// not existing if the policy rejects it.
+pub async fn open_special(path: &Path, root: &Path, symlinks: SymlinkPolicy)
+    -> io::Result<Option<File>>
+{
+    match allowed(path, root, symlinks).await {
+        Ok(true) => open_if_exists(path).await,
+        Ok(false) => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether the symlink policy allows what's at `path`, inside `root`, to be served.
+async fn allowed(path: &Path, root: &Path, symlinks: SymlinkPolicy) -> io::Result<bool> {
+    match symlinks {
+        SymlinkPolicy::Follow => (),
+        SymlinkPolicy::Reject => {
+            if has_symlink(path, root).await? {
+                info!("rejecting symlink {path:?}");
+                return Ok(false);
+            }
+        }
+        SymlinkPolicy::RejectOutsideRoot => {
+            let canonical = fs::canonicalize(path).await?;
+            if !canonical.starts_with(root) {
+                info!("rejecting {path:?}: resolves to {canonical:?}, outside the document root");
+                return Ok(false);
+            }
+        }
     }
+    Ok(true)
 }
 
-pub async fn lookup(path: &Path) -> io::Result<FileType> {
-    async fn inner(path: &Path) -> io::Result<FileType> {
+/// Whether any component of `path` below `root` is a symlink.
+async fn has_symlink(path: &Path, root: &Path) -> io::Result<bool> {
+    let mut current = root.to_owned();
+    for component in path.strip_prefix(root).unwrap_or(path).components() {
+        current.push(component);
+        if fs::symlink_metadata(&current).await?.is_symlink() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Look up what's at the given path, which must be inside `root`. The root must already be
+/// canonicalized.
+pub async fn lookup(path: &Path, root: &Path, symlinks: SymlinkPolicy) -> io::Result<FileType> {
+    async fn inner(path: &Path, root: &Path, symlinks: SymlinkPolicy) -> io::Result<FileType> {
+        if !allowed(path, root, symlinks).await? {
+            return Ok(FileType::NotFound);
+        }
         let meta = fs::metadata(path).await?;
         if meta.is_dir() {
-            let menu_path = path.join("!menu");
-            match File::open(&menu_path).await {
-                Ok(file) => Ok(FileType::Menu { file, path: menu_path }),
-                Err(e) => map_not_found(Err(e), FileType::Directory),
+            for (name, format) in MENU_FILES {
+                let menu_path = path.join(name);
+                let menu = open_special(&menu_path, root, symlinks).await
+                    .map_err(with_path(&menu_path))?;
+                if let Some(file) = menu {
+                    return Ok(FileType::Menu { file, path: menu_path, format: *format });
+                }
+            }
+            let phlog_path = path.join(PHLOG_FILE);
+            let phlog = open_special(&phlog_path, root, symlinks).await
+                .map_err(with_path(&phlog_path))?;
+            if let Some(file) = phlog {
+                return Ok(FileType::Phlog(file));
             }
+            Ok(FileType::Directory)
         } else {
             Ok(FileType::File(File::open(path).await?))
         }
     }
-    map_not_found(inner(path).await, FileType::NotFound)
+    match inner(path, root, symlinks).await {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(FileType::NotFound),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            warn!("permission denied looking up {path:?}: {e}");
+            Ok(FileType::PermissionDenied)
+        }
+        r => r,
+    }
+}
+
+/// An entry of a directory.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub file_name: OsString,
+    pub is_dir: bool,
+    /// Only filled in if metadata was asked for.
+    pub modified: Option<SystemTime>,
+    /// Only filled in if metadata was asked for.
+    pub size: u64,
+}
+
+/// Where requests are looked up: the real filesystem, except in tests.
+///
+/// Only what a request is for, and the entries of directories, come from here. Files that affect
+/// how things are served, like `.gofer` and `!header`, and anything a menu file includes or
+/// globs, are always read from the real filesystem.
+pub trait FileSystemProvider: Sync {
+    /// Look up what's at the given path, which must be inside `root`, like [`lookup`].
+    fn lookup(&self, path: &Path, root: &Path, symlinks: SymlinkPolicy)
+        -> impl Future<Output = io::Result<FileType>> + Send;
+
+    /// The entries of the directory at `path`, with their modification times and sizes if
+    /// `metadata` is set. Entries that can't be looked at are left out.
+    fn read_dir(&self, path: &Path, metadata: bool)
+        -> impl Future<Output = io::Result<Vec<DirEntry>>> + Send;
+
+    /// When what's at the given path, which must be inside `root`, was last modified. It counts
+    /// as not found if the symlink policy rejects it, like with [`lookup`].
+    fn modified(&self, path: &Path, root: &Path, symlinks: SymlinkPolicy)
+        -> impl Future<Output = io::Result<SystemTime>> + Send;
+
+    /// Up to the first `len` bytes of the file at `path`, which must be inside `root`. It counts as
+    /// not found if the symlink policy rejects it, like with [`lookup`].
+    fn read_start(&self, path: &Path, root: &Path, symlinks: SymlinkPolicy, len: usize)
+        -> impl Future<Output = io::Result<Vec<u8>>> + Send;
+}
+
+/// The filesystem the server runs on.
+pub struct RealFileSystem;
+
+impl FileSystemProvider for RealFileSystem {
+    async fn lookup(&self, path: &Path, root: &Path, symlinks: SymlinkPolicy)
+        -> io::Result<FileType>
+    {
+        lookup(path, root, symlinks).await
+    }
+
+    async fn read_dir(&self, path: &Path, metadata: bool) -> io::Result<Vec<DirEntry>> {
+        let stream = fs::read_dir(path).await?;
+        Ok(ReadDirStream::new(stream)
+            .filter_map(|result| future::ready(result.ok()))
+            .filter_map(|entry| dir_entry(entry, metadata))
+            .collect()
+            .await)
+    }
+
+    async fn modified(&self, path: &Path, root: &Path, symlinks: SymlinkPolicy)
+        -> io::Result<SystemTime>
+    {
+        if !allowed(path, root, symlinks).await? {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        fs::metadata(path).await?.modified()
+    }
+
+    async fn read_start(&self, path: &Path, root: &Path, symlinks: SymlinkPolicy, len: usize)
+        -> io::Result<Vec<u8>>
+    {
+        if !allowed(path, root, symlinks).await? {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        read_start(&mut File::open(path).await?, len).await
+    }
+}
+
+/// What's needed to list a directory entry, or `None` if it can't be looked at. Metadata is only
+/// looked at if it's asked for, because it's another system call per entry.
+async fn dir_entry(entry: fs::DirEntry, metadata: bool) -> Option<DirEntry> {
+    let is_dir = match entry.file_type().await {
+        Ok(ft) => ft.is_dir(),
+        Err(e) => {
+            warn!("error getting file type of {:?}: {}", entry.path(), e);
+            return None;
+        }
+    };
+    let (modified, size) = if metadata {
+        match entry.metadata().await {
+            Ok(meta) => (meta.modified().ok(), meta.len()),
+            Err(e) => {
+                warn!("error getting metadata of {:?}: {}", entry.path(), e);
+                return None;
+            }
+        }
+    } else {
+        (None, 0)
+    };
+    Some(DirEntry { file_name: entry.file_name(), is_dir, modified, size })
+}
+
+/// A made-up filesystem for testing request handling, without setting up real files. Paths in it
+/// are only ever found by exact match, and there are no symlinks.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockFileSystem {
+    files: std::collections::HashMap<PathBuf, MockFile>,
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub enum MockFile {
+    Directory,
+    File(Vec<u8>),
+    /// Something the server isn't allowed to read.
+    Unreadable,
+}
+
+#[cfg(test)]
+impl MockFileSystem {
+    /// Add a file, and any of its parent directories that aren't there yet.
+    pub fn with(mut self, path: impl Into<PathBuf>, file: MockFile) -> Self {
+        let path = path.into();
+        for parent in path.ancestors().skip(1) {
+            if self.files.contains_key(parent) {
+                break;
+            }
+            self.files.insert(parent.to_owned(), MockFile::Directory);
+        }
+        self.files.insert(path, file);
+        self
+    }
+
+    /// Open a file's contents. The server needs a real file to read from, so it's an anonymous
+    /// temporary one.
+    fn open(&self, path: &Path) -> io::Result<Option<File>> {
+        use std::io::{Seek, Write};
+        match self.files.get(path) {
+            Some(MockFile::File(contents)) => {
+                let mut file = tempfile::tempfile()?;
+                file.write_all(contents)?;
+                file.rewind()?;
+                Ok(Some(File::from_std(file)))
+            }
+            Some(MockFile::Unreadable) => Err(io::ErrorKind::PermissionDenied.into()),
+            Some(MockFile::Directory) | None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+impl FileSystemProvider for MockFileSystem {
+    async fn lookup(&self, path: &Path, _root: &Path, _symlinks: SymlinkPolicy)
+        -> io::Result<FileType>
+    {
+        let file_type = || -> io::Result<FileType> {
+            match self.files.get(path) {
+                None => Ok(FileType::NotFound),
+                Some(MockFile::Directory) => {
+                    for (name, format) in MENU_FILES {
+                        let menu_path = path.join(name);
+                        if let Some(file) = self.open(&menu_path)? {
+                            return Ok(FileType::Menu { file, path: menu_path, format: *format });
+                        }
+                    }
+                    match self.open(&path.join(PHLOG_FILE))? {
+                        Some(file) => Ok(FileType::Phlog(file)),
+                        None => Ok(FileType::Directory),
+                    }
+                }
+                Some(_) => Ok(FileType::File(self.open(path)?.expect("file not opened"))),
+            }
+        };
+        match file_type() {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Ok(FileType::PermissionDenied),
+            r => r,
+        }
+    }
+
+    async fn read_dir(&self, path: &Path, _metadata: bool) -> io::Result<Vec<DirEntry>> {
+        match self.files.get(path) {
+            Some(MockFile::Directory) => (),
+            Some(_) => return Err(io::Error::other(format!("{path:?} isn't a directory"))),
+            None => return Err(io::ErrorKind::NotFound.into()),
+        }
+        Ok(self.files.iter()
+            .filter(|(child, _)| child.parent() == Some(path))
+            .map(|(child, file)| DirEntry {
+                file_name: child.file_name().unwrap_or_default().to_owned(),
+                is_dir: matches!(file, MockFile::Directory),
+                modified: None,
+                size: match file {
+                    MockFile::File(contents) => contents.len() as u64,
+                    _ => 0,
+                },
+            })
+            .collect())
+    }
+
+    /// Everything was last modified at the start of 1970.
+    async fn modified(&self, path: &Path, _root: &Path, _symlinks: SymlinkPolicy)
+        -> io::Result<SystemTime>
+    {
+        match self.files.get(path) {
+            Some(_) => Ok(SystemTime::UNIX_EPOCH),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    async fn read_start(&self, path: &Path, _root: &Path, _symlinks: SymlinkPolicy, len: usize)
+        -> io::Result<Vec<u8>>
+    {
+        match self.files.get(path) {
+            Some(MockFile::File(contents)) => Ok(contents[.. len.min(contents.len())].to_vec()),
+            Some(MockFile::Unreadable) => Err(io::ErrorKind::PermissionDenied.into()),
+            Some(MockFile::Directory) => Err(io::Error::other(format!("{path:?} is a directory"))),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    struct Fixture {
+        _outside: tempfile::TempDir,
+        _root: tempfile::TempDir,
+        root: PathBuf,
+    }
+
+    /// A document root containing a regular file, a symlink to it, and symlinks to a file and a
+    /// directory outside the root.
+    fn fixture() -> Fixture {
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), "secret").unwrap();
+        let root_dir = tempfile::tempdir().unwrap();
+        let root = root_dir.path().canonicalize().unwrap();
+        std::fs::write(root.join("file"), "file").unwrap();
+        symlink(root.join("file"), root.join("inside")).unwrap();
+        symlink(outside.path().join("secret"), root.join("outside")).unwrap();
+        symlink(outside.path(), root.join("outside_dir")).unwrap();
+        Fixture { _outside: outside, _root: root_dir, root }
+    }
+
+    async fn is_found(root: &Path, name: &str, policy: SymlinkPolicy) -> bool {
+        match lookup(&root.join(name), root, policy).await.unwrap() {
+            FileType::NotFound => false,
+            FileType::File(_) => true,
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    async fn is_opened(root: &Path, name: &str, policy: SymlinkPolicy) -> bool {
+        open_special(&root.join(name), root, policy).await.unwrap().is_some()
+    }
+
+    #[tokio::test]
+    async fn follow() {
+        let f = fixture();
+        assert!(is_found(&f.root, "file", SymlinkPolicy::Follow).await);
+        assert!(is_found(&f.root, "inside", SymlinkPolicy::Follow).await);
+        assert!(is_found(&f.root, "outside", SymlinkPolicy::Follow).await);
+        assert!(is_found(&f.root, "outside_dir/secret", SymlinkPolicy::Follow).await);
+    }
+
+    #[tokio::test]
+    async fn reject() {
+        let f = fixture();
+        assert!(is_found(&f.root, "file", SymlinkPolicy::Reject).await);
+        assert!(!is_found(&f.root, "inside", SymlinkPolicy::Reject).await);
+        assert!(!is_found(&f.root, "outside", SymlinkPolicy::Reject).await);
+        assert!(!is_found(&f.root, "outside_dir/secret", SymlinkPolicy::Reject).await);
+    }
+
+    #[tokio::test]
+    async fn permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+        if nix::unistd::geteuid().is_root() {
+            eprintln!("skipping permission test: root can read anything");
+            return;
+        }
+        let f = fixture();
+        std::fs::set_permissions(f.root.join("file"), PermissionsExt::from_mode(0o000)).unwrap();
+        std::fs::create_dir(f.root.join("dir")).unwrap();
+        std::fs::write(f.root.join("dir/!menu"), "").unwrap();
+        std::fs::set_permissions(f.root.join("dir/!menu"), PermissionsExt::from_mode(0o000))
+            .unwrap();
+        for name in ["file", "dir"] {
+            match lookup(&f.root.join(name), &f.root, SymlinkPolicy::Follow).await.unwrap() {
+                FileType::PermissionDenied => (),
+                other => panic!("{name}: unexpected {other:?}"),
+            }
+        }
+        assert!(!is_found(&f.root, "missing", SymlinkPolicy::Follow).await);
+    }
+
+    #[tokio::test]
+    async fn reject_outside_root() {
+        let f = fixture();
+        assert!(is_found(&f.root, "file", SymlinkPolicy::RejectOutsideRoot).await);
+        assert!(is_found(&f.root, "inside", SymlinkPolicy::RejectOutsideRoot).await);
+        assert!(!is_found(&f.root, "outside", SymlinkPolicy::RejectOutsideRoot).await);
+        assert!(!is_found(&f.root, "outside_dir/secret", SymlinkPolicy::RejectOutsideRoot).await);
+    }
+
+    #[tokio::test]
+    async fn special_files() {
+        let f = fixture();
+        for name in ["file", "inside", "outside"] {
+            assert!(is_opened(&f.root, name, SymlinkPolicy::Follow).await, "{name}");
+        }
+        assert!(is_opened(&f.root, "file", SymlinkPolicy::Reject).await);
+        assert!(!is_opened(&f.root, "inside", SymlinkPolicy::Reject).await);
+        assert!(!is_opened(&f.root, "outside", SymlinkPolicy::Reject).await);
+        assert!(is_opened(&f.root, "inside", SymlinkPolicy::RejectOutsideRoot).await);
+        assert!(!is_opened(&f.root, "outside", SymlinkPolicy::RejectOutsideRoot).await);
+        assert!(!is_opened(&f.root, "outside_dir/secret", SymlinkPolicy::RejectOutsideRoot).await);
+        assert!(!is_opened(&f.root, "missing", SymlinkPolicy::Reject).await);
+        assert!(!is_opened(&f.root, "missing", SymlinkPolicy::RejectOutsideRoot).await);
+
+        // Menu files are checked too, when their directories are looked up.
+        std::fs::create_dir(f.root.join("dir")).unwrap();
+        symlink(f.root.join("outside"), f.root.join("dir/!menu")).unwrap();
+        let dir = f.root.join("dir");
+        match lookup(&dir, &f.root, SymlinkPolicy::RejectOutsideRoot).await.unwrap() {
+            FileType::Directory => (),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
 }
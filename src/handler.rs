@@ -0,0 +1,1451 @@
+//! Responses to requests: files, menus, generated listings, and everything else a selector can
+//! lead to.
+
+use crate::config::{Config, LongTextPolicy};
+use crate::dir_cache::DirCache;
+use crate::file_cache::FileCache;
+use crate::fs::{FileSystemProvider, FileType, MenuFormat};
+use crate::listing::{generate_menu, generate_phlog, warn_long_text};
+use crate::menu::{Menu, MenuItem};
+use crate::menu_cache::MenuCache;
+use crate::menu_file::{load_menu, menu_items};
+use crate::request::Request;
+use crate::request_stream::Peer;
+use crate::response::Response;
+use crate::types::ItemType;
+use crate::{cgi, fs, phlog, response};
+#[cfg(feature = "opentelemetry")]
+use crate::telemetry;
+use futures::stream::{self, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, warn};
+
+/// The response for a selector that doesn't exist: the `!404` menu if there is one, or an error.
+pub async fn not_found(selector: &str, config: &Arc<Config>) -> Response {
+    let path = config.document_root.join(fs::NOT_FOUND_FILE);
+    match fs::open_special(&path, &config.document_root, config.symlink_policy).await {
+        Ok(Some(file)) => {
+            // It's in the root, whatever it's standing in for.
+            let items = menu_items(file, path, MenuFormat::Menu, selector, "/",
+                &config.document_root, config.clone()).collect().await;
+            warn_long_text(&items, config);
+            Response::Menu(Menu::from_vec(items))
+        }
+        Ok(None) => Response::NotFound,
+        Err(e) => e.into(),
+    }
+}
+
+/// Answer a Gopher+ request for an item's attributes. Only menus have any.
+async fn attributes(config: &Arc<Config>, files: &impl FileSystemProvider, menu_cache: &MenuCache,
+    file_cache: &FileCache, dir_cache: &DirCache, req: Request) -> Response
+{
+    let selector = req.selector.clone();
+    match lookup_request(config, files, menu_cache, file_cache, dir_cache, None, req).await {
+        Response::Menu(_) => (),
+        response @ (Response::NotFound | Response::Error(_)) => return response,
+        _ => return Response::Error("attributes are only available for menus".into()),
+    }
+    // A '?' is part of the name, unless that isn't found and it's a phlog index's page number.
+    let mut path_selector = selector.as_str();
+    let (root, mut path) = match config.local_path(path_selector) {
+        Ok(local) => local,
+        Err(msg) => return Response::Error(msg.into()),
+    };
+    let mut modified = files.modified(&path, root, config.symlink_policy).await;
+    if let (Err(_), Some((base, _))) = (&modified, selector.split_once('?')) {
+        if let Ok((root, base_path)) = config.local_path(base) {
+            (path_selector, path) = (base, base_path);
+            modified = files.modified(&path, root, config.symlink_policy).await;
+        }
+    }
+    let modified = match modified {
+        Ok(time) => time,
+        Err(e) => return e.into(),
+    };
+    let text = match path.file_name() {
+        Some(name) if !path_selector.trim_matches('/').is_empty() => {
+            name.to_string_lossy().into_owned()
+        }
+        _ => config.hostname.clone(),
+    };
+    let admin = match (&config.admin_name, &config.admin_email) {
+        (Some(name), Some(email)) => Some(format!("{name} <{email}>")),
+        (Some(name), None) => Some(name.clone()),
+        (None, Some(email)) => Some(format!("<{email}>")),
+        (None, None) => None,
+    };
+    Response::Attributes(response::Attributes {
+        info: MenuItem::new(
+            ItemType::Directory, text, selector, &config.hostname, config.port.to_string()),
+        admin,
+        modified,
+    })
+}
+
+/// Respond to a request. `peer` is who it's from, if that's known.
+pub async fn handle_request(config: &Arc<Config>, files: &impl FileSystemProvider,
+    menu_cache: &MenuCache, file_cache: &FileCache, dir_cache: &DirCache, peer: Option<&Peer>,
+    req: Request) -> Response
+{
+    if req.attributes {
+        return attributes(config, files, menu_cache, file_cache, dir_cache, req).await;
+    }
+    let selector = req.selector.clone();
+    let respond = async {
+        let response = match lookup_request(config, files, menu_cache, file_cache, dir_cache,
+            peer, req).await
+        {
+            Response::NotFound => match &config.upstream {
+                Some(upstream) => forward(upstream, &selector, config).await,
+                None => not_found(&selector, config).await,
+            },
+            response => response,
+        };
+        match response {
+            Response::Menu(menu) => Response::Menu(fit_item_text(menu, config)),
+            response => response,
+        }
+    };
+    #[cfg(feature = "opentelemetry")]
+    let respond = telemetry::traced(&selector, peer, respond);
+    respond.await
+}
+
+/// Apply `max_item_text` to a menu's items as they're sent, whether they were read from a menu
+/// file or generated. Ones that are to be sent as they are were already warned about, by
+/// [`warn_long_text`], when they were.
+pub fn fit_item_text(menu: Menu, config: &Config) -> Menu {
+    let Some(max) = config.max_item_text else { return menu };
+    let policy = config.long_item_text;
+    Menu::new(menu.items.flat_map(move |mut item| {
+        let len = item.text.chars().count();
+        let items = match policy {
+            _ if len <= max => vec![item],
+            LongTextPolicy::Warn => vec![item],
+            LongTextPolicy::Truncate => {
+                item.truncate(max);
+                vec![item]
+            }
+            LongTextPolicy::Wrap => item.wrap(max),
+        };
+        stream::iter(items)
+    }))
+}
+
+/// Pass a request on to the upstream server, and get ready to send its response back.
+pub async fn forward(upstream: &str, selector: &str, config: &Config) -> Response {
+    debug!("forwarding to upstream {upstream}");
+    let timeout = Duration::from_secs(config.upstream_timeout_secs);
+    let connect = async {
+        let mut stream = tokio::net::TcpStream::connect(upstream).await?;
+        stream.write_all(format!("{selector}\r\n").as_bytes()).await?;
+        Ok::<_, std::io::Error>(stream)
+    };
+    match tokio::time::timeout(timeout, connect).await {
+        Ok(Ok(stream)) => Response::Upstream { stream, timeout },
+        Ok(Err(e)) => {
+            warn!("error forwarding to upstream {upstream}: {e}");
+            Response::Error("upstream server unavailable".into())
+        }
+        Err(_) => {
+            warn!("timed out forwarding to upstream {upstream}");
+            Response::Error("upstream server unavailable".into())
+        }
+    }
+}
+
+/// How much of a file without a recognized extension is looked at to tell if it's text.
+const SNIFF_BYTES: usize = 512;
+
+pub async fn lookup_request(config: &Arc<Config>, files: &impl FileSystemProvider,
+    menu_cache: &MenuCache, file_cache: &FileCache, dir_cache: &DirCache, peer: Option<&Peer>,
+    req: Request) -> Response
+{
+    if req.selector.starts_with("URL:") {
+        return Response::Raw(html_redirect(&req.selector[4..]).into_bytes());
+    } else if let Some(url) = req.selector.strip_prefix("GOPHER:") {
+        return Response::Redirect(url.to_owned());
+    } else if req.selector.starts_with("GET ")
+        && (req.selector.ends_with(" HTTP/1.1") || req.selector.ends_with(" HTTP/1.0"))
+    {
+        // We don't know what the type is, but let's assume directory.
+        let url = format!("gopher://{}:{}/1{}",
+            config.hostname,
+            config.port,
+            &req.selector[4 .. req.selector.len() - 9],
+        );
+        return Response::Raw(http_response(&url).into_bytes());
+    } else if !req.selector.is_empty() && !req.selector.starts_with('/') {
+        return Response::NotFound;
+    }
+
+    let mut selector = req.selector.as_str();
+    let mut query = None;
+    let mut local = config.local_path(selector);
+    let mut found = match &local {
+        Ok((root, path)) => files.lookup(path, root, config.symlink_policy).await,
+        Err(_) => Ok(FileType::NotFound),
+    };
+    // Only phlog indexes, for the page number, and scripts take a query string. Anything else is
+    // only found by a selector with a '?' in it if that's in its name.
+    if let (Ok(FileType::NotFound), Some((base, rest))) = (&found, selector.split_once('?')) {
+        if let Ok((base_root, base_path)) = config.local_path(base) {
+            let base_found = files.lookup(&base_path, base_root, config.symlink_policy).await;
+            let takes_query = match &base_found {
+                Ok(FileType::Phlog(_)) => true,
+                Ok(FileType::File(_)) => {
+                    !req.attributes && cgi::is_script(&base_path, config).await
+                }
+                _ => false,
+            };
+            if takes_query {
+                (selector, query) = (base, Some(rest));
+                local = Ok((base_root, base_path));
+                found = base_found;
+            }
+        }
+    }
+    let (root, path) = match local {
+        Ok(local) => local,
+        Err(msg) => return Response::Error(msg.into()),
+    };
+
+    match found {
+        Ok(FileType::Menu { file: menu_file, path: menu_path, format }) => {
+            debug!("{} {menu_path:?}", ItemType::Directory);
+            let items = menu_cache.get(menu_path.clone(), selector, menu_file, config, |file| {
+                load_menu(file, menu_path, format, selector, selector, root, config.clone())
+            }).await;
+            let items = (0 .. items.len()).map(move |i| items[i].clone());
+            Response::Menu(Menu::new(stream::iter(items)))
+        }
+        Ok(FileType::Directory) => {
+            debug!("{} {path:?}", ItemType::Directory);
+            generate_menu(files, &path, root, selector, config, menu_cache, dir_cache).await
+        }
+        Ok(FileType::Phlog(file)) => {
+            debug!("{} {path:?} (phlog)", ItemType::Directory);
+            match phlog::page_number(query) {
+                Some(page) => generate_phlog(files, &path, selector, file, page, config).await,
+                None => Response::Error("invalid page number".into()),
+            }
+        }
+        // Scripts aren't run for attributes requests, which only menus can answer anyway.
+        Ok(FileType::File(_)) if !req.attributes && cgi::is_script(&path, config).await => {
+            cgi::run(&path, selector, query, peer, config).await
+        }
+        Ok(FileType::File(mut file)) => {
+            let typ = match ItemType::for_extension(&path) {
+                Some(typ) => typ,
+                // Without an extension to go by, look at what's in it, so that binary files aren't
+                // sent as text, with lines starting with '.' doubled.
+                None => match fs::read_start(&mut file, SNIFF_BYTES).await {
+                    Ok(start) => ItemType::for_contents(&start),
+                    Err(e) => return e.into(),
+                },
+            };
+            if let Some(bytes) = file_cache.get(&path, &file, typ.is_text(), config).await {
+                debug!("{typ} {path:?} (cached)");
+                return Response::Cached(bytes);
+            }
+            debug!("{typ} {path:?}");
+            match file_cache.insert(&path, &mut file, typ.is_text(), config).await {
+                Ok(Some(bytes)) => return Response::Cached(bytes),
+                Ok(None) => (),
+                Err(e) => return e.into(),
+            }
+            if typ.is_text() {
+                Response::TextFile { file, crlf: config.crlf_convert }
+            } else {
+                Response::File(file)
+            }
+        }
+        Ok(FileType::NotFound) => {
+            debug!("not found {path:?}");
+            Response::NotFound
+        }
+        Ok(FileType::PermissionDenied) => Response::Error(response::ACCESS_DENIED.into()),
+        Err(e) => e.into(),
+    }
+}
+
+/// For clients that don't understand the "URL:..." selector format.
+fn html_redirect(url: &str) -> String {
+    format!(r#"<!doctype html>
+<html>
+    <head>
+        <meta http-equiv="refresh" content="5;URL={url}">
+        <title>Gopher redirect to URL: {url}</title>
+    </head>
+    <body>
+        <p>You're being redirected to a HTTP URL: <code>{url}</code>
+        <p>Click <a href="{url}">here</a> if you are not redirected automatically.
+        <address>generated by gofer</address>
+    </body>
+</html>"#)
+}
+
+fn http_response(url: &str) -> String {
+    // This isn't really valid HTTP because it's missing required headers, but it's enough to get
+    // the page to display in a browser.
+    format!("HTTP/1.0 400 Bad Request\r
+Content-Type: text/html\r
+\r
+<!doctype html>
+<html>
+    <head>
+        <title>This is a Gopher server</title>
+    </head>
+    <body>
+        <p>This is a Gopher server but it looks like you've made a HTTP request.
+        <p>If you're using a Gopher-capable browser, click <a href=\"{url}\">here</a> to use a Gopher
+           URL to view this page properly.
+        <address>generated by gofer</address>
+    </body>
+</html>")
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::menu::MenuItemEncoder;
+    use crate::config;
+    use crate::fs::RealFileSystem;
+    use std::path::Path;
+    use std::time::SystemTime;
+    #[cfg(unix)]
+    use crate::privileges;
+
+    pub fn test_config(root: &Path) -> Arc<Config> {
+        let root = root.canonicalize().unwrap();
+        Arc::new(toml::from_str(&format!(r#"
+            server_address = "127.0.0.1:7070"
+            document_root = {root:?}
+            hostname = "localhost"
+            port = 7070
+            "#)).unwrap())
+    }
+
+    /// Serve a request from inside a real chroot. That needs root, and can't be undone, so it's
+    /// done by running `chroot_child` in a separate process.
+    #[cfg(unix)]
+    #[test]
+    fn chroot() {
+        if !nix::unistd::geteuid().is_root() {
+            eprintln!("skipping chroot test: needs root");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub").join("hello.txt"), "hello\n").unwrap();
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "handler::test::chroot_child", "--test-threads=1"])
+            .env("GOFER_TEST_CHROOT", dir.path())
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{stdout}");
+        assert!(stdout.contains("1 passed"), "{stdout}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn chroot_child() {
+        let Some(root) = std::env::var_os("GOFER_TEST_CHROOT") else {
+            return;
+        };
+        let root = Path::new(&root).canonicalize().unwrap();
+        let mut config = (*test_config(&root)).clone();
+        config.mounts.push(config::Mount {
+            prefix: "/m".to_owned(),
+            document_root: root.join("sub"),
+        });
+        config.chroot_paths().unwrap();
+        privileges::chroot(&root).unwrap();
+        assert!(!root.join("sub").exists());
+        let config = Arc::new(config);
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        assert_eq!(rt.block_on(fetch(&config, "/sub/hello.txt")), b"hello\n.\r\n");
+        assert_eq!(rt.block_on(fetch(&config, "/m/hello.txt")), b"hello\n.\r\n");
+        assert_eq!(rt.block_on(fetch_menu(&config, "/")).get(2).map(String::as_str), Some("1sub"));
+    }
+
+    fn error_line(msg: &str) -> Vec<u8> {
+        response::error_line(msg, &MenuItemEncoder::default())
+    }
+
+    async fn fetch(config: &Arc<Config>, selector: &str) -> Vec<u8> {
+        fetch_from(&RealFileSystem, config, selector).await
+    }
+
+    async fn fetch_from(files: &impl FileSystemProvider, config: &Arc<Config>, selector: &str)
+        -> Vec<u8>
+    {
+        let req = Request { selector: selector.to_owned(), attributes: false };
+        fetch_request(files, config, req).await
+    }
+
+    async fn fetch_request(files: &impl FileSystemProvider, config: &Arc<Config>, req: Request)
+        -> Vec<u8>
+    {
+        let mut out = vec![];
+        let menu_cache = MenuCache::new(0, Duration::ZERO);
+        handle_request(config, files, &menu_cache, &FileCache::default(), &DirCache::default(),
+            None, req).await.write(&mut out, &config.menu_encoder()).await.unwrap();
+        out
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+        if nix::unistd::geteuid().is_root() {
+            eprintln!("skipping permission test: root can read anything");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        std::fs::set_permissions(dir.path().join("secret.txt"), PermissionsExt::from_mode(0o000))
+            .unwrap();
+        let config = test_config(dir.path());
+        assert_eq!(fetch(&config, "/secret.txt").await, error_line("access denied"));
+        assert_eq!(fetch(&config, "/missing.txt").await, error_line("not found"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn non_utf8_filename() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = tempfile::tempdir().unwrap();
+        let name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+        std::fs::write(dir.path().join(name), "contents").unwrap();
+        let config = test_config(dir.path());
+
+        let menu = String::from_utf8(fetch(&config, "").await).unwrap();
+        let line = menu.lines()
+            .find(|line| line.starts_with('0'))
+            .expect("no file item in menu");
+        let fields = line.split('\t').collect::<Vec<_>>();
+        assert_eq!(fields[0], "0caf\u{fffd}.txt");
+        assert_eq!(fields[1], "/caf%E9.txt");
+
+        assert_eq!(fetch(&config, fields[1]).await, b"contents\r\n.\r\n");
+    }
+
+    #[tokio::test]
+    async fn traversal_denied() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let denied = b"3directory traversal denied\terror\terror.host\t1\r\n.\r\n";
+        assert_eq!(fetch(&config, "/foo/../../etc/passwd").await, denied);
+        assert_eq!(fetch(&config, "/foo/bar/..").await, denied);
+        assert_eq!(fetch(&config, "/%2e%2e/etc/passwd").await, denied);
+        assert_eq!(fetch(&config, "//etc/passwd").await, denied);
+    }
+
+    #[tokio::test]
+    async fn gopher_plus_attributes() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub dir")).unwrap();
+        std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        let mut config = (*test_config(dir.path())).clone();
+        config.admin_name = Some("Gopher Admin".to_owned());
+        config.admin_email = Some("admin@example.com".to_owned());
+        let config = Arc::new(config);
+
+        let fetch_attributes = |selector: &str| {
+            let req = Request { selector: selector.to_owned(), attributes: true };
+            let config = config.clone();
+            async move {
+                let mut out = vec![];
+                let menu_cache = MenuCache::new(0, Duration::ZERO);
+                handle_request(&config, &RealFileSystem, &menu_cache, &FileCache::default(),
+                    &DirCache::default(), None, req).await
+                    .write(&mut out, &config.menu_encoder()).await.unwrap();
+                String::from_utf8(out).unwrap()
+            }
+        };
+
+        let response = fetch_attributes("/sub%20dir").await;
+        let lines = response.split("\r\n").collect::<Vec<_>>();
+        assert_eq!(lines[.. 4], [
+            "+-1",
+            "+INFO: 1sub dir\t/sub%20dir\tlocalhost\t7070\t+",
+            "+ADMIN:",
+            " Admin: Gopher Admin <admin@example.com>",
+        ]);
+        let mod_date = lines[4].strip_prefix(" Mod-Date: ").unwrap();
+        let (readable, compact) = mod_date.split_once(" <").unwrap();
+        assert_eq!(readable.len(), "Wed Jul 28 17:02:01 1993".len());
+        let compact = compact.strip_suffix('>').unwrap();
+        assert!(compact.len() == 14 && compact.bytes().all(|b| b.is_ascii_digit()), "{compact}");
+        assert_eq!(lines[5 ..], [".", ""]);
+
+        let root = fetch_attributes("").await;
+        assert!(root.starts_with("+-1\r\n+INFO: 1localhost\t\tlocalhost\t7070\t+\r\n"), "{root}");
+
+        assert_eq!(fetch_attributes("/a.txt").await.as_bytes(),
+            error_line("attributes are only available for menus"));
+        assert_eq!(fetch_attributes("/missing").await.as_bytes(),
+            error_line("not found"));
+    }
+
+    pub async fn fetch_menu(config: &Arc<Config>, selector: &str) -> Vec<String> {
+        String::from_utf8(fetch(config, selector).await)
+            .unwrap()
+            .lines()
+            .map(|line| line.split('\t').next().unwrap().to_owned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn header_and_footer() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        let config = test_config(dir.path());
+
+        assert_eq!(fetch_menu(&config, "").await, ["i[localhost]", "i", "0file", "."]);
+
+        std::fs::write(dir.path().join("!header"), "iWelcome\n1Home\t/\n").unwrap();
+        assert_eq!(fetch_menu(&config, "").await, ["iWelcome", "1Home", "0file", "."]);
+
+        std::fs::write(dir.path().join("!footer"), "iBye\n\tbad line\n").unwrap();
+        assert_eq!(fetch_menu(&config, "").await, ["iWelcome", "1Home", "0file", "iBye", "."]);
+
+        std::fs::remove_file(dir.path().join("!header")).unwrap();
+        assert_eq!(fetch_menu(&config, "").await, ["i[localhost]", "i", "0file", "iBye", "."]);
+    }
+
+    #[tokio::test]
+    async fn menu_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(root.join("shared")).unwrap();
+        std::fs::write(root.join("shared/nav"), "1Home\t/\n!include disclaimer\n").unwrap();
+        std::fs::write(root.join("shared/disclaimer"), "iNo warranty\n").unwrap();
+        std::fs::write(root.join("sub/!menu"),
+            "iTop\n=include ../shared/nav\n!include missing\niBottom\n").unwrap();
+        let config = test_config(root);
+        assert_eq!(fetch_menu(&config, "/sub").await, ["iTop", "1Home", "iNo warranty",
+            "3!menu line 3: can't include \"missing\": not found", "iBottom", "."]);
+
+        // Files including themselves, directly or not, are caught.
+        std::fs::write(root.join("shared/disclaimer"), "iNo warranty\n!include nav\n").unwrap();
+        std::fs::write(root.join("sub/!menu"), "!include ../shared/nav\n").unwrap();
+        assert_eq!(fetch_menu(&config, "/sub").await, ["1Home", "iNo warranty",
+            "3disclaimer line 2: can't include \"nav\": it's already being included", "."]);
+        std::fs::write(root.join("sub/!menu"), "iSelf\n!include !menu\n").unwrap();
+        assert_eq!(fetch_menu(&config, "/sub").await, ["iSelf",
+            "3!menu line 2: can't include \"!menu\": it's already being included", "."]);
+
+        // Files outside the document root can't be included, even if they exist.
+        std::fs::write(dir.path().join("outside"), "iSecret\n").unwrap();
+        let config = test_config(&root.join("sub"));
+        std::fs::write(root.join("sub/!menu"), "!include ../outside\n").unwrap();
+        assert_eq!(fetch_menu(&config, "/").await,
+            ["3!menu line 1: can't include \"../outside\": outside the document root", "."]);
+        let config = test_config(root);
+
+        // So are ones nested too deeply.
+        for i in 0 .. 20 {
+            std::fs::write(root.join(format!("shared/{i}")), format!("i{i}\n!include {}\n", i + 1))
+                .unwrap();
+        }
+        std::fs::write(root.join("sub/!menu"), "!include ../shared/0\n").unwrap();
+        assert_eq!(fetch_menu(&config, "/sub").await, ["i0", "i1", "i2", "i3", "i4", "i5", "i6",
+            "i7", "i8", "i9", "39 line 2: can't include \"10\": includes nested more than 10 deep",
+            "."]);
+    }
+
+    /// Like [`fetch_menu`], but with a menu cache that's kept between requests.
+    async fn fetch_cached_menu(config: &Arc<Config>, menu_cache: &MenuCache, selector: &str)
+        -> Vec<String>
+    {
+        let req = Request { selector: selector.to_owned(), attributes: false };
+        let mut out = vec![];
+        handle_request(config, &RealFileSystem, menu_cache, &FileCache::default(),
+            &DirCache::default(), None, req).await
+            .write(&mut out, &config.menu_encoder()).await.unwrap();
+        String::from_utf8(out).unwrap()
+            .lines()
+            .map(|line| line.split('\t').next().unwrap().to_owned())
+            .collect()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn special_file_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), "iSecret\n").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir(root.join("sub")).unwrap();
+        for name in [fs::HEADER_FILE, fs::NOT_FOUND_FILE, "sub/!menu"] {
+            symlink(outside.path().join("secret"), root.join(name)).unwrap();
+        }
+        let config = test_config(root);
+        // Symlinks to outside the document root are rejected, like for any other file.
+        assert_eq!(fetch_menu(&config, "/").await, ["i[localhost/]", "i", "1sub", "."]);
+        assert_eq!(fetch_menu(&config, "/sub").await,
+            ["i[localhost/sub]", "i", "1[parent directory]", "0!menu", "."]);
+        assert_eq!(fetch(&config, "/missing").await, error_line("not found"));
+
+        let config = Arc::new(Config {
+            symlink_policy: config::SymlinkPolicy::Follow,
+            ..(*config).clone()
+        });
+        assert_eq!(fetch_menu(&config, "/").await, ["iSecret", "1sub", "."]);
+        assert_eq!(fetch_menu(&config, "/sub").await, ["iSecret", "."]);
+        assert_eq!(fetch_menu(&config, "/missing").await, ["iSecret", "."]);
+    }
+
+    #[tokio::test]
+    async fn cached_menu_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::write(root.join("nav"), "1Home\t/\n").unwrap();
+        std::fs::write(root.join("!menu"), "iTop\n!include nav\n!include later\n").unwrap();
+        let config = test_config(root);
+        let menu_cache = MenuCache::new(10, Duration::from_secs(60));
+        let fetch = || fetch_cached_menu(&config, &menu_cache, "/");
+        let missing = "3!menu line 3: can't include \"later\": not found";
+        assert_eq!(fetch().await, ["iTop", "1Home", missing, "."]);
+
+        // Editing an included file, without touching the menu file, shows up.
+        std::fs::write(root.join("nav"), "1Home\t/\n1About\t/about\n").unwrap();
+        let file = std::fs::File::options().write(true).open(root.join("nav")).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(fetch().await, ["iTop", "1Home", "1About", missing, "."]);
+
+        // So does one that was missing turning up.
+        std::fs::write(root.join("later"), "iLater\n").unwrap();
+        assert_eq!(fetch().await, ["iTop", "1Home", "1About", "iLater", "."]);
+    }
+
+    #[tokio::test]
+    async fn menu_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir(root.join("sub")).unwrap();
+        std::fs::write(root.join("nav"), "1Reload\t{{selector}}\n").unwrap();
+        std::fs::write(root.join("sub/!menu"),
+            "iYou're on {{hostname}} port {{port}}\n!include ../nav\n").unwrap();
+        let config = Arc::new(Config {
+            menu_cache_max_entries: 10,
+            ..(*test_config(root)).clone()
+        });
+        let menu = String::from_utf8(fetch(&config, "/sub").await).unwrap();
+        assert_eq!(menu, "iYou're on localhost port 7070\t\terror.host\t1\r\n\
+            1Reload\t/sub\tlocalhost\t7070\r\n.\r\n");
+        // Requested another way, the selector's different.
+        let menu = String::from_utf8(fetch(&config, "/sub/").await).unwrap();
+        assert!(menu.contains("1Reload\t/sub/\t"), "{menu}");
+    }
+
+    #[tokio::test]
+    async fn info_placeholders() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir(root.join("written")).unwrap();
+        std::fs::write(root.join("written/!menu"), "ihello\n").unwrap();
+        std::fs::create_dir(root.join("listed")).unwrap();
+        std::fs::write(root.join("listed/file.txt"), "").unwrap();
+        let config = Arc::new(Config {
+            info_host: "null.host".to_owned(),
+            info_port: 0,
+            ..(*test_config(root)).clone()
+        });
+
+        let menu = String::from_utf8(fetch(&config, "/written").await).unwrap();
+        assert_eq!(menu, "ihello\t\tnull.host\t0\r\n.\r\n");
+        let menu = String::from_utf8(fetch(&config, "/listed").await).unwrap();
+        let info: Vec<_> = menu.lines().filter(|line| line.starts_with('i')).collect();
+        assert!(!info.is_empty(), "{menu}");
+        assert!(info.iter().all(|line| line.ends_with("\tnull.host\t0")), "{menu}");
+        assert!(menu.contains("0file.txt\t/listed/file.txt\tlocalhost\t7070\r\n"), "{menu}");
+        assert_eq!(String::from_utf8(fetch(&config, "/missing").await).unwrap(),
+            "3not found\terror\tnull.host\t0\r\n.\r\n");
+    }
+
+    #[tokio::test]
+    async fn long_item_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir(root.join("written")).unwrap();
+        std::fs::write(root.join("written/!menu"), "ißéé all the wonderful things\n").unwrap();
+        std::fs::create_dir(root.join("listed")).unwrap();
+        std::fs::write(root.join("listed/a very long file name.txt"), "").unwrap();
+        let config = |policy| Arc::new(Config {
+            max_item_text: Some(12),
+            long_item_text: policy,
+            menu_header: Some(vec![]),
+            ..(*test_config(root)).clone()
+        });
+
+        let warn = config(LongTextPolicy::Warn);
+        assert_eq!(fetch_menu(&warn, "/written").await, ["ißéé all the wonderful things", "."]);
+
+        let truncate = config(LongTextPolicy::Truncate);
+        assert_eq!(fetch_menu(&truncate, "/written").await, ["ißéé all the…", "."]);
+        assert_eq!(fetch_menu(&truncate, "/listed").await,
+            ["1[parent dir…", "0a very long…", "."]);
+
+        let wrap = config(LongTextPolicy::Wrap);
+        assert_eq!(fetch_menu(&wrap, "/written").await,
+            ["ißéé all the", "iwonderful", "ithings", "."]);
+        assert_eq!(fetch_menu(&wrap, "/listed").await,
+            ["1[parent", "idirectory]", "0a very long", "ifile", "iname.txt", "."]);
+    }
+
+    #[tokio::test]
+    async fn menu_default_host() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::write(root.join("!menu"),
+            "1Here\t/a\t\n1Port\t/b\texample.org\t\n1Host\t/c\t\t7071\n1Plus\t/d\t\t\t+\n")
+            .unwrap();
+        let menu = String::from_utf8(fetch(&test_config(root), "/").await).unwrap();
+        assert_eq!(menu, "1Here\t/a\tlocalhost\t7070\r\n1Port\t/b\texample.org\t70\r\n\
+            1Host\t/c\tlocalhost\t7071\r\n1Plus\t/d\tlocalhost\t7070\t+\r\n.\r\n");
+    }
+
+    #[tokio::test]
+    async fn menu_variables() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/!menu"), "iOn $hostname:$port for $$5 at $selector\n\
+            1Variables\t$selector/a\t$hostname\t$port\n\
+            1Explicit\t/b\texample.org\t71\n\
+            1Mixed\t/c\tgopher.$hostname\t\n\
+            1Default\t/d\n").unwrap();
+        let menu = String::from_utf8(fetch(&test_config(root), "/sub").await).unwrap();
+        assert_eq!(menu, "iOn localhost:7070 for $5 at /sub\t\terror.host\t1\r\n\
+            1Variables\t/sub/a\tlocalhost\t7070\r\n\
+            1Explicit\t/b\texample.org\t71\r\n\
+            1Mixed\t/c\tgopher.localhost\t70\r\n\
+            1Default\t/d\tlocalhost\t7070\r\n.\r\n");
+    }
+
+    #[tokio::test]
+    async fn menu_relative_selectors() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("a/b")).unwrap();
+        std::fs::write(root.join("a/b/!menu"), "0Absolute\t/abs\n\
+            0Relative\tpage.txt\n\
+            1Up\t../\n\
+            1Escape\t../../../..\n\
+            1Elsewhere\trelative\texample.org\t70\n\
+            hLink\tURL:http://example.org/\n").unwrap();
+        let menu = String::from_utf8(fetch(&test_config(root), "/a/b/").await).unwrap();
+        let selectors = menu.lines()
+            .filter_map(|line| line.split('\t').nth(1))
+            .collect::<Vec<_>>();
+        assert_eq!(selectors,
+            ["/abs", "/a/b/page.txt", "/a/", "/", "relative", "URL:http://example.org/"]);
+    }
+
+    #[tokio::test]
+    async fn menu_globs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir(root.join("shows")).unwrap();
+        for name in ["b.mp3", "a.mp3", ".hidden.mp3", "notes.txt", "dir.mp3/"] {
+            match name.strip_suffix('/') {
+                Some(name) => std::fs::create_dir(root.join("shows").join(name)).unwrap(),
+                None => std::fs::write(root.join("shows").join(name), "").unwrap(),
+            }
+        }
+        std::fs::write(root.join("shows/!menu"),
+            "iEpisodes:\n=glob *.mp3\niNotes:\n=glob *.txt 9\niVideos:\n=glob *.mkv\n").unwrap();
+        let menu = String::from_utf8(fetch(&test_config(root), "/shows/").await).unwrap();
+        assert_eq!(menu, "iEpisodes:\t\terror.host\t1\r\n\
+            sa.mp3\t/shows/a.mp3\tlocalhost\t7070\r\n\
+            sb.mp3\t/shows/b.mp3\tlocalhost\t7070\r\n\
+            1dir.mp3\t/shows/dir.mp3\tlocalhost\t7070\r\n\
+            iNotes:\t\terror.host\t1\r\n\
+            9notes.txt\t/shows/notes.txt\tlocalhost\t7070\r\n\
+            iVideos:\t\terror.host\t1\r\n\
+            i(none)\t\terror.host\t1\r\n.\r\n");
+    }
+
+    #[tokio::test]
+    async fn cached_menu_globs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::write(root.join("a.txt"), "").unwrap();
+        std::fs::write(root.join("!menu"), "=glob *.txt\n").unwrap();
+        let config = test_config(root);
+        let menu_cache = MenuCache::new(10, Duration::from_secs(60));
+        assert_eq!(fetch_cached_menu(&config, &menu_cache, "/").await, ["0a.txt", "."]);
+
+        // New entries show up, even though the menu file hasn't changed.
+        std::fs::write(root.join("b.txt"), "").unwrap();
+        let dir = std::fs::File::open(root).unwrap();
+        dir.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(fetch_cached_menu(&config, &menu_cache, "/").await,
+            ["0a.txt", "0b.txt", "."]);
+
+        // So do changes to the settings for listing them.
+        let config = Arc::new(Config {
+            hide_patterns: vec!["a.*".to_owned()],
+            ..(*config).clone()
+        });
+        assert_eq!(fetch_cached_menu(&config, &menu_cache, "/").await, ["0b.txt", "."]);
+    }
+
+    #[tokio::test]
+    async fn dir_titles() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for sub in ["projects", "plain", "broken/!menu"] {
+            std::fs::create_dir_all(root.join(sub)).unwrap();
+        }
+        std::fs::write(root.join("projects/!menu"), "=title My Projects\niHi\n").unwrap();
+        let config = test_config(root);
+        // A !menu that's a directory can be opened, but not read.
+        assert_eq!(fetch_menu(&config, "/").await,
+            ["i[localhost/]", "i", "1broken", "1My Projects", "1plain", "."]);
+        let menu = String::from_utf8(fetch(&config, "").await).unwrap();
+        assert!(menu.contains("1My Projects\t/projects\tlocalhost\t7070\r\n"), "{menu}");
+        assert_eq!(fetch_menu(&config, "/projects").await, ["iHi", "."]);
+    }
+
+    #[tokio::test]
+    async fn cached_dir_titles() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for sub in ["projects", "plain"] {
+            std::fs::create_dir(root.join(sub)).unwrap();
+        }
+        std::fs::write(root.join("projects/!menu"), "=title My Projects\n").unwrap();
+        let config = test_config(root);
+        let menu_cache = MenuCache::new(10, Duration::from_secs(60));
+        let dir_cache = DirCache::new(10, Duration::from_secs(60));
+        let fetch = || async {
+            let req = Request { selector: "/".to_owned(), attributes: false };
+            let mut out = vec![];
+            handle_request(&config, &RealFileSystem, &menu_cache, &FileCache::default(),
+                &dir_cache, None, req).await
+                .write(&mut out, &config.menu_encoder()).await.unwrap();
+            String::from_utf8(out).unwrap()
+                .lines()
+                .filter_map(|line| line.strip_prefix('1')?.split('\t').next().map(str::to_owned))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(fetch().await, ["My Projects", "plain"]);
+
+        // Changes to subdirectories' menu files show up, even though the directory being listed
+        // hasn't changed.
+        std::fs::write(root.join("projects/!menu"), "=title Old Projects\n").unwrap();
+        let file = std::fs::File::options().write(true).open(root.join("projects/!menu")).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(fetch().await, ["Old Projects", "plain"]);
+        std::fs::write(root.join("plain/gophermap"), "=title Not So Plain\n").unwrap();
+        assert_eq!(fetch().await, ["Not So Plain", "Old Projects"]);
+        std::fs::remove_file(root.join("projects/!menu")).unwrap();
+        assert_eq!(fetch().await, ["Not So Plain", "projects"]);
+    }
+
+    #[tokio::test]
+    async fn menu_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::write(root.join("!menu"), "# Comment\niBefore\nGOPHER:nope\niAfter\n").unwrap();
+        let config = test_config(root);
+        assert_eq!(fetch_menu(&config, "/").await, ["iBefore", "iAfter", "."]);
+
+        let config = Arc::new(Config { show_menu_errors: true, ..(*config).clone() });
+        assert_eq!(fetch_menu(&config, "/").await,
+            ["iBefore", "3!menu line 3: invalid gopher URL \"nope\"", "iAfter", "."]);
+    }
+
+    #[tokio::test]
+    async fn mounts() {
+        let main_root = tempfile::tempdir().unwrap();
+        std::fs::write(main_root.path().join("main"), "main").unwrap();
+        let docs_root = tempfile::tempdir().unwrap();
+        std::fs::write(docs_root.path().join("doc"), "doc").unwrap();
+        let nested_root = tempfile::tempdir().unwrap();
+        std::fs::write(nested_root.path().join("nested"), "nested").unwrap();
+
+        let mut config: Config = toml::from_str(&format!(r#"
+            server_address = "127.0.0.1:7070"
+            document_root = {:?}
+            hostname = "localhost"
+            port = 7070
+
+            [[mounts]]
+            prefix = "/docs/"
+            document_root = {:?}
+
+            [[mounts]]
+            prefix = "/docs/nested"
+            document_root = {:?}
+            "#,
+            main_root.path().canonicalize().unwrap(),
+            docs_root.path().canonicalize().unwrap(),
+            nested_root.path().canonicalize().unwrap(),
+        )).unwrap();
+        config.sort_mounts();
+        let config = Arc::new(config);
+
+        assert_eq!(fetch(&config, "/main").await, b"main\r\n.\r\n");
+        assert_eq!(fetch(&config, "/docs/doc").await, b"doc\r\n.\r\n");
+        assert_eq!(fetch(&config, "/docs/nested/nested").await, b"nested\r\n.\r\n");
+        assert_eq!(fetch(&config, "/docsdoc").await, error_line("not found"));
+        assert_eq!(fetch(&config, "/docs/../main").await,
+            error_line("directory traversal denied"));
+
+        assert_eq!(fetch_menu(&config, "/docs").await,
+            ["i[localhost/docs]", "i", "1[parent directory]", "0doc", "."]);
+        let menu = String::from_utf8(fetch(&config, "/docs").await).unwrap();
+        assert!(menu.contains("\t/docs/doc\t"), "{menu}");
+    }
+
+    #[tokio::test]
+    async fn gopher_redirect() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        assert_eq!(
+            String::from_utf8(fetch(&config, "GOPHER:gopher://example.org:7070/1/dir").await)
+                .unwrap(),
+            "iThis resource has moved to:\t\terror.host\t1\r\n\
+            1gopher://example.org:7070/1/dir\t/dir\texample.org\t7070\r\n\
+            .\r\n");
+        // The link is to a menu, even if the URL says it's something else.
+        assert_eq!(fetch_menu(&config, "GOPHER:gopher://example.org/0/file.txt").await,
+            ["iThis resource has moved to:", "1gopher://example.org/0/file.txt", "."]);
+        assert_eq!(fetch(&config, "GOPHER:bogus").await,
+            error_line("invalid redirect URL"));
+    }
+
+    #[tokio::test]
+    async fn custom_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        assert_eq!(fetch(&config, "/missing").await, error_line("not found"));
+
+        std::fs::write(dir.path().join("!404"), "iNothing here.\n1Go home\t/\n").unwrap();
+        assert_eq!(
+            String::from_utf8(fetch(&config, "/missing").await).unwrap(),
+            "iNothing here.\t\terror.host\t1\r\n\
+            1Go home\t/\tlocalhost\t7070\r\n\
+            .\r\n");
+        assert_eq!(fetch_menu(&config, "bogus").await, ["iNothing here.", "1Go home", "."]);
+    }
+
+    #[tokio::test]
+    async fn mock_filesystem() {
+        use crate::fs::{MockFile, MockFileSystem};
+        let root = Path::new("/mock");
+        let files = MockFileSystem::default()
+            .with(root.join("hello.txt"), MockFile::File(b"hello\n".to_vec()))
+            .with(root.join("secret.txt"), MockFile::Unreadable)
+            .with(root.join("menu/!menu"), MockFile::File(b"iA menu\n".to_vec()))
+            .with(root.join("locked/!menu"), MockFile::Unreadable)
+            .with(root.join("phlog/!phlog"), MockFile::File(vec![]))
+            .with(root.join("phlog/2024-01-15-hello.txt"), MockFile::File(vec![]))
+            .with(root.join("empty"), MockFile::Directory);
+        let config = Arc::new(toml::from_str(r#"
+            document_root = "/mock"
+            hostname = "localhost"
+            port = 7070
+            "#).unwrap());
+        let (files, config) = (&files, &config);
+        let fetch = |selector| async move {
+            String::from_utf8(fetch_from(files, config, selector).await).unwrap()
+        };
+        let menu = |selector| async move {
+            fetch(selector).await
+                .lines()
+                .map(|line| line.split('\t').next().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(fetch("/hello.txt").await, "hello\n.\r\n");
+        assert_eq!(menu("/").await,
+            ["i[localhost/]", "i", "1empty", "0hello.txt", "1locked", "1menu", "1phlog",
+            "0secret.txt", "."]);
+        assert_eq!(menu("/empty").await, ["i[localhost/empty]", "i", "1[parent directory]", "."]);
+        assert_eq!(menu("/menu").await, ["iA menu", "."]);
+        assert_eq!(menu("/phlog/").await,
+            ["i[localhost/phlog/]", "i", "02024-01-15 hello", "."]);
+        let denied = String::from_utf8(error_line(response::ACCESS_DENIED)).unwrap();
+        assert_eq!(fetch("/secret.txt").await, denied);
+        assert_eq!(fetch("/locked").await, denied);
+        let not_found = String::from_utf8(error_line("not found")).unwrap();
+        assert_eq!(fetch("/missing").await, not_found);
+        assert_eq!(fetch("/hello.txt/more").await, not_found);
+
+        let req = Request { selector: "/menu".to_owned(), attributes: true };
+        let attributes = String::from_utf8(fetch_request(files, config, req).await).unwrap();
+        assert!(attributes.contains(" Mod-Date: Thu Jan  1 00:00:00 1970 <19700101000000>\r\n"),
+            "{attributes}");
+    }
+
+    #[tokio::test]
+    async fn detect_image_type() {
+        use crate::fs::{MockFile, MockFileSystem};
+        let root = Path::new("/mock");
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        let files = MockFileSystem::default()
+            .with(root.join("really-a.gif"), MockFile::File(png))
+            .with(root.join("really-a.jpg"), MockFile::File(b"GIF89a".to_vec()))
+            .with(root.join("unknown.png"), MockFile::File(b"???".to_vec()))
+            .with(root.join("locked.jpg"), MockFile::Unreadable)
+            .with(root.join("notes.txt"), MockFile::File(b"GIF89a".to_vec()));
+        let config: Config = toml::from_str(r#"
+            document_root = "/mock"
+            hostname = "localhost"
+            port = 7070
+            menu_header = []
+            "#).unwrap();
+        let menu = |config| async {
+            String::from_utf8(fetch_from(&files, &Arc::new(config), "/").await).unwrap()
+                .lines()
+                .map(|line| line.split('\t').next().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(menu(config.clone()).await,
+            ["Ilocked.jpg", "0notes.txt", "greally-a.gif", "Ireally-a.jpg", "Iunknown.png", "."]);
+        // Files that can't be read, or aren't recognized, go by their extensions.
+        assert_eq!(menu(Config { detect_image_type: true, ..config }).await,
+            ["Ilocked.jpg", "0notes.txt", "Ireally-a.gif", "greally-a.jpg", "Iunknown.png", "."]);
+    }
+
+    #[tokio::test]
+    async fn detect_image_type_symlinks() {
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("image.png"), b"\x89PNG\r\n\x1a\n").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path().join("image.png"), dir.path().join("image.gif"))
+            .unwrap();
+        let config = Arc::new(Config {
+            detect_image_type: true,
+            menu_header: Some(vec![]),
+            ..(*test_config(dir.path())).clone()
+        });
+        // Files the symlink policy rejects aren't opened, so they go by their extensions.
+        assert_eq!(fetch_menu(&config, "/").await, ["gimage.gif", "."]);
+        let config = Arc::new(Config {
+            symlink_policy: config::SymlinkPolicy::Follow,
+            ..(*config).clone()
+        });
+        assert_eq!(fetch_menu(&config, "/").await, ["Iimage.gif", "."]);
+    }
+
+    #[tokio::test]
+    async fn text_and_binary_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("text.txt"), ".hidden\r\nshown\r\n.\r\n").unwrap();
+        let binary = (0 ..= 255).cycle().take(4096).collect::<Vec<u8>>();
+        std::fs::write(dir.path().join("data.bin"), &binary).unwrap();
+        let config = test_config(dir.path());
+
+        assert_eq!(fetch(&config, "/text.txt").await, b"..hidden\r\nshown\r\n..\r\n.\r\n");
+        assert_eq!(fetch(&config, "/data.bin").await, binary);
+
+        // Without an extension to go by, binary files are told apart by what's in them.
+        let mut unnamed = b".\r\n".to_vec();
+        unnamed.extend(&binary);
+        std::fs::write(dir.path().join("data"), &unnamed).unwrap();
+        std::fs::write(dir.path().join("README"), ".hidden\r\n").unwrap();
+        assert_eq!(fetch(&config, "/data").await, unnamed);
+        assert_eq!(fetch(&config, "/README").await, b"..hidden\r\n.\r\n");
+        std::fs::remove_file(dir.path().join("data")).unwrap();
+        std::fs::remove_file(dir.path().join("README")).unwrap();
+
+        let menu = fetch_menu(&config, "").await;
+        assert_eq!(menu, ["i[localhost]", "i", "9data.bin", "0text.txt", "."]);
+    }
+
+    #[tokio::test]
+    async fn query_strings() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("file.txt"), "plain\n").unwrap();
+        std::fs::write(dir.path().join("what?.txt"), "question\n").unwrap();
+        let config = test_config(dir.path());
+
+        // Only phlog indexes and scripts take one, so anything else isn't found with one.
+        assert_eq!(fetch(&config, "/file.txt?x").await, error_line("not found"));
+        assert_eq!(fetch(&config, "/sub/?x").await, error_line("not found"));
+        // A '?' can be part of a name.
+        assert_eq!(fetch(&config, "/what?.txt").await, b"question\n.\r\n");
+    }
+
+    #[tokio::test]
+    async fn rejected_files_not_sniffed() {
+        let outside = tempfile::tempdir().unwrap();
+        nix::unistd::mkfifo(&outside.path().join("pipe"), nix::sys::stat::Mode::S_IRWXU).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path().join("pipe"), dir.path().join("pipe")).unwrap();
+        let config = test_config(dir.path());
+        // Opening the pipe to look at what's in it would wait for something to write to it.
+        let response = tokio::time::timeout(Duration::from_secs(5), fetch(&config, "/pipe")).await;
+        // Let it be opened, if it's being waited on, so the test can finish.
+        let pipe = outside.path().join("pipe");
+        std::thread::spawn(move || std::fs::write(pipe, ""));
+        assert_eq!(response.expect("waited on the pipe"), error_line("not found"));
+    }
+
+    #[tokio::test]
+    async fn parent_link() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("foo/bar")).unwrap();
+        let config = test_config(dir.path());
+        let menu = String::from_utf8(fetch(&config, "/foo/bar").await).unwrap();
+        assert!(menu.contains("1[parent directory]\t/foo\tlocalhost\t7070\r\n"), "{menu}");
+        let menu = String::from_utf8(fetch(&config, "/foo").await).unwrap();
+        assert!(menu.contains("1[parent directory]\t\tlocalhost\t7070\r\n"), "{menu}");
+        assert!(!fetch_menu(&config, "").await.contains(&"1[parent directory]".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn menu_header_footer() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub/a.txt"), "").unwrap();
+        let mut config = (*test_config(dir.path())).clone();
+        config.menu_header = Some(vec!["Welcome to {hostname}:{port}".to_owned(), String::new()]);
+        config.menu_footer = Some(vec![String::new(), "You are at {selector}".to_owned()]);
+        let config = Arc::new(config);
+
+        assert_eq!(fetch_menu(&config, "/sub").await,
+            ["iWelcome to localhost:7070", "i", "1[parent directory]", "0a.txt", "i",
+                "iYou are at /sub", "."]);
+
+        // Files in the directory still take precedence.
+        std::fs::write(dir.path().join("sub").join(fs::FOOTER_FILE), "iBye\t\n").unwrap();
+        assert_eq!(fetch_menu(&config, "/sub").await,
+            ["iWelcome to localhost:7070", "i", "1[parent directory]", "0a.txt", "iBye", "."]);
+    }
+
+    #[tokio::test]
+    async fn dir_config_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("sub/deeper")).unwrap();
+        std::fs::create_dir(root.join("sub/adir")).unwrap();
+        for name in ["a.txt", "b.log", "sub/a.txt", "sub/b.log", "sub/deeper/a.txt",
+            "sub/deeper/b.log", "sub/deeper/c.txt"]
+        {
+            std::fs::write(root.join(name), "").unwrap();
+        }
+        let config = test_config(root);
+
+        assert_eq!(fetch_menu(&config, "/sub").await, ["i[localhost/sub]", "i",
+            "1[parent directory]", "0a.txt", "1adir", "0b.log", "1deeper", "."]);
+
+        std::fs::write(root.join("sub/.gofer"), r#"
+            hide_patterns = ["*.log"]
+            dirs_first = true
+            menu_header = ["Custom header"]
+            max_entries = 2
+            "#).unwrap();
+        assert_eq!(fetch_menu(&config, "").await,
+            ["i[localhost]", "i", "0a.txt", "0b.log", "1sub", "."]);
+        assert_eq!(fetch_menu(&config, "/sub").await, ["iCustom header", "1[parent directory]",
+            "1adir", "1deeper", "i... and 1 more entry (listing truncated)", "."]);
+        assert_eq!(fetch_menu(&config, "/sub/deeper").await,
+            ["iCustom header", "1[parent directory]", "0a.txt", "0c.txt", "."]);
+
+        // The nearest one wins, and replaces the parent's entirely.
+        std::fs::write(root.join("sub/deeper/.gofer"), "dir_sort = \"name_reverse\"").unwrap();
+        assert_eq!(fetch_menu(&config, "/sub/deeper").await, ["i[localhost/sub/deeper]", "i",
+            "1[parent directory]", "0c.txt", "0b.log", "0a.txt", "."]);
+    }
+
+    #[tokio::test]
+    async fn listing_meta() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let jan_15 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_705_320_000);
+        let file = std::fs::File::create(root.join("a.txt")).unwrap();
+        file.set_len(4300).unwrap();
+        file.set_modified(jan_15).unwrap();
+        std::fs::write(root.join("b.log"), "").unwrap();
+        std::fs::create_dir_all(root.join("sub/deeper")).unwrap();
+        std::fs::write(root.join("sub/c.txt"), "").unwrap();
+        std::fs::write(root.join("sub/.gofer"), "").unwrap();
+        std::fs::File::open(root.join("sub")).unwrap().set_modified(jan_15).unwrap();
+
+        let mut config = (*test_config(root)).clone();
+        config.dir_listing_show_meta = true;
+        config.hide_patterns = vec!["*.log".to_owned()];
+        assert_eq!(fetch_menu(&Arc::new(config.clone()), "").await,
+            ["i[localhost]", "i", "0a.txt 4.2 KB 2024-01-15", "1sub 2 items 2024-01-15", "."]);
+
+        config.dir_listing_meta_format = "{mtime} {name} ({size})".to_owned();
+        assert_eq!(fetch_menu(&Arc::new(config), "").await,
+            ["i[localhost]", "i", "02024-01-15 a.txt (4.2 KB)", "12024-01-15 sub (2 items)", "."]);
+    }
+
+    #[test]
+    fn response_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Response>();
+        assert_send::<Menu>();
+    }
+
+    #[tokio::test]
+    async fn upstream() {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (conn, _) = upstream.accept().await.unwrap();
+                let mut conn = BufReader::new(conn);
+                let mut line = String::new();
+                conn.read_line(&mut line).await.unwrap();
+                conn.write_all(format!("upstream got {line:?}").as_bytes()).await.unwrap();
+            }
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("local.txt"), "local\n").unwrap();
+        let mut config = (*test_config(dir.path())).clone();
+        config.upstream = Some(upstream_addr.to_string());
+        let config = Arc::new(config);
+
+        assert_eq!(fetch(&config, "/local.txt").await, b"local\n.\r\n");
+        assert_eq!(fetch(&config, "/missing?x").await, br#"upstream got "/missing?x\r\n""#);
+        assert_eq!(fetch(&config, "legacy").await, br#"upstream got "legacy\r\n""#);
+
+        // Nothing listening there any more.
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = (*config).clone();
+        config.upstream = Some(closed.local_addr().unwrap().to_string());
+        drop(closed);
+        assert_eq!(fetch(&Arc::new(config), "/missing").await,
+            error_line("upstream server unavailable"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn cgi() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let cgi_dir = root.join("cgi-bin");
+        std::fs::create_dir(&cgi_dir).unwrap();
+        let script = |path: &Path, body: &str| {
+            std::fs::write(path, format!("#!/bin/sh\n{body}\n")).unwrap();
+            std::fs::set_permissions(path, PermissionsExt::from_mode(0o755)).unwrap();
+        };
+        script(&cgi_dir.join("hello"), "echo \"$SELECTOR $QUERY_STRING $REMOTE_ADDR \
+            $SERVER_NAME:$SERVER_PORT\"; echo oops >&2");
+        script(&cgi_dir.join("slow"), "sleep 10");
+        script(&root.join("outside"), "echo ran");
+        std::os::unix::fs::symlink(root.join("outside"), cgi_dir.join("link")).unwrap();
+        let config = Arc::new(Config {
+            cgi_dir: Some(cgi_dir),
+            cgi_timeout_secs: 1,
+            symlink_policy: config::SymlinkPolicy::Follow,
+            ..(*test_config(&root)).clone()
+        });
+        let peer = Peer::Tcp("192.0.2.1:1234".parse().unwrap());
+        let fetch = |selector: &str| {
+            let req = Request { selector: selector.to_owned(), attributes: false };
+            let (config, peer) = (&config, &peer);
+            async move {
+                let menu_cache = MenuCache::new(0, Duration::ZERO);
+                let mut out = vec![];
+                handle_request(config, &RealFileSystem, &menu_cache, &FileCache::default(),
+                    &DirCache::default(), Some(peer), req).await
+                    .write(&mut out, &config.menu_encoder()).await.unwrap();
+                String::from_utf8(out).unwrap()
+            }
+        };
+
+        assert_eq!(fetch("/cgi-bin/hello?a=b").await,
+            "/cgi-bin/hello a=b 192.0.2.1 localhost:7070\n");
+        assert_eq!(fetch("/cgi-bin/slow").await,
+            String::from_utf8(error_line("script timed out")).unwrap());
+        // Scripts outside the directory are just files, even when linked to from inside it.
+        assert_eq!(fetch("/outside").await, "#!/bin/sh\necho ran\n.\r\n");
+        assert_eq!(fetch("/cgi-bin/link").await, "#!/bin/sh\necho ran\n.\r\n");
+    }
+
+    #[tokio::test]
+    async fn file_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hello.txt");
+        std::fs::write(&path, "hello\n").unwrap();
+        let config = test_config(dir.path());
+        let menu_cache = MenuCache::new(0, Duration::ZERO);
+        let file_cache = FileCache::new(1024, 100);
+        let fetch = |selector: &str| {
+            let req = Request { selector: selector.to_owned(), attributes: false };
+            async {
+                let mut response = handle_request(&config, &RealFileSystem, &menu_cache,
+                    &file_cache, &DirCache::default(), None, req).await;
+                let mut out = vec![];
+                response.write(&mut out, &config.menu_encoder()).await.unwrap();
+                (response.kind(), String::from_utf8(out).unwrap())
+            }
+        };
+
+        assert_eq!(fetch("/hello.txt").await, ("cached", "hello\n.\r\n".to_owned()));
+        assert_eq!(fetch("/hello.txt").await, ("cached", "hello\n.\r\n".to_owned()));
+        std::fs::write(&path, "goodbye\n").unwrap();
+        assert_eq!(fetch("/hello.txt").await, ("cached", "goodbye\n.\r\n".to_owned()));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(fetch("/hello.txt").await.0, "not_found");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn file_cache_scripts() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::create_dir(root.join("cgi-bin")).unwrap();
+        let script = root.join("cgi-bin/hello");
+        std::fs::write(&script, "#!/bin/sh\necho ran\n").unwrap();
+        std::fs::set_permissions(&script, PermissionsExt::from_mode(0o755)).unwrap();
+        let menu_cache = MenuCache::new(0, Duration::ZERO);
+        let file_cache = FileCache::new(1024, 100);
+        let fetch = |config: Arc<Config>| {
+            let req = Request { selector: "/cgi-bin/hello".to_owned(), attributes: false };
+            let (menu_cache, file_cache) = (&menu_cache, &file_cache);
+            async move {
+                let mut out = vec![];
+                handle_request(&config, &RealFileSystem, menu_cache, file_cache,
+                    &DirCache::default(), None, req).await
+                    .write(&mut out, &config.menu_encoder()).await.unwrap();
+                String::from_utf8(out).unwrap()
+            }
+        };
+
+        let config = test_config(&root);
+        assert_eq!(fetch(config.clone()).await, "#!/bin/sh\necho ran\n.\r\n");
+        // Once it's a script, it's run, rather than served from the cache.
+        let config = Arc::new(Config {
+            cgi_dir: Some(root.join("cgi-bin")),
+            ..(*config).clone()
+        });
+        assert_eq!(fetch(config).await, "ran\n");
+    }
+
+    #[tokio::test]
+    async fn dir_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.txt", "b.gif", ".hidden"] {
+            std::fs::write(dir.path().join(name), "hello\n").unwrap();
+        }
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        let config = Arc::new(Config {
+            hide_patterns: vec![".*".to_owned()],
+            dir_listing_show_meta: true,
+            ..(*test_config(dir.path())).clone()
+        });
+        let menu_cache = MenuCache::new(0, Duration::ZERO);
+        let file_cache = FileCache::default();
+        let dir_cache = DirCache::new(10, Duration::from_secs(60));
+        let fetch = |config: Arc<Config>, dir_cache: DirCache| {
+            let (menu_cache, file_cache) = (&menu_cache, &file_cache);
+            async move {
+                let req = Request { selector: "/".to_owned(), attributes: false };
+                let mut out = vec![];
+                handle_request(&config, &RealFileSystem, menu_cache, file_cache, &dir_cache, None,
+                    req).await.write(&mut out, &config.menu_encoder()).await.unwrap();
+                String::from_utf8(out).unwrap()
+            }
+        };
+
+        let uncached = fetch(config.clone(), DirCache::default()).await;
+        assert!(uncached.contains("a.txt") && !uncached.contains(".hidden"), "{uncached}");
+        assert_eq!(fetch(config.clone(), dir_cache.clone()).await, uncached);
+        assert_eq!(fetch(config.clone(), dir_cache.clone()).await, uncached);
+
+        // Settings that go into the listing aren't served stale after a reload.
+        let reloaded = Arc::new(Config { hostname: "example.com".to_owned(),
+            hide_patterns: vec![], ..(*config).clone() });
+        let listing = fetch(reloaded.clone(), dir_cache.clone()).await;
+        assert_eq!(listing, fetch(reloaded, DirCache::default()).await);
+        assert!(listing.contains("\texample.com\t") && listing.contains(".hidden"), "{listing}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spawned_over_duplex() {
+        use tokio::io::AsyncReadExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("hello.txt"), "hello\n").unwrap();
+        let config = test_config(dir.path());
+        let menu_cache = MenuCache::new(10, Duration::from_secs(60));
+
+        // No socket involved: the response goes through an in-memory pipe, from another task.
+        let (tx, mut rx) = tokio::io::duplex(16);
+        let handler = tokio::spawn(async move {
+            let req = Request { selector: "/hello.txt".to_owned(), attributes: false };
+            let file_cache = FileCache::default();
+            let mut response = handle_request(&config, &RealFileSystem, &menu_cache, &file_cache,
+                &DirCache::default(), None, req).await;
+            let idle = Duration::from_secs(10);
+            response.write_with_timeouts(tx, &config.menu_encoder(), idle, None).await
+        });
+        let mut out = String::new();
+        rx.read_to_string(&mut out).await.unwrap();
+        assert_eq!(out, "hello\n.\r\n");
+        let (bytes, result) = handler.await.unwrap();
+        result.unwrap();
+        assert_eq!(bytes, out.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn phlog() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("phlog");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("!phlog"), "My phlog\n").unwrap();
+        std::fs::create_dir(root.join("2024-01-15-hello-world")).unwrap();
+        std::fs::write(root.join("2024-02-01-second.txt"), "").unwrap();
+        std::fs::write(root.join("2023-12-31"), "").unwrap();
+        std::fs::write(root.join("about.txt"), "").unwrap();
+        let config = Arc::new(Config {
+            phlog_entries_per_page: 2,
+            ..(*test_config(dir.path())).clone()
+        });
+
+        assert_eq!(fetch_menu(&config, "/phlog").await, [
+            "iMy phlog",
+            "02024-02-01 second",
+            "12024-01-15 hello world",
+            "i-------------------------------------------------------------------",
+            "1Older posts",
+            ".",
+        ]);
+        let menu = String::from_utf8(fetch(&config, "/phlog").await).unwrap();
+        assert!(menu.contains("\t/phlog/2024-01-15-hello-world\t"), "{menu}");
+        assert!(menu.contains("\t/phlog/?page=2\t"), "{menu}");
+
+        assert_eq!(fetch_menu(&config, "/phlog/?page=2").await, [
+            "iMy phlog",
+            "02023-12-31",
+            "i-------------------------------------------------------------------",
+            "1Newer posts",
+            ".",
+        ]);
+        let menu = String::from_utf8(fetch(&config, "/phlog/?page=2").await).unwrap();
+        assert!(menu.contains("\t/phlog/\t"), "{menu}");
+
+        assert_eq!(fetch_menu(&config, "/phlog?page=3").await, ["3no such page", "."]);
+        assert_eq!(fetch_menu(&config, "/phlog?page=0").await, ["3invalid page number", "."]);
+
+        // Nothing else takes a query string.
+        assert_eq!(fetch(&config, "/?page=2").await, error_line("not found"));
+    }
+}
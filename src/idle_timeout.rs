@@ -0,0 +1,87 @@
+use pin_project_lite::pin_project;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+pin_project! {
+    /// A writer which fails with `ErrorKind::TimedOut` if the underlying writer can't make any
+    /// progress for too long, i.e. the client has stopped reading. It works the same way on a
+    /// reader, for when the other end stops sending.
+    pub struct IdleTimeout<W> {
+        #[pin]
+        inner: W,
+
+        #[pin]
+        sleep: Sleep,
+
+        timeout: Duration,
+
+        // Whether the inner writer is currently blocked, and the sleep is armed.
+        waiting: bool,
+    }
+}
+
+impl<W> IdleTimeout<W> {
+    pub fn new(inner: W, timeout: Duration) -> Self {
+        Self {
+            inner,
+            sleep: tokio::time::sleep(timeout),
+            timeout,
+            waiting: false,
+        }
+    }
+
+    fn poll_op<T>(
+        self: Pin<&mut Self>,
+        ctx: &mut Context<'_>,
+        waiting_for: &str,
+        op: impl FnOnce(Pin<&mut W>, &mut Context<'_>) -> Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        let mut this = self.project();
+        match op(this.inner, ctx) {
+            Poll::Ready(result) => {
+                *this.waiting = false;
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                if !*this.waiting {
+                    this.sleep.as_mut().reset(Instant::now() + *this.timeout);
+                    *this.waiting = true;
+                }
+                match this.sleep.poll(ctx) {
+                    Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut, format!("timed out waiting for {waiting_for}")))),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for IdleTimeout<W> {
+    fn poll_write(self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        self.poll_op(ctx, "client to read", |w, ctx| w.poll_write(ctx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_op(ctx, "client to read", |w, ctx| w.poll_flush(ctx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_op(ctx, "client to read", |w, ctx| w.poll_shutdown(ctx))
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for IdleTimeout<R> {
+    fn poll_read(self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &mut ReadBuf<'_>)
+        -> Poll<io::Result<()>>
+    {
+        self.poll_op(ctx, "data", |r, ctx| r.poll_read(ctx, buf))
+    }
+}
@@ -0,0 +1,155 @@
+//! Checks of the menu files in a document tree, for `gofer check`. They're read the same way as
+//! when they're served, so anything the server would log about them is reported, along with links
+//! to this server that don't lead anywhere.
+
+use anyhow::{bail, Result};
+use crate::config::Config;
+use crate::fs::{self, FileSystemProvider, FileType, MenuFormat, RealFileSystem};
+use crate::menu::MenuItem;
+use crate::menu_file::{LoadedLine, MenuLoader};
+use crate::selector;
+use crate::types::ItemType;
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::File;
+
+/// Something wrong with a line of a menu file, or with a directory that couldn't be checked, in
+/// which case the line is 0.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Problem {
+    pub path: PathBuf,
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.line {
+            0 => write!(f, "{}: {}", self.path.display(), self.message),
+            line => write!(f, "{}:{line}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+/// For `gofer check`: report every problem with the menu files in the document roots, and fail if
+/// there are any.
+pub async fn run(config: Config) -> Result<()> {
+    let problems = check_tree(&Arc::new(config)).await;
+    for problem in &problems {
+        println!("{problem}");
+    }
+    match problems.len() {
+        0 => println!("Menus OK"),
+        1 => bail!("found 1 problem in menu files"),
+        n => bail!("found {n} problems in menu files"),
+    }
+    Ok(())
+}
+
+/// Check the menu file of every directory in the document roots: the one the server would use,
+/// if there's more than one. Problems are sorted by file and line.
+pub async fn check_tree(config: &Arc<Config>) -> Vec<Problem> {
+    let mut problems = vec![];
+    // Each directory to look at, with the document root it's in, and its selector.
+    let roots = std::iter::once((&config.document_root, ""))
+        .chain(config.mounts.iter().map(|mount| (&mount.document_root, mount.prefix.as_str())));
+    let mut dirs = roots
+        .map(|(root, selector)| (root.clone(), root.clone(), selector.to_owned()))
+        .collect::<Vec<_>>();
+    while let Some((root, dir, dir_selector)) = dirs.pop() {
+        match RealFileSystem.read_dir(&dir, false).await {
+            Ok(entries) => {
+                for entry in entries.into_iter().filter(|entry| entry.is_dir) {
+                    let child = format!("{}/{}", dir_selector.trim_end_matches('/'),
+                        selector::encode_segment(&entry.file_name));
+                    dirs.push((root.clone(), dir.join(&entry.file_name), child));
+                }
+            }
+            Err(e) => {
+                let message = format!("can't read directory: {e}");
+                problems.push(Problem { path: dir.clone(), line: 0, message });
+            }
+        }
+        for (name, format) in fs::MENU_FILES {
+            let path = dir.join(name);
+            match fs::open_special(&path, &root, config.symlink_policy).await {
+                Ok(Some(file)) => {
+                    problems.extend(check_menu(file, path, *format, &root, &dir_selector, config)
+                        .await);
+                }
+                Ok(None) => continue,
+                Err(e) => problems.push(Problem { path, line: 0, message: e.to_string() }),
+            }
+            break;
+        }
+    }
+    problems.sort();
+    // Files included from more than one menu are checked each time.
+    problems.dedup();
+    problems
+}
+
+/// Check a menu file at `path`, in `root`, for the directory selected by `dir`. It's read by the
+/// same [`MenuLoader`] as when it's served, so files it includes are checked too, with their
+/// problems reported against them.
+pub async fn check_menu(file: File, path: PathBuf, format: MenuFormat, root: &Path, dir: &str,
+    config: &Arc<Config>) -> Vec<Problem>
+{
+    let mut loader = MenuLoader::new(file, path, format, dir, dir, root, config.clone());
+    let mut problems = vec![];
+    while let Some((path, line, loaded)) = loader.next().await {
+        let messages = match loaded {
+            LoadedLine::Item(item) => check_item(&item, config).await,
+            LoadedLine::Invalid(e) | LoadedLine::Missing(e) => vec![e.to_string()],
+        };
+        problems.extend(messages.into_iter()
+            .map(|message| Problem { path: path.clone(), line, message }));
+    }
+    problems
+}
+
+/// What's wrong with an item as it would be sent, if anything.
+async fn check_item(item: &MenuItem, config: &Config) -> Vec<String> {
+    let mut problems = vec![];
+    if let ItemType::Other(c) = item.typ {
+        problems.push(format!("unknown item type {:?}", char::from(c)));
+    }
+    if let Some(max) = config.max_item_text {
+        let len = item.text.chars().count();
+        if len > max {
+            problems.push(format!("text is {len} characters, more than {max}"));
+        }
+    }
+    if let Some(problem) = check_link(item, config).await {
+        problems.push(problem);
+    }
+    problems
+}
+
+/// Whether a link to this server leads anywhere, going by the same lookup as a request for it.
+/// Links elsewhere aren't followed, and nothing is missing if there's an upstream server to pass
+/// requests for it on to.
+async fn check_link(item: &MenuItem, config: &Config) -> Option<String> {
+    let here = item.host.as_deref() == Some(config.hostname.as_str())
+        && item.port.as_deref() == Some(config.port.to_string().as_str());
+    if !here || matches!(item.typ, ItemType::Info | ItemType::Error) || config.upstream.is_some() {
+        return None;
+    }
+    let selector = item.selector.split_once('?').map_or(item.selector.as_str(), |(s, _)| s);
+    if !selector.is_empty() && !selector.starts_with('/') {
+        return None;
+    }
+    let (root, path) = match config.local_path(selector) {
+        Ok(found) => found,
+        Err(msg) => return Some(format!("selector {:?}: {msg}", item.selector)),
+    };
+    match RealFileSystem.lookup(&path, root, config.symlink_policy).await {
+        Ok(FileType::NotFound) => Some(format!("selector {:?} doesn't lead to anything",
+            item.selector)),
+        Ok(FileType::PermissionDenied) => Some(format!("selector {:?} leads to something the \
+            server can't read", item.selector)),
+        Ok(_) => None,
+        Err(e) => Some(format!("selector {:?}: {e}", item.selector)),
+    }
+}
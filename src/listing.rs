@@ -0,0 +1,497 @@
+//! Generated menus for directories without menu files of their own, and for phlogs.
+
+use crate::config::{Config, LongTextPolicy, SortOrder};
+use crate::dir_cache::DirCache;
+use crate::fs::{DirEntry, FileSystemProvider, MenuFormat};
+use crate::menu::{Menu, MenuItem, SEPARATOR_WIDTH, peek_title};
+use crate::menu_cache::{Dependency, MenuCache};
+use crate::menu_file::menu_items;
+use crate::response::Response;
+use crate::types::ItemType;
+use crate::{fs, phlog, selector};
+use futures::future;
+use futures::stream::{self, StreamExt};
+use std::cmp::Ordering;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tracing::{debug, warn};
+
+/// A directory entry, with what's needed to sort it and make a menu item out of it.
+#[derive(Debug)]
+pub struct ListedEntry {
+    file_name: OsString,
+    /// The file name for display purposes; lossy if it isn't valid UTF-8.
+    name: String,
+    is_dir: bool,
+    typ: ItemType,
+    modified: Option<SystemTime>,
+    size: u64,
+    /// How many entries a directory has, if it's been counted.
+    dir_entries: Option<usize>,
+}
+
+/// How much of the start of an image file is read to tell what kind it is.
+const IMAGE_MAGIC_BYTES: usize = 8;
+
+/// Get what's needed to list an entry of the directory `dir`, in `root`. What's in it is only
+/// counted if it's a directory and that's going to be shown. Images are only opened to check what
+/// kind they are if `detect_image_type` is on, and the symlink policy allows it.
+pub async fn list_entry(files: &impl FileSystemProvider, dir: &Path, root: &Path, entry: DirEntry,
+    config: &Config) -> ListedEntry
+{
+    let path = dir.join(&entry.file_name);
+    let dir_entries = if entry.is_dir && config.dir_listing_show_meta {
+        count_entries(files, &path).await
+    } else {
+        None
+    };
+    let typ = match (entry.is_dir, ItemType::for_file(Path::new(&entry.file_name))) {
+        (true, _) => ItemType::Directory,
+        (false, typ @ (ItemType::Gif | ItemType::Image)) if config.detect_image_type => {
+            match files.read_start(&path, root, config.symlink_policy, IMAGE_MAGIC_BYTES).await {
+                Ok(start) => ItemType::for_image(&start).unwrap_or(typ),
+                Err(e) => {
+                    debug!("can't read the start of {path:?}: {e}");
+                    typ
+                }
+            }
+        }
+        (false, typ) => typ,
+    };
+    let name = entry.file_name.to_string_lossy().into_owned();
+    ListedEntry {
+        file_name: entry.file_name,
+        name,
+        is_dir: entry.is_dir,
+        typ,
+        modified: entry.modified,
+        size: entry.size,
+        dir_entries,
+    }
+}
+
+/// How many entries a directory has, not counting special files.
+async fn count_entries(files: &impl FileSystemProvider, path: &Path) -> Option<usize> {
+    match files.read_dir(path, false).await {
+        Ok(entries) => Some(entries.iter()
+            .filter(|entry| !fs::is_special_file(&entry.file_name))
+            .count()),
+        Err(e) => {
+            debug!("can't count entries of {path:?}: {e}");
+            None
+        }
+    }
+}
+
+/// A size in bytes, in the largest unit that keeps it at least 1, like `4.2 KB`.
+fn human_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["KB", "MB", "GB", "TB", "PB"];
+    if size < 1024 {
+        return format!("{size} B");
+    }
+    let mut size = size as f64 / 1024.;
+    let mut unit = 0;
+    while size >= 1024. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// The text for a directory entry's menu item when its metadata is shown, filled in from the
+/// configured format.
+fn meta_text(entry: &ListedEntry, format: &str) -> String {
+    let size = match (entry.is_dir, entry.dir_entries) {
+        (true, Some(1)) => "1 item".to_owned(),
+        (true, Some(count)) => format!("{count} items"),
+        (true, None) => "-".to_owned(),
+        (false, _) => human_size(entry.size),
+    };
+    let mtime = entry.modified
+        .map(|time| time::OffsetDateTime::from(time)
+            .format(time::macros::format_description!("[year]-[month]-[day]"))
+            .unwrap())
+        .unwrap_or_else(|| "-".to_owned());
+    format
+        .replace("{name}", &entry.name)
+        .replace("{size}", &size)
+        .replace("{mtime}", &mtime)
+}
+
+pub fn sort_entries(entries: &mut [ListedEntry], order: SortOrder, dirs_first: bool) {
+    fn by_name(a: &ListedEntry, b: &ListedEntry) -> Ordering {
+        a.name.to_lowercase().cmp(&b.name.to_lowercase())
+            .then_with(|| a.name.cmp(&b.name))
+    }
+
+    entries.sort_by(|a, b| {
+        let dirs = if dirs_first {
+            b.is_dir.cmp(&a.is_dir)
+        } else {
+            Ordering::Equal
+        };
+        dirs.then_with(|| match order {
+            SortOrder::Name => by_name(a, b),
+            SortOrder::NameReverse => by_name(b, a),
+            SortOrder::Modified => a.modified.cmp(&b.modified).then_with(|| by_name(a, b)),
+            SortOrder::ModifiedReverse => b.modified.cmp(&a.modified).then_with(|| by_name(a, b)),
+            SortOrder::Size => a.size.cmp(&b.size).then_with(|| by_name(a, b)),
+            SortOrder::SizeReverse => b.size.cmp(&a.size).then_with(|| by_name(a, b)),
+        })
+    });
+}
+
+pub fn direntry_menuitem(entry: ListedEntry, selector: &str, config: &Config) -> MenuItem {
+    let selector = selector.to_owned() + "/" + &selector::encode_segment(&entry.file_name);
+    let typ = entry.typ;
+    let text = if config.dir_listing_show_meta {
+        meta_text(&entry, &config.dir_listing_meta_format)
+    } else {
+        entry.name
+    };
+    MenuItem::new(
+        typ,
+        text,
+        selector,
+        config.hostname.clone(),
+        config.port.to_string())
+}
+
+/// How much of the start of a menu file is read to look for its title.
+const TITLE_PEEK_BYTES: usize = 1024;
+
+/// The title the menu file in the directory `dir`, in `root`, gives it with a `=title` line, if it
+/// has one. Only the first few lines of the file are looked at. The menu files looked for, whether
+/// they were there or not, are added to `dependencies`.
+pub async fn dir_title(dir: &Path, root: &Path, config: &Config, menu_cache: &MenuCache,
+    dependencies: &mut Vec<Dependency>) -> Option<String>
+{
+    for (name, _format) in fs::MENU_FILES {
+        let path = dir.join(name);
+        dependencies.push(Dependency::new(path.clone()).await);
+        let file = match fs::open_special(&path, root, config.symlink_policy).await {
+            Ok(Some(file)) => file,
+            Ok(None) => continue,
+            Err(e) => {
+                debug!("can't open {path:?} for its title: {e}");
+                return None;
+            }
+        };
+        return menu_cache.title(path.clone(), file, |file| async move {
+            let mut start = vec![];
+            match file.take(TITLE_PEEK_BYTES as u64).read_to_end(&mut start).await {
+                Ok(len) => peek_title(&start, len < TITLE_PEEK_BYTES),
+                Err(e) => {
+                    debug!("can't read {path:?} for its title: {e}");
+                    None
+                }
+            }
+        }).await;
+    }
+    None
+}
+
+/// Read an optional menu file to be merged into a generated menu.
+pub async fn menu_part(path: PathBuf, root: &Path, selector: &str, config: &Arc<Config>)
+    -> Option<Vec<MenuItem>>
+{
+    match fs::open_special(&path, root, config.symlink_policy).await {
+        Ok(Some(file)) => {
+            let items = menu_items(file, path, MenuFormat::Menu, selector, selector, root,
+                config.clone());
+            Some(items.collect().await)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            warn!("error opening {path:?}: {e}");
+            None
+        }
+    }
+}
+
+/// The selector of the directory containing the one given, or `None` for the root.
+pub fn parent_selector(selector: &str) -> Option<&str> {
+    let selector = selector.trim_end_matches('/');
+    if selector.is_empty() {
+        return None;
+    }
+    Some(selector.rsplit_once('/').map_or("", |(parent, _)| parent))
+}
+
+/// Info lines from the config, with `{hostname}`, `{port}`, and `{selector}` filled in.
+pub fn info_lines(lines: &[String], selector: &str, config: &Config) -> Vec<MenuItem> {
+    let port = config.port.to_string();
+    lines.iter()
+        .map(|line| MenuItem::info(line
+            .replace("{hostname}", &config.hostname)
+            .replace("{port}", &port)
+            .replace("{selector}", selector)))
+        .collect()
+}
+
+/// Log a warning for each item whose text is longer than `max_item_text`, if they're to be sent as
+/// they are. It's done when a menu is read or generated, rather than every time it's sent, so a
+/// cached one is only warned about once.
+pub fn warn_long_text(items: &[MenuItem], config: &Config) {
+    let (Some(max), LongTextPolicy::Warn) = (config.max_item_text, config.long_item_text) else {
+        return;
+    };
+    for item in items {
+        let len = item.text.chars().count();
+        if len > max {
+            warn!("menu item text is {len} characters, more than {max}: {:?}", item.text);
+        }
+    }
+}
+
+/// Compile the configured patterns of file names to hide. Invalid ones are logged and ignored.
+pub fn hide_patterns(config: &Config) -> Vec<glob::Pattern> {
+    config.hide_patterns.iter()
+        .filter_map(|pattern| match glob::Pattern::new(pattern) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                warn!("invalid hide pattern {pattern:?}: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+pub async fn generate_menu(files: &impl FileSystemProvider, path: &Path, root: &Path,
+    selector: &str, config: &Arc<Config>, menu_cache: &MenuCache, dir_cache: &DirCache)
+    -> Response
+{
+    let config = &match fs::load_dir_config(path, root).await {
+        Some(overrides) => Arc::new(config.with_overrides(overrides)),
+        None => config.clone(),
+    };
+    let items = dir_cache.get(path, selector, config,
+        || dir_listing(files, path, root, selector, config, menu_cache));
+    match items.await {
+        Ok(items) => {
+            let items = (0 .. items.len()).map(move |i| items[i].clone());
+            Response::Menu(Menu::new(stream::iter(items)))
+        }
+        Err(e) => e.into(),
+    }
+}
+
+/// All the items of a generated directory menu, with `config` already including the directory's
+/// overrides, and the menu files in subdirectories that were looked at for their titles.
+pub async fn dir_listing(files: &impl FileSystemProvider, path: &Path, root: &Path, selector: &str,
+    config: &Arc<Config>, menu_cache: &MenuCache) -> io::Result<(Vec<MenuItem>, Vec<Dependency>)>
+{
+    let show_meta = config.dir_listing_show_meta;
+    let entries = files.read_dir(path, config.dir_sort.needs_metadata() || show_meta).await?;
+    let header = match menu_part(path.join(fs::HEADER_FILE), root, selector, config).await {
+        Some(items) => items,
+        None => match &config.menu_header {
+            Some(lines) => info_lines(lines, selector, config),
+            None => vec![
+                MenuItem::info(format!("[{}{}]", &config.hostname, selector)),
+                MenuItem::blank(),
+            ],
+        },
+    };
+    let footer = match menu_part(path.join(fs::FOOTER_FILE), root, selector, config).await {
+        Some(items) => items,
+        None => match &config.menu_footer {
+            Some(lines) => info_lines(lines, selector, config),
+            None => vec![],
+        },
+    };
+
+    let mut entries = stream::iter(entries)
+        .filter(|entry| future::ready(!fs::is_special_file(&entry.file_name)))
+        .then(|entry| list_entry(files, path, root, entry, config))
+        .collect::<Vec<_>>()
+        .await;
+    // Subdirectories go by the titles their menu files give them, if they have any.
+    let mut dependencies = vec![];
+    for entry in entries.iter_mut().filter(|entry| entry.is_dir) {
+        let dir = path.join(&entry.file_name);
+        if let Some(title) = dir_title(&dir, root, config, menu_cache, &mut dependencies).await {
+            entry.name = title;
+        }
+    }
+    sort_entries(&mut entries, config.dir_sort, config.dirs_first);
+
+    let items = entries.into_iter()
+        .map(|entry| direntry_menuitem(entry, selector, config))
+        .collect::<Vec<_>>();
+    // The patterns match file names, which are at the end of the selector; the item text might
+    // have more than that in it.
+    let hide = hide_patterns(config);
+    let listing = Menu::from_vec(items).filter(move |item| {
+        let name = item.selector.rsplit('/').next().unwrap_or_default();
+        let name = selector::decode(name).unwrap_or_default();
+        !hide.iter().any(|p| p.matches(&name.to_string_lossy()))
+    });
+    let listing = match config.max_dir_entries {
+        Some(max) => listing.truncate(max, |more| MenuItem::info(match more {
+            1 => "... and 1 more entry (listing truncated)".to_owned(),
+            _ => format!("... and {more} more entries (listing truncated)"),
+        })),
+        None => listing,
+    };
+
+    let parent = parent_selector(selector).map(|parent| MenuItem::new(
+        ItemType::Directory,
+        "[parent directory]",
+        parent,
+        &config.hostname,
+        config.port.to_string()));
+    let items = stream::iter(header.into_iter().chain(parent))
+        .chain(listing.items)
+        .chain(stream::iter(footer))
+        .collect::<Vec<_>>()
+        .await;
+    warn_long_text(&items, config);
+    Ok((items, dependencies))
+}
+
+/// Generate the index of a phlog directory: its entries whose names start with a date, newest
+/// first, split into pages.
+pub async fn generate_phlog(files: &impl FileSystemProvider, path: &Path, selector: &str,
+    mut phlog_file: File, page: usize, config: &Arc<Config>) -> Response
+{
+    let mut text = String::new();
+    if let Err(e) = phlog_file.read_to_string(&mut text).await {
+        return e.into();
+    }
+    let mut items = if text.trim().is_empty() {
+        vec![MenuItem::info(format!("[{}{}]", &config.hostname, selector)), MenuItem::blank()]
+    } else {
+        phlog::header(&text)
+    };
+
+    let entries = match files.read_dir(path, false).await {
+        Ok(entries) => entries,
+        Err(e) => return e.into(),
+    };
+    let hide = hide_patterns(config);
+    let mut posts = entries.into_iter()
+        .filter(|entry| !fs::is_special_file(&entry.file_name)
+            && !hide.iter().any(|p| p.matches(&entry.file_name.to_string_lossy())))
+        .filter_map(|entry| phlog::Post::parse(entry.file_name, entry.is_dir))
+        .collect::<Vec<_>>();
+    phlog::sort(&mut posts);
+
+    let per_page = config.phlog_entries_per_page;
+    let start = (page - 1).saturating_mul(per_page);
+    if page > 1 && start >= posts.len() {
+        return Response::Error("no such page".into());
+    }
+    let older = posts.len() > start.saturating_add(per_page);
+    let base = selector.trim_end_matches('/');
+    let link = |text: &str, selector: String| MenuItem::new(
+        ItemType::Directory, text, selector, config.hostname.clone(), config.port.to_string());
+    let page_link = |text: &str, page: usize| match page {
+        1 => link(text, format!("{base}/")),
+        _ => link(text, format!("{base}/?page={page}")),
+    };
+
+    items.extend(posts.into_iter().skip(start).take(per_page).map(|post| {
+        let typ = if post.is_dir {
+            ItemType::Directory
+        } else {
+            ItemType::for_file(Path::new(&post.file_name))
+        };
+        MenuItem::new(
+            typ,
+            post.text(),
+            format!("{base}/{}", selector::encode_segment(&post.file_name)),
+            config.hostname.clone(),
+            config.port.to_string())
+    }));
+    if page > 1 || older {
+        items.push(MenuItem::separator(SEPARATOR_WIDTH));
+    }
+    if page > 1 {
+        items.push(page_link("Newer posts", page - 1));
+    }
+    if older {
+        items.push(page_link("Older posts", page + 1));
+    }
+    warn_long_text(&items, config);
+    Response::Menu(Menu::from_vec(items))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn entry(name: &str, is_dir: bool, age: u64, size: u64) -> ListedEntry {
+        ListedEntry {
+            file_name: name.into(),
+            name: name.to_owned(),
+            is_dir,
+            typ: if is_dir { ItemType::Directory } else { ItemType::File },
+            modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1000 - age)),
+            size,
+            dir_entries: None,
+        }
+    }
+
+    fn sorted(order: SortOrder, dirs_first: bool) -> Vec<String> {
+        let mut entries = vec![
+            entry("banana", false, 1, 30),
+            entry("Apple", false, 3, 10),
+            entry("cherry", true, 2, 20),
+            entry("apple", false, 4, 40),
+        ];
+        sort_entries(&mut entries, order, dirs_first);
+        entries.into_iter().map(|e| e.name).collect()
+    }
+
+    #[test]
+    fn sort_by_name() {
+        assert_eq!(sorted(SortOrder::Name, false), ["Apple", "apple", "banana", "cherry"]);
+        assert_eq!(sorted(SortOrder::NameReverse, false), ["cherry", "banana", "apple", "Apple"]);
+    }
+
+    #[test]
+    fn sort_by_modified() {
+        assert_eq!(sorted(SortOrder::Modified, false), ["apple", "Apple", "cherry", "banana"]);
+        assert_eq!(sorted(SortOrder::ModifiedReverse, false), ["banana", "cherry", "Apple", "apple"]);
+    }
+
+    #[test]
+    fn sort_by_size() {
+        assert_eq!(sorted(SortOrder::Size, false), ["Apple", "cherry", "banana", "apple"]);
+        assert_eq!(sorted(SortOrder::SizeReverse, false), ["apple", "banana", "cherry", "Apple"]);
+    }
+
+    #[test]
+    fn sort_dirs_first() {
+        assert_eq!(sorted(SortOrder::Name, true), ["cherry", "Apple", "apple", "banana"]);
+        assert_eq!(sorted(SortOrder::SizeReverse, true), ["cherry", "apple", "banana", "Apple"]);
+    }
+
+    #[test]
+    fn parent_selectors() {
+        assert_eq!(parent_selector(""), None);
+        assert_eq!(parent_selector("/"), None);
+        assert_eq!(parent_selector("/foo"), Some(""));
+        assert_eq!(parent_selector("/foo/"), Some(""));
+        assert_eq!(parent_selector("/foo/bar"), Some("/foo"));
+        assert_eq!(parent_selector("/foo/bar%20baz/"), Some("/foo"));
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1024), "1.0 KB");
+        assert_eq!(human_size(4300), "4.2 KB");
+        assert_eq!(human_size(5 * 1024 * 1024), "5.0 MB");
+        assert_eq!(human_size(3 << 40), "3.0 TB");
+        assert_eq!(human_size(u64::MAX), "16384.0 PB");
+    }
+}
@@ -1,234 +1,176 @@
+mod access_log;
 mod bounded_futures_unordered;
+mod byte_counter;
+mod cgi;
+mod cidr;
 mod config;
+mod dir_cache;
+mod file_cache;
 mod fs;
+mod handler;
+mod idle_timeout;
+mod lint;
+mod listing;
 mod menu;
+mod menu_cache;
+mod menu_file;
+mod phlog;
+mod pid_file;
+#[cfg(unix)]
+mod privileges;
+mod proxy_protocol;
+mod rate_limit;
 mod request;
+mod request_id;
 mod request_stream;
 mod response;
+mod selector;
+mod server;
+mod stats;
+#[cfg(unix)]
+mod systemd;
+#[cfg(feature = "opentelemetry")]
+mod telemetry;
+mod text;
+mod throttle;
+mod tls;
 mod types;
 
 use anyhow::{bail, Context, Result};
 use crate::config::Config;
-use crate::fs::{DirEntry, FileType};
-use crate::menu::{Menu, MenuItem, MenuItemDecoder};
+use crate::dir_cache::DirCache;
+use crate::file_cache::FileCache;
+use crate::fs::RealFileSystem;
+use crate::menu_cache::MenuCache;
 use crate::request::Request;
-use crate::request_stream::RequestStream;
 use crate::response::Response;
-use crate::types::ItemType;
-use futures::future;
-use futures::stream::{self, StreamExt};
-use std::path::Path;
-use std::rc::Rc;
-use tokio_stream::wrappers::ReadDirStream;
-use tokio_util::codec::FramedRead;
-
-// Accepted connections waiting on reading a full request.
-pub const MAX_QUEUED_REQUESTS: usize = 50;
-
-fn parse_args() -> Result<Config> {
-    match std::env::args_os().nth(1) {
-        Some(path) => {
-            let text = std::fs::read_to_string(&path)
-                .with_context(|| format!("failed to read config file {path:?}"))?;
-            let config = toml::from_str(&text)
-                .with_context(|| format!("error parsing config file {path:?}"))?;
-            Ok(config)
-        }
-        None => {
-            bail!("usage: {} <path to config.toml>", std::env::args().next().unwrap());
-        }
-    }
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+
+/// What to do, once the config file is loaded.
+enum Mode {
+    /// Run the server.
+    Serve,
+    /// Say whether the config file is valid, and exit.
+    Check,
+    /// Check the config file and every menu file it serves, report any problems, and exit.
+    CheckMenus,
+    /// Write the response to a selector to stdout, and exit.
+    DumpMenu(String),
 }
 
-async fn handle_request(config: &Config, req: Request) -> Response {
-    let path = if req.selector.is_empty() {
-        config.document_root.clone()
-    } else if req.selector.starts_with("URL:") {
-        return Response::Raw(html_redirect(&req.selector[4..]).into_bytes());
-    } else if req.selector.starts_with("GET ")
-        && (req.selector.ends_with(" HTTP/1.1") || req.selector.ends_with(" HTTP/1.0"))
-    {
-        // We don't know what the type is, but let's assume directory.
-        let url = format!("gopher://{}:{}/1{}",
-            config.hostname,
-            config.port,
-            &req.selector[4 .. req.selector.len() - 9],
-        );
-        return Response::Raw(http_response(&url).into_bytes());
-    } else if req.selector.starts_with('/') {
-        if req.selector == "/.." || req.selector.contains("/../") || req.selector.contains("//") {
-            return Response::Error("directory traversal denied".into());
+fn parse_args() -> Result<(PathBuf, Mode)> {
+    let mut args = std::env::args_os().skip(1);
+    let (path, mode) = match (args.next(), args.next(), args.next(), args.next()) {
+        (Some(command), Some(path), None, None) if command == "check" => (path, Mode::CheckMenus),
+        (Some(path), None, None, None) => (path, Mode::Serve),
+        (Some(path), Some(flag), None, None) if flag == "--check" => (path, Mode::Check),
+        (Some(path), Some(flag), Some(selector), None) if flag == "--dump-menu" => {
+            let selector = selector.into_string()
+                .map_err(|s| anyhow::anyhow!("selector {s:?} isn't valid UTF-8"))?;
+            (path, Mode::DumpMenu(selector))
         }
-        config.document_root.join(&req.selector[1..])
-    } else {
-        return Response::Error("not found".into());
+        _ => bail!("usage: {0} <path to config.toml> [--check | --dump-menu <selector>]\n       \
+            {0} check <path to config.toml>", std::env::args().next().unwrap()),
     };
-
-    match fs::lookup(&path).await {
-        Ok(FileType::Menu { file: menu_file, path: menu_path }) => {
-            eprintln!("menu {menu_path:?}");
-            let config_rc = Rc::new(config.to_owned());
-            let items = FramedRead::new(menu_file, MenuItemDecoder)
-                .enumerate()
-                .filter_map(move |(line, result)| future::ready(
-                    match result {
-                        Ok(x) => Some(x),
-                        Err(e) => {
-                            eprintln!("error in {:?} on line {}: {}",
-                                menu_path,
-                                line + 1,
-                                e);
-                            None
-                        }
-                    }))
-                .map(move |mut item| {
-                    if item.typ != ItemType::Info && item.typ != ItemType::Error {
-                        if item.port.is_none() {
-                            if item.host.is_none() {
-                                item.host = Some(config_rc.hostname.clone());
-                                item.port = Some(config_rc.port.to_string());
-                            } else {
-                                item.port = Some("70".to_owned());
-                            }
-                        } else if item.host.is_none() {
-                            item.host = Some(config_rc.hostname.clone());
-                        }
-                    }
-                    item
-                });
-            Response::Menu(Menu::new(items))
-        }
-        Ok(FileType::Directory) => {
-            eprintln!("directory {path:?}");
-            generate_menu(&path, &req.selector, config).await
-        }
-        Ok(FileType::File(file)) => {
-            eprintln!("file {path:?}");
-            Response::File(file)
-        }
-        Ok(FileType::NotFound) => {
-            eprintln!("not found {path:?}");
-            Response::Error("not found".into())
-        }
-        Err(e) => e.into(),
-    }
+    Ok((path.into(), mode))
 }
 
-async fn direntry_menuitem(entry: DirEntry, selector: Rc<String>, config: Rc<Config>)
-    -> Option<MenuItem>
-{
-    async fn inner(entry: DirEntry, selector: &str, config: &Config) -> Option<MenuItem> {
-        let is_dir = match entry.file_type()
-            .await
-            .map(|ft| ft.is_dir())
-        {
-            Ok(b) => b,
-            Err(e) => {
-                eprintln!("error getting file type of {:?}: {}", entry.path(), e);
-                return None;
-            }
-        };
-
-        // TODO: if it's not representable as UTF-8, this will be bad.
-        let text = entry.file_name().to_string_lossy().into_owned();
-        let selector = selector.to_owned() + "/" + &text;
-        let typ = if is_dir {
-            ItemType::Directory
-        } else {
-            // TODO: file types for images, audio, etc. based on extensions.
-            ItemType::File
-        };
-        Some(MenuItem::new(
-            typ,
-            text,
-            selector,
-            config.hostname.clone(),
-            config.port.to_string()))
+/// Extra checks for `--check`, too slow or unreliable to hold up every start and reload with:
+/// warn if the hostname doesn't resolve, since clients need it to follow links in menus.
+fn check_config(config: &Config) {
+    use std::net::ToSocketAddrs;
+    if let Err(e) = (config.hostname.as_str(), config.port).to_socket_addrs() {
+        warn!("hostname: {:?} doesn't resolve ({e}); clients may not be able to follow links in \
+            generated menus", config.hostname);
     }
-    inner(entry, &selector, &config).await
 }
 
-
-async fn generate_menu(path: &Path, selector: &str, config: &Config) -> Response {
-    match fs::read_dir(path).await {
-        Ok(stream) => {
-            let header = stream::iter(vec![
-                MenuItem::info(format!("[{}{}]", &config.hostname, selector)),
-                MenuItem::info("")
-            ]);
-
-            let selector_rc = Rc::new(selector.to_owned());
-            let config_rc = Rc::new(config.to_owned());
-            let items = ReadDirStream::new(stream)
-                .filter_map(|result| future::ready(result.ok()))
-                .filter_map(move |entry| {
-                    direntry_menuitem(entry, selector_rc.clone(), config_rc.clone())
-                });
-
-            Response::Menu(Menu::new(header.chain(items)))
-        }
-        Err(e) => e.into(),
-    }
+/// Log to stderr, at the level given by `RUST_LOG` if it's set, or else the config. With the
+/// `opentelemetry` feature, spans are exported too.
+fn init_logging(config: &Config) -> Result<()> {
+    use tracing_subscriber::prelude::*;
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&config.log_level)
+            .with_context(|| format!("invalid log_level {:?}", config.log_level))?,
+    };
+    let stderr = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    let registry = tracing_subscriber::registry().with(filter).with(stderr);
+    #[cfg(feature = "opentelemetry")]
+    let registry = registry.with(telemetry::layer()?);
+    registry.init();
+    Ok(())
 }
 
-/// For clients that don't understand the "URL:..." selector format.
-fn html_redirect(url: &str) -> String {
-    format!(r#"<!doctype html>
-<html>
-    <head>
-        <meta http-equiv="refresh" content="5;URL={url}">
-        <title>Gopher redirect to URL: {url}</title>
-    </head>
-    <body>
-        <p>You're being redirected to a HTTP URL: <code>{url}</code>
-        <p>Click <a href="{url}">here</a> if you are not redirected automatically.
-        <address>generated by gofer</address>
-    </body>
-</html>"#)
-}
+fn main() -> Result<()> {
+    let (config_path, mode) = parse_args()?;
+    // Logging has to be set up first, so that warnings about the config get seen.
+    let config = config::read(&config_path)?;
+    init_logging(&config)?;
+    let config = config::prepare(config, &config_path)?;
+    if let Mode::Check | Mode::CheckMenus = mode {
+        check_config(&config);
+    }
+    if let Mode::Check = mode {
+        println!("Config OK");
+        return Ok(());
+    }
 
-fn http_response(url: &str) -> String {
-    // This isn't really valid HTTP because it's missing required headers, but it's enough to get
-    // the page to display in a browser.
-    format!("HTTP/1.0 400 Bad Request\r
-Content-Type: text/html\r
-\r
-<!doctype html>
-<html>
-    <head>
-        <title>This is a Gopher server</title>
-    </head>
-    <body>
-        <p>This is a Gopher server but it looks like you've made a HTTP request.
-        <p>If you're using a Gopher-capable browser, click <a href=\"{url}\">here</a> to use a Gopher
-           URL to view this page properly.
-        <address>generated by gofer</address>
-    </body>
-</html>")
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = config.worker_threads {
+        runtime.worker_threads(threads);
+    }
+    let runtime = runtime.build().context("failed to start the async runtime")?;
+    let result = match mode {
+        Mode::DumpMenu(selector) => runtime.block_on(dump_response(config, selector)),
+        Mode::CheckMenus => runtime.block_on(lint::run(config)),
+        _ => runtime.block_on(server::run(config, config_path)),
+    };
+    // Don't wait on anything still going in the background, like file reads for abandoned
+    // requests.
+    runtime.shutdown_background();
+    #[cfg(feature = "opentelemetry")]
+    telemetry::shutdown();
+    result
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let config = parse_args()?;
-
-    let mut incoming = RequestStream::bind(&config.server_address).await
-        .with_context(|| format!("failed to bind to address {}", config.server_address))?;
-    eprintln!("listening for connections at {}", config.server_address);
-
-    loop {
-        let (req, tx) = incoming.next_request().await;
-        let mut response = match req {
-            Ok(req) => {
-                eprintln!("selector: {}", req.selector);
-                handle_request(&config, req).await
-            }
-            Err(e) => {
-                eprintln!("error: {e:?}");
-                Response::Error(format!("Bad request: {e:?}"))
-            }
-        };
-        if let Err(e) = response.write(tx).await {
-            eprintln!("error writing response: {e}");
+/// For `--dump-menu`: write the response to `selector` to stdout, as a client would get it, to see
+/// what a menu or listing looks like without running the server. Only the start of a file is
+/// written.
+async fn dump_response(config: Config, selector: String) -> Result<()> {
+    const MAX_FILE_BYTES: u64 = 4096;
+    let config = Arc::new(config);
+    let menu_cache = MenuCache::new(0, Duration::ZERO);
+    let req = Request { selector, attributes: false };
+    let mut response = handler::handle_request(&config, &RealFileSystem, &menu_cache,
+        &FileCache::default(), &DirCache::default(), None, req).await;
+    let mut stdout = tokio::io::stdout();
+    let size = match &mut response {
+        Response::File(file) | Response::TextFile { file, .. } => {
+            tokio::io::copy(&mut file.take(MAX_FILE_BYTES), &mut stdout).await?;
+            file.metadata().await?.len()
         }
+        Response::Cached(bytes) => {
+            stdout.write_all(&bytes[.. bytes.len().min(MAX_FILE_BYTES as usize)]).await?;
+            bytes.len() as u64
+        }
+        _ => {
+            response.write(&mut stdout, &config.menu_encoder()).await?;
+            0
+        }
+    };
+    if size > MAX_FILE_BYTES {
+        eprintln!("[file truncated: showed {MAX_FILE_BYTES} of {size} bytes]");
     }
+    stdout.flush().await?;
+    Ok(())
 }
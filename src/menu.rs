@@ -1,32 +1,75 @@
 use bytes::{Buf, BytesMut};
 use crate::types::ItemType;
-use futures::stream::Stream;
+use futures::future;
+use futures::stream::{self, Stream, StreamExt};
+use std::borrow::Cow;
 use std::pin::Pin;
 use thiserror::Error;
 use tokio::io;
 use tokio_util::codec::{Decoder, Encoder};
+use tracing::warn;
 
 pub struct Menu {
-    pub items: Pin<Box<dyn Stream<Item = MenuItem>>>,
+    pub items: Pin<Box<dyn Stream<Item = MenuItem> + Send>>,
 }
 
 impl Menu {
-    pub fn new<S: Stream<Item = MenuItem> + 'static>(s: S) -> Self {
+    pub fn new<S: Stream<Item = MenuItem> + Send + 'static>(s: S) -> Self {
         Self {
             items: Box::pin(s),
         }
     }
+
+    /// A menu of items that are already in hand.
+    pub fn from_vec(items: Vec<MenuItem>) -> Self {
+        Self::new(stream::iter(items))
+    }
+
+    /// Read all of the menu's items.
+    pub async fn collect(self) -> Vec<MenuItem> {
+        self.items.collect().await
+    }
+
+    /// Leave out the items the predicate rejects, as they go by.
+    pub fn filter<F>(self, pred: F) -> Self
+        where F: Fn(&MenuItem) -> bool + Send + 'static
+    {
+        Self::new(self.items.filter(move |item| future::ready(pred(item))))
+    }
+
+    /// Stop after `max` items. If there were more than that, the last item is made by `notice`
+    /// from how many were left out.
+    pub fn truncate<F>(self, max: usize, notice: F) -> Self
+        where F: FnOnce(usize) -> MenuItem + Send + 'static
+    {
+        Self::new(stream::unfold(Some((self.items, max, notice)), |state| async move {
+            let (mut items, left, notice) = state?;
+            if left > 0 {
+                let item = items.next().await?;
+                Some((item, Some((items, left - 1, notice))))
+            } else {
+                let more = items.count().await;
+                (more > 0).then(|| (notice(more), None))
+            }
+        }))
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MenuItem {
     pub typ: ItemType,
     pub text: String,
     pub selector: String,
     pub host: Option<String>,
     pub port: Option<String>,
+    /// The field after the port, like the `+` that marks Gopher+ items, or `?` for ones that ask
+    /// for input. Lenient parsing keeps any fields after it in here too, tab-separated.
+    pub gopher_plus: Option<String>,
 }
 
+/// How wide `MenuItem::separator` lines usually are, to fit a typical terminal.
+pub const SEPARATOR_WIDTH: usize = 67;
+
 impl MenuItem {
     pub fn info(text: impl Into<String>) -> Self {
         Self {
@@ -35,7 +78,51 @@ impl MenuItem {
             selector: String::new(),
             host: None,
             port: None,
+            gopher_plus: None,
+        }
+    }
+
+    /// An empty info line.
+    pub fn blank() -> Self {
+        Self::info("")
+    }
+
+    /// An info line that's a horizontal rule of dashes, `width` long.
+    pub fn separator(width: usize) -> Self {
+        Self::info("-".repeat(width))
+    }
+
+    /// Cut the text down to `width` characters, ending with an ellipsis if any was cut.
+    pub fn truncate(&mut self, width: usize) {
+        if self.text.chars().count() > width {
+            let keep = self.text.char_indices().nth(width.saturating_sub(1)).map_or(0, |(i, _)| i);
+            self.text.truncate(keep);
+            self.text.push('…');
+        }
+    }
+
+    /// Split the text into lines of at most `width` characters, breaking between words where
+    /// possible. The item keeps the first line, and the rest follow it as info lines.
+    pub fn wrap(mut self, width: usize) -> Vec<Self> {
+        let width = width.max(1);
+        let mut lines = vec![];
+        let mut rest = self.text.as_str();
+        while let Some((end, next_char)) = rest.char_indices().nth(width) {
+            // Break at the last space that fits, or mid-word if there isn't one.
+            let (line, next) = match rest[.. end].rfind(' ') {
+                _ if next_char == ' ' => rest.split_at(end),
+                Some(space) if !rest[.. space].trim_end().is_empty() => rest.split_at(space),
+                _ => rest.split_at(end),
+            };
+            lines.push(line.trim_end().to_owned());
+            rest = next.trim_start();
+        }
+        if !rest.is_empty() || lines.is_empty() {
+            lines.push(rest.to_owned());
         }
+        let mut lines = lines.into_iter();
+        self.text = lines.next().unwrap_or_default();
+        std::iter::once(self).chain(lines.map(Self::info)).collect()
     }
 
     pub fn new(typ: ItemType, text: impl Into<String>, selector: impl Into<String>, host: impl Into<String>, port: impl Into<String>) -> Self {
@@ -45,30 +132,179 @@ impl MenuItem {
             selector: selector.into(),
             host: Some(host.into()),
             port: Some(port.into()),
+            gopher_plus: None,
+        }
+    }
+
+    pub fn with_host(self, host: impl Into<String>) -> Self {
+        Self { host: Some(host.into()), ..self }
+    }
+
+    pub fn with_port(self, port: impl Into<String>) -> Self {
+        Self { port: Some(port.into()), ..self }
+    }
+
+    /// Fill in placeholders in every field, and variables in the selector, host, and port, and in
+    /// the text of info lines.
+    pub fn substitute(self, vars: &TemplateVars) -> Self {
+        let sub = |field: String, dollars| match vars.substitute(&field, dollars) {
+            Cow::Borrowed(_) => field,
+            Cow::Owned(substituted) => substituted,
+        };
+        let info = self.typ == ItemType::Info;
+        Self {
+            text: sub(self.text, info),
+            selector: sub(self.selector, true),
+            host: self.host.map(|host| sub(host, true)),
+            port: self.port.map(|port| sub(port, true)),
+            ..self
+        }
+    }
+
+    /// Check that the item can be sent in a menu. Tabs and line breaks would throw off the client
+    /// reading the menu, and there's no way to escape them in the selector, host, or port. (In
+    /// the text, they're replaced with spaces when the item is sent.)
+    pub fn validate(&self) -> Result<(), String> {
+        let fields = [
+            ("selector", Some(&self.selector)),
+            ("host", self.host.as_ref()),
+            ("port", self.port.as_ref()),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value.filter(|value| value.contains(['\t', '\r', '\n'])) {
+                return Err(format!("{name} {value:?} contains a tab or line break"));
+            }
+        }
+        // Any more fields after it are separated by tabs, so only line breaks are a problem.
+        let gopher_plus = self.gopher_plus.as_ref();
+        if let Some(extra) = gopher_plus.filter(|extra| extra.contains(['\r', '\n'])) {
+            return Err(format!("Gopher+ field {extra:?} contains a line break"));
+        }
+        Ok(())
+    }
+}
+
+impl MenuItem {
+    /// A link to a non-gopher URL, using the `URL:` selector convention. Clients that don't
+    /// understand it will ask the server that the item points at for a page that redirects them,
+    /// so the host and port are left for the caller to fill in with this server's.
+    pub fn url(text: impl Into<String>, url: &str) -> Self {
+        Self {
+            typ: ItemType::Html,
+            text: text.into(),
+            selector: format!("URL:{url}"),
+            host: None,
+            port: None,
+            gopher_plus: None,
+        }
+    }
+
+    /// A link to a `gopher://` URL, or `None` if it isn't a valid one.
+    ///
+    /// The item type comes from the URL, defaulting to a directory if it doesn't specify one.
+    pub fn gopher_url(text: impl Into<String>, url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("gopher://")?;
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let (host, port) = match authority.rfind(':') {
+            Some(idx) if !authority[idx..].contains(']') => {
+                (&authority[..idx], &authority[idx + 1 ..])
+            }
+            _ => (authority, "70"),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || port.parse::<u16>().is_err() {
+            return None;
+        }
+        let (typ, selector) = match path.chars().next() {
+            Some(c) if c.is_ascii() => (ItemType::from_u8(c as u8), &path[1..]),
+            // Item types are one byte, so this isn't one.
+            Some(_) => return None,
+            None => (ItemType::Directory, ""),
+        };
+        Some(Self::new(typ, text, selector, host, port))
+    }
+}
+
+/// Resolve a selector relative to the directory selected by `dir`, like a relative URL. `.` and
+/// `..` are taken care of, but `..` can't go above the root.
+pub fn resolve_selector(dir: &str, selector: &str) -> String {
+    let mut segments = dir.split('/').filter(|s| !s.is_empty()).collect::<Vec<_>>();
+    for segment in selector.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
         }
     }
+    let mut resolved = segments.iter().fold(String::new(), |mut resolved, segment| {
+        resolved.push('/');
+        resolved.push_str(segment);
+        resolved
+    });
+    if resolved.is_empty() || selector.ends_with('/') {
+        resolved.push('/');
+    }
+    resolved
 }
 
-pub struct MenuItemEncoder;
+/// Encodes menu items for sending. Items with no host and port of their own, like info and error
+/// lines, get `info_host` and `info_port`. Nothing is expected to connect to them, but clients
+/// want something there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MenuItemEncoder {
+    pub info_host: String,
+    pub info_port: u16,
+}
+
+impl Default for MenuItemEncoder {
+    fn default() -> Self {
+        Self {
+            info_host: "error.host".to_owned(),
+            info_port: 1,
+        }
+    }
+}
 
 impl Encoder<MenuItem> for MenuItemEncoder {
     type Error = io::Error;
 
     fn encode(&mut self, item: MenuItem, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        item.validate().map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))?;
         dst.extend_from_slice(&[item.typ.into_u8()]);
-        dst.extend_from_slice(item.text.as_bytes());
+        if item.text.contains(['\t', '\r', '\n']) {
+            dst.extend_from_slice(item.text.replace(['\t', '\r', '\n'], " ").as_bytes());
+        } else {
+            dst.extend_from_slice(item.text.as_bytes());
+        }
         dst.extend_from_slice(b"\t");
         dst.extend_from_slice(item.selector.as_bytes());
         dst.extend_from_slice(b"\t");
-        dst.extend_from_slice(item.host.as_ref().map(String::as_bytes).unwrap_or(b"error.host"));
+        dst.extend_from_slice(item.host.as_ref().unwrap_or(&self.info_host).as_bytes());
         dst.extend_from_slice(b"\t");
-        dst.extend_from_slice(item.port.as_ref().map(String::as_bytes).unwrap_or(b"1"));
+        match &item.port {
+            Some(port) => dst.extend_from_slice(port.as_bytes()),
+            None => dst.extend_from_slice(self.info_port.to_string().as_bytes()),
+        }
+        if let Some(gopher_plus) = &item.gopher_plus {
+            dst.extend_from_slice(b"\t");
+            dst.extend_from_slice(gopher_plus.as_bytes());
+        }
         dst.extend_from_slice(b"\r\n");
         Ok(())
     }
 }
 
-pub struct MenuItemDecoder;
+/// Decoder for `!menu` files.
+#[derive(Default)]
+pub struct MenuItemDecoder {
+    /// Reject lines with more fields than they should have, instead of making the best of them:
+    /// taking all of an info or error line with a field after the port as its text, with its tabs
+    /// turned into spaces, and keeping any fields past the Gopher+ one of other lines in
+    /// `MenuItem::gopher_plus`. One field after the port is always allowed, for Gopher+.
+    pub strict: bool,
+}
 
 #[derive(Error, Debug)]
 pub enum MenuItemParseError {
@@ -82,117 +318,432 @@ pub enum MenuItemParseError {
     Message(String),
 }
 
+/// Split the next complete line off the front of the buffer, without its line terminator.
+fn next_line(buf: &mut BytesMut) -> Option<BytesMut> {
+    let mut line = {
+        match buf.iter().position(|c| *c == b'\n') {
+            Some(idx) => buf.split_to(idx + 1),
+            None => {
+                // We need at least a whole line.
+                return None;
+            }
+        }
+    };
+
+    if line.ends_with(b"\r\n") {
+        line.truncate(line.len() - 2);
+    } else {
+        assert!(line.ends_with(b"\n"));
+        line.truncate(line.len() - 1);
+    }
+
+    Some(line)
+}
+
+/// At the end of the file, take whatever's left in the buffer as the last line, for files that
+/// don't end with a line terminator.
+fn last_line(buf: &mut BytesMut) -> Result<Option<BytesMut>, MenuItemParseError> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    let mut line = buf.split();
+    if line.ends_with(b"\r") {
+        line.truncate(line.len() - 1);
+    }
+    // Anything else wrong with it is as salvageable as on any other line, but a character cut
+    // off partway can't be told apart from garbage.
+    if let Err(e) = std::str::from_utf8(&line) {
+        if e.error_len().is_none() {
+            return Err(MenuItemParseError::Message(
+                "file ends partway through a character".to_owned()));
+        }
+    }
+    Ok(Some(line))
+}
+
+fn parse_line(mut line: BytesMut, strict: bool) -> Result<MenuItem, MenuItemParseError> {
+    /// Split the next field off the rest of the line, or return `None` if there are no more. A
+    /// tab at the end of the line has an empty field after it, so that empty fields in the middle
+    /// of the line aren't mistaken for the end of it.
+    fn next_field(rest: &mut Option<BytesMut>) -> Option<BytesMut> {
+        let buf = rest.as_mut()?;
+        match buf.iter().position(|c| *c == b'\t') {
+            Some(idx) => {
+                let field = buf.split_to(idx);
+                buf.advance(1); // Skip the tab.
+                Some(field)
+            }
+            None => {
+                // Take the entire buffer.
+                rest.take()
+            }
+        }
+    }
+
+    fn next_string(rest: &mut Option<BytesMut>) -> Result<Option<String>, MenuItemParseError> {
+        next_field(rest).map(|field| Ok(std::str::from_utf8(&field)?.to_owned())).transpose()
+    }
+
+    // Tabs separate fields, so a line with one in it isn't blank.
+    if line.iter().all(|&c| c == b' ') {
+        return Ok(MenuItem {
+            typ: ItemType::Info,
+            text: String::new(),
+            selector: String::new(),
+            host: None,
+            port: None,
+            gopher_plus: None,
+        });
+    }
+
+    if let Some(url) = line.strip_prefix(b"GOPHER:") {
+        let url = std::str::from_utf8(url)?;
+        return MenuItem::gopher_url(url, url)
+            .ok_or_else(|| MenuItemParseError::Message(format!("invalid gopher URL {url:?}")));
+    }
+
+    if let Some(url) = line.strip_prefix(b"URL:") {
+        let url = std::str::from_utf8(url)?;
+        return Ok(MenuItem::url(url, url));
+    }
+
+    let typ = match line[0] {
+        0 ..= 0x20 => {
+            // disallow unprintable characters
+            let msg = format!("invalid item type {:?}", char::from(line[0]));
+            return Err(MenuItemParseError::Message(msg));
+        }
+        byte => ItemType::from_u8(byte),
+    };
+    line.advance(1);
+    let whole = line.clone();
+
+    let mut rest = Some(line);
+    let text = next_string(&mut rest)?.unwrap_or_default();
+    let selector = next_string(&mut rest)?.unwrap_or_default();
+    let host = next_string(&mut rest)?;
+    let port = next_string(&mut rest)?;
+    // A tab after the port, with nothing after it, isn't worth complaining about.
+    let Some(extra) = rest.filter(|extra| !extra.is_empty()) else {
+        return Ok(MenuItem { typ, text, selector, host, port, gopher_plus: None });
+    };
+
+    if strict && extra.contains(&b'\t') {
+        let msg = format!("extra garbage at end of line: {:?}",
+            std::str::from_utf8(&extra));
+        return Err(MenuItemParseError::Message(msg));
+    }
+    if !strict && matches!(typ, ItemType::Info | ItemType::Error) {
+        // Nothing's going to follow these anyway, so the tabs were most likely meant as part of
+        // the text. They can't go out in a menu as they are, though.
+        let text = std::str::from_utf8(&whole)?.replace('\t', " ");
+        return Ok(MenuItem { typ, text, selector: String::new(), host: None, port: None,
+            gopher_plus: None });
+    }
+    Ok(MenuItem {
+        typ,
+        text,
+        selector,
+        host,
+        port,
+        gopher_plus: Some(std::str::from_utf8(&extra)?.to_owned()),
+    })
+}
+
+impl MenuItemDecoder {
+    fn decode_line(&self, line: BytesMut) -> Result<Option<MenuItem>, MenuItemParseError> {
+        // Comments, like in most other servers' menu files.
+        if line.starts_with(b"#") {
+            return Ok(None);
+        }
+        parse_line(line, self.strict).map(Some)
+    }
+}
+
 impl Decoder for MenuItemDecoder {
     type Item = MenuItem;
     type Error = MenuItemParseError;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let mut line = {
-            match buf.iter().position(|c| *c == b'\n') {
-                Some(idx) => buf.split_to(idx + 1),
-                None => {
-                    // We need at least a whole line.
-                    return Ok(None);
-                }
+        while let Some(line) = next_line(buf) {
+            if let Some(item) = self.decode_line(line)? {
+                return Ok(Some(item));
             }
-        };
+        }
+        Ok(None)
+    }
 
-        if line.ends_with(b"\r\n") {
-            line.truncate(line.len() - 2);
-        } else {
-            assert!(line.ends_with(b"\n"));
-            line.truncate(line.len() - 1);
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(item) => Ok(Some(item)),
+            None => Ok(last_line(buf)?.map(|line| self.decode_line(line)).transpose()?.flatten()),
         }
+    }
+}
 
-        fn next_field(buf: &mut BytesMut) -> BytesMut {
-            match buf.iter().position(|c| *c == b'\t') {
-                Some(idx) => {
-                    let field = buf.split_to(idx);
-                    buf.advance(1); // Skip the tab.
-                    field
-                }
-                None => {
-                    // Take the entire buffer.
-                    buf.split()
-                }
+/// Decoder for Bucktooth-style `gophermap` files.
+///
+/// These are like `!menu` files, except that lines without any tabs are info text in their
+/// entirety (no type byte), and lines starting with `#` are comments.
+pub struct GophermapDecoder;
+
+impl GophermapDecoder {
+    fn decode_line(line: BytesMut) -> Result<Option<MenuItem>, MenuItemParseError> {
+        if line.starts_with(b"#") {
+            return Ok(None);
+        }
+        if !line.contains(&b'\t') {
+            let text = std::str::from_utf8(&line)?;
+            return Ok(Some(MenuItem::info(text)));
+        }
+        parse_line(line, false).map(Some)
+    }
+}
+
+impl Decoder for GophermapDecoder {
+    type Item = MenuItem;
+    type Error = MenuItemParseError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        while let Some(line) = next_line(buf) {
+            if let Some(item) = Self::decode_line(line)? {
+                return Ok(Some(item));
             }
         }
+        Ok(None)
+    }
 
-        fn next_string(buf: &mut BytesMut) -> Result<String, MenuItemParseError> {
-            Ok(std::str::from_utf8(&next_field(buf))?.to_owned())
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(item) => Ok(Some(item)),
+            None => Ok(last_line(buf)?.map(Self::decode_line).transpose()?.flatten()),
         }
+    }
+}
 
-        if line.is_empty() {
-            return Ok(Some(MenuItem {
-                typ: ItemType::Info,
-                text: String::new(),
-                selector: String::new(),
-                host: None,
-                port: None,
-            }));
+/// Values for the `{{hostname}}`, `{{port}}`, and `{{selector}}` placeholders in menu files, and
+/// the `$hostname`, `$port`, and `$selector` variables, which are the same but only work in some
+/// fields.
+#[derive(Debug, Clone, Default)]
+pub struct TemplateVars {
+    pub hostname: String,
+    pub port: String,
+    /// The selector the menu was requested with.
+    pub selector: String,
+}
+
+impl TemplateVars {
+    fn get(&self, name: &str) -> Option<&str> {
+        match name.trim() {
+            "hostname" => Some(&self.hostname),
+            "port" => Some(&self.port),
+            "selector" => Some(&self.selector),
+            _ => None,
         }
+    }
 
-        let typ = match line[0] {
-            0 ..= 0x20 => {
-                // disallow unprintable characters
-                let msg = format!("invalid item type {:?}", char::from(line[0]));
-                return Err(MenuItemParseError::Message(msg));
+    /// Fill in the placeholders and variables in a field of a parsed item: `{{name}}`, and
+    /// `$name` too if `dollars` is set, with `$$` for a literal `$`. It's done in one pass, going
+    /// by whichever comes first, so what's filled in, like a selector with `$port` in it, is never
+    /// filled in again. Unknown ones are left as they are.
+    pub fn substitute<'a>(&self, field: &'a str, dollars: bool) -> Cow<'a, str> {
+        let next = |rest: &str| {
+            let braces = rest.find("{{");
+            match rest.find('$').filter(|_| dollars) {
+                Some(dollar) => Some(braces.map_or(dollar, |braces| braces.min(dollar))),
+                None => braces,
             }
-            byte => ItemType::from_u8(byte),
         };
-        line.advance(1);
+        let Some(mut start) = next(field) else { return Cow::Borrowed(field) };
+        let mut out = String::with_capacity(field.len());
+        let mut rest = field;
+        loop {
+            out.push_str(&rest[.. start]);
+            rest = &rest[start ..];
+            if let Some(inside) = rest.strip_prefix("{{") {
+                let Some(len) = inside.find("}}") else { break };
+                let (placeholder, after) = rest.split_at(len + 4);
+                match self.get(&inside[.. len]) {
+                    Some(value) => out.push_str(value),
+                    None => {
+                        warn!("unknown placeholder {placeholder:?} in menu file");
+                        out.push_str(placeholder);
+                    }
+                }
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix("$$") {
+                out.push('$');
+                rest = after;
+            } else {
+                rest = &rest[1 ..];
+                let len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                let (name, after) = rest.split_at(len);
+                match self.get(name) {
+                    Some(value) => out.push_str(value),
+                    None => {
+                        // Lone dollar signs, like in "$5", aren't worth a warning.
+                        if !name.is_empty() {
+                            warn!("unknown variable ${name} in menu file");
+                        }
+                        out.push('$');
+                        out.push_str(name);
+                    }
+                }
+                rest = after;
+            }
+            match next(rest) {
+                Some(next) => start = next,
+                None => break,
+            }
+        }
+        out.push_str(rest);
+        Cow::Owned(out)
+    }
+}
 
-        let text = next_string(&mut line)?;
+/// A line of a menu file: either an item, or `!include <path>`, which puts the contents of
+/// another menu file in its place, or one with an error in it.
+#[derive(Debug)]
+pub enum MenuLine {
+    Item(MenuItem),
+    Include(String),
+    /// `=glob <pattern> [type]`, which lists the entries in the menu's directory whose names
+    /// match the pattern, with the given item type if there is one.
+    Glob { pattern: String, typ: Option<ItemType> },
+    /// `=title <text>`, which names the menu's directory in the listing of its parent. That's done
+    /// by [`peek_title`], so it's skipped when the menu is read.
+    Title,
+    /// Returned instead of an error, so the lines after it can still be read.
+    Invalid(MenuItemParseError),
+}
 
-        if line.is_empty() {
-            return Ok(Some(MenuItem {
-                typ,
-                text,
-                selector: String::new(),
-                host: None,
-                port: None,
-            }));
-        }
+/// Wraps a menu file decoder to also pick out `!include`, `=glob`, and `=title` lines. Each line
+/// comes with its line number, counting the comments the inner decoder skips.
+pub struct IncludeDecoder<D> {
+    inner: D,
+    /// How many lines have been taken so far.
+    line: usize,
+}
 
-        let selector = next_string(&mut line)?;
+impl<D> IncludeDecoder<D> {
+    pub fn new(inner: D) -> Self {
+        Self { inner, line: 0 }
+    }
+}
 
-        if line.is_empty() {
-            return Ok(Some(MenuItem {
-                typ,
-                text,
-                selector,
-                host: None,
-                port: None,
-            }));
+/// The path from an `!include` or `=include` line, or `None` if it's some other kind of line.
+fn include_path(line: &[u8]) -> Result<Option<&str>, MenuItemParseError> {
+    let Some(path) = line.strip_prefix(b"!include ").or_else(|| line.strip_prefix(b"=include "))
+    else {
+        return Ok(None);
+    };
+    let path = std::str::from_utf8(path)?.trim_end_matches(['\r', '\n']).trim();
+    if path.is_empty() {
+        return Err(MenuItemParseError::Message("include needs a path".to_owned()));
+    }
+    Ok(Some(path))
+}
+
+/// The pattern and item type from a `=glob` line, or `None` if it's some other kind of line.
+fn glob_args(line: &[u8]) -> Result<Option<MenuLine>, MenuItemParseError> {
+    let Some(args) = line.strip_prefix(b"=glob ") else { return Ok(None) };
+    let mut args = std::str::from_utf8(args)?.split_whitespace();
+    let Some(pattern) = args.next() else {
+        return Err(MenuItemParseError::Message("glob needs a pattern".to_owned()));
+    };
+    let typ = match args.next().map(str::as_bytes) {
+        None => None,
+        Some(&[typ]) if typ > b' ' => Some(ItemType::from_u8(typ)),
+        Some(typ) => {
+            let msg = format!("invalid item type {:?}", String::from_utf8_lossy(typ));
+            return Err(MenuItemParseError::Message(msg));
         }
+    };
+    if let Some(extra) = args.next() {
+        return Err(MenuItemParseError::Message(format!("unexpected {extra:?} after glob")));
+    }
+    Ok(Some(MenuLine::Glob { pattern: pattern.to_owned(), typ }))
+}
 
-        let host = next_string(&mut line)?;
+/// The text from a `=title` line, or `None` if it's some other kind of line.
+fn title_text(line: &[u8]) -> Result<Option<String>, MenuItemParseError> {
+    let Some(text) = line.strip_prefix(b"=title ") else { return Ok(None) };
+    let text = std::str::from_utf8(text)?.replace(char::is_control, " ");
+    match text.trim() {
+        "" => Err(MenuItemParseError::Message("title needs some text".to_owned())),
+        text => Ok(Some(text.to_owned())),
+    }
+}
 
-        if line.is_empty() {
-            return Ok(Some(MenuItem {
-                typ,
-                text,
-                selector,
-                host: Some(host),
-                port: None,
-            }));
+/// How many lines at the start of a menu file are looked at for its title.
+pub const TITLE_LINES: usize = 10;
+
+/// The title from a `=title` line in the first few lines of a menu file, given the start of it.
+/// Unless that's the whole file, the last line might have been cut off partway, so it's ignored.
+pub fn peek_title(start: &[u8], whole_file: bool) -> Option<String> {
+    let mut lines = start.split(|c| *c == b'\n').collect::<Vec<_>>();
+    if !whole_file {
+        lines.pop();
+    }
+    lines.into_iter().take(TITLE_LINES).find_map(|line| title_text(line).ok().flatten())
+}
+
+impl<D> IncludeDecoder<D>
+    where D: Decoder<Item = MenuItem, Error = MenuItemParseError>
+{
+    /// Decode one line, which is the last one if `eof` is set.
+    fn decode_line(&mut self, mut line: BytesMut, eof: bool) -> Option<MenuLine> {
+        self.line += 1;
+        let directive = match include_path(&line) {
+            Ok(Some(path)) => Ok(Some(MenuLine::Include(path.to_owned()))),
+            Ok(None) => match glob_args(&line) {
+                Ok(None) => title_text(&line).map(|title| title.map(|_| MenuLine::Title)),
+                result => result,
+            },
+            Err(e) => Err(e),
+        };
+        let result = match directive {
+            Ok(Some(directive)) => return Some(directive),
+            Ok(None) if eof => self.inner.decode_eof(&mut line),
+            Ok(None) => self.inner.decode(&mut line),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(item) => item.map(MenuLine::Item),
+            Err(e) => Some(MenuLine::Invalid(e)),
         }
+    }
+}
 
-        let port = next_string(&mut line)?;
+impl<D> Decoder for IncludeDecoder<D>
+    where D: Decoder<Item = MenuItem, Error = MenuItemParseError>
+{
+    /// The line number, and what's on the line.
+    type Item = (usize, MenuLine);
+    type Error = MenuItemParseError;
 
-        if line.is_empty() {
-            return Ok(Some(MenuItem {
-                typ,
-                text,
-                selector,
-                host: Some(host),
-                port: Some(port),
-            }));
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // Hand the inner decoder one line at a time, so it can't get past an include.
+        while let Some(idx) = buf.iter().position(|c| *c == b'\n') {
+            let line = buf.split_to(idx + 1);
+            if let Some(line) = self.decode_line(line, false) {
+                return Ok(Some((self.line, line)));
+            }
         }
+        Ok(None)
+    }
 
-        let msg = format!("extra garbage at end of line: {:?}",
-            std::str::from_utf8(&line));
-        Err(MenuItemParseError::Message(msg))
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(line) = self.decode(buf)? {
+            return Ok(Some(line));
+        }
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        // A last line without a line terminator.
+        let line = buf.split();
+        Ok(self.decode_line(line, true).map(|line| (self.line, line)))
     }
 }
 
@@ -200,10 +751,52 @@ impl Decoder for MenuItemDecoder {
 mod test {
     use super::*;
 
+    #[tokio::test]
+    async fn test_collect() {
+        for len in [0, 1, 100] {
+            let items = (0 .. len).map(|i| MenuItem::info(i.to_string())).collect::<Vec<_>>();
+            let collected = Menu::from_vec(items).collect().await;
+            assert_eq!(collected.len(), len);
+            for (i, item) in collected.iter().enumerate() {
+                assert_eq!(ItemType::Info, item.typ);
+                assert_eq!(i.to_string(), item.text);
+            }
+        }
+
+        let streamed = Menu::new(stream::iter(["a", "b"]).map(MenuItem::info)).collect().await;
+        let texts = streamed.iter().map(|item| item.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(texts, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_filter() {
+        let items = (0 .. 10).map(|i| MenuItem::info(i.to_string())).collect::<Vec<_>>();
+        let menu = Menu::from_vec(items)
+            .filter(|item| item.text.parse::<u32>().unwrap() % 3 == 0);
+        let items = menu.collect().await;
+        let texts = items.iter().map(|item| item.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(texts, ["0", "3", "6", "9"]);
+    }
+
+    #[tokio::test]
+    async fn test_truncate() {
+        let texts = |menu: Menu| async move {
+            menu.collect().await.into_iter().map(|item| item.text).collect::<Vec<_>>()
+        };
+        let menu = |len| {
+            Menu::from_vec((0 .. len).map(|i| MenuItem::info(i.to_string())).collect())
+        };
+        let notice = |more| MenuItem::info(format!("{more} more"));
+        assert_eq!(texts(menu(5).truncate(3, notice)).await, ["0", "1", "2", "2 more"]);
+        assert_eq!(texts(menu(3).truncate(3, notice)).await, ["0", "1", "2"]);
+        assert_eq!(texts(menu(2).truncate(3, notice)).await, ["0", "1"]);
+        assert_eq!(texts(menu(2).truncate(0, notice)).await, ["2 more"]);
+    }
+
     #[test]
     fn test_parse_menuitem() {
         let mut buf = BytesMut::from("1text\tselector\thost\tport\r\n");
-        let item = MenuItemDecoder.decode(&mut buf).unwrap().unwrap();
+        let item = MenuItemDecoder::default().decode(&mut buf).unwrap().unwrap();
         assert_eq!(ItemType::Directory, item.typ);
         assert_eq!("text", item.text);
         assert_eq!("selector", item.selector);
@@ -215,7 +808,7 @@ mod test {
     #[test]
     fn test_parse_menuitem_incomplete() {
         let mut buf = BytesMut::from("1text\tselector\r\n");
-        let item = MenuItemDecoder.decode(&mut buf).unwrap().unwrap();
+        let item = MenuItemDecoder::default().decode(&mut buf).unwrap().unwrap();
         assert_eq!(ItemType::Directory, item.typ);
         assert_eq!("text", item.text);
         assert_eq!("selector", item.selector);
@@ -227,7 +820,7 @@ mod test {
     #[test]
     fn test_parse_info_short() {
         let mut buf = BytesMut::from("itext\r\n");
-        let item = MenuItemDecoder.decode(&mut buf).unwrap().unwrap();
+        let item = MenuItemDecoder::default().decode(&mut buf).unwrap().unwrap();
         assert_eq!(ItemType::Info, item.typ);
         assert_eq!("text", item.text);
         assert_eq!("", item.selector);
@@ -239,7 +832,7 @@ mod test {
     #[test]
     fn test_parse_info_only_line() {
         let mut buf = BytesMut::from("i\r\n");
-        let item = MenuItemDecoder.decode(&mut buf).unwrap().unwrap();
+        let item = MenuItemDecoder::default().decode(&mut buf).unwrap().unwrap();
         assert_eq!(ItemType::Info, item.typ);
         assert_eq!("", item.text);
         assert_eq!("", item.selector);
@@ -251,7 +844,7 @@ mod test {
     #[test]
     fn test_parse_only_newline() {
         let mut buf = BytesMut::from("\r\n");
-        let item = MenuItemDecoder.decode(&mut buf).unwrap().unwrap();
+        let item = MenuItemDecoder::default().decode(&mut buf).unwrap().unwrap();
         assert_eq!(ItemType::Info, item.typ);
         assert_eq!("", item.text);
         assert_eq!("", item.selector);
@@ -260,10 +853,40 @@ mod test {
         assert_eq!(buf.len(), 0);
     }
 
+    #[test]
+    fn test_parse_comment_line() {
+        let mut buf = BytesMut::from("#this is a comment\r\n");
+        assert!(MenuItemDecoder::default().decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 0);
+
+        let mut buf = BytesMut::from("#comment\r\n#\titem-like\r\niafter\r\n");
+        let item = MenuItemDecoder::default().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(ItemType::Info, item.typ);
+        assert_eq!("after", item.text);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn test_parse_whitespace_line() {
+        let mut buf = BytesMut::from("   \r\n");
+        let item = MenuItemDecoder::default().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(ItemType::Info, item.typ);
+        assert_eq!("", item.text);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn test_parse_unknown_type() {
+        let mut buf = BytesMut::from("Qtext\tselector\thost\tport\r\n");
+        let item = MenuItemDecoder::default().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(ItemType::Reserved(b'Q'), item.typ);
+        assert_eq!("text", item.text);
+    }
+
     #[test]
     fn test_parse_bad_type() {
         let mut buf = BytesMut::from("\t\r\n");
-        match MenuItemDecoder.decode(&mut buf) {
+        match MenuItemDecoder::default().decode(&mut buf) {
             Err(MenuItemParseError::Message(_)) => (),
             other => panic!("unexpected {other:?}"),
         }
@@ -271,19 +894,487 @@ mod test {
 
     #[test]
     fn test_parse_extra_garbage() {
-        let mut buf = BytesMut::from("itext\tselector\thost\tport\tspaghetti\r\n");
-        match MenuItemDecoder.decode(&mut buf) {
+        let lines = ["itext\tselector\thost\tport\tspaghetti\r\n",
+            "1text\tsel\thost\t70\t+\tmore\r\n"];
+        let mut buf = BytesMut::from(lines[1]);
+        match (MenuItemDecoder { strict: true }).decode(&mut buf) {
             Err(MenuItemParseError::Message(_)) => (),
             other => panic!("unexpected {other:?}"),
         }
+
+        let mut buf = BytesMut::from(lines.concat().as_str());
+        let mut decoder = MenuItemDecoder::default();
+        let item = decoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(ItemType::Info, item.typ);
+        assert_eq!("text selector host port spaghetti", item.text);
+        assert_eq!((None, None, None), (item.host, item.port, item.gopher_plus));
+        let item = decoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(ItemType::Directory, item.typ);
+        assert_eq!(("text", "sel"), (item.text.as_str(), item.selector.as_str()));
+        assert_eq!(Some("70"), item.port.as_deref());
+        assert_eq!(Some("+\tmore"), item.gopher_plus.as_deref());
+
+        // The extra fields go back out as they came in.
+        let mut out = BytesMut::new();
+        MenuItemEncoder::default().encode(item, &mut out).unwrap();
+        assert_eq!(out, lines[1]);
+    }
+
+    #[test]
+    fn test_parse_gopher_plus() {
+        let menu = "1dir\t/d\thost\t70\t+\r\n7search\t/s\thost\t70\t?\r\n\
+            0plain\t/p\thost\t70\r\n";
+        for strict in [false, true] {
+            let mut buf = BytesMut::from(menu);
+            let mut decoder = MenuItemDecoder { strict };
+            let mut items = vec![];
+            while let Some(item) = decoder.decode(&mut buf).unwrap() {
+                items.push(item);
+            }
+            let fields = items.iter().map(|item| item.gopher_plus.as_deref()).collect::<Vec<_>>();
+            assert_eq!(fields, [Some("+"), Some("?"), None]);
+            // Lines without the field are the same as they ever were.
+            let plain = MenuItem::new(ItemType::File, "plain", "/p", "host", "70");
+            assert_eq!(format!("{:?}", items[2]), format!("{plain:?}"));
+
+            let mut out = BytesMut::new();
+            for item in items {
+                MenuItemEncoder::default().encode(item, &mut out).unwrap();
+            }
+            assert_eq!(out, menu);
+        }
+    }
+
+    #[test]
+    fn test_parse_empty_fields() {
+        // Empty fields at the end of the line are still there, not missing.
+        let mut buf = BytesMut::from("1text\tsel\t\t\r\n1text\tsel\thost\t\r\n0text\t\r\n");
+        let mut decoder = MenuItemDecoder { strict: true };
+        let item = decoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!((Some(""), Some("")), (item.host.as_deref(), item.port.as_deref()));
+        let item = decoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!((Some("host"), Some("")), (item.host.as_deref(), item.port.as_deref()));
+        let item = decoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(("text", ""), (item.text.as_str(), item.selector.as_str()));
+        assert_eq!((None, None), (item.host, item.port));
+
+        // So they go back out as they came in.
+        let item = MenuItem::new(ItemType::File, "text", "", "", "");
+        let mut out = BytesMut::new();
+        MenuItemEncoder::default().encode(item, &mut out).unwrap();
+        let item = decoder.decode(&mut out).unwrap().unwrap();
+        assert_eq!((Some(""), Some("")), (item.host.as_deref(), item.port.as_deref()));
+
+        // A tab after the port is ignored, even when parsing strictly.
+        let mut buf = BytesMut::from("1text\tsel\thost\t70\t\r\n");
+        let item = decoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!((Some("70"), None), (item.port.as_deref(), item.gopher_plus));
     }
 
     #[test]
     fn test_parse_truncated() {
         let mut buf = BytesMut::from("itext\tselector\thost\tport"); // missing CR-LF
-        match MenuItemDecoder.decode(&mut buf) {
+        match MenuItemDecoder::default().decode(&mut buf) {
             Ok(None) => (),
             other => panic!("unexpected {other:?}"),
         }
     }
+
+    #[test]
+    fn test_no_final_newline() {
+        let mut buf = BytesMut::from("1one\tsel\n1two\tsel");
+        let item = MenuItemDecoder::default().decode_eof(&mut buf).unwrap().unwrap();
+        assert_eq!("one", item.text);
+        let item = MenuItemDecoder::default().decode_eof(&mut buf).unwrap().unwrap();
+        assert_eq!("two", item.text);
+        assert_eq!("sel", item.selector);
+        assert!(MenuItemDecoder::default().decode_eof(&mut buf).unwrap().is_none());
+
+        // A lone CR at the end is as good as a CRLF, and a trailing comment is still a comment.
+        let mut buf = BytesMut::from("itext\r");
+        assert_eq!("text", MenuItemDecoder::default().decode_eof(&mut buf).unwrap().unwrap().text);
+        let mut buf = BytesMut::from("text\r");
+        assert_eq!("text", GophermapDecoder.decode_eof(&mut buf).unwrap().unwrap().text);
+        let mut buf = BytesMut::from("# comment");
+        assert!(MenuItemDecoder::default().decode_eof(&mut buf).unwrap().is_none());
+        let mut buf = BytesMut::from("# comment");
+        assert!(GophermapDecoder.decode_eof(&mut buf).unwrap().is_none());
+
+        let mut decoder = IncludeDecoder::new(MenuItemDecoder::default());
+        let mut buf = BytesMut::from("!include nav");
+        match decoder.decode_eof(&mut buf).unwrap() {
+            Some((_, MenuLine::Include(path))) => assert_eq!("nav", path),
+            other => panic!("unexpected {other:?}"),
+        }
+        let mut buf = BytesMut::from("iabove\r\niend");
+        for text in ["above", "end"] {
+            match decoder.decode_eof(&mut buf).unwrap() {
+                Some((_, MenuLine::Item(item))) => assert_eq!(text, item.text),
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+        assert!(decoder.decode_eof(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_empty_at_eof() {
+        let decode_eof = |buf: &mut BytesMut| {
+            IncludeDecoder::new(MenuItemDecoder::default()).decode_eof(buf).unwrap()
+        };
+        let mut buf = BytesMut::new();
+        assert!(decode_eof(&mut buf).is_none());
+        let mut buf = BytesMut::from("iline\n");
+        assert!(decode_eof(&mut buf).is_some());
+        assert!(decode_eof(&mut buf).is_none());
+
+        // Cut off in the middle of a character.
+        let mut buf = BytesMut::from(&b"iend \xe2\x82"[..]);
+        match MenuItemDecoder::default().decode_eof(&mut buf) {
+            Err(MenuItemParseError::Message(msg)) => assert!(msg.contains("partway"), "{msg}"),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let items = [
+            MenuItem::info("tab\there"),
+            MenuItem::info("line\r\nbreak\n"),
+            MenuItem::new(ItemType::File, "a\tb\rc", "/a b", "host", "70"),
+            MenuItem {
+                gopher_plus: Some("+\tmore".to_owned()),
+                ..MenuItem::new(ItemType::File, "extra", "/x", "host", "70")
+            },
+            MenuItem::info(".\n."),
+        ];
+        let mut buf = BytesMut::new();
+        for item in items.clone() {
+            item.validate().unwrap();
+            MenuItemEncoder::default().encode(item, &mut buf).unwrap();
+        }
+        let mut decoded = vec![];
+        let mut decoder = MenuItemDecoder::default();
+        while let Some(item) = decoder.decode_eof(&mut buf).unwrap() {
+            decoded.push(item);
+        }
+        assert_eq!(decoded.len(), items.len());
+        let texts = decoded.iter().map(|item| item.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(texts, ["tab here", "line  break ", "a b c", "extra", ". ."]);
+        assert_eq!(Some("+\tmore"), decoded[3].gopher_plus.as_deref());
+
+        // Fields that can't be fixed up are refused, rather than sent broken.
+        let bad = [
+            MenuItem::new(ItemType::File, "text", "/a\tb", "host", "70"),
+            MenuItem::new(ItemType::File, "text", "/a\nb", "host", "70"),
+            MenuItem::new(ItemType::File, "text", "/a", "ho\rst", "70"),
+            MenuItem::new(ItemType::File, "text", "/a", "host", "7\t0"),
+            MenuItem { gopher_plus: Some("+\r\n".to_owned()), ..MenuItem::info("extra") },
+        ];
+        for item in bad {
+            assert!(item.validate().is_err(), "{item:?}");
+            let err = MenuItemEncoder::default().encode(item, &mut buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_template() {
+        let vars = TemplateVars {
+            hostname: "example.com".to_owned(),
+            port: "7070".to_owned(),
+            selector: "/here".to_owned(),
+        };
+        let mut buf = BytesMut::from("1Here {{port}}\t{{selector}}\t{{ hostname }}\t{{port}}\n\
+            iSee {{unknown}} at {{hostname}}:{{port}} {{\n\
+            1Last\t{{selector}}/..");
+        let mut decoder = MenuItemDecoder::default();
+        let item = decoder.decode_eof(&mut buf).unwrap().unwrap().substitute(&vars);
+        assert_eq!(("Here 7070", "/here", "example.com", "7070"), (item.text.as_str(),
+            item.selector.as_str(), item.host.as_deref().unwrap(), item.port.as_deref().unwrap()));
+        let item = decoder.decode_eof(&mut buf).unwrap().unwrap().substitute(&vars);
+        assert_eq!("See {{unknown}} at example.com:7070 {{", item.text);
+        let item = decoder.decode_eof(&mut buf).unwrap().unwrap().substitute(&vars);
+        assert_eq!("/here/..", item.selector);
+        assert!(decoder.decode_eof(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_substitute() {
+        let vars = TemplateVars {
+            hostname: "example.com".to_owned(),
+            port: "7070".to_owned(),
+            selector: "/here".to_owned(),
+        };
+        assert_eq!("example.com", vars.substitute("$hostname", true));
+        assert_eq!("7070", vars.substitute("$port", true));
+        assert_eq!("/here/sub", vars.substitute("$selector/sub", true));
+        assert_eq!("$port costs $5, $", vars.substitute("$$port costs $5, $", true));
+        assert_eq!("$foo at example.com:7070", vars.substitute("$foo at $hostname:$port", true));
+        assert!(matches!(vars.substitute("no variables", true), Cow::Borrowed(_)));
+        assert_eq!("$port at 7070", vars.substitute("$port at {{port}}", false));
+        // Both kinds at once, whichever comes first.
+        assert_eq!("example.com:7070 {{$port}} $example.com",
+            vars.substitute("{{hostname}}:$port {{$port}} $${{hostname}}", true));
+
+        let item = MenuItem::info("On $hostname").substitute(&vars);
+        assert_eq!("On example.com", item.text);
+        // Only info lines' text has variables filled in.
+        let item = MenuItem::new(ItemType::File, "$port", "$selector", "$hostname", "$port")
+            .substitute(&vars);
+        assert_eq!(("$port", "/here"), (item.text.as_str(), item.selector.as_str()));
+        assert_eq!(Some("example.com"), item.host.as_deref());
+        assert_eq!(Some("7070"), item.port.as_deref());
+
+        // What's filled in isn't filled in again, even if it looks like a variable.
+        let vars = TemplateVars { selector: "/$port/{{hostname}}/$$".to_owned(), ..vars };
+        assert_eq!("/$port/{{hostname}}/$$ and /$port/{{hostname}}/$$",
+            vars.substitute("{{selector}} and $selector", true));
+    }
+
+    #[test]
+    fn test_resolve_selector() {
+        assert_eq!("/dir/page", resolve_selector("/dir", "page"));
+        assert_eq!("/dir/page", resolve_selector("/dir/", "./page"));
+        assert_eq!("/dir/sub/", resolve_selector("/dir", "sub/"));
+        assert_eq!("/other", resolve_selector("/dir/sub", "../../other"));
+        assert_eq!("/page", resolve_selector("", "page"));
+        // There's nothing above the root.
+        assert_eq!("/page", resolve_selector("/dir", "../../../page"));
+        assert_eq!("/", resolve_selector("/dir", ".."));
+    }
+
+    #[test]
+    fn test_gophermap_info_without_tab() {
+        let mut buf = BytesMut::from("1not a link\r\n");
+        let item = GophermapDecoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(ItemType::Info, item.typ);
+        assert_eq!("1not a link", item.text);
+        assert_eq!(None, item.host);
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn test_gophermap_link() {
+        let mut buf = BytesMut::from("1text\tselector\thost\tport\n");
+        let item = GophermapDecoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(ItemType::Directory, item.typ);
+        assert_eq!("text", item.text);
+        assert_eq!("selector", item.selector);
+        assert_eq!(Some("host"), item.host.as_deref());
+        assert_eq!(Some("port"), item.port.as_deref());
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn test_gophermap_comments() {
+        let mut buf = BytesMut::from("# comment\n#another\ttabbed\none\r\n#trailing\n");
+        let item = GophermapDecoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(ItemType::Info, item.typ);
+        assert_eq!("one", item.text);
+        assert!(GophermapDecoder.decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 0);
+    }
+
+    #[test]
+    fn test_include() {
+        let mut buf = BytesMut::from("iabove\r\n!include ../nav\r\n# comment\n=include  x y \n");
+        let mut decoder = IncludeDecoder::new(GophermapDecoder);
+        match decoder.decode(&mut buf).unwrap() {
+            Some((1, MenuLine::Item(item))) => assert_eq!("iabove", item.text),
+            other => panic!("unexpected {other:?}"),
+        }
+        match decoder.decode(&mut buf).unwrap() {
+            Some((2, MenuLine::Include(path))) => assert_eq!("../nav", path),
+            other => panic!("unexpected {other:?}"),
+        }
+        // The comment is skipped without swallowing the include after it, and still counts as a
+        // line.
+        match decoder.decode(&mut buf).unwrap() {
+            Some((4, MenuLine::Include(path))) => assert_eq!("x y", path),
+            other => panic!("unexpected {other:?}"),
+        }
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+
+        // Errors don't stop the lines after them being read.
+        let mut buf = BytesMut::from("!include \r\niafter\r\n");
+        let mut decoder = IncludeDecoder::new(MenuItemDecoder::default());
+        match decoder.decode(&mut buf).unwrap() {
+            Some((_, MenuLine::Invalid(MenuItemParseError::Message(_)))) => (),
+            other => panic!("unexpected {other:?}"),
+        }
+        match decoder.decode(&mut buf).unwrap() {
+            Some((_, MenuLine::Item(item))) => assert_eq!("after", item.text),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_glob() {
+        let mut buf =
+            BytesMut::from("=glob *.mp3\n=glob  *.txt 9 \n=glob\n=glob * ab\n=glob * 0 x\n");
+        let mut decoder = IncludeDecoder::new(MenuItemDecoder::default());
+        match decoder.decode(&mut buf).unwrap() {
+            Some((_, MenuLine::Glob { pattern, typ: None })) => assert_eq!("*.mp3", pattern),
+            other => panic!("unexpected {other:?}"),
+        }
+        match decoder.decode(&mut buf).unwrap() {
+            Some((_, MenuLine::Glob { pattern, typ: Some(ItemType::Binary) })) => {
+                assert_eq!("*.txt", pattern)
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        // "=glob" alone is an item, of type '=', with no pattern.
+        assert!(matches!(decoder.decode(&mut buf).unwrap(), Some((_, MenuLine::Item(_)))));
+        for _ in 0 .. 2 {
+            match decoder.decode(&mut buf).unwrap() {
+                Some((_, MenuLine::Invalid(MenuItemParseError::Message(_)))) => (),
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_title() {
+        let mut buf = BytesMut::from("=title  My Projects \r\n=title \n");
+        let mut decoder = IncludeDecoder::new(MenuItemDecoder::default());
+        match decoder.decode(&mut buf).unwrap() {
+            Some((_, MenuLine::Title)) => (),
+            other => panic!("unexpected {other:?}"),
+        }
+        match decoder.decode(&mut buf).unwrap() {
+            Some((_, MenuLine::Invalid(MenuItemParseError::Message(_)))) => (),
+            other => panic!("unexpected {other:?}"),
+        }
+
+        let start = b"iHello\n=title  My Projects \r\n";
+        assert_eq!(peek_title(start, false).as_deref(), Some("My Projects"));
+        assert_eq!(peek_title(b"=title Hi", true).as_deref(), Some("Hi"));
+        // Cut off partway, so it might not be the whole title.
+        assert_eq!(peek_title(b"=title Hi", false), None);
+        let late = format!("{}=title Too late\n", "i\n".repeat(TITLE_LINES));
+        assert_eq!(peek_title(late.as_bytes(), true), None);
+        assert_eq!(peek_title(b"=title \xff\n", true), None);
+    }
+
+    #[test]
+    fn test_gopher_url() {
+        let item = MenuItem::gopher_url("text", "gopher://example.org:7070/0/file.txt").unwrap();
+        assert_eq!(ItemType::File, item.typ);
+        assert_eq!("text", item.text);
+        assert_eq!("/file.txt", item.selector);
+        assert_eq!(Some("example.org"), item.host.as_deref());
+        assert_eq!(Some("7070"), item.port.as_deref());
+
+        let item = MenuItem::gopher_url("text", "gopher://example.org").unwrap();
+        assert_eq!(ItemType::Directory, item.typ);
+        assert_eq!("", item.selector);
+        assert_eq!(Some("example.org"), item.host.as_deref());
+        assert_eq!(Some("70"), item.port.as_deref());
+
+        let item = MenuItem::gopher_url("text", "gopher://[::1]:70/1/dir").unwrap();
+        assert_eq!(Some("::1"), item.host.as_deref());
+        assert_eq!(Some("70"), item.port.as_deref());
+        assert_eq!("/dir", item.selector);
+
+        assert!(MenuItem::gopher_url("text", "http://example.org/").is_none());
+        assert!(MenuItem::gopher_url("text", "gopher://example.org:http/").is_none());
+        assert!(MenuItem::gopher_url("text", "gopher:///1/").is_none());
+        assert!(MenuItem::gopher_url("text", "gopher://example.org/é").is_none());
+    }
+
+    #[test]
+    fn test_blank_and_separator() {
+        let blank = MenuItem::blank();
+        assert_eq!(ItemType::Info, blank.typ);
+        assert_eq!("", blank.text);
+        assert_eq!("", blank.selector);
+
+        let separator = MenuItem::separator(SEPARATOR_WIDTH);
+        assert_eq!(ItemType::Info, separator.typ);
+        assert_eq!(67, separator.text.len());
+        assert!(separator.text.chars().all(|c| c == '-'));
+        assert_eq!("---", MenuItem::separator(3).text);
+        assert_eq!("", MenuItem::separator(0).text);
+    }
+
+    #[test]
+    fn test_truncate_text() {
+        let truncated = |text: &str, width| {
+            let mut item = MenuItem::new(ItemType::File, text, "/x", "localhost", "70");
+            item.truncate(width);
+            item.text
+        };
+        assert_eq!(truncated("short", 10), "short");
+        assert_eq!(truncated("exactly 10", 10), "exactly 10");
+        assert_eq!(truncated("a bit too long", 10), "a bit too…");
+        // Counted in characters, so multi-byte ones aren't cut in half.
+        assert_eq!(truncated("ünïcödé ünïcödé", 10), "ünïcödé ü…");
+        assert_eq!(truncated("日本語のテキストです", 5), "日本語の…");
+    }
+
+    #[test]
+    fn test_wrap_text() {
+        let wrapped = |text: &str, width| {
+            MenuItem::new(ItemType::File, text, "/x", "localhost", "70").wrap(width)
+        };
+        let items = wrapped("short", 10);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].text, "short");
+
+        let items = wrapped("the quick brown fox jumps over", 10);
+        let text = items.iter().map(|item| item.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(text, ["the quick", "brown fox", "jumps over"]);
+        assert_eq!(items[0].typ, ItemType::File);
+        assert_eq!(items[0].selector, "/x");
+        assert!(items[1 ..].iter().all(|item| item.typ == ItemType::Info && item.host.is_none()));
+
+        // Words longer than a line are broken up, by characters rather than bytes.
+        let items = wrapped("ünïcödéünïcödé ok", 5);
+        let text = items.iter().map(|item| item.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(text, ["ünïcö", "déünï", "cödé", "ok"]);
+        let items = wrapped("日本語のテキストです", 4);
+        let text = items.iter().map(|item| item.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(text, ["日本語の", "テキスト", "です"]);
+    }
+
+    #[test]
+    fn test_builders() {
+        let item = MenuItem::url("example", "https://example.org/")
+            .with_host("gopher.example")
+            .with_port("7070");
+        assert_eq!(ItemType::Html, item.typ);
+        assert_eq!("example", item.text);
+        assert_eq!("URL:https://example.org/", item.selector);
+        assert_eq!(Some("gopher.example"), item.host.as_deref());
+        assert_eq!(Some("7070"), item.port.as_deref());
+    }
+
+    #[test]
+    fn test_parse_url_directive() {
+        let mut buf = BytesMut::from("URL:https://example.org/\r\n");
+        let item = MenuItemDecoder::default().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(ItemType::Html, item.typ);
+        assert_eq!("https://example.org/", item.text);
+        assert_eq!("URL:https://example.org/", item.selector);
+        assert_eq!(None, item.host);
+        assert_eq!(None, item.port);
+    }
+
+    #[test]
+    fn test_parse_gopher_directive() {
+        let mut buf = BytesMut::from("GOPHER:gopher://example.org/1/dir\r\n");
+        let item = MenuItemDecoder::default().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(ItemType::Directory, item.typ);
+        assert_eq!("gopher://example.org/1/dir", item.text);
+        assert_eq!("/dir", item.selector);
+        assert_eq!(Some("example.org"), item.host.as_deref());
+        assert_eq!(Some("70"), item.port.as_deref());
+
+        let mut buf = BytesMut::from("GOPHER:not a url\r\n");
+        match MenuItemDecoder::default().decode(&mut buf) {
+            Err(MenuItemParseError::Message(_)) => (),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
 }
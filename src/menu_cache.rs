@@ -0,0 +1,287 @@
+use crate::config::{Config, SortOrder, SymlinkPolicy};
+use crate::menu::MenuItem;
+use futures::stream::{self, StreamExt};
+use moka::future::Cache;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::fs::File;
+use tracing::debug;
+
+/// Parsed menu files, so popular ones don't have to be re-read for every request, and the titles
+/// they give their directories in generated menus. Clones share the same cache.
+#[derive(Clone)]
+pub struct MenuCache {
+    /// `None` if caching is turned off.
+    cache: Option<Cache<Key, Arc<Entry>>>,
+    /// Keyed by the menu file's path and modification time.
+    titles: Option<Cache<(PathBuf, SystemTime), Option<String>>>,
+}
+
+/// Everything a parsed menu depends on, besides the other files it was made from. The modification
+/// time is included so an edited file is never served from the cache, the hostname, port, and
+/// selector because they get filled in to items, `show_menu_errors` because it decides what
+/// becomes of bad lines, and the rest because they decide what `=glob` lines list, and how.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+struct Key {
+    path: PathBuf,
+    selector: String,
+    modified: SystemTime,
+    hostname: String,
+    port: u16,
+    show_errors: bool,
+    hide_patterns: Vec<String>,
+    dir_sort: SortOrder,
+    dirs_first: bool,
+    show_meta: bool,
+    meta_format: String,
+    detect_image_type: bool,
+    symlink_policy: SymlinkPolicy,
+}
+
+/// A parsed menu, and what else it was made from.
+struct Entry {
+    items: Arc<Vec<MenuItem>>,
+    dependencies: Vec<Dependency>,
+}
+
+/// A file a menu was made from, other than its own menu file, like one it includes or a directory
+/// it lists entries of with `=glob`, or one a directory listing got a subdirectory's title from,
+/// and when it was last modified. That's `None` if it couldn't
+/// be found out, like if the file doesn't exist, so the menu is made again if it turns up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl Dependency {
+    /// Note when the file at `path` was last modified, before it's read.
+    pub async fn new(path: PathBuf) -> Self {
+        let modified = modified(&path).await;
+        Self { path, modified }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether it's been modified since it was noted.
+    pub async fn changed(&self) -> bool {
+        modified(&self.path).await != self.modified
+    }
+}
+
+async fn modified(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.and_then(|meta| meta.modified()).ok()
+}
+
+impl MenuCache {
+    /// A cache holding up to `max_entries` menus, each for up to `ttl`. If `max_entries` is 0,
+    /// nothing is cached.
+    pub fn new(max_entries: u64, ttl: Duration) -> Self {
+        let cache = (max_entries > 0).then(|| Cache::builder()
+            .max_capacity(max_entries)
+            .time_to_live(ttl)
+            .build());
+        let titles = (max_entries > 0).then(|| Cache::builder()
+            .max_capacity(max_entries)
+            .time_to_live(ttl)
+            .build());
+        Self { cache, titles }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.menu_cache_max_entries, Duration::from_secs(config.menu_cache_ttl_secs))
+    }
+
+    /// Get the items of the menu file at `path`, which is open as `file` and was requested as
+    /// `selector`, from the cache or else by passing the file to `load`, which also returns the
+    /// other files the menu was made from. It's made again if any of them have changed.
+    pub async fn get<F: Future<Output = (Vec<MenuItem>, Vec<Dependency>)>>(
+        &self,
+        path: PathBuf,
+        selector: &str,
+        file: File,
+        config: &Config,
+        load: impl FnOnce(File) -> F,
+    ) -> Arc<Vec<MenuItem>> {
+        let Some(cache) = &self.cache else {
+            return Arc::new(load(file).await.0);
+        };
+        let modified = match file.metadata().await.and_then(|meta| meta.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                debug!("not caching {path:?}: can't get its modification time: {e}");
+                return Arc::new(load(file).await.0);
+            }
+        };
+        let key = Key {
+            path,
+            selector: selector.to_owned(),
+            modified,
+            hostname: config.hostname.clone(),
+            port: config.port,
+            show_errors: config.show_menu_errors,
+            hide_patterns: config.hide_patterns.clone(),
+            dir_sort: config.dir_sort,
+            dirs_first: config.dirs_first,
+            show_meta: config.dir_listing_show_meta,
+            meta_format: config.dir_listing_meta_format.clone(),
+            detect_image_type: config.detect_image_type,
+            symlink_policy: config.symlink_policy,
+        };
+        if let Some(entry) = cache.get(&key).await {
+            if !stream::iter(&entry.dependencies).any(Dependency::changed).await {
+                return entry.items.clone();
+            }
+            debug!("not using cached {:?}: a file or directory it uses has changed", key.path);
+            cache.invalidate(&key).await;
+        }
+        let init = async {
+            let (items, dependencies) = load(file).await;
+            Arc::new(Entry { items: Arc::new(items), dependencies })
+        };
+        cache.get_with(key, init).await.items.clone()
+    }
+
+    /// Get the title the menu file at `path`, which is open as `file`, gives its directory, from
+    /// the cache or else by passing the file to `load`.
+    pub async fn title<F: Future<Output = Option<String>>>(
+        &self,
+        path: PathBuf,
+        file: File,
+        load: impl FnOnce(File) -> F,
+    ) -> Option<String> {
+        let Some(titles) = &self.titles else {
+            return load(file).await;
+        };
+        match file.metadata().await.and_then(|meta| meta.modified()) {
+            Ok(modified) => titles.get_with((path, modified), load(file)).await,
+            Err(e) => {
+                debug!("not caching the title of {path:?}: can't get its modification time: {e}");
+                load(file).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn cached_until_modified() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("!menu");
+        std::fs::write(&path, "").unwrap();
+        let config: Config = toml::from_str(&format!(r#"
+            document_root = {:?}
+            hostname = "localhost"
+            "#, dir.path())).unwrap();
+        let cache = MenuCache::new(10, Duration::from_secs(60));
+        let loads = AtomicUsize::new(0);
+        let get = || async {
+            let file = File::open(&path).await.unwrap();
+            cache.get(path.clone(), "/", file, &config, |_| async {
+                loads.fetch_add(1, Ordering::SeqCst);
+                (vec![MenuItem::info("hi")], vec![])
+            }).await
+        };
+
+        assert_eq!(get().await[0].text, "hi");
+        get().await;
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        get().await;
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn cached_until_dependency_modified() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("!menu");
+        let included = dir.path().join("included");
+        let missing = dir.path().join("missing");
+        std::fs::write(&path, "").unwrap();
+        std::fs::write(&included, "").unwrap();
+        let config: Config = toml::from_str(&format!(r#"
+            document_root = {:?}
+            hostname = "localhost"
+            "#, dir.path())).unwrap();
+        let cache = MenuCache::new(10, Duration::from_secs(60));
+        let loads = AtomicUsize::new(0);
+        let get = || async {
+            let file = File::open(&path).await.unwrap();
+            cache.get(path.clone(), "/", file, &config, |_| async {
+                loads.fetch_add(1, Ordering::SeqCst);
+                let dependencies = vec![
+                    Dependency::new(included.clone()).await,
+                    Dependency::new(missing.clone()).await,
+                ];
+                (vec![], dependencies)
+            }).await
+        };
+
+        get().await;
+        get().await;
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        let file = std::fs::File::options().write(true).open(&included).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        get().await;
+        get().await;
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+
+        std::fs::write(&missing, "").unwrap();
+        get().await;
+        assert_eq!(loads.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn titles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("!menu");
+        std::fs::write(&path, "").unwrap();
+        let cache = MenuCache::new(10, Duration::from_secs(60));
+        let loads = AtomicUsize::new(0);
+        let get = || async {
+            let file = File::open(&path).await.unwrap();
+            cache.title(path.clone(), file, |_| async {
+                loads.fetch_add(1, Ordering::SeqCst);
+                Some("Title".to_owned())
+            }).await
+        };
+
+        assert_eq!(get().await.as_deref(), Some("Title"));
+        get().await;
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        get().await;
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn disabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("!menu");
+        std::fs::write(&path, "").unwrap();
+        let config: Config = toml::from_str(&format!("document_root = {:?}", dir.path())).unwrap();
+        let cache = MenuCache::new(0, Duration::from_secs(60));
+        let loads = AtomicUsize::new(0);
+        for _ in 0 .. 2 {
+            let file = File::open(&path).await.unwrap();
+            cache.get(path.clone(), "/", file, &config, |_| async {
+                loads.fetch_add(1, Ordering::SeqCst);
+                (vec![], vec![])
+            }).await;
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+    }
+}
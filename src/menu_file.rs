@@ -0,0 +1,363 @@
+//! Reading menu files, with the files they include and the entries their globs match.
+
+use crate::config::Config;
+use crate::fs::{self, FileSystemProvider, FileType, MenuFormat, RealFileSystem};
+use crate::listing::{
+    direntry_menuitem, hide_patterns, list_entry, sort_entries, warn_long_text,
+};
+use crate::menu_cache::Dependency;
+use crate::menu::{
+    GophermapDecoder, IncludeDecoder, Menu, MenuItem, MenuItemDecoder, MenuItemParseError,
+    MenuLine, TemplateVars, resolve_selector,
+};
+use crate::types::ItemType;
+use futures::future;
+use futures::stream::{self, Stream, StreamExt};
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio_util::codec::FramedRead;
+use tracing::warn;
+
+/// How deeply menu files can include others.
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+type MenuLines = Pin<Box<dyn Stream<Item = Result<(usize, MenuLine), MenuItemParseError>> + Send>>;
+
+/// A menu file being read, and where it's up to.
+struct MenuFile {
+    lines: MenuLines,
+    path: PathBuf,
+    /// The number of the last line read.
+    line: usize,
+}
+
+impl MenuFile {
+    fn new(file: File, path: PathBuf, format: MenuFormat) -> Self {
+        let lines = match format {
+            MenuFormat::Menu => {
+                FramedRead::new(file, IncludeDecoder::new(MenuItemDecoder::default())).boxed()
+            }
+            MenuFormat::Gophermap => {
+                FramedRead::new(file, IncludeDecoder::new(GophermapDecoder)).boxed()
+            }
+        };
+        Self { lines, path, line: 0 }
+    }
+}
+
+/// Open a file included from the last of the menu files being read, given their paths. Its path
+/// is relative to that file's directory. It has to be inside `root`, going by the symlink policy
+/// like any other file, and it mustn't be one of the files including it.
+async fn open_include(reading: &[PathBuf], include: &str, format: MenuFormat, root: &Path,
+    config: &Config) -> Result<MenuFile, MenuItemParseError>
+{
+    let error = |msg: &str| {
+        MenuItemParseError::Message(format!("can't include {include:?}: {msg}"))
+    };
+    if reading.len() > MAX_INCLUDE_DEPTH {
+        return Err(error(&format!("includes nested more than {MAX_INCLUDE_DEPTH} deep")));
+    }
+    let including = reading.last().expect("no menu file to include from");
+    let path = include_target(including, include);
+    if !path.starts_with(root) {
+        return Err(error("outside the document root"));
+    }
+    let canonical = tokio::fs::canonicalize(&path).await.map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => error("not found"),
+        _ => error(&e.to_string()),
+    })?;
+    for outer in reading {
+        if tokio::fs::canonicalize(outer).await.ok().as_ref() == Some(&canonical) {
+            return Err(error("it's already being included"));
+        }
+    }
+    match fs::lookup(&path, root, config.symlink_policy).await {
+        Ok(FileType::File(file)) => Ok(MenuFile::new(file, path, format)),
+        Ok(FileType::NotFound) => Err(error("not found")),
+        Ok(FileType::PermissionDenied) => Err(error("permission denied")),
+        Ok(_) => Err(error("not a file")),
+        Err(e) => Err(error(&e.to_string())),
+    }
+}
+
+/// The path of the file `include` refers to, when it's included from the file at `including`.
+fn include_target(including: &Path, include: &str) -> PathBuf {
+    normalize(&including.parent().unwrap_or(Path::new("")).join(include))
+}
+
+/// Take out `.` and `..` components of a path, without looking at what's there.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// What a line of a menu file comes to, once includes and globs have been taken care of.
+pub enum LoadedLine {
+    /// An item, with its default host and port filled in.
+    Item(MenuItem),
+    /// A line that couldn't be parsed, or an item that couldn't be sent.
+    Invalid(MenuItemParseError),
+    /// An include or glob that failed, leaving a whole part of the menu missing.
+    Missing(MenuItemParseError),
+}
+
+/// Reads a menu file a line at a time, along with the files it includes and the entries its
+/// globs match. This is how menus are read both for serving them and for `gofer check`.
+pub struct MenuLoader {
+    /// Included files go on top of the ones including them, and come off when they run out.
+    stack: Vec<MenuFile>,
+    /// Items from a glob, waiting their turn.
+    pending: VecDeque<(PathBuf, usize, LoadedLine)>,
+    /// Every file an include has referred to so far, whether it could be read or not, and the
+    /// directory globs list, once there's been one.
+    dependencies: Vec<Dependency>,
+    format: MenuFormat,
+    vars: TemplateVars,
+    /// Globs are in the directory of the menu being requested, even in included files.
+    glob_dir: PathBuf,
+    dir: String,
+    root: PathBuf,
+    config: Arc<Config>,
+}
+
+impl MenuLoader {
+    /// Start reading a menu file. `selector` is what the menu was requested with, for
+    /// `{{selector}}` and `$selector`, and `dir` selects the directory it's in, which relative
+    /// selectors are resolved against. `root` is the document root it's in, which included files
+    /// have to be in too.
+    pub fn new(file: File, path: PathBuf, format: MenuFormat, selector: &str, dir: &str,
+        root: &Path, config: Arc<Config>) -> Self
+    {
+        let vars = TemplateVars {
+            hostname: config.hostname.clone(),
+            port: config.port.to_string(),
+            selector: selector.to_owned(),
+        };
+        let glob_dir = path.parent().unwrap_or(Path::new("")).to_owned();
+        Self {
+            stack: vec![MenuFile::new(file, path, format)],
+            pending: VecDeque::new(),
+            dependencies: Vec::new(),
+            format,
+            vars,
+            glob_dir,
+            dir: dir.to_owned(),
+            root: root.to_owned(),
+            config,
+        }
+    }
+
+    /// The next line, with the path of the file it's from and its line number in that file, or
+    /// `None` once they've all been read.
+    pub async fn next(&mut self) -> Option<(PathBuf, usize, LoadedLine)> {
+        loop {
+            if let Some(loaded) = self.pending.pop_front() {
+                return Some(loaded);
+            }
+            let current = self.stack.last_mut()?;
+            let Some(result) = current.lines.next().await else {
+                self.stack.pop();
+                continue;
+            };
+            let result = match result {
+                Ok((line, menu_line)) => {
+                    current.line = line;
+                    Ok(menu_line)
+                }
+                // Errors reading the file come up before the line they're on is finished.
+                Err(e) => {
+                    current.line += 1;
+                    Err(e)
+                }
+            };
+            let (path, line) = (current.path.clone(), current.line);
+            let loaded = match result {
+                Ok(MenuLine::Item(item)) => {
+                    let item = item.substitute(&self.vars);
+                    // Caught here, rather than when it's sent and it's too late to skip it.
+                    match item.validate() {
+                        Ok(()) => LoadedLine::Item(complete_item(item, &self.dir, &self.config)),
+                        Err(msg) => LoadedLine::Invalid(MenuItemParseError::Message(msg)),
+                    }
+                }
+                Ok(MenuLine::Include(include)) => {
+                    let target = include_target(&path, &include);
+                    self.dependencies.push(Dependency::new(target).await);
+                    let reading = self.stack.iter().map(|file| file.path.clone())
+                        .collect::<Vec<_>>();
+                    match open_include(&reading, &include, self.format, &self.root, &self.config)
+                        .await
+                    {
+                        Ok(included) => {
+                            self.stack.push(included);
+                            continue;
+                        }
+                        Err(e) => LoadedLine::Missing(e),
+                    }
+                }
+                Ok(MenuLine::Glob { pattern, typ }) => {
+                    // Its modification time changes when entries are added, removed, or renamed.
+                    if !self.dependencies.iter().any(|dep| dep.path() == self.glob_dir) {
+                        self.dependencies.push(Dependency::new(self.glob_dir.clone()).await);
+                    }
+                    let glob = glob_items(&self.glob_dir, &self.dir, &self.root, &pattern, typ,
+                        &self.config);
+                    match glob.await {
+                        Ok(items) => {
+                            self.pending.extend(items.into_iter()
+                                .map(|item| (path.clone(), line, LoadedLine::Item(item))));
+                            continue;
+                        }
+                        Err(e) => LoadedLine::Missing(e),
+                    }
+                }
+                Ok(MenuLine::Title) => continue,
+                Ok(MenuLine::Invalid(e)) | Err(e) => LoadedLine::Invalid(e),
+            };
+            return Some((path, line, loaded));
+        }
+    }
+
+    /// The files besides the menu file that it was made from, like the ones it includes. If any
+    /// of them change, so might the menu.
+    pub fn dependencies(self) -> Vec<Dependency> {
+        self.dependencies
+    }
+
+    /// The next item to show, skipping lines with errors unless they're to be shown. They're
+    /// logged either way.
+    async fn next_item(&mut self) -> Option<MenuItem> {
+        loop {
+            let (path, line, loaded) = self.next().await?;
+            let (e, show) = match loaded {
+                LoadedLine::Item(item) => return Some(item),
+                LoadedLine::Invalid(e) => (e, self.config.show_menu_errors),
+                LoadedLine::Missing(e) => (e, true),
+            };
+            warn!("error in {path:?} on line {line}: {e}");
+            if show {
+                return Some(menu_error(&path, line, &e));
+            }
+        }
+    }
+}
+
+/// Parse a menu file into items, as [`MenuLoader`] reads it. Lines with errors are logged, and
+/// skipped, or shown as error items if `show_menu_errors` is on. Includes and globs that fail are
+/// always shown, since a whole part of the menu is missing.
+pub fn menu_items(file: File, path: PathBuf, format: MenuFormat, selector: &str, dir: &str,
+    root: &Path, config: Arc<Config>) -> Menu
+{
+    let loader = MenuLoader::new(file, path, format, selector, dir, root, config);
+    Menu::new(stream::unfold(loader, |mut loader| async move {
+        let item = loader.next_item().await?;
+        Some((item, loader))
+    }))
+}
+
+/// Read a whole menu file, like [`menu_items`], for the menu cache: with the items, it gives the
+/// other files they were made from, so the cached menu can be made again if any of them change.
+pub async fn load_menu(file: File, path: PathBuf, format: MenuFormat, selector: &str, dir: &str,
+    root: &Path, config: Arc<Config>) -> (Vec<MenuItem>, Vec<Dependency>)
+{
+    let mut loader = MenuLoader::new(file, path, format, selector, dir, root, config);
+    let mut items = Vec::new();
+    while let Some(item) = loader.next_item().await {
+        items.push(item);
+    }
+    warn_long_text(&items, &loader.config);
+    (items, loader.dependencies())
+}
+
+/// Fill in what an item from a menu file in the directory selected by `dir` leaves out. A host or
+/// port given in the file is used as it is, or with any variables in it filled in, and one that's
+/// missing or empty gets the default.
+fn complete_item(item: MenuItem, dir: &str, config: &Config) -> MenuItem {
+    if item.typ == ItemType::Info || item.typ == ItemType::Error {
+        return item;
+    }
+    // Selectors for this server are relative to the menu's directory, unless they start with a
+    // slash. Empty ones are for the root, as usual.
+    let here = item.host.as_deref().is_none_or(str::is_empty);
+    let relative = !item.selector.is_empty() && !item.selector.starts_with('/')
+        && !item.selector.starts_with("URL:");
+    let item = if here && relative {
+        let selector = resolve_selector(dir, &item.selector);
+        MenuItem { selector, ..item }
+    } else {
+        item
+    };
+    // Empty ones are as good as missing, like in "1Text\t/selector\t" with a trailing tab.
+    let host = item.host.as_deref().filter(|host| !host.is_empty());
+    let port = item.port.as_deref().filter(|port| !port.is_empty());
+    match (host, port) {
+        (None, None) => item.with_host(&config.hostname).with_port(config.port.to_string()),
+        (Some(_), None) => item.with_port("70"),
+        (None, Some(_)) => item.with_host(&config.hostname),
+        (Some(_), Some(_)) => item,
+    }
+}
+
+/// The items for a `=glob` line in a menu file in `path`, in `root`, whose directory is selected by
+/// `selector`: the entries whose names match `pattern`, listed like in a generated directory
+/// menu, with `typ` overriding their types if it's given. An info line says if there are none.
+async fn glob_items(path: &Path, selector: &str, root: &Path, pattern: &str,
+    typ: Option<ItemType>, config: &Config) -> Result<Vec<MenuItem>, MenuItemParseError>
+{
+    let error = |msg: String| MenuItemParseError::Message(format!("glob {pattern:?}: {msg}"));
+    if pattern.contains('/') {
+        return Err(error("can only match names in the menu's directory".to_owned()));
+    }
+    let glob = glob::Pattern::new(pattern).map_err(|e| error(e.to_string()))?;
+    let hide = hide_patterns(config);
+    let show_meta = config.dir_listing_show_meta;
+    let files = &RealFileSystem;
+    let entries = files.read_dir(path, config.dir_sort.needs_metadata() || show_meta)
+        .await
+        .map_err(|e| error(e.to_string()))?;
+    let mut entries = stream::iter(entries)
+        .filter(|entry| {
+            let name = entry.file_name.to_string_lossy();
+            let hidden = name.starts_with('.') || fs::is_special_file(&entry.file_name)
+                || hide.iter().any(|p| p.matches(&name));
+            future::ready(!hidden && glob.matches(&name))
+        })
+        .then(|entry| list_entry(files, path, root, entry, config))
+        .collect::<Vec<_>>()
+        .await;
+    if entries.is_empty() {
+        return Ok(vec![MenuItem::info("(none)")]);
+    }
+    sort_entries(&mut entries, config.dir_sort, config.dirs_first);
+    let selector = selector.trim_end_matches('/');
+    Ok(entries.into_iter()
+        .map(|entry| {
+            let item = direntry_menuitem(entry, selector, config);
+            match typ {
+                Some(typ) => MenuItem { typ, ..item },
+                None => item,
+            }
+        })
+        .collect())
+}
+
+/// An error item standing in for a line of a menu file that couldn't be parsed. Only the file's
+/// name goes in it, not where it is on the server.
+pub fn menu_error(path: &Path, line: usize, e: &MenuItemParseError) -> MenuItem {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let msg = e.to_string().replace(char::is_control, " ");
+    MenuItem { typ: ItemType::Error, ..MenuItem::info(format!("{name} line {line}: {msg}")) }
+}
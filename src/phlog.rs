@@ -0,0 +1,117 @@
+use crate::menu::MenuItem;
+use std::ffi::OsString;
+
+/// One post in a phlog: a file or directory whose name starts with a `YYYY-MM-DD` date.
+#[derive(Debug, PartialEq)]
+pub struct Post {
+    pub file_name: OsString,
+    pub is_dir: bool,
+    pub date: String,
+    /// The rest of the name after the date, with separators turned into spaces, and any file
+    /// extension removed. May be empty.
+    pub title: String,
+}
+
+impl Post {
+    /// Make a post out of a directory entry, if its name starts with a date.
+    pub fn parse(file_name: OsString, is_dir: bool) -> Option<Self> {
+        let name = file_name.to_str()?;
+        let date = name.get(.. 10)?;
+        let is_date = date.bytes().enumerate().all(|(i, b)| match i {
+            4 | 7 => b == b'-',
+            _ => b.is_ascii_digit(),
+        });
+        if !is_date {
+            return None;
+        }
+        let mut rest = &name[10 ..];
+        if !is_dir {
+            if let Some((stem, _extension)) = rest.rsplit_once('.') {
+                rest = stem;
+            }
+        }
+        let title = rest.split(['-', '_', ' '])
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        Some(Self { date: date.to_owned(), title, file_name, is_dir })
+    }
+
+    /// The text to show for the post in the index.
+    pub fn text(&self) -> String {
+        if self.title.is_empty() {
+            self.date.clone()
+        } else {
+            format!("{} {}", self.date, self.title)
+        }
+    }
+}
+
+/// Sort posts newest first.
+pub fn sort(posts: &mut [Post]) {
+    posts.sort_by(|a, b| b.file_name.cmp(&a.file_name));
+}
+
+/// The header for the index, from the text of the `!phlog` file, one info line per line.
+pub fn header(text: &str) -> Vec<MenuItem> {
+    text.lines().map(MenuItem::info).collect()
+}
+
+/// Get the page number out of a selector's query string, like `page=2`. Pages start at 1.
+pub fn page_number(query: Option<&str>) -> Option<usize> {
+    match query {
+        None => Some(1),
+        Some(query) => query.strip_prefix("page=")?.parse().ok().filter(|&page| page > 0),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn post(name: &str, is_dir: bool) -> Option<(String, String)> {
+        Post::parse(name.into(), is_dir).map(|post| (post.date, post.title))
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(post("2024-01-15-my-first_post", true),
+            Some(("2024-01-15".to_owned(), "my first post".to_owned())));
+        assert_eq!(post("2024-01-15 notes.txt", false),
+            Some(("2024-01-15".to_owned(), "notes".to_owned())));
+        assert_eq!(post("2024-01-15.txt", false),
+            Some(("2024-01-15".to_owned(), String::new())));
+        assert_eq!(post("2024-01-15", true), Some(("2024-01-15".to_owned(), String::new())));
+        assert_eq!(post("2024-1-15-short", true), None);
+        assert_eq!(post("20240115-nodashes", true), None);
+        assert_eq!(post("about.txt", false), None);
+        assert_eq!(post("2024", false), None);
+    }
+
+    #[test]
+    fn text() {
+        let post = Post::parse("2024-01-15-hello-world".into(), true).unwrap();
+        assert_eq!(post.text(), "2024-01-15 hello world");
+        let post = Post::parse("2024-01-15".into(), true).unwrap();
+        assert_eq!(post.text(), "2024-01-15");
+    }
+
+    #[test]
+    fn newest_first() {
+        let mut posts = ["2023-12-31-b", "2024-01-15-a", "2024-01-02"].into_iter()
+            .map(|name| Post::parse(name.into(), true).unwrap())
+            .collect::<Vec<_>>();
+        sort(&mut posts);
+        let names = posts.iter().map(|p| p.file_name.to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(names, ["2024-01-15-a", "2024-01-02", "2023-12-31-b"]);
+    }
+
+    #[test]
+    fn pages() {
+        assert_eq!(page_number(None), Some(1));
+        assert_eq!(page_number(Some("page=3")), Some(3));
+        assert_eq!(page_number(Some("page=0")), None);
+        assert_eq!(page_number(Some("page=x")), None);
+        assert_eq!(page_number(Some("foo")), None);
+    }
+}
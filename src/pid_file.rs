@@ -0,0 +1,157 @@
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// A file with the server's process ID in it, for init systems and monitoring tools. It's removed
+/// when this is dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    /// The directory it's in, kept open so it can be removed from there even after chrooting,
+    /// when `path` no longer leads to it.
+    #[cfg(unix)]
+    dir: std::fs::File,
+}
+
+impl PidFile {
+    /// Write this process's ID to the file at `path`. Refuses to if the file is already there and
+    /// names another process that's still running, since that's most likely another server.
+    pub fn create(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => match text.trim().parse::<u32>() {
+                Ok(pid) if pid != std::process::id() && is_running(pid) => {
+                    bail!("PID file {path:?} names process {pid}, which is still running");
+                }
+                Ok(_) => (),
+                Err(_) => warn!("replacing PID file {path:?}, which doesn't have a PID in it"),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e).with_context(|| format!("can't read PID file {path:?}")),
+        }
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("can't write PID file {path:?}"))?;
+        #[cfg(unix)]
+        let dir = {
+            let dir = match path.parent() {
+                Some(dir) if dir != Path::new("") => dir,
+                _ => Path::new("."),
+            };
+            std::fs::File::open(dir)
+                .with_context(|| format!("can't open the directory of PID file {path:?}"))?
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            #[cfg(unix)]
+            dir,
+        })
+    }
+
+    /// Warn if the file can't be removed on exit, once privileges have been dropped, because the
+    /// server can't change the directory it's in anymore.
+    #[cfg(unix)]
+    pub fn check_removable(&self) {
+        use nix::fcntl::AtFlags;
+        use nix::unistd::{faccessat, AccessFlags};
+        use std::os::fd::AsRawFd;
+        let access = AccessFlags::W_OK | AccessFlags::X_OK;
+        if let Err(e) = faccessat(Some(self.dir.as_raw_fd()), ".", access, AtFlags::empty()) {
+            warn!("PID file {:?} won't be removed on exit: can't change its directory ({e}); \
+                put it in a directory the server's user can write to", self.path);
+        }
+    }
+
+    #[cfg(unix)]
+    fn remove(&self) -> std::io::Result<()> {
+        use nix::unistd::{unlinkat, UnlinkatFlags};
+        use std::os::fd::AsRawFd;
+        let name = self.path.file_name().unwrap_or_default();
+        unlinkat(Some(self.dir.as_raw_fd()), name, UnlinkatFlags::NoRemoveDir)?;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn remove(&self) -> std::io::Result<()> {
+        std::fs::remove_file(&self.path)
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.remove() {
+            warn!("can't remove PID file {:?}: {e}", self.path);
+        }
+    }
+}
+
+/// Whether there's a process with the given ID. It might not be one we're allowed to signal.
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    use nix::errno::Errno;
+    use nix::sys::signal::kill;
+    use nix::unistd::Pid;
+    let Ok(pid) = i32::try_from(pid) else { return false };
+    matches!(kill(Pid::from_raw(pid), None), Ok(()) | Err(Errno::EPERM))
+}
+
+/// There's no cheap way to tell, so the file is assumed to be left over from before.
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn created_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gofer.pid");
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn removed_after_moving() {
+        // Like after chrooting, the path it was created at doesn't lead to it anymore.
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("run")).unwrap();
+        let pid_file = PidFile::create(&dir.path().join("run/gofer.pid")).unwrap();
+        std::fs::rename(dir.path().join("run"), dir.path().join("moved")).unwrap();
+        assert!(dir.path().join("moved/gofer.pid").exists());
+        drop(pid_file);
+        assert!(!dir.path().join("moved/gofer.pid").exists());
+    }
+
+    #[test]
+    fn running() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gofer.pid");
+        // The test runner's parent is still running, waiting for it.
+        std::fs::write(&path, format!("{}\n", std::os::unix::process::parent_id())).unwrap();
+        assert!(PidFile::create(&path).is_err());
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gofer.pid");
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        for text in [child.id().to_string(), "garbage".to_owned()] {
+            std::fs::write(&path, text).unwrap();
+            let _pid_file = PidFile::create(&path).unwrap();
+            assert_eq!(std::fs::read_to_string(&path).unwrap().trim(),
+                std::process::id().to_string());
+        }
+    }
+
+    #[test]
+    fn unwritable() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(PidFile::create(&dir.path().join("missing/gofer.pid")).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::{anyhow, bail, Context, Result};
+use nix::unistd::{geteuid, getgid, getuid, setgid, setuid, Gid, Group, Uid, User};
+use std::path::Path;
+
+/// One step in switching users. These have to happen in the order given by `steps`, while we
+/// still have the privileges to do each one.
+#[derive(Debug, PartialEq)]
+enum Step {
+    /// Drop all supplementary groups, leaving only the given one.
+    Groups(Gid),
+    Gid(Gid),
+    Uid(Uid),
+}
+
+/// The user and group to switch to.
+#[derive(Debug, PartialEq)]
+pub struct Target {
+    user: Option<User>,
+    gid: Option<Gid>,
+}
+
+/// Look up the user and group to switch to. If only a user is given, its primary group is used.
+///
+/// This needs the user and group databases, so it has to be done before chrooting.
+pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Target> {
+    let user = user
+        .map(|name| {
+            User::from_name(name)
+                .with_context(|| format!("failed to look up user {name:?}"))?
+                .ok_or_else(|| anyhow!("no such user {name:?}"))
+        })
+        .transpose()?;
+
+    let gid = match group {
+        Some(name) => {
+            let group = Group::from_name(name)
+                .with_context(|| format!("failed to look up group {name:?}"))?
+                .ok_or_else(|| anyhow!("no such group {name:?}"))?;
+            Some(group.gid)
+        }
+        None => user.as_ref().map(|user| user.gid),
+    };
+
+    Ok(Target { user, gid })
+}
+
+/// The steps to switch to the given user and group. The groups have to be changed first, because
+/// that needs root.
+fn steps(uid: Option<Uid>, gid: Option<Gid>) -> Vec<Step> {
+    let mut steps = vec![];
+    if let Some(gid) = gid {
+        steps.push(Step::Groups(gid));
+        steps.push(Step::Gid(gid));
+    }
+    if let Some(uid) = uid {
+        steps.push(Step::Uid(uid));
+    }
+    steps
+}
+
+/// Confine the process to the given directory, which becomes `/`. Needs root.
+pub fn chroot(path: &Path) -> Result<()> {
+    nix::unistd::chroot(path).with_context(|| format!("failed to chroot into {path:?}"))?;
+    std::env::set_current_dir("/").context("failed to change directory into the chroot")?;
+    tracing::info!("chrooted into {path:?}");
+    Ok(())
+}
+
+/// Switch to running as the given user and/or group, permanently.
+///
+/// If we aren't root, there's nothing we can switch to, so this only warns if it would have
+/// changed anything.
+pub fn drop_privileges(Target { user, gid }: Target) -> Result<()> {
+    let uid = user.as_ref().map(|user| user.uid);
+
+    if !geteuid().is_root() {
+        if uid.is_some_and(|uid| uid != getuid()) || gid.is_some_and(|gid| gid != getgid()) {
+            tracing::warn!("not running as root, so not switching user or group");
+        }
+        return Ok(());
+    }
+
+    for step in steps(uid, gid) {
+        match step {
+            Step::Groups(gid) => set_groups(gid)
+                .with_context(|| format!("failed to set supplementary groups to {gid}"))?,
+            Step::Gid(gid) => setgid(gid)
+                .with_context(|| format!("failed to set group ID to {gid}"))?,
+            Step::Uid(uid) => setuid(uid)
+                .with_context(|| format!("failed to set user ID to {uid}"))?,
+        }
+    }
+
+    if let Some(user) = user {
+        if !user.uid.is_root() && (getuid().is_root() || geteuid().is_root()) {
+            bail!("still running as root after switching to user {:?}", user.name);
+        }
+        tracing::info!("running as user {:?}", user.name);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_vendor = "apple"))]
+fn set_groups(gid: Gid) -> nix::Result<()> {
+    nix::unistd::setgroups(&[gid])
+}
+
+/// macOS can't set the supplementary groups this way; they're left as they are.
+#[cfg(target_vendor = "apple")]
+fn set_groups(_gid: Gid) -> nix::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn order() {
+        let (uid, gid) = (Uid::from_raw(1000), Gid::from_raw(100));
+        assert_eq!(steps(Some(uid), Some(gid)),
+            [Step::Groups(gid), Step::Gid(gid), Step::Uid(uid)]);
+        assert_eq!(steps(None, Some(gid)), [Step::Groups(gid), Step::Gid(gid)]);
+        assert_eq!(steps(Some(uid), None), [Step::Uid(uid)]);
+        assert_eq!(steps(None, None), []);
+    }
+
+    #[test]
+    fn resolution() {
+        let Target { user, gid } = resolve(Some("root"), None).unwrap();
+        let user = user.unwrap();
+        assert!(user.uid.is_root());
+        assert_eq!(gid, Some(user.gid));
+
+        let root_group = Group::from_gid(Gid::from_raw(0)).unwrap().unwrap();
+        let Target { user, gid } = resolve(None, Some(&root_group.name)).unwrap();
+        assert!(user.is_none());
+        assert_eq!(gid, Some(root_group.gid));
+
+        assert_eq!(resolve(None, None).unwrap(), Target { user: None, gid: None });
+        let e = resolve(Some("no-such-user-for-gofer"), None).unwrap_err();
+        assert_eq!(e.to_string(), "no such user \"no-such-user-for-gofer\"");
+        let e = resolve(Some("root"), Some("no-such-group-for-gofer")).unwrap_err();
+        assert_eq!(e.to_string(), "no such group \"no-such-group-for-gofer\"");
+    }
+}
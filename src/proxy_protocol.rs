@@ -0,0 +1,253 @@
+//! The PROXY protocol, used by load balancers like HAProxy to tell the server who the client they
+//! accepted a connection from is. It's a header, in either a text (v1) or binary (v2) format, sent
+//! at the start of the connection before anything from the client.
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::io::{self, Cursor};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest a v1 header can be, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The signature, followed by the version and command, address family and protocol, and length.
+const V2_FIXED_LEN: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum ProxyHeaderError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("connection doesn't start with a PROXY protocol header")]
+    Missing,
+
+    #[error("PROXY protocol header too long")]
+    TooLong,
+
+    #[error("invalid PROXY protocol v1 header {0:?}")]
+    InvalidV1(String),
+
+    #[error("invalid PROXY protocol v2 header: {0}")]
+    InvalidV2(&'static str),
+}
+
+/// The client address from a header, and the header's length.
+type Parsed = Option<(Option<SocketAddr>, usize)>;
+
+/// Parse a header from the start of `buf`. Gives the client address it names, if any, and how
+/// long the header is; or `None` if there isn't enough of it yet.
+///
+/// Health checks from the proxy itself don't name a client, and neither do connections whose
+/// client isn't a TCP one, so the address is left out for those.
+pub fn parse(buf: &[u8]) -> Result<Parsed, ProxyHeaderError> {
+    if V2_SIGNATURE.starts_with(&buf[.. buf.len().min(V2_SIGNATURE.len())]) {
+        parse_v2(buf)
+    } else if V1_PREFIX.starts_with(&buf[.. buf.len().min(V1_PREFIX.len())]) {
+        parse_v1(buf)
+    } else {
+        Err(ProxyHeaderError::Missing)
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Parsed, ProxyHeaderError> {
+    let search = &buf[.. buf.len().min(V1_MAX_LEN)];
+    let Some(end) = search.windows(2).position(|pair| pair == b"\r\n") else {
+        return if buf.len() >= V1_MAX_LEN {
+            Err(ProxyHeaderError::TooLong)
+        } else {
+            Ok(None)
+        };
+    };
+    let line = std::str::from_utf8(&buf[.. end])
+        .map_err(|_| ProxyHeaderError::InvalidV1(String::from_utf8_lossy(&buf[.. end]).into()))?;
+    let invalid = || ProxyHeaderError::InvalidV1(line.to_owned());
+    let fields = line.split(' ').collect::<Vec<_>>();
+    let addr = match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, dst, src_port, dst_port] => {
+            let ip = |s: &str| match family {
+                "TCP4" => s.parse::<Ipv4Addr>().map(IpAddr::V4).ok(),
+                _ => s.parse::<Ipv6Addr>().map(IpAddr::V6).ok(),
+            };
+            let port = |s: &str| s.parse::<u16>().ok();
+            let src = ip(src).zip(port(src_port)).ok_or_else(invalid)?;
+            ip(dst).zip(port(dst_port)).ok_or_else(invalid)?;
+            Some(SocketAddr::from(src))
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(Some((addr, end + 2)))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Parsed, ProxyHeaderError> {
+    if buf.len() < V2_FIXED_LEN {
+        return Ok(None);
+    }
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0xf;
+    let family = buf[13] >> 4;
+    let len = V2_FIXED_LEN + usize::from(u16::from_be_bytes([buf[14], buf[15]]));
+    if version != 2 {
+        return Err(ProxyHeaderError::InvalidV2("unsupported version"));
+    }
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let addrs = &buf[V2_FIXED_LEN .. len];
+    let addr = match (command, family) {
+        // LOCAL: a connection from the proxy itself.
+        (0, _) => None,
+        // PROXY, over IPv4: source and destination addresses, then source and destination ports.
+        (1, 1) => {
+            let addrs: &[u8; 12] = addrs.get(.. 12)
+                .and_then(|a| a.try_into().ok())
+                .ok_or(ProxyHeaderError::InvalidV2("IPv4 addresses too short"))?;
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addrs[.. 4]).unwrap());
+            Some(SocketAddr::from((ip, u16::from_be_bytes([addrs[8], addrs[9]]))))
+        }
+        // PROXY, over IPv6: the same, with longer addresses.
+        (1, 2) => {
+            let addrs: &[u8; 36] = addrs.get(.. 36)
+                .and_then(|a| a.try_into().ok())
+                .ok_or(ProxyHeaderError::InvalidV2("IPv6 addresses too short"))?;
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addrs[.. 16]).unwrap());
+            Some(SocketAddr::from((ip, u16::from_be_bytes([addrs[32], addrs[33]]))))
+        }
+        // PROXY, with an unspecified or Unix socket address.
+        (1, 0 | 3) => None,
+        (1, _) => return Err(ProxyHeaderError::InvalidV2("unknown address family")),
+        _ => return Err(ProxyHeaderError::InvalidV2("unknown command")),
+    };
+    Ok(Some((addr, len)))
+}
+
+/// Read a header from the start of a connection. Gives back the rest of the connection, along
+/// with the client address the header names, if any.
+pub async fn read_header<R: AsyncRead + Send + Unpin>(mut rx: R)
+    -> Result<(impl AsyncRead + Send + Unpin, Option<SocketAddr>), ProxyHeaderError>
+{
+    let mut buf = Vec::with_capacity(V1_MAX_LEN);
+    loop {
+        if let Some((addr, len)) = parse(&buf)? {
+            let rest = Cursor::new(buf.split_off(len));
+            return Ok((rest.chain(rx), addr));
+        }
+        if rx.read_buf(&mut buf).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                "connection closed during PROXY protocol header").into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_ok(buf: &[u8]) -> Option<(Option<String>, usize)> {
+        parse(buf).unwrap().map(|(addr, len)| (addr.map(|a| a.to_string()), len))
+    }
+
+    fn v2(command: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(0x20 | command);
+        buf.push(family << 4 | 1);
+        buf.extend_from_slice(&u16::try_from(addrs.len()).unwrap().to_be_bytes());
+        buf.extend_from_slice(addrs);
+        buf
+    }
+
+    #[test]
+    fn v1() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 70\r\n";
+        assert_eq!(parse_ok(header), Some((Some("192.0.2.1:56324".to_owned()), header.len())));
+        let mut with_request = header.to_vec();
+        with_request.extend_from_slice(b"/sel\r\n");
+        assert_eq!(parse_ok(&with_request), Some((Some("192.0.2.1:56324".to_owned()), 44)));
+
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 70\r\n";
+        assert_eq!(parse_ok(header), Some((Some("[2001:db8::1]:56324".to_owned()), 45)));
+
+        assert_eq!(parse_ok(b"PROXY UNKNOWN\r\n"), Some((None, 15)));
+        assert_eq!(parse_ok(b"PROXY UNKNOWN 192.0.2.1 198.51.100.2 1 2\r\n"), Some((None, 42)));
+
+        // Incomplete headers need more data.
+        assert_eq!(parse_ok(b""), None);
+        assert_eq!(parse_ok(b"PRO"), None);
+        assert_eq!(parse_ok(b"PROXY TCP4 192.0.2.1 198.51"), None);
+        assert_eq!(parse_ok(b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 70\r"), None);
+    }
+
+    #[test]
+    fn v2_binary() {
+        let mut addrs = vec![192, 0, 2, 1, 198, 51, 100, 2];
+        addrs.extend_from_slice(&56324u16.to_be_bytes());
+        addrs.extend_from_slice(&70u16.to_be_bytes());
+        let header = v2(1, 1, &addrs);
+        assert_eq!(parse_ok(&header), Some((Some("192.0.2.1:56324".to_owned()), 28)));
+
+        // Anything after the addresses (i.e. TLVs) is skipped over.
+        addrs.extend_from_slice(&[4, 0, 1, 0]);
+        let header = v2(1, 1, &addrs);
+        assert_eq!(parse_ok(&header), Some((Some("192.0.2.1:56324".to_owned()), 32)));
+        assert_eq!(parse_ok(&header[.. 31]), None);
+        assert_eq!(parse_ok(&header[.. 5]), None);
+
+        let mut addrs = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addrs.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addrs.extend_from_slice(&[0xdc, 0x04, 0, 70]);
+        let header = v2(1, 2, &addrs);
+        assert_eq!(parse_ok(&header), Some((Some("[2001:db8::1]:56324".to_owned()), 52)));
+
+        // A health check from the proxy.
+        assert_eq!(parse_ok(&v2(0, 0, &[])), Some((None, 16)));
+        // A Unix socket client.
+        assert_eq!(parse_ok(&v2(1, 3, &[0; 216])), Some((None, 232)));
+    }
+
+    #[test]
+    fn malformed() {
+        let err = |buf: &[u8]| parse(buf).unwrap_err().to_string();
+        assert_eq!(err(b"/selector\r\n"),
+            "connection doesn't start with a PROXY protocol header");
+        assert_eq!(err(b"PROXY TCP4 192.0.2.1 198.51.100.2 56324\r\n"),
+            "invalid PROXY protocol v1 header \"PROXY TCP4 192.0.2.1 198.51.100.2 56324\"");
+        assert_eq!(err(b"PROXY TCP4 2001:db8::1 198.51.100.2 56324 70\r\n"),
+            "invalid PROXY protocol v1 header \"PROXY TCP4 2001:db8::1 198.51.100.2 56324 70\"");
+        assert_eq!(err(b"PROXY TCP4 192.0.2.1 198.51.100.2 99999 70\r\n"),
+            "invalid PROXY protocol v1 header \"PROXY TCP4 192.0.2.1 198.51.100.2 99999 70\"");
+        assert_eq!(err(b"PROXY UDP4 192.0.2.1 198.51.100.2 56324 70\r\n"),
+            "invalid PROXY protocol v1 header \"PROXY UDP4 192.0.2.1 198.51.100.2 56324 70\"");
+        assert_eq!(err(&[b'X'; 200]), "connection doesn't start with a PROXY protocol header");
+        let mut long = V1_PREFIX.to_vec();
+        long.resize(200, b'1');
+        assert_eq!(err(&long), "PROXY protocol header too long");
+
+        let mut header = v2(1, 1, &[0; 12]);
+        header[12] = 0x11;
+        assert_eq!(err(&header), "invalid PROXY protocol v2 header: unsupported version");
+        assert_eq!(err(&v2(2, 1, &[0; 12])), "invalid PROXY protocol v2 header: unknown command");
+        assert_eq!(err(&v2(1, 4, &[0; 12])),
+            "invalid PROXY protocol v2 header: unknown address family");
+        assert_eq!(err(&v2(1, 1, &[0; 8])),
+            "invalid PROXY protocol v2 header: IPv4 addresses too short");
+        assert_eq!(err(&v2(1, 2, &[0; 12])),
+            "invalid PROXY protocol v2 header: IPv6 addresses too short");
+    }
+
+    #[tokio::test]
+    async fn read() {
+        let input = &b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 70\r\n/sel\r\n"[..];
+        let (mut rest, addr) = read_header(input).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        let mut request = String::new();
+        rest.read_to_string(&mut request).await.unwrap();
+        assert_eq!(request, "/sel\r\n");
+
+        let e = read_header(&b"PROXY TCP4 192.0.2.1"[..]).await.err().unwrap();
+        assert!(matches!(e, ProxyHeaderError::Io(ref e)
+            if e.kind() == io::ErrorKind::UnexpectedEof), "{e}");
+    }
+}
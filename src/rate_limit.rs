@@ -0,0 +1,139 @@
+use crate::config::RateLimit;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often to forget about clients that have stopped making requests.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits how often each client can make requests, with a token bucket for each: every request
+/// takes a token, and tokens come back at a steady rate, up to a maximum which allows for short
+/// bursts.
+pub struct RateLimiter {
+    /// Tokens given back per second.
+    rate: f64,
+    /// How many tokens a bucket can hold.
+    burst: f64,
+    buckets: HashMap<IpAddr, Bucket>,
+    last_pruned: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            rate: f64::from(limit.requests_per_minute) / 60.,
+            burst: f64::from(limit.burst),
+            buckets: HashMap::new(),
+            last_pruned: Instant::now(),
+        }
+    }
+
+    /// Take a token for a request from the client, returning whether there was one to take.
+    pub fn check(&mut self, client: IpAddr) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.last_pruned) >= PRUNE_INTERVAL {
+            self.prune(now);
+        }
+        let bucket = self.buckets.entry(client)
+            .or_insert(Bucket { tokens: self.burst, updated: now });
+        bucket.refill(now, self.rate, self.burst);
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forget clients whose buckets have filled back up, since they're no different from new
+    /// ones.
+    fn prune(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(now, rate, burst);
+            bucket.tokens < burst
+        });
+        self.last_pruned = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const A: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const B: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    fn limiter(requests_per_minute: u32, burst: u32) -> RateLimiter {
+        RateLimiter::new(RateLimit { requests_per_minute, burst })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_then_steady_rate() {
+        let mut limiter = limiter(60, 3);
+        for _ in 0 .. 3 {
+            assert!(limiter.check(A));
+        }
+        assert!(!limiter.check(A));
+
+        // One token a second comes back.
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(!limiter.check(A));
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(limiter.check(A));
+        assert!(!limiter.check(A));
+
+        // Waiting a long time only gives back as many as the burst allows.
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        for _ in 0 .. 3 {
+            assert!(limiter.check(A));
+        }
+        assert!(!limiter.check(A));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clients_are_separate() {
+        let mut limiter = limiter(1, 1);
+        assert!(limiter.check(A));
+        assert!(!limiter.check(A));
+        assert!(limiter.check(B));
+        assert!(!limiter.check(B));
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(limiter.check(A));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn prune_idle() {
+        let mut limiter = limiter(60, 10);
+        assert!(limiter.check(A));
+        for _ in 0 .. 10 {
+            limiter.check(B);
+        }
+        assert_eq!(limiter.buckets.len(), 2);
+
+        // A has long since filled back up, but B keeps on using all of its tokens.
+        tokio::time::advance(PRUNE_INTERVAL - Duration::from_secs(1)).await;
+        for _ in 0 .. 60 {
+            limiter.check(B);
+        }
+        assert_eq!(limiter.buckets.len(), 2);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(limiter.check(B));
+        assert_eq!(limiter.buckets.keys().collect::<Vec<_>>(), [&B]);
+    }
+}
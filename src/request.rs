@@ -6,6 +6,9 @@ use tokio_util::codec::Decoder;
 #[derive(Debug)]
 pub struct Request {
     pub selector: String,
+    /// A Gopher+ request for the item's attributes, rather than the item itself: the selector was
+    /// followed by a tab and `!`.
+    pub attributes: bool,
 }
 
 #[derive(Error, Debug)]
@@ -21,10 +24,21 @@ pub enum RequestError {
 
     #[error("Invalid selector: {0}")]
     InvalidSelector(String),
+
+    #[error("Timed out waiting for request")]
+    Timeout,
+
+    #[error("Too many requests from this client")]
+    RateLimited,
+
+    #[error("Too many responses in progress")]
+    Busy,
 }
 
 pub struct RequestDecoder {
     max_length: usize,
+    /// Where to pick up looking for the end of the line, on the next call. Everything before it
+    /// has already been checked.
     next_index: usize,
     finished: bool,
 }
@@ -54,16 +68,20 @@ impl Decoder for RequestDecoder {
         //  - TAB
         //  - LF
         //  - CR
-        // This reader is going to forbid all of these.
+        // This reader is going to forbid all of these, except for a TAB followed by '!', which is
+        // how Gopher+ asks for an item's attributes.
         // Additionally we impose the requirement that the selector is UTF-8.
 
         let read_to = std::cmp::min(self.max_length + 2, buf.len());
+        // The buffer only grows between calls, so this holds as long as it's never set past the
+        // end of what's been read.
+        debug_assert!(self.next_index <= read_to, "{} > {read_to}", self.next_index);
 
         let offset = buf[self.next_index .. read_to]
             .windows(2)
             .enumerate()
             .filter_map(|(i, pair)| {
-                let invalid = |c| matches!(c, b'\r' | b'\n' | b'\t' | b'\0');
+                let invalid = |c| matches!(c, b'\r' | b'\n' | b'\0');
                 match pair {
                     [b'\r', b'\n'] => Some(Ok(i)),
                     [first, b'\r'] => if invalid(*first) { Some(Err(i)) } else { None },
@@ -81,12 +99,19 @@ impl Decoder for RequestDecoder {
                 let line = std::str::from_utf8(&bytes[..newline_index])
                     .map_err(RequestError::Utf8)?;
                 self.finished = true;
-                Ok(Some(Request { selector: line.to_owned() }))
+                match line.split_once('\t') {
+                    None => Ok(Some(Request { selector: line.to_owned(), attributes: false })),
+                    Some((selector, "!")) => {
+                        Ok(Some(Request { selector: selector.to_owned(), attributes: true }))
+                    }
+                    Some((selector, _)) => Err(RequestError::InvalidSelector(format!(
+                        "selector {line:?} contains invalid characters at {}", selector.len()))),
+                }
             }
             Some(Err(offset)) => {
                 // Invalid selector.
                 let msg = format!("selector {:?} contains invalid characters at {}",
-                    String::from_utf8_lossy(buf), offset);
+                    String::from_utf8_lossy(buf), offset + self.next_index);
                 Err(RequestError::InvalidSelector(msg))
             }
             None if buf.len() > self.max_length => {
@@ -94,7 +119,9 @@ impl Decoder for RequestDecoder {
                 Err(RequestError::TooLong)
             }
             None => {
-                // Request the caller to read some more data into the buffer.
+                // Request the caller to read some more data into the buffer. Only the last byte
+                // needs looking at again, in case it's the CR of a CR-LF.
+                self.next_index = read_to.saturating_sub(1);
                 Ok(None)
             }
         }
@@ -125,16 +152,21 @@ impl<R: tokio::io::AsyncRead + Unpin> RequestReader<R> {
         }
     }
 
-    pub async fn read_request(mut self) -> Result<Request, RequestError> {
+    pub async fn read_request(&mut self) -> Result<Request, RequestError> {
         // This is a little weird. FramedRead is a stream of "frames", but Gopher protocol always
-        // has only one request per connection, so we just take the first one, consuming Self in
-        // the process.
+        // has only one request per connection, so we just take the first one.
         // Note that this means any garbage after the first CR-LF will be discarded and silently
         // ignored, because CR-LF is what separates frames.
         self.inner.next()
             .await
             .unwrap_or_else(|| Err(RequestError::InvalidSelector("missing CR-LF".into())))
     }
+
+    /// Give back the reader, once the request has been read. Anything buffered after the request
+    /// is thrown away.
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
 }
 
 #[cfg(test)]
@@ -160,6 +192,28 @@ mod test {
         assert!(!decoder.finished);
     }
 
+    #[test]
+    fn line_in_pieces() {
+        let mut decoder = RequestDecoder::with_max_length(100);
+        let mut buf = BytesMut::new();
+        for piece in ["fo", "o", "\r"] {
+            buf.extend_from_slice(piece.as_bytes());
+            assert!(decoder.decode(&mut buf).unwrap().is_none());
+        }
+        buf.extend_from_slice(b"\n");
+        assert_eq!(decoder.decode(&mut buf).unwrap().unwrap().selector, "foo");
+
+        // A bad character is reported where it is in the whole line, not the last piece.
+        let mut decoder = RequestDecoder::with_max_length(100);
+        let mut buf = BytesMut::from("abcd");
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"\0\r\n");
+        match decoder.decode(&mut buf) {
+            Err(RequestError::InvalidSelector(msg)) => assert!(msg.ends_with(" at 3"), "{msg}"),
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
+
     #[test]
     fn too_long() {
         let mut decoder = RequestDecoder::with_max_length(3);
@@ -181,7 +235,7 @@ mod test {
     #[tokio::test]
     async fn empty_reader() {
         let input = "";
-        let reader = RequestReader::with_max_length(100, Cursor::new(input));
+        let mut reader = RequestReader::with_max_length(100, Cursor::new(input));
         match reader.read_request().await {
             Err(RequestError::InvalidSelector(_)) => (),
             other => panic!("{other:?}"),
@@ -193,7 +247,7 @@ mod test {
         macro_rules! check {
             ($e:expr) => {
                 let mut decoder = RequestDecoder::with_max_length(100);
-                match decoder.decode(&mut BytesMut::from("abcd\ref")) {
+                match decoder.decode(&mut BytesMut::from($e)) {
                     Err(RequestError::InvalidSelector(_)) => (),
                     other => panic!("unexpected result {:?}", other),
                 }
@@ -201,9 +255,22 @@ mod test {
         }
 
         check!("abc\rdef");
-        check!("abc\r");
         check!("abc\ndef\r\n");
         check!("abc\0def\r\n");
         check!("abc\tdef\r\n");
     }
+
+    #[test]
+    fn attributes() {
+        let mut decoder = RequestDecoder::with_max_length(100);
+        let req = decoder.decode(&mut BytesMut::from("/foo\t!\r\n")).unwrap().unwrap();
+        assert_eq!(req.selector, "/foo");
+        assert!(req.attributes);
+
+        let mut decoder = RequestDecoder::with_max_length(100);
+        match decoder.decode(&mut BytesMut::from("/foo\t+\r\n")) {
+            Err(RequestError::InvalidSelector(_)) => (),
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
 }
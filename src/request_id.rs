@@ -0,0 +1,59 @@
+use std::collections::hash_map::RandomState;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+tokio::task_local! {
+    static CURRENT: RequestId;
+}
+
+/// A random ID for a connection, for telling apart the log lines of requests handled at the same
+/// time.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RequestId(u64);
+
+impl RequestId {
+    pub fn new() -> Self {
+        // Each RandomState is seeded differently, which is random enough for this.
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        Self(hasher.finish())
+    }
+
+    /// The ID of the request the current task is handling, if it's running inside `scope`.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|id| *id).ok()
+    }
+
+    /// Run a future with this as the current request ID.
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CURRENT.scope(self, f).await
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unique() {
+        let ids = (0 .. 1000).map(|_| RequestId::new().0).collect::<std::collections::HashSet<_>>();
+        assert_eq!(ids.len(), 1000);
+    }
+
+    #[tokio::test]
+    async fn current() {
+        let id = RequestId::new();
+        assert_eq!(RequestId::current(), None);
+        assert_eq!(id.scope(async { RequestId::current() }).await, Some(id));
+        assert_eq!(RequestId::current(), None);
+    }
+}
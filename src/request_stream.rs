@@ -1,55 +1,1233 @@
-use crate::bounded_futures_unordered::BoundedFuturesUnordered;
+use crate::bounded_futures_unordered::{BoundedFuturesUnordered, EvictionStrategy, Overflow};
+use crate::cidr::AccessList;
+use crate::config::{Config, QueuePolicy, RateLimit, SocketOptions};
+use crate::menu::MenuItemEncoder;
+use crate::proxy_protocol;
+use crate::rate_limit::RateLimiter;
 use crate::request::{Request, RequestError, RequestReader};
-use futures::future::FutureExt;
-use futures::stream::StreamExt;
+use crate::request_id::RequestId;
+use crate::response;
+use crate::stats::ActiveConnection;
+use crate::tls::TlsConnection;
+use futures::ready;
+use futures::stream::{self, SelectAll, Stream, StreamExt};
+use std::fmt::{self, Display, Formatter};
 use std::future::Future;
 use std::pin::Pin;
 use std::io;
-use tokio::net::{TcpListener, ToSocketAddrs};
-use tokio::net::tcp::OwnedWriteHalf;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+
+// How long to spend telling a client we're too busy for them before giving up on it.
+const BUSY_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How long to wait before accepting again after an error, to begin with and at most.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+// How often to warn about errors accepting connections, while they keep happening.
+const ACCEPT_WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Limits on connections which haven't finished sending their request yet.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// How many connections can be waiting at once.
+    pub max_queued: usize,
+    /// What to do with new connections when `max_queued` are already waiting.
+    pub when_full: QueuePolicy,
+    pub max_selector_length: usize,
+    /// How long to wait for the entire request to arrive.
+    pub request_timeout: Duration,
+    /// How many connections each client address can have open, including ones being responded to.
+    pub max_per_ip: Option<usize>,
+    /// How often each client address can make requests.
+    pub rate_limit: Option<RateLimit>,
+    /// Which client addresses can connect at all.
+    pub access: AccessList,
+    /// What to tell clients that can't connect, if anything.
+    pub deny_message: Option<String>,
+    /// Whether connections start with a PROXY protocol header saying who the client is. This
+    /// doesn't apply to TLS connections.
+    pub proxy_protocol: bool,
+    /// Options to set on TCP connections as they're accepted.
+    pub socket: SocketOptions,
+    /// How to write the errors sent to clients that are turned away.
+    pub encoder: MenuItemEncoder,
+    /// Set `SO_REUSEPORT` on TCP listening sockets, so other processes can listen on the same
+    /// port and share its connections.
+    pub reuse_port: bool,
+}
+
+impl From<&Config> for Limits {
+    fn from(config: &Config) -> Self {
+        Self {
+            max_queued: config.max_queued_requests,
+            when_full: config.when_queue_full,
+            max_selector_length: config.max_selector_length,
+            request_timeout: Duration::from_secs(config.request_timeout_secs),
+            max_per_ip: config.max_connections_per_ip,
+            rate_limit: config.rate_limit,
+            access: AccessList {
+                allow: config.allow_from.clone(),
+                deny: config.deny_from.clone(),
+            },
+            deny_message: config.deny_message.clone(),
+            proxy_protocol: config.proxy_protocol,
+            socket: config.socket,
+            encoder: config.menu_encoder(),
+            reuse_port: config.reuse_port,
+        }
+    }
+}
+
+/// The write half of a client connection.
+pub type ClientWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// The read half of a client connection.
+pub type ClientReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Who a connection is from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// Unix socket clients don't have a useful address, so these are identified by the path of
+    /// the socket they connected to.
+    Unix(Arc<PathBuf>),
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{addr}"),
+            Peer::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A client connection whose request has been read.
+pub struct Connection {
+    pub tx: ClientWriter,
+    /// The read half, handed back once the request has been read from it. It's `None` if reading
+    /// timed out.
+    pub rx: Option<ClientReader>,
+    pub peer: Peer,
+    pub id: RequestId,
+    /// Span for logging everything to do with this connection. It has an empty `selector` field
+    /// to fill in once that's known.
+    pub span: Span,
+    _active: ActiveConnection,
+    _per_ip: Option<IpSlot>,
+}
+
+/// How many connections are open from each client address.
+#[derive(Clone, Default)]
+struct PerIp(Arc<Mutex<HashMap<IpAddr, usize>>>);
+
+impl PerIp {
+    /// Count a new connection from the address, unless it already has `max` of them.
+    fn acquire(&self, addr: IpAddr, max: usize) -> Option<IpSlot> {
+        let key = client_key(addr);
+        let mut counts = self.0.lock().unwrap();
+        let count = counts.entry(key).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(IpSlot { counts: self.clone(), key })
+    }
+}
+
+/// One connection's place in the per-address count, given back when it's dropped.
+struct IpSlot {
+    counts: PerIp,
+    key: IpAddr,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.0.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// The address connections are counted under. IPv6 clients typically get a whole /64 to pick
+/// addresses from, so that counts as one client.
+pub fn client_key(addr: IpAddr) -> IpAddr {
+    match addr.to_canonical() {
+        IpAddr::V4(v4) => IpAddr::V4(v4),
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & u128::MAX << 64)),
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    /// Connections get a TLS handshake before the request is read.
+    Tls(TcpListener, TlsAcceptor),
+    #[cfg(unix)]
+    Unix(UnixListener, Arc<PathBuf>),
+}
+
+type AcceptedConn = (ClientReader, ClientWriter, Peer);
+type Accepted = io::Result<AcceptedConn>;
+
+/// The connections accepted on one listener, tagged with its name, and whether they start with a
+/// PROXY protocol header.
+type AcceptStream = Pin<Box<dyn Stream<Item = (Accepted, Arc<str>, bool)> + Send>>;
+
+impl Listener {
+    /// A name for the listener, for logging.
+    fn name(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => "tcp".to_owned(),
+            },
+            Listener::Tls(listener, _) => match listener.local_addr() {
+                Ok(addr) => format!("tls:{addr}"),
+                Err(_) => "tls".to_owned(),
+            },
+            #[cfg(unix)]
+            Listener::Unix(_, path) => format!("unix:{}", path.display()),
+        }
+    }
+
+    /// Accept connections, waiting a while after errors that are likely to go away. Any other
+    /// error is passed along.
+    fn into_stream(self, limits: &Limits) -> AcceptStream {
+        let name = Arc::<str>::from(self.name());
+        let proxied = limits.proxy_protocol && !matches!(self, Listener::Tls(..));
+        let options = limits.socket;
+        let state = (self, AcceptBackoff::new(name.clone()));
+        Box::pin(stream::unfold(state, move |(listener, mut backoff)| {
+            let name = name.clone();
+            async move {
+                loop {
+                    let accepted = listener.accept(&options).await;
+                    let accepted = match accepted {
+                        Ok(conn) => {
+                            backoff.reset();
+                            Ok(conn)
+                        }
+                        Err(e) => match backoff.wait(e).await {
+                            Ok(()) => continue,
+                            Err(e) => Err(e),
+                        },
+                    };
+                    return Some(((accepted, name, proxied), (listener, backoff)));
+                }
+            }
+        }))
+    }
+
+    async fn accept(&self, options: &SocketOptions) -> Accepted {
+        match self {
+            Listener::Tcp(listener) => {
+                let (conn, addr) = listener.accept().await?;
+                set_options(&conn, options, addr);
+                let (rx, tx) = conn.into_split();
+                Ok((Box::new(rx), Box::new(tx), Peer::Tcp(addr)))
+            }
+            Listener::Tls(listener, acceptor) => {
+                let (conn, addr) = listener.accept().await?;
+                set_options(&conn, options, addr);
+                let (rx, tx) = tokio::io::split(TlsConnection::new(acceptor, conn));
+                Ok((Box::new(rx), Box::new(tx), Peer::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                let (conn, _) = listener.accept().await?;
+                let (rx, tx) = conn.into_split();
+                Ok((Box::new(rx), Box::new(tx), Peer::Unix(path.clone())))
+            }
+        }
+    }
+}
+
+/// Set options on a newly accepted TCP connection. It works without them, so failures are only
+/// logged.
+fn set_options(conn: &TcpStream, options: &SocketOptions, addr: SocketAddr) {
+    let socket = socket2::SockRef::from(conn);
+    if let Err(e) = socket.set_nodelay(options.nodelay) {
+        warn!("error setting TCP_NODELAY on connection from {addr}: {e}");
+    }
+    if options.keepalive_secs > 0 {
+        let keepalive = socket2::TcpKeepalive::new()
+            .with_time(Duration::from_secs(options.keepalive_secs));
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos",
+            target_os = "ios", target_os = "freebsd", target_os = "netbsd", windows))]
+        let keepalive = keepalive
+            .with_interval(Duration::from_secs(options.keepalive_interval_secs));
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos",
+            target_os = "ios", target_os = "freebsd", target_os = "netbsd"))]
+        let keepalive = match options.keepalive_retries {
+            Some(retries) => keepalive.with_retries(retries),
+            None => keepalive,
+        };
+        if let Err(e) = socket.set_tcp_keepalive(&keepalive) {
+            warn!("error setting keepalive on connection from {addr}: {e}");
+        }
+    }
+    if let Some(size) = options.send_buffer_size {
+        if let Err(e) = socket.set_send_buffer_size(size) {
+            warn!("error setting send buffer size on connection from {addr}: {e}");
+        }
+    }
+}
+
+/// Whether an error from accepting a connection is worth retrying. Running out of file
+/// descriptors or memory, or a client giving up before its connection was accepted, all go away
+/// on their own; a listening socket that's been closed or broken doesn't.
+fn is_transient(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if let Some(code) = e.raw_os_error() {
+        use nix::errno::Errno;
+        return !matches!(Errno::from_raw(code),
+            Errno::EBADF | Errno::ENOTSOCK | Errno::EINVAL | Errno::EOPNOTSUPP | Errno::EFAULT);
+    }
+    e.kind() != io::ErrorKind::InvalidInput
+}
+
+/// Paces retries after a listener fails to accept a connection, so that e.g. running out of file
+/// descriptors doesn't have us spinning on errors, and limits how often they're logged.
+struct AcceptBackoff {
+    listener: Arc<str>,
+    delay: Duration,
+    last_warning: Option<tokio::time::Instant>,
+    suppressed: u64,
+}
+
+impl AcceptBackoff {
+    fn new(listener: Arc<str>) -> Self {
+        Self { listener, delay: ACCEPT_BACKOFF_MIN, last_warning: None, suppressed: 0 }
+    }
+
+    /// Accepting worked, so go back to retrying quickly after the next error.
+    fn reset(&mut self) {
+        self.delay = ACCEPT_BACKOFF_MIN;
+    }
+
+    /// Wait before accepting again after an error, each time longer than the last, up to a
+    /// limit. Errors that won't go away by waiting are returned instead.
+    async fn wait(&mut self, e: io::Error) -> io::Result<()> {
+        let listener = &self.listener;
+        if !is_transient(&e) {
+            return Err(io::Error::new(e.kind(),
+                format!("error accepting connection on {listener}: {e}")));
+        }
+        let now = tokio::time::Instant::now();
+        if self.last_warning.is_some_and(|last| now - last < ACCEPT_WARN_INTERVAL) {
+            self.suppressed += 1;
+        } else {
+            let delay = self.delay;
+            match self.suppressed {
+                0 => warn!("error accepting connection on {listener}: {e}; retrying in {delay:?}"),
+                n => warn!("error accepting connection on {listener}: {e}; retrying in {delay:?} \
+                    ({n} more errors since the last warning)"),
+            }
+            self.last_warning = Some(now);
+            self.suppressed = 0;
+        }
+        tokio::time::sleep(self.delay).await;
+        self.delay = (self.delay * 2).min(ACCEPT_BACKOFF_MAX);
+        Ok(())
+    }
+}
 
 pub struct RequestStream {
-    listener: TcpListener,
+    /// Connections from all the listeners. These are polled round-robin, so a busy listener can't
+    /// starve the others.
+    listeners: SelectAll<AcceptStream>,
+
+    /// Addresses of the TCP listeners, in the order they were added.
+    local_addrs: Vec<SocketAddr>,
+
+    /// Connections whose PROXY protocol header is still being read.
+    proxied: BoundedFuturesUnordered<PendingHeader>,
+
+    pending: BoundedFuturesUnordered<PendingRequest>,
 
-    pending: BoundedFuturesUnordered<ReqWritePair>,
+    per_ip: PerIp,
+
+    rate_limiter: Option<RateLimiter>,
+
+    limits: Limits,
 }
 
 impl RequestStream {
-    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
-        Ok(Self {
-            listener: TcpListener::bind(addr).await?,
-            pending: BoundedFuturesUnordered::new(crate::MAX_QUEUED_REQUESTS),
-        })
+    /// A stream with nothing to listen on yet. Add at least one listener before asking it for
+    /// requests.
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            listeners: SelectAll::new(),
+            local_addrs: vec![],
+            proxied: BoundedFuturesUnordered::new(limits.max_queued),
+            pending: BoundedFuturesUnordered::with_strategy(limits.max_queued,
+                match limits.when_full {
+                    QueuePolicy::DropOldest => EvictionStrategy::DropOldest,
+                    QueuePolicy::DropNewest => EvictionStrategy::DropNewest,
+                    QueuePolicy::Wait => EvictionStrategy::Block,
+                }),
+            per_ip: PerIp::default(),
+            rate_limiter: limits.rate_limit.map(RateLimiter::new),
+            limits,
+        }
+    }
+
+    /// Also accept connections on the given TCP address.
+    pub async fn listen<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
+        self.add(Listener::Tcp(bind_any(addr, self.limits.reuse_port).await?))
+    }
+
+    /// Also accept TLS connections on the given TCP address.
+    pub async fn listen_tls<A: ToSocketAddrs>(&mut self, addr: A, acceptor: TlsAcceptor)
+        -> io::Result<()>
+    {
+        self.add(Listener::Tls(bind_any(addr, self.limits.reuse_port).await?, acceptor))
+    }
+
+    /// Also accept connections on the given port on all IPv4 and all IPv6 addresses, using a
+    /// separate socket for each.
+    pub async fn listen_both(&mut self, port: u16) -> io::Result<()> {
+        let reuse_port = self.limits.reuse_port;
+        let listener = bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)), false, reuse_port)?;
+        let port = listener.local_addr()?.port();
+        // Not also accepting IPv4 connections, which would conflict with the IPv4 socket on the
+        // same port on many systems.
+        let listener6 = bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)), true, reuse_port)?;
+        self.add(Listener::Tcp(listener))?;
+        self.add(Listener::Tcp(listener6))
+    }
+
+    /// Also accept connections on a Unix domain socket. If there's already a socket at the path,
+    /// it's assumed to be left over from a previous run, and replaced.
+    #[cfg(unix)]
+    pub async fn listen_unix(&mut self, path: &Path) -> io::Result<()> {
+        use std::os::unix::fs::FileTypeExt;
+        match tokio::fs::symlink_metadata(path).await {
+            Ok(meta) if meta.file_type().is_socket() => tokio::fs::remove_file(path).await?,
+            _ => (),
+        }
+        self.add(Listener::Unix(UnixListener::bind(path)?, Arc::new(path.to_owned())))
+    }
+
+    /// Also accept connections on an already-listening socket, e.g. one passed in by systemd. It
+    /// can be either a TCP or a Unix socket.
+    #[cfg(unix)]
+    pub fn listen_fd(&mut self, fd: std::os::unix::io::OwnedFd) -> io::Result<()> {
+        let socket = socket2::SockRef::from(&fd);
+        if socket.r#type()? != socket2::Type::STREAM {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a stream socket"));
+        }
+        let addr = socket.local_addr()?;
+        if addr.is_ipv4() || addr.is_ipv6() {
+            let listener = std::net::TcpListener::from(fd);
+            listener.set_nonblocking(true)?;
+            self.add(Listener::Tcp(TcpListener::from_std(listener)?))
+        } else if addr.is_unix() {
+            let path = addr.as_pathname().map(Path::to_owned).unwrap_or_default();
+            let listener = std::os::unix::net::UnixListener::from(fd);
+            listener.set_nonblocking(true)?;
+            self.add(Listener::Unix(UnixListener::from_std(listener)?, Arc::new(path)))
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "socket is neither TCP nor a Unix socket"))
+        }
+    }
+
+    fn add(&mut self, listener: Listener) -> io::Result<()> {
+        if let Listener::Tcp(tcp) | Listener::Tls(tcp, _) = &listener {
+            self.local_addrs.push(tcp.local_addr()?);
+        }
+        self.listeners.push(listener.into_stream(&self.limits));
+        Ok(())
+    }
+
+    /// The addresses of all the TCP sockets being listened on.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Stop accepting new connections, and close the listening sockets. Connections already
+    /// accepted can still be had from `next_queued`, except for ones that haven't sent their PROXY
+    /// protocol header yet.
+    pub fn stop_listening(&mut self) {
+        self.listeners = SelectAll::new();
+        self.proxied = BoundedFuturesUnordered::new(self.limits.max_queued);
+        self.local_addrs.clear();
+    }
+
+    /// The next connection to finish sending its request, out of ones already accepted, or `None`
+    /// if there aren't any left.
+    pub async fn next_queued(&mut self) -> Option<ReqWriteOutput> {
+        let output = self.pending.next().await?;
+        Some(self.check_rate(output))
     }
 
-    pub async fn next_request(&mut self) -> (Result<Request, RequestError>, OwnedWriteHalf) {
+    /// The next connection to finish sending its request. Fails if a listener can't accept
+    /// connections anymore.
+    pub async fn next_request(&mut self) -> io::Result<ReqWriteOutput> {
         loop {
             if self.pending.len() > 1 {
-                eprintln!("{} pending requests", self.pending.len());
+                debug!("{} pending requests", self.pending.len());
             }
             tokio::select! {
-                Some((req_result, tx)) = self.pending.next(), if !self.pending.is_empty() => {
-                    return (req_result, tx);
+                Some(output) = self.pending.next(), if !self.pending.is_empty() => {
+                    return Ok(self.check_rate(output));
                 }
-                accept_res = self.listener.accept() => {
-                    match accept_res {
-                        Ok((conn, remote_addr)) => {
-                            eprintln!("got connection from {remote_addr:?}");
-                            let (rx, tx) = conn.into_split();
-                            self.pending.push(Box::pin(
-                                RequestReader::with_max_length(1024, rx)
-                                    .read_request()
-                                    .map(move |req_result| (req_result, tx))));
-                        }
-                        Err(e) => {
-                            eprintln!("error accepting connection: {e}");
-                        }
+                Some((accept_res, listener, proxied)) = self.listeners.next() => {
+                    if let Some(output) = self.accepted(accept_res?, listener, proxied).await {
+                        return Ok(self.check_rate(output));
+                    }
+                }
+                Some((header_res, conn)) = self.proxied.next(), if !self.proxied.is_empty() => {
+                    if let Some(output) = self.got_header(header_res, conn).await {
+                        return Ok(self.check_rate(output));
                     }
                 }
             };
         }
     }
+
+    /// Turn a request away if its client has been making too many.
+    fn check_rate(&mut self, (result, conn): ReqWriteOutput) -> ReqWriteOutput {
+        if let (Ok(_), Some(limiter), Peer::Tcp(addr)) =
+            (&result, &mut self.rate_limiter, &conn.peer)
+        {
+            if !limiter.check(client_key(addr.ip())) {
+                return (Err(RequestError::RateLimited), conn);
+            }
+        }
+        (result, conn)
+    }
+
+    /// Start on a new connection. If it had to wait for room in the queue, returns the request
+    /// that made room.
+    async fn accepted(&mut self, (rx, tx, peer): AcceptedConn, listener: Arc<str>, proxied: bool)
+        -> Option<ReqWriteOutput>
+    {
+        let id = RequestId::new();
+        let span = info_span!("conn", request_id = %id, peer = field::Empty, %listener,
+            selector = field::Empty);
+        if proxied {
+            // Who the client is isn't known until the header has been read.
+            debug!(parent: &span, "got connection from proxy {peer}");
+            let timeout = self.limits.request_timeout;
+            let read = async move {
+                tokio::time::timeout(timeout, proxy_protocol::read_header(rx))
+                    .await
+                    .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut,
+                        "timed out waiting for PROXY protocol header").into()))
+                    .map(|(rx, addr)| (Box::new(rx) as ClientReader, addr))
+            };
+            // Nothing is sent to a connection evicted here, since it's the proxy and not the
+            // client that would get it.
+            self.proxied.push(PendingHeader {
+                read: Box::pin(read.instrument(span.clone())),
+                conn: Some((tx, peer, id, span)),
+            }).await;
+            None
+        } else {
+            span.record("peer", field::display(&peer));
+            debug!(parent: &span, "got connection");
+            self.admit(rx, tx, peer, id, span).await
+        }
+    }
+
+    async fn got_header(&mut self, header_res: HeaderResult, (tx, peer, id, span): HeaderConn)
+        -> Option<ReqWriteOutput>
+    {
+        match header_res {
+            Ok((rx, addr)) => {
+                let peer = addr.map(Peer::Tcp).unwrap_or(peer);
+                span.record("peer", field::display(&peer));
+                debug!(parent: &span, "got connection");
+                self.admit(rx, tx, peer, id, span).await
+            }
+            Err(e) => {
+                warn!(parent: &span, "bad PROXY protocol header from {peer}: {e}; \
+                    dropping connection");
+                None
+            }
+        }
+    }
+
+    /// Start reading the request from a new connection, if its client is allowed to make one.
+    /// If the queue is full and the policy is to wait, returns the request that made room for it.
+    async fn admit(&mut self, rx: ClientReader, tx: ClientWriter, peer: Peer, id: RequestId,
+        span: Span) -> Option<ReqWriteOutput>
+    {
+        if let Peer::Tcp(addr) = &peer {
+            if !self.limits.access.permits(addr.ip()) {
+                info!(parent: &span, "address not allowed; dropping connection");
+                if let Some(msg) = &self.limits.deny_message {
+                    reply_error(tx, msg.clone(), &self.limits.encoder, span);
+                }
+                return None;
+            }
+        }
+        let per_ip = match (&peer, self.limits.max_per_ip) {
+            (Peer::Tcp(addr), Some(max)) => match self.per_ip.acquire(addr.ip(), max) {
+                Some(slot) => Some(slot),
+                None => {
+                    warn!(parent: &span, "too many connections from this address; \
+                        dropping connection");
+                    reply_error(tx, "too many connections from your address".to_owned(),
+                        &self.limits.encoder, span);
+                    return None;
+                }
+            },
+            _ => None,
+        };
+        let mut reader = RequestReader::with_max_length(self.limits.max_selector_length, rx);
+        let timeout = self.limits.request_timeout;
+        let read = async move {
+            match tokio::time::timeout(timeout, reader.read_request()).await {
+                Ok(result) => (result, Some(reader.into_inner())),
+                Err(_) => (Err(RequestError::Timeout), None),
+            }
+        };
+        let pending = PendingRequest {
+            read: Box::pin(read.instrument(span.clone())),
+            conn: Some(Connection {
+                tx,
+                rx: None,
+                peer,
+                id,
+                span,
+                _active: ActiveConnection::new(),
+                _per_ip: per_ip,
+            }),
+        };
+        match self.pending.push(pending).await? {
+            Overflow::Evicted(dropped) | Overflow::Rejected(dropped) => {
+                dropped.reply_busy(&self.limits.encoder);
+                None
+            }
+            Overflow::Finished(output) => Some(output),
+        }
+    }
 }
 
-// The future result of reading the request, and the associated write half of the connection.
-type ReqWritePair = Pin<Box<dyn Future<Output=(Result<Request, RequestError>, OwnedWriteHalf)>>>;
+/// Bind to the first of the addresses `addr` resolves to that works, like `TcpListener::bind`.
+async fn bind_any<A: ToSocketAddrs>(addr: A, reuse_port: bool) -> io::Result<TcpListener> {
+    let mut last_err = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        match bind(addr, false, reuse_port) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+        "could not resolve to any address")))
+}
+
+/// Bind a TCP listening socket. With `v6_only`, an IPv6 socket doesn't also accept IPv4
+/// connections.
+fn bind(addr: SocketAddr, v6_only: bool, reuse_port: bool) -> io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if v6_only {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    {
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(reuse_port)?;
+    }
+    #[cfg(not(unix))]
+    if reuse_port {
+        return Err(io::Error::new(io::ErrorKind::Unsupported,
+            "reuse_port isn't supported on this platform"));
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+type HeaderResult = Result<(ClientReader, Option<SocketAddr>), proxy_protocol::ProxyHeaderError>;
+type HeaderConn = (ClientWriter, Peer, RequestId, Span);
+
+/// A connection waiting on its PROXY protocol header to be read. Resolves to the rest of the
+/// connection and the client address, along with everything else about the connection.
+struct PendingHeader {
+    read: Pin<Box<dyn Future<Output = HeaderResult> + Send>>,
+    conn: Option<HeaderConn>,
+}
+
+impl Future for PendingHeader {
+    type Output = (HeaderResult, HeaderConn);
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let result = ready!(self.read.as_mut().poll(ctx));
+        let conn = self.conn.take().expect("PendingHeader polled after completion");
+        Poll::Ready((result, conn))
+    }
+}
+
+/// The request, if it could be read, and the reader to give back to the connection.
+type ReadResult = (Result<Request, RequestError>, Option<ClientReader>);
+
+/// A connection waiting on its request to be read. Resolves to the request result, along with the
+/// connection.
+struct PendingRequest {
+    read: Pin<Box<dyn Future<Output = ReadResult> + Send>>,
+    conn: Option<Connection>,
+}
+
+impl PendingRequest {
+    /// Tell the client we dropped their connection because too many others were waiting.
+    fn reply_busy(mut self, encoder: &MenuItemEncoder) {
+        let Some(Connection { tx, span, .. }) = self.conn.take() else { return };
+        warn!(parent: &span, "too many pending requests; dropping connection");
+        reply_error(tx, "server busy, try again".to_owned(), encoder, span);
+    }
+}
+
+/// Send an error to a client whose connection we're dropping without reading its request.
+///
+/// This is best-effort: it happens in the background, and gives up after a short time so a
+/// stalled client can't hold anything up.
+fn reply_error(mut tx: ClientWriter, msg: String, encoder: &MenuItemEncoder, span: Span) {
+    let line = response::error_line(&msg, encoder);
+    tokio::spawn(async move {
+        match tokio::time::timeout(BUSY_WRITE_TIMEOUT, tx.write_all(&line)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => debug!("error writing {msg:?} response: {e}"),
+            Err(_) => debug!("timed out writing {msg:?} response"),
+        }
+    }.instrument(span));
+}
+
+impl Future for PendingRequest {
+    type Output = ReqWriteOutput;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let (req_result, rx) = ready!(self.read.as_mut().poll(ctx));
+        let mut conn = self.conn.take().expect("PendingRequest polled after completion");
+        conn.rx = rx;
+        Poll::Ready((req_result, conn))
+    }
+}
+
+type ReqWriteOutput = (Result<Request, RequestError>, Connection);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    fn limits(max_queued: usize, max_selector_length: usize) -> Limits {
+        Limits {
+            max_queued,
+            when_full: QueuePolicy::DropOldest,
+            max_selector_length,
+            request_timeout: Duration::from_secs(10),
+            max_per_ip: None,
+            rate_limit: None,
+            access: AccessList::default(),
+            deny_message: None,
+            proxy_protocol: false,
+            socket: SocketOptions::default(),
+            encoder: MenuItemEncoder::default(),
+            reuse_port: false,
+        }
+    }
+
+    async fn bind(limits: Limits) -> (RequestStream, SocketAddr) {
+        let mut stream = RequestStream::new(limits);
+        stream.listen("127.0.0.1:0").await.unwrap();
+        let addr = stream.local_addrs()[0];
+        (stream, addr)
+    }
+
+    #[tokio::test]
+    async fn overflow_replies_busy() {
+        let (mut stream, addr) = bind(limits(2, 1024)).await;
+
+        let clients = async {
+            let mut first = TcpStream::connect(addr).await.unwrap();
+            let _second = TcpStream::connect(addr).await.unwrap();
+            let _third = TcpStream::connect(addr).await.unwrap();
+            let mut response = String::new();
+            first.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        tokio::select! {
+            _ = stream.next_request() => panic!("no requests were sent"),
+            response = clients => {
+                assert_eq!(response, "3server busy, try again\terror\terror.host\t1\r\n.\r\n");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn overflow_drops_newest() {
+        let limits = Limits { when_full: QueuePolicy::DropNewest, ..limits(2, 1024) };
+        let (mut stream, addr) = bind(limits).await;
+
+        let clients = async {
+            let _first = TcpStream::connect(addr).await.unwrap();
+            let _second = TcpStream::connect(addr).await.unwrap();
+            let mut third = TcpStream::connect(addr).await.unwrap();
+            let mut response = String::new();
+            third.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        tokio::select! {
+            _ = stream.next_request() => panic!("no requests were sent"),
+            response = clients => {
+                assert_eq!(response, "3server busy, try again\terror\terror.host\t1\r\n.\r\n");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn full_queue_waits() {
+        let limits = Limits { when_full: QueuePolicy::Wait, ..limits(1, 1024) };
+        let (mut stream, addr) = bind(limits).await;
+
+        // The second connection has to wait for the first one to send its request, rather than
+        // pushing it out.
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        second.write_all(b"second\r\n").await.unwrap();
+        let send_first = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            first.write_all(b"first\r\n").await.unwrap();
+        };
+        let (next, ()) = tokio::join!(stream.next_request(), send_first);
+        match next.unwrap() {
+            (Ok(req), _) => assert_eq!(req.selector, "first"),
+            (other, _) => panic!("unexpected {other:?}"),
+        }
+        match stream.next_request().await.unwrap() {
+            (Ok(req), _) => assert_eq!(req.selector, "second"),
+            (other, _) => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn client_keys() {
+        let key = |addr: &str| client_key(addr.parse().unwrap()).to_string();
+        assert_eq!(key("192.0.2.1"), "192.0.2.1");
+        assert_eq!(key("::ffff:192.0.2.1"), "192.0.2.1");
+        assert_eq!(key("2001:db8:1:2:3:4:5:6"), "2001:db8:1:2::");
+        assert_eq!(key("2001:db8:1:2:ffff::1"), "2001:db8:1:2::");
+        assert_eq!(key("2001:db8:1:3::1"), "2001:db8:1:3::");
+    }
+
+    #[tokio::test]
+    async fn per_ip_limit() {
+        let limits = Limits { max_per_ip: Some(2), ..limits(10, 1024) };
+        let (mut stream, addr) = bind(limits).await;
+
+        let connect_from = |ip: &'static str| async move {
+            let socket = tokio::net::TcpSocket::new_v4().unwrap();
+            socket.bind(SocketAddr::new(ip.parse().unwrap(), 0)).unwrap();
+            socket.connect(addr).await.unwrap()
+        };
+
+        let mut first = connect_from("127.0.0.1").await;
+        let _second = connect_from("127.0.0.1").await;
+        let mut third = connect_from("127.0.0.1").await;
+
+        // The third connection from the same address is refused.
+        let mut response = String::new();
+        tokio::select! {
+            _ = stream.next_request() => panic!("no requests were sent"),
+            result = third.read_to_string(&mut response) => result.unwrap(),
+        };
+        assert_eq!(response,
+            "3too many connections from your address\terror\terror.host\t1\r\n.\r\n");
+
+        // Another address is unaffected.
+        let mut other = connect_from("127.0.0.2").await;
+        other.write_all(b"other\r\n").await.unwrap();
+        match stream.next_request().await.unwrap() {
+            (Ok(req), Connection { peer: Peer::Tcp(addr), .. }) => {
+                assert_eq!(req.selector, "other");
+                assert_eq!(addr.ip().to_string(), "127.0.0.2");
+            }
+            (other, _) => panic!("unexpected {other:?}"),
+        }
+
+        // Once a connection is done with, its slot is free for another one.
+        first.write_all(b"first\r\n").await.unwrap();
+        match stream.next_request().await.unwrap() {
+            (Ok(req), conn) => {
+                assert_eq!(req.selector, "first");
+                drop(conn);
+            }
+            (other, _) => panic!("unexpected {other:?}"),
+        }
+        let mut fourth = connect_from("127.0.0.1").await;
+        fourth.write_all(b"fourth\r\n").await.unwrap();
+        match stream.next_request().await.unwrap() {
+            (Ok(req), _) => assert_eq!(req.selector, "fourth"),
+            (other, _) => panic!("unexpected {other:?}"),
+        }
+        assert!(stream.per_ip.0.lock().unwrap().get(&"127.0.0.2".parse().unwrap()).is_none());
+    }
+
+    #[tokio::test]
+    async fn access_list() {
+        let limits = Limits {
+            access: AccessList { allow: vec![], deny: vec!["127.0.0.1/32".parse().unwrap()] },
+            deny_message: Some("go away".to_owned()),
+            ..limits(10, 1024)
+        };
+        let (mut stream, addr) = bind(limits).await;
+
+        let mut denied = TcpStream::connect(addr).await.unwrap();
+        let mut response = String::new();
+        tokio::select! {
+            _ = stream.next_request() => panic!("no requests were sent"),
+            result = denied.read_to_string(&mut response) => result.unwrap(),
+        };
+        assert_eq!(response, "3go away\terror\terror.host\t1\r\n.\r\n");
+
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let mut allowed = socket.connect(addr).await.unwrap();
+        allowed.write_all(b"sel\r\n").await.unwrap();
+        match stream.next_request().await.unwrap() {
+            (Ok(req), _) => assert_eq!(req.selector, "sel"),
+            (other, _) => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let limits = Limits {
+            rate_limit: Some(RateLimit { requests_per_minute: 1, burst: 2 }),
+            ..limits(10, 1024)
+        };
+        let (mut stream, addr) = bind(limits).await;
+        for expect_ok in [true, true, false] {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(b"sel\r\n").await.unwrap();
+            match stream.next_request().await.unwrap() {
+                (Ok(_), _) if expect_ok => (),
+                (Err(RequestError::RateLimited), _) if !expect_ok => (),
+                (other, _) => panic!("unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol() {
+        let limits = Limits {
+            proxy_protocol: true,
+            access: AccessList { allow: vec![], deny: vec!["192.0.2.66".parse().unwrap()] },
+            ..limits(10, 1024)
+        };
+        let (mut stream, addr) = bind(limits).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"PROXY TCP4 192.0.2.1 127.0.0.1 56324 70\r\nsel\r\n").await.unwrap();
+        match stream.next_request().await.unwrap() {
+            (Ok(req), conn) => {
+                assert_eq!(req.selector, "sel");
+                assert_eq!(conn.peer, Peer::Tcp("192.0.2.1:56324".parse().unwrap()));
+            }
+            (other, _) => panic!("unexpected {other:?}"),
+        }
+
+        // The access list goes by the address from the header, and so do connections without a
+        // header, which are dropped.
+        for request in [&b"PROXY TCP4 192.0.2.66 127.0.0.1 56324 70\r\nsel\r\n"[..], b"sel\r\n"] {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(request).await.unwrap();
+            let mut response = vec![];
+            tokio::select! {
+                _ = stream.next_request() => panic!("request should have been dropped"),
+                result = client.read_to_end(&mut response) => result.unwrap(),
+            };
+            assert!(response.is_empty());
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reuse_port() {
+        let shared = Limits { reuse_port: true, ..limits(2, 1024) };
+        let (_first, addr) = bind(shared.clone()).await;
+        let mut second = RequestStream::new(shared);
+        second.listen(addr).await.unwrap();
+        // Every socket on the port has to agree to share it.
+        let mut third = RequestStream::new(limits(2, 1024));
+        third.listen(addr).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (conn, peer) = listener.accept().await.unwrap();
+
+        let options = SocketOptions {
+            nodelay: true,
+            keepalive_secs: 120,
+            keepalive_interval_secs: 20,
+            keepalive_retries: Some(3),
+            send_buffer_size: Some(64 * 1024),
+        };
+        set_options(&conn, &options, peer);
+        let socket = socket2::SockRef::from(&conn);
+        assert!(socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(120));
+        #[cfg(target_os = "linux")]
+        assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(20));
+        #[cfg(target_os = "linux")]
+        assert_eq!(socket.keepalive_retries().unwrap(), 3);
+        // Linux doubles the size asked for, to allow for bookkeeping.
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+
+        let (conn, peer) = {
+            let _client = TcpStream::connect(addr).await.unwrap();
+            listener.accept().await.unwrap()
+        };
+        let options = SocketOptions { nodelay: false, keepalive_secs: 0, ..options };
+        set_options(&conn, &options, peer);
+        let socket = socket2::SockRef::from(&conn);
+        assert!(!socket.nodelay().unwrap());
+        assert!(!socket.keepalive().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn transient_accept_errors() {
+        use nix::errno::Errno;
+        let os_error = |errno: Errno| io::Error::from_raw_os_error(errno as i32);
+        assert!(is_transient(&os_error(Errno::EMFILE)));
+        assert!(is_transient(&os_error(Errno::ENFILE)));
+        assert!(is_transient(&os_error(Errno::ECONNABORTED)));
+        assert!(is_transient(&os_error(Errno::ENOBUFS)));
+        assert!(!is_transient(&os_error(Errno::EBADF)));
+        assert!(!is_transient(&os_error(Errno::EINVAL)));
+        assert!(is_transient(&io::Error::new(io::ErrorKind::ConnectionAborted, "aborted")));
+        assert!(!is_transient(&io::Error::new(io::ErrorKind::InvalidInput, "invalid")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn accept_backoff() {
+        let mut backoff = AcceptBackoff::new(Arc::from("test"));
+        let transient = || io::Error::new(io::ErrorKind::ConnectionAborted, "aborted");
+
+        // Each retry waits twice as long as the last, up to a limit.
+        let mut waited = vec![];
+        for _ in 0 .. 9 {
+            let start = tokio::time::Instant::now();
+            backoff.wait(transient()).await.unwrap();
+            waited.push(start.elapsed().as_millis());
+        }
+        assert_eq!(waited, [10, 20, 40, 80, 160, 320, 640, 1000, 1000]);
+
+        // Only the first of those was logged; the rest are counted until it's time to warn again.
+        assert_eq!(backoff.suppressed, 8);
+        tokio::time::advance(ACCEPT_WARN_INTERVAL).await;
+        backoff.wait(transient()).await.unwrap();
+        assert_eq!(backoff.suppressed, 0);
+
+        // Accepting a connection resets the wait.
+        backoff.reset();
+        let start = tokio::time::Instant::now();
+        backoff.wait(transient()).await.unwrap();
+        assert_eq!(start.elapsed(), ACCEPT_BACKOFF_MIN);
+
+        // Other errors are passed along straight away.
+        let start = tokio::time::Instant::now();
+        let e = backoff.wait(io::Error::new(io::ErrorKind::InvalidInput, "broken")).await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "error accepting connection on test: broken");
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn selector_too_long() {
+        let (mut stream, addr) = bind(limits(2, 4)).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"toolong\r\n").await.unwrap();
+
+        match stream.next_request().await.unwrap() {
+            (Err(RequestError::TooLong), _) => (),
+            (other, _) => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        let limits = Limits {
+            request_timeout: Duration::from_millis(100),
+            ..limits(2, 1024)
+        };
+        let (mut stream, addr) = bind(limits).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"partial sel").await.unwrap();
+
+        match stream.next_request().await.unwrap() {
+            (Err(RequestError::Timeout), _) => (),
+            (other, _) => panic!("unexpected {other:?}"),
+        }
+        assert!(stream.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn silent_client_disconnected() {
+        let limits = Limits {
+            request_timeout: Duration::from_millis(100),
+            ..limits(2, 1024)
+        };
+        let (mut stream, addr) = bind(limits).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        match stream.next_request().await.unwrap() {
+            (Err(RequestError::Timeout), conn) => drop(conn),
+            (other, _) => panic!("unexpected {other:?}"),
+        }
+
+        // The connection gets closed without any response.
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+    }
+
+    #[tokio::test]
+    async fn bind_both() {
+        let mut stream = RequestStream::new(limits(2, 1024));
+        stream.listen_both(0).await.unwrap();
+        let port = stream.local_addrs()[0].port();
+
+        for addr in [SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            SocketAddr::from((Ipv6Addr::LOCALHOST, port))]
+        {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(b"sel\r\n").await.unwrap();
+            match stream.next_request().await.unwrap() {
+                (Ok(req), Connection { peer: Peer::Tcp(remote_addr), .. }) => {
+                    assert_eq!(req.selector, "sel");
+                    assert_eq!(remote_addr.ip(), addr.ip());
+                }
+                (other, _) => panic!("unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gofer.sock");
+        // A stale socket gets replaced.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let mut stream = RequestStream::new(limits(2, 1024));
+        stream.listen_unix(&path).await.unwrap();
+
+        let mut client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        client.write_all(b"sel\r\n").await.unwrap();
+        match stream.next_request().await.unwrap() {
+            (Ok(req), mut conn) => {
+                assert_eq!(req.selector, "sel");
+                assert_eq!(conn.peer.to_string(), format!("unix:{}", path.display()));
+                conn.tx.write_all(b"hello").await.unwrap();
+            }
+            (other, _) => panic!("unexpected {other:?}"),
+        }
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "hello");
+    }
+
+    #[tokio::test]
+    async fn multiple_listeners() {
+        let mut stream = RequestStream::new(limits(4, 1024));
+        stream.listen("127.0.0.1:0").await.unwrap();
+        stream.listen("127.0.0.1:0").await.unwrap();
+        let addrs = stream.local_addrs().to_vec();
+        assert_eq!(addrs.len(), 2);
+
+        // Keep one listener busy; the other still gets its connections accepted.
+        let mut busy = vec![];
+        for _ in 0 .. 3 {
+            let mut client = TcpStream::connect(addrs[0]).await.unwrap();
+            client.write_all(b"first\r\n").await.unwrap();
+            busy.push(client);
+        }
+        let mut client = TcpStream::connect(addrs[1]).await.unwrap();
+        client.write_all(b"second\r\n").await.unwrap();
+
+        let mut selectors = vec![];
+        for _ in 0 .. 4 {
+            match stream.next_request().await.unwrap() {
+                (Ok(req), _) => selectors.push(req.selector),
+                (other, _) => panic!("unexpected {other:?}"),
+            }
+        }
+        selectors.sort();
+        assert_eq!(selectors, ["first", "first", "first", "second"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn inherited_sockets() {
+        use std::os::unix::io::OwnedFd;
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gofer.sock");
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let mut stream = RequestStream::new(limits(2, 1024));
+        stream.listen_fd(OwnedFd::from(tcp)).unwrap();
+        stream.listen_fd(OwnedFd::from(unix)).unwrap();
+        assert_eq!(stream.local_addrs(), [tcp_addr]);
+
+        let mut client = TcpStream::connect(tcp_addr).await.unwrap();
+        client.write_all(b"tcp\r\n").await.unwrap();
+        match stream.next_request().await.unwrap() {
+            (Ok(req), conn) => {
+                assert_eq!(req.selector, "tcp");
+                assert!(matches!(conn.peer, Peer::Tcp(_)));
+            }
+            (other, _) => panic!("unexpected {other:?}"),
+        }
+
+        let mut client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        client.write_all(b"unix\r\n").await.unwrap();
+        match stream.next_request().await.unwrap() {
+            (Ok(req), conn) => {
+                assert_eq!(req.selector, "unix");
+                assert_eq!(conn.peer.to_string(), format!("unix:{}", path.display()));
+            }
+            (other, _) => panic!("unexpected {other:?}"),
+        }
+
+        // Datagram sockets are no good.
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(stream.listen_fd(OwnedFd::from(udp)).is_err());
+    }
+}
@@ -1,31 +1,161 @@
-use crate::menu::{Menu, MenuItemEncoder};
+use crate::byte_counter::ByteCounter;
+use crate::idle_timeout::IdleTimeout;
+use crate::menu::{Menu, MenuItem, MenuItemEncoder};
+use crate::request_id::RequestId;
+use crate::stats;
+use crate::text::TextEncoder;
 use crate::types::ItemType;
+use bytes::{Bytes, BytesMut};
 use futures::sink::SinkExt;
-use futures::stream::StreamExt;
+use futures::stream::{self, StreamExt};
+use std::time::{Duration, SystemTime};
+use time::macros::format_description;
+use time::OffsetDateTime;
 use tokio::fs::File;
+use tokio::net::TcpStream;
 use tokio::io::{self, AsyncWrite, AsyncWriteExt};
-use tokio_util::codec::FramedWrite;
+use tokio_util::codec::{BytesCodec, Encoder, FramedRead, FramedWrite};
 
 pub enum Response {
     Menu(Menu),
+    /// A binary file, sent as-is.
     File(File),
+    /// A text file, which gets dot-stuffed and terminated, and optionally has its line endings
+    /// converted to CRLF.
+    TextFile { file: File, crlf: bool },
     Raw(Vec<u8>),
+    /// A menu pointing the client at a `gopher://` URL, as a menu, whatever type the URL gives.
+    Redirect(String),
+    /// Nothing at the selector. Written the same as an `Error("not found")`, but kept separate so
+    /// it can be logged as such, and replaced by the `!404` menu if there is one.
+    NotFound,
     Error(String),
+    /// Gopher+ attribute information about an item.
+    Attributes(Attributes),
+    /// Another server's response, passed on as-is. Gives up if the server stops sending for
+    /// longer than the timeout.
+    Upstream { stream: TcpStream, timeout: Duration },
+    /// A file from the cache, already encoded for sending.
+    Cached(Bytes),
 }
 
+/// The attributes of an item, for a Gopher+ `!` request.
+pub struct Attributes {
+    /// The item itself, as it would appear in a menu.
+    pub info: MenuItem,
+    /// The server administrator, as `Name <email>`, or whichever part of that is known.
+    pub admin: Option<String>,
+    pub modified: SystemTime,
+}
+
+impl Attributes {
+    /// The complete wire format: a header saying the data ends with a '.' line, then the `+INFO`
+    /// and `+ADMIN` blocks.
+    pub fn to_bytes(&self, encoder: &MenuItemEncoder) -> Vec<u8> {
+        let mut info = BytesMut::new();
+        encoder.clone().encode(self.info.clone(), &mut info).expect("encoding can't fail");
+        info.truncate(info.len() - 2);
+
+        let mut out = b"+-1\r\n+INFO: ".to_vec();
+        out.extend_from_slice(&info);
+        out.extend_from_slice(b"\t+\r\n+ADMIN:\r\n");
+        if let Some(admin) = &self.admin {
+            out.extend_from_slice(format!(" Admin: {admin}\r\n").as_bytes());
+        }
+        let mod_date = mod_date(self.modified);
+        out.extend_from_slice(format!(" Mod-Date: {mod_date}\r\n.\r\n").as_bytes());
+        out
+    }
+}
+
+/// A time the way Gopher+ wants it: `Wed Jul 28 17:02:01 1993 <19930728170201>`, in UTC.
+fn mod_date(time: SystemTime) -> String {
+    let time = OffsetDateTime::from(time);
+    let readable = format_description!("[weekday repr:short] [month repr:short] \
+        [day padding:space] [hour]:[minute]:[second] [year]");
+    let compact = format_description!("[year][month][day][hour][minute][second]");
+    format!("{} <{}>", time.format(readable).unwrap(), time.format(compact).unwrap())
+}
+
+/// What clients are told when the server isn't allowed to read what they asked for.
+pub const ACCESS_DENIED: &str = "access denied";
+
 impl From<io::Error> for Response {
     fn from(e: io::Error) -> Response {
-        eprintln!("I/O error: {e}");
-        // Don't leak details of the error to clients.
-        Response::Error("I/O error".to_owned())
+        match e.kind() {
+            io::ErrorKind::NotFound => return Response::NotFound,
+            io::ErrorKind::PermissionDenied => {
+                tracing::warn!("permission denied: {e}");
+                return Response::Error(ACCESS_DENIED.to_owned());
+            }
+            _ => (),
+        }
+        tracing::warn!("I/O error: {e}");
+        // Don't leak details of the error to clients, but give them something to report that can
+        // be matched up with the logs.
+        match RequestId::current() {
+            Some(id) => Response::Error(format!("I/O error (request {id})")),
+            None => Response::Error("I/O error".to_owned()),
+        }
     }
 }
 
 impl Response {
-    pub async fn write<W: AsyncWrite + Unpin>(&mut self, mut w: W) -> Result<(), io::Error> {
+    /// A short name for the kind of response, for logging.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Response::Menu(_) => "menu",
+            Response::File(_) => "file",
+            Response::TextFile { .. } => "text",
+            Response::Raw(_) => "raw",
+            Response::Redirect(_) => "redirect",
+            Response::NotFound => "not_found",
+            Response::Error(_) => "error",
+            Response::Attributes(_) => "attributes",
+            Response::Upstream { .. } => "upstream",
+            Response::Cached(_) => "cached",
+        }
+    }
+
+    /// Write the response and shut down the writer, giving up if the client stops reading for
+    /// longer than `idle`, or if the whole thing takes longer than `total`. Returns how many bytes
+    /// were written, whether or not it succeeded. Menu items are written with `encoder`.
+    ///
+    /// Shutting down matters for TLS, where it flushes the last of the data and tells the client
+    /// the response wasn't truncated.
+    pub async fn write_with_timeouts<W: AsyncWrite + Unpin>(
+        &mut self,
+        w: W,
+        encoder: &MenuItemEncoder,
+        idle: Duration,
+        total: Option<Duration>,
+    ) -> (u64, Result<(), io::Error>) {
+        let mut counted = ByteCounter::new(w);
+        let mut w = std::pin::pin!(IdleTimeout::new(&mut counted, idle));
+        let write = async {
+            self.write(w.as_mut(), encoder).await?;
+            w.shutdown().await
+        };
+        let result = match total {
+            Some(total) => tokio::time::timeout(total, write)
+                .await
+                .unwrap_or_else(|_| Err(io::Error::new(
+                    io::ErrorKind::TimedOut, "timed out writing response"))),
+            None => write.await,
+        };
+        stats::bytes_sent(counted.count());
+        (counted.count(), result)
+    }
+
+    /// Write the response, returning how many bytes were written. Menu items are written with
+    /// `encoder`.
+    pub async fn write<W: AsyncWrite + Unpin>(&mut self, w: W, encoder: &MenuItemEncoder)
+        -> Result<u64, io::Error>
+    {
+        let mut w = ByteCounter::new(w);
         match self {
             Response::Menu(menu) => {
-                FramedWrite::new(&mut w, MenuItemEncoder)
+                FramedWrite::new(&mut w, encoder.clone())
                     .send_all(&mut menu.items.by_ref().map(Ok))
                     .await?;
                 w.write_all(b".\r\n").await?;
@@ -33,15 +163,285 @@ impl Response {
             Response::File(f) => {
                 io::copy(f, &mut w).await?;
             }
+            Response::TextFile { file, crlf } => {
+                let mut framed = FramedWrite::new(&mut w, TextEncoder::new(*crlf));
+                framed.send_all(&mut FramedRead::new(file, BytesCodec::new())
+                        .map(|chunk| chunk.map(BytesMut::freeze)))
+                    .await?;
+                let mut end = BytesMut::new();
+                framed.encoder_mut().finish(&mut end);
+                w.write_all(&end).await?;
+            }
             Response::Raw(bytes) => {
                 io::copy(&mut std::io::Cursor::new(bytes), &mut w).await?;
             }
+            Response::Redirect(url) => {
+                let Some(link) = MenuItem::gopher_url(url.as_str(), url) else {
+                    w.write_all(&error_line("invalid redirect URL", encoder)).await?;
+                    return Ok(w.count());
+                };
+                let link = MenuItem { typ: ItemType::Directory, ..link };
+                let items = [MenuItem::info("This resource has moved to:"), link];
+                FramedWrite::new(&mut w, encoder.clone())
+                    .send_all(&mut stream::iter(items).map(Ok))
+                    .await?;
+                w.write_all(b".\r\n").await?;
+            }
+            Response::NotFound => {
+                w.write_all(&error_line("not found", encoder)).await?;
+            }
             Response::Error(msg) => {
-                w.write_all(&[ItemType::Error.into_u8()]).await?;
-                w.write_all(msg.as_bytes()).await?;
-                w.write_all(b"\terror\terror.host\t1\r\n.\r\n").await?;
+                w.write_all(&error_line(msg, encoder)).await?;
+            }
+            Response::Attributes(attributes) => {
+                w.write_all(&attributes.to_bytes(encoder)).await?;
+            }
+            Response::Upstream { stream, timeout } => {
+                let mut upstream = std::pin::pin!(IdleTimeout::new(stream, *timeout));
+                io::copy(&mut upstream, &mut w).await?;
+            }
+            Response::Cached(bytes) => {
+                w.write_all(bytes).await?;
+            }
+        }
+        Ok(w.count())
+    }
+}
+
+/// The complete wire format of an error response with the given message, with the host and port
+/// from `encoder`.
+pub fn error_line(msg: &str, encoder: &MenuItemEncoder) -> Vec<u8> {
+    let mut line = vec![ItemType::Error.into_u8()];
+    line.extend_from_slice(msg.as_bytes());
+    line.extend_from_slice(b"\terror\t");
+    line.extend_from_slice(encoder.info_host.as_bytes());
+    line.extend_from_slice(format!("\t{}\r\n.\r\n", encoder.info_port).as_bytes());
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    const IDLE: Duration = Duration::from_secs(10);
+
+    fn encoder() -> MenuItemEncoder {
+        MenuItemEncoder::default()
+    }
+
+    async fn file_response(len: usize) -> (tempfile::TempDir, Response) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big");
+        std::fs::write(&path, vec![b'x'; len]).unwrap();
+        let file = File::open(path).await.unwrap();
+        (dir, Response::File(file))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_reader() {
+        let (_dir, mut response) = file_response(1024 * 1024).await;
+        let (tx, _rx) = io::duplex(1024);
+        let (bytes, result) = response.write_with_timeouts(tx, &encoder(), IDLE, None).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!((1024 .. 1024 * 1024).contains(&bytes), "{bytes}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_reader() {
+        let len = 64 * 1024;
+        let (_dir, mut response) = file_response(len).await;
+        let (tx, mut rx) = io::duplex(1024);
+        let reader = tokio::spawn(async move {
+            let mut total = 0;
+            let mut buf = [0; 1024];
+            loop {
+                tokio::time::sleep(IDLE / 2).await;
+                match rx.read(&mut buf).await.unwrap() {
+                    0 => return total,
+                    n => total += n,
+                }
+            }
+        });
+
+        // Slow, but always within the idle timeout, so this is fine...
+        let (bytes, result) = response.write_with_timeouts(tx, &encoder(), IDLE, None).await;
+        result.unwrap();
+        assert_eq!(bytes, len as u64);
+        assert_eq!(reader.await.unwrap(), len);
+
+        // ...unless the whole thing takes too long.
+        let (_dir, mut response) = file_response(len).await;
+        let (tx, mut rx) = io::duplex(1024);
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            loop {
+                tokio::time::sleep(IDLE / 2).await;
+                if rx.read(&mut buf).await.unwrap() == 0 {
+                    break;
+                }
             }
+        });
+        let (_, result) = response.write_with_timeouts(tx, &encoder(), IDLE, Some(IDLE * 5)).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn client_disconnects() {
+        let (_dir, mut response) = file_response(1024 * 1024).await;
+        let (tx, mut rx) = io::duplex(1024);
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            rx.read_exact(&mut buf).await.unwrap();
+            // and then hang up
+        });
+        let (_, result) = response.write_with_timeouts(tx, &encoder(), IDLE, None).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn io_error_includes_request_id() {
+        let id = RequestId::new();
+        let err = || io::Error::other("secret details");
+        match id.scope(async { Response::from(err()) }).await {
+            Response::Error(msg) => assert_eq!(msg, format!("I/O error (request {id})")),
+            _ => panic!("expected an error response"),
+        }
+        match Response::from(err()) {
+            Response::Error(msg) => assert_eq!(msg, "I/O error"),
+            _ => panic!("expected an error response"),
         }
-        Ok(())
+        match Response::from(io::Error::from(io::ErrorKind::PermissionDenied)) {
+            Response::Error(msg) => assert_eq!(msg, "access denied"),
+            _ => panic!("expected an error response"),
+        }
+    }
+
+    #[test]
+    fn attributes() {
+        let attributes = Attributes {
+            info: MenuItem::new(ItemType::Directory, "Stuff", "/stuff", "example.com", "70"),
+            admin: Some("Gopher Admin <admin@example.com>".to_owned()),
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(743878921),
+        };
+        let bytes = attributes.to_bytes(&encoder());
+        assert_eq!(String::from_utf8(bytes).unwrap(), "+-1\r\n\
+            +INFO: 1Stuff\t/stuff\texample.com\t70\t+\r\n\
+            +ADMIN:\r\n \
+            Admin: Gopher Admin <admin@example.com>\r\n \
+            Mod-Date: Wed Jul 28 17:02:01 1993 <19930728170201>\r\n\
+            .\r\n");
+        assert_eq!(mod_date(SystemTime::UNIX_EPOCH + Duration::from_secs(742114921)),
+            "Thu Jul  8 07:02:01 1993 <19930708070201>");
+    }
+
+    #[tokio::test]
+    async fn not_found() {
+        let response = Response::from(io::Error::from(io::ErrorKind::NotFound));
+        assert!(matches!(response, Response::NotFound));
+        let mut out = vec![];
+        Response::NotFound.write(&mut out, &encoder()).await.unwrap();
+        assert_eq!(out, error_line("not found", &encoder()));
+    }
+
+    #[tokio::test]
+    async fn byte_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), "some text\n").unwrap();
+        let file = || File::open(dir.path().join("file"));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            conn.write_all(b"from upstream").await.unwrap();
+        });
+        let upstream = TcpStream::connect(addr).await.unwrap();
+        let attributes = || Attributes {
+            info: MenuItem::info("hi"),
+            admin: None,
+            modified: SystemTime::UNIX_EPOCH,
+        };
+
+        let responses = vec![
+            (Response::Menu(Menu::from_vec(vec![MenuItem::info("hi")])),
+                "ihi\t\terror.host\t1\r\n.\r\n".to_owned()),
+            (Response::File(file().await.unwrap()), "some text\n".to_owned()),
+            (Response::TextFile { file: file().await.unwrap(), crlf: true },
+                "some text\r\n.\r\n".to_owned()),
+            (Response::Raw(b"raw".to_vec()), "raw".to_owned()),
+            (Response::Redirect("gopher://example.com/0/x".to_owned()),
+                "iThis resource has moved to:\t\terror.host\t1\r\n\
+                1gopher://example.com/0/x\t/x\texample.com\t70\r\n.\r\n".to_owned()),
+            (Response::NotFound, "3not found\terror\terror.host\t1\r\n.\r\n".to_owned()),
+            (Response::Error("oops".to_owned()),
+                "3oops\terror\terror.host\t1\r\n.\r\n".to_owned()),
+            (Response::Attributes(attributes()),
+                String::from_utf8(attributes().to_bytes(&encoder())).unwrap()),
+            (Response::Upstream { stream: upstream, timeout: IDLE }, "from upstream".to_owned()),
+        ];
+        for (mut response, expected) in responses {
+            let (tx, mut rx) = io::duplex(1024);
+            let kind = response.kind();
+            let mut out = String::new();
+            let (written, read) = tokio::join!(
+                async move { response.write_with_timeouts(tx, &encoder(), IDLE, None).await },
+                rx.read_to_string(&mut out));
+            let (bytes, result) = written;
+            result.unwrap();
+            read.unwrap();
+            assert_eq!(out, expected, "{kind}");
+            assert_eq!(bytes, expected.len() as u64, "{kind}");
+        }
+    }
+
+    #[tokio::test]
+    async fn placeholders() {
+        let encoder = MenuItemEncoder { info_host: "fake".to_owned(), info_port: 70 };
+        let responses = [
+            (Response::Menu(Menu::from_vec(vec![MenuItem::info("hi")])),
+                "ihi\t\tfake\t70\r\n.\r\n"),
+            (Response::Redirect("gopher://example.com/".to_owned()),
+                "iThis resource has moved to:\t\tfake\t70\r\n\
+                1gopher://example.com/\t\texample.com\t70\r\n.\r\n"),
+            (Response::NotFound, "3not found\terror\tfake\t70\r\n.\r\n"),
+            (Response::Error("oops".to_owned()), "3oops\terror\tfake\t70\r\n.\r\n"),
+        ];
+        for (mut response, expected) in responses {
+            let mut out = vec![];
+            response.write(&mut out, &encoder).await.unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn large_text_file_streams() {
+        let lines = 1024 * 1024;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.txt");
+        std::fs::write(&path, "line\n".repeat(lines)).unwrap();
+        let file = File::open(path).await.unwrap();
+        let mut response = Response::TextFile { file, crlf: true };
+
+        let (tx, mut rx) = io::duplex(1024);
+        let encoder = encoder();
+        let mut write = std::pin::pin!(response.write(tx, &encoder));
+
+        // The writer can only get as far as the pipe and the codec's buffer allow, so it has to
+        // still be going once the reader gets its first bytes.
+        let mut start = [0; 12];
+        tokio::select! {
+            biased;
+            result = rx.read_exact(&mut start) => { result.unwrap(); }
+            _ = &mut write => panic!("whole response was buffered"),
+        }
+        assert_eq!(&start, b"line\r\nline\r\n");
+
+        let mut rest = Vec::new();
+        let (written, read) = tokio::join!(write, rx.read_to_end(&mut rest));
+        assert_eq!(written.unwrap(), lines as u64 * 6 + 3);
+        read.unwrap();
+        assert_eq!(start.len() + rest.len(), lines * 6 + 3);
+        assert!(rest.ends_with(b"line\r\n.\r\n"));
     }
 }
@@ -0,0 +1,103 @@
+use percent_encoding::{AsciiSet, CONTROLS};
+use std::ffi::{OsStr, OsString};
+
+/// Characters that get percent-encoded in a selector path segment, in addition to all non-ASCII.
+///
+/// Control characters (TAB, CR, LF in particular) would corrupt the menu line, spaces get mangled
+/// by some clients, '%' itself must be encoded so that decoding round-trips, and '?' starts a query
+/// string.
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'%')
+    .add(b'/')
+    .add(b'?');
+
+/// Encode a file name for use as one path segment of a selector.
+///
+/// On Unix the raw bytes of the name are encoded, so names that aren't valid UTF-8 still get a
+/// selector that maps back to the file.
+#[cfg(unix)]
+pub fn encode_segment(name: &OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    percent_encoding::percent_encode(name.as_bytes(), SEGMENT).to_string()
+}
+
+#[cfg(not(unix))]
+pub fn encode_segment(name: &OsStr) -> String {
+    percent_encoding::utf8_percent_encode(&name.to_string_lossy(), SEGMENT).to_string()
+}
+
+/// Decode a percent-encoded selector back into the path it refers to.
+#[cfg(unix)]
+pub fn decode(selector: &str) -> Result<OsString, std::str::Utf8Error> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(OsString::from_vec(percent_encoding::percent_decode_str(selector).collect()))
+}
+
+#[cfg(not(unix))]
+pub fn decode(selector: &str) -> Result<OsString, std::str::Utf8Error> {
+    percent_encoding::percent_decode_str(selector)
+        .decode_utf8()
+        .map(|s| OsString::from(s.into_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(name: &str) -> String {
+        let encoded = encode_segment(OsStr::new(name));
+        assert_eq!(decode(&encoded).unwrap(), name);
+        encoded
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(round_trip("notes.txt"), "notes.txt");
+    }
+
+    #[test]
+    fn spaces() {
+        assert_eq!(round_trip("my notes.txt"), "my%20notes.txt");
+    }
+
+    #[test]
+    fn tabs_and_newlines() {
+        assert_eq!(round_trip("a\tb\r\nc"), "a%09b%0D%0Ac");
+    }
+
+    #[test]
+    fn percent() {
+        assert_eq!(round_trip("100%"), "100%25");
+    }
+
+    #[test]
+    fn already_encoded() {
+        assert_eq!(round_trip("a%20b"), "a%2520b");
+    }
+
+    #[test]
+    fn non_ascii() {
+        assert_eq!(round_trip("café"), "caf%C3%A9");
+    }
+
+    #[test]
+    fn question_mark() {
+        assert_eq!(round_trip("what?"), "what%3F");
+    }
+
+    #[test]
+    fn slash() {
+        assert_eq!(round_trip("a/b"), "a%2Fb");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn invalid_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        let name = OsStr::from_bytes(b"bad\xffname");
+        let encoded = encode_segment(name);
+        assert_eq!(encoded, "bad%FFname");
+        assert_eq!(decode(&encoded).unwrap(), name);
+    }
+}
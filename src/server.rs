@@ -0,0 +1,772 @@
+//! Accepting connections, and handing their requests off to be answered, until it's time to shut
+//! down.
+
+use anyhow::{bail, Context, Result};
+use arc_swap::ArcSwap;
+use crate::access_log::{self, AccessLog};
+use crate::config::{self, BusyPolicy, Config};
+use crate::dir_cache::DirCache;
+use crate::file_cache::FileCache;
+use crate::fs::RealFileSystem;
+use crate::handler::handle_request;
+use crate::menu_cache::MenuCache;
+use crate::pid_file::PidFile;
+use crate::request::{Request, RequestError};
+use crate::request_stream::{ClientReader, Connection, Limits, RequestStream};
+use crate::response::Response;
+use crate::throttle::Throttle;
+use crate::{stats, tls};
+#[cfg(unix)]
+use crate::{privileges, systemd};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::AsyncReadExt;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn, Instrument, Span};
+
+/// Re-read the config file, and make it the one new requests use. Requests already in progress
+/// keep using the config they started with. If the new config is invalid, the old one stays.
+pub fn reload_config(path: &Path, config: &ArcSwap<Config>) -> Result<()> {
+    if config.load().chroot {
+        bail!("can't reload the config from inside the chroot; restart to change it");
+    }
+    let mut new_config = config::load(path)?;
+    for name in new_config.keep_startup_settings(&config.load()) {
+        warn!("{name} can't be changed without restarting; ignoring the new value");
+    }
+    config.store(Arc::new(new_config));
+    info!("reloaded config from {path:?}");
+    Ok(())
+}
+
+/// Reload the config file whenever we get a SIGHUP.
+#[cfg(unix)]
+pub fn reload_on_sighup(path: PathBuf, config: Arc<ArcSwap<Config>>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = signal(SignalKind::hangup())
+        .context("failed to install SIGHUP handler")?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            if let Err(e) = reload_config(&path, &config) {
+                error!("error reloading config; keeping the old one: {e:#}");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Bind to all the addresses in the config, or use the sockets systemd passed us if we were
+/// socket-activated. Failing to bind any of them is an error.
+pub async fn listen(config: &Config) -> Result<RequestStream> {
+    let mut incoming = RequestStream::new(Limits::from(config));
+    #[cfg(unix)]
+    let activated = match systemd::listen_fds() {
+        Ok(fds) if !fds.is_empty() => {
+            let count = fds.len();
+            for fd in fds {
+                incoming.listen_fd(fd).context("failed to use a socket passed by systemd")?;
+            }
+            info!("listening on {count} sockets passed by systemd, instead of server_address");
+            true
+        }
+        Ok(_) => false,
+        Err(e) => {
+            warn!("not using sockets passed by systemd: {e}");
+            false
+        }
+    };
+    #[cfg(not(unix))]
+    let activated = false;
+    if !activated {
+        for address in &config.server_address {
+            if let Some(path) = address.strip_prefix("unix:") {
+                #[cfg(unix)]
+                incoming.listen_unix(Path::new(path)).await
+                    .with_context(|| format!("failed to bind to Unix socket {path:?}"))?;
+                #[cfg(not(unix))]
+                bail!("Unix sockets aren't supported on this platform: {path:?}");
+                info!("listening for connections at {path:?}");
+            } else if config.bind_both {
+                let port = address.parse::<SocketAddr>()
+                    .with_context(|| format!("server_address {address:?} must be an IP address and \
+                        port to use bind_both"))?
+                    .port();
+                incoming.listen_both(port).await
+                    .with_context(|| format!("failed to bind to port {port}"))?;
+                info!("listening for connections on port {port}, IPv4 and IPv6");
+            } else {
+                incoming.listen(address.as_str()).await
+                    .with_context(|| format!("failed to bind to address {address}"))?;
+                // The address actually bound, in case the port was 0 and the system picked one.
+                let bound = incoming.local_addrs().last().expect("no address bound");
+                info!("listening for connections at {bound}");
+            }
+        }
+    }
+    if let Some(tls) = &config.tls {
+        let acceptor = tls::acceptor(&tls.cert_path, &tls.key_path)
+            .context("failed to set up TLS")?;
+        incoming.listen_tls(tls.address.as_str(), acceptor).await
+            .with_context(|| format!("failed to bind to TLS address {}", tls.address))?;
+        info!("listening for TLS connections at {}", tls.address);
+    }
+    Ok(incoming)
+}
+
+/// Bind, drop privileges, and serve requests forever.
+pub async fn run(mut config: Config, config_path: PathBuf) -> Result<()> {
+    let mut incoming = listen(&config).await?;
+
+    if let Some(addr) = &config.metrics_address {
+        stats::start(addr).await?;
+    }
+
+    // Opened before dropping privileges, and not affected by reloading the config.
+    let access_log = AccessLog::start(config.access_log.as_deref()).await
+        .with_context(|| format!("failed to open access log {:?}", config.access_log))?;
+
+    // Written before dropping privileges, so it can go somewhere like /run. It's removed once
+    // requests in progress have finished.
+    let pid_file = config.pid_file.as_deref().map(PidFile::create).transpose()?;
+
+    #[cfg(unix)]
+    {
+        let privileged = incoming.local_addrs().iter().any(|addr| addr.port() < 1024);
+        if privileged && config.user.is_none() {
+            warn!("listening on a privileged port without a user to switch to; \
+                the server will keep running with its current privileges");
+        }
+        // Looked up before chrooting, while the user database is still in reach.
+        let target = privileges::resolve(config.user.as_deref(), config.group.as_deref())
+            .context("failed to drop privileges")?;
+        if config.chroot {
+            let jail = config.document_root.clone();
+            config.chroot_paths()?;
+            privileges::chroot(&jail)?;
+        }
+        privileges::drop_privileges(target).context("failed to drop privileges")?;
+        if let Some(pid_file) = &pid_file {
+            pid_file.check_removable();
+        }
+    }
+
+    // Check now, rather than on the first request, that we can still read the files we serve.
+    let roots = std::iter::once(&config.document_root)
+        .chain(config.mounts.iter().map(|mount| &mount.document_root));
+    for root in roots {
+        std::fs::read_dir(root)
+            .with_context(|| format!("can't read document root {root:?}"))?;
+    }
+
+    let config = Arc::new(ArcSwap::from_pointee(config));
+    #[cfg(unix)]
+    reload_on_sighup(config_path, config.clone())?;
+    let mut signals = ShutdownSignals::new()?;
+
+    let menu_cache = MenuCache::from_config(&config.load());
+    let mut handlers = Handlers::new(config, access_log, menu_cache);
+    // If the listeners fail, still let requests in progress finish before exiting.
+    let accepting = accept_loop(&mut incoming, &mut handlers, signals.recv()).await;
+    if let Err(e) = &accepting {
+        error!("{e}");
+    }
+
+    let grace = Duration::from_secs(handlers.config.load().shutdown_grace_secs);
+    info!("shutting down; waiting up to {grace:?} for requests in progress");
+    tokio::select! {
+        result = tokio::time::timeout(grace, drain(incoming, &mut handlers)) => {
+            if result.is_err() {
+                warn!("requests still in progress after {grace:?}; exiting anyway");
+            }
+        }
+        () = signals.recv() => warn!("got a second signal; exiting immediately"),
+    }
+    drop(pid_file);
+    accepting.context("can't accept connections")
+}
+
+/// SIGINT, and on Unix SIGTERM, which ask the server to shut down.
+struct ShutdownSignals {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl ShutdownSignals {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            terminate: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .context("failed to install SIGTERM handler")?,
+        })
+    }
+
+    /// Wait for the next signal.
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = self.terminate.recv() => (),
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// What request handlers share, and the tasks they're running in.
+pub struct Handlers {
+    config: Arc<ArcSwap<Config>>,
+    access_log: AccessLog,
+    menu_cache: MenuCache,
+    file_cache: FileCache,
+    dir_cache: DirCache,
+    tasks: JoinSet<()>,
+    /// Permits for responses in progress, if they're limited.
+    responses: Option<Arc<Semaphore>>,
+    when_busy: BusyPolicy,
+}
+
+impl Handlers {
+    fn new(config: Arc<ArcSwap<Config>>, access_log: AccessLog, menu_cache: MenuCache) -> Self {
+        let (responses, when_busy) = {
+            let config = config.load();
+            (config.max_concurrent_responses.map(|max| Arc::new(Semaphore::new(max))),
+                config.when_busy)
+        };
+        let file_cache = FileCache::from_config(&config.load());
+        let dir_cache = DirCache::from_config(&config.load());
+        Self { config, access_log, menu_cache, file_cache, dir_cache, tasks: JoinSet::new(),
+            responses, when_busy }
+    }
+
+    /// Wait for room to handle another request, if the server waits for that when it's busy.
+    /// Otherwise, there's no waiting, and no permit.
+    fn reserve(&self) -> impl Future<Output = Option<OwnedSemaphorePermit>> {
+        let responses = match (&self.responses, self.when_busy) {
+            (Some(responses), BusyPolicy::Wait) => Some(responses.clone()),
+            _ => None,
+        };
+        async move {
+            match responses {
+                Some(responses) => Some(responses.acquire_owned().await.expect("semaphore closed")),
+                None => None,
+            }
+        }
+    }
+
+    /// Handle a request in a new task, using the permit from `reserve`. If the server doesn't
+    /// wait when it's busy, the client is told so instead.
+    fn spawn(&mut self, mut req: Result<Request, RequestError>, conn: Connection,
+        mut permit: Option<OwnedSemaphorePermit>)
+    {
+        if let (Some(responses), None) = (&self.responses, &permit) {
+            match responses.clone().try_acquire_owned() {
+                Ok(acquired) => permit = Some(acquired),
+                Err(_) => req = Err(RequestError::Busy),
+            }
+        }
+        let (id, span) = (conn.id, conn.span.clone());
+        let (config, access_log) = (self.config.clone(), self.access_log.clone());
+        let (menu_cache, file_cache) = (self.menu_cache.clone(), self.file_cache.clone());
+        let dir_cache = self.dir_cache.clone();
+        self.tasks.spawn(id.scope(async move {
+            let in_flight = stats::ResponseInFlight::new();
+            let rx = serve(&config, &access_log, &menu_cache, &file_cache, &dir_cache, req, conn)
+                .await;
+            // The response is done, so it makes room for others while the client hangs up.
+            drop((permit, in_flight));
+            if let Some(rx) = rx {
+                drain_reader(rx).await;
+            }
+        }).instrument(span));
+    }
+}
+
+/// Take requests as they come in, and handle each one in its own task, until `shutdown`
+/// completes, or a listener fails.
+pub async fn accept_loop(
+    incoming: &mut RequestStream,
+    handlers: &mut Handlers,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let mut shutdown = pin!(shutdown);
+    // Room for the next request is reserved before taking it, so that if there isn't any, the
+    // server waits without accepting more connections.
+    let mut reserved = None;
+    loop {
+        tokio::select! {
+            permit = handlers.reserve(), if reserved.is_none() => reserved = Some(permit),
+            next = incoming.next_request(), if reserved.is_some() => {
+                let (req, conn) = next?;
+                handlers.spawn(req, conn, reserved.take().unwrap());
+                // Clean up after finished tasks as we go. This isn't a branch of its own, since
+                // that would cancel taking the next request, which can be holding on to a
+                // connection while it waits for room in the queue.
+                while let Some(result) = handlers.tasks.try_join_next() {
+                    if let Err(e) = result {
+                        error!("request handler failed: {e}");
+                    }
+                }
+            }
+            () = &mut shutdown => return Ok(()),
+        }
+    }
+}
+
+/// Stop accepting connections, and finish handling the ones already accepted.
+pub async fn drain(mut incoming: RequestStream, handlers: &mut Handlers) {
+    incoming.stop_listening();
+    loop {
+        let permit = handlers.reserve().await;
+        let Some((req, conn)) = incoming.next_queued().await else { break };
+        handlers.spawn(req, conn, permit);
+    }
+    while let Some(result) = handlers.tasks.join_next().await {
+        if let Err(e) = result {
+            error!("request handler failed: {e}");
+        }
+    }
+}
+
+/// Respond to one request, and log it. The config as it is when this starts is used for all of it,
+/// even if it's reloaded part way through. The connection stops counting against the limits on
+/// connections once the response is written, and the read half is given back, to drain with
+/// [`drain_reader`] before closing it, if it was written in full.
+pub async fn serve(
+    config: &ArcSwap<Config>,
+    access_log: &AccessLog,
+    menu_cache: &MenuCache,
+    file_cache: &FileCache,
+    dir_cache: &DirCache,
+    req: Result<Request, RequestError>,
+    conn: Connection,
+) -> Option<ClientReader> {
+    let Connection { tx, rx, peer, .. } = conn;
+    let config = config.load_full();
+    let start = Instant::now();
+    let mut entry = access_log::Entry {
+        time: SystemTime::now(),
+        peer,
+        selector: None,
+        kind: "none",
+        bytes: 0,
+        duration: Duration::ZERO,
+        error: None,
+    };
+    let mut response = match req {
+        Ok(req) => {
+            Span::current().record("selector", req.selector.as_str());
+            info!("got request");
+            entry.selector = Some(req.selector.clone());
+            let response = handle_request(&config, &RealFileSystem, menu_cache, file_cache,
+                dir_cache, Some(&entry.peer), req).await;
+            match &response {
+                Response::NotFound => info!("not found"),
+                Response::Error(msg) => warn!("responding with error: {msg}"),
+                _ => (),
+            }
+            response
+        }
+        Err(RequestError::Timeout) => {
+            // The client is probably gone; don't bother trying to respond.
+            info!("timed out waiting for request");
+            entry.error = Some(RequestError::Timeout.to_string());
+            entry.duration = start.elapsed();
+            stats::request_finished(false, entry.duration);
+            access_log.log(entry);
+            return None;
+        }
+        Err(RequestError::RateLimited) => {
+            info!("too many requests from this client");
+            entry.error = Some(RequestError::RateLimited.to_string());
+            Response::Error("slow down".into())
+        }
+        Err(RequestError::Busy) => {
+            warn!("too many responses in progress");
+            entry.error = Some(RequestError::Busy.to_string());
+            Response::Error("server busy, try again".into())
+        }
+        Err(e) => {
+            info!("bad request: {e:?}");
+            entry.error = Some(e.to_string());
+            Response::Error(format!("Bad request: {e:?}"))
+        }
+    };
+    let tx = match config.max_bytes_per_sec {
+        Some(rate) => Box::new(Throttle::new(tx, rate)),
+        None => tx,
+    };
+    let encoder = config.menu_encoder();
+    let write = response.write_with_timeouts(
+        tx,
+        &encoder,
+        Duration::from_secs(config.response_idle_timeout_secs),
+        config.response_timeout_secs.map(Duration::from_secs));
+    let (bytes, result) = write.await;
+    let written = result.is_ok();
+    if let Err(e) = result {
+        info!("error writing response: {e}");
+        entry.error = Some(e.to_string());
+    }
+    entry.kind = response.kind();
+    entry.bytes = bytes;
+    entry.duration = start.elapsed();
+    debug!("sent {bytes} bytes in {} ms", entry.duration.as_millis());
+    let ok = entry.error.is_none() && !matches!(response, Response::Error(_) | Response::NotFound);
+    stats::request_finished(ok, entry.duration);
+    access_log.log(entry);
+    if written { rx } else { None }
+}
+
+/// How long to wait for the client to close its end of the connection after its response.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Read and throw away whatever the client sends after its request, until it closes the
+/// connection, or for at most `DRAIN_TIMEOUT`.
+///
+/// The write half has already been shut down by now. Closing the socket with unread data still in
+/// it makes the system reset the connection, and the client can lose the end of the response that
+/// it hasn't read yet.
+async fn drain_reader(mut rx: ClientReader) {
+    let mut buf = [0; 1024];
+    let drain = async {
+        while rx.read(&mut buf).await? != 0 {}
+        Ok::<_, io::Error>(())
+    };
+    match tokio::time::timeout(DRAIN_TIMEOUT, drain).await {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => debug!("error draining connection: {e}"),
+        Err(_) => debug!("timed out draining connection"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handler::test::{fetch_menu, test_config};
+    use futures::future;
+
+    #[tokio::test]
+    async fn reload() {
+        use tokio::io::AsyncReadExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        for i in 0 .. 100 {
+            std::fs::write(root.join(format!("file{i}")), "").unwrap();
+        }
+        let path = dir.path().join("config.toml");
+        let write_config = |address: &str, hostname: &str| {
+            std::fs::write(&path, format!(r#"
+                server_address = "{address}"
+                document_root = {root:?}
+                hostname = "{hostname}"
+                port = 7070
+                "#)).unwrap();
+        };
+        write_config("127.0.0.1:7070", "old.example");
+        let config = ArcSwap::from_pointee(config::load(&path).unwrap());
+
+        // Start writing a menu, but only let part of it through before reloading.
+        let menu_cache = MenuCache::new(0, Duration::ZERO);
+        let mut old_response = handle_request(&config.load_full(), &RealFileSystem, &menu_cache,
+            &FileCache::default(), &DirCache::default(), None, Request {
+            selector: String::new(),
+            attributes: false,
+        }).await;
+        let (tx, mut rx) = tokio::io::duplex(64);
+        let encoder = config.load().menu_encoder();
+        let old_transfer = async { old_response.write(tx, &encoder).await.unwrap() };
+        let reload_and_read = async {
+            let mut out = vec![0; 64];
+            rx.read_exact(&mut out).await.unwrap();
+
+            write_config("127.0.0.1:7071", "new.example");
+            reload_config(&path, &config).unwrap();
+            assert_eq!(fetch_menu(&config.load_full(), "").await[0], "i[new.example]");
+            // This can't change without rebinding.
+            assert_eq!(config.load().server_address, ["127.0.0.1:7070"]);
+
+            // A broken config doesn't replace a working one.
+            std::fs::write(&path, "hostname = ").unwrap();
+            reload_config(&path, &config).unwrap_err();
+            assert_eq!(config.load().hostname, "new.example");
+
+            rx.read_to_end(&mut out).await.unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let (_, old_menu) = tokio::join!(old_transfer, reload_and_read);
+        assert!(old_menu.starts_with("i[old.example]"));
+        assert_eq!(old_menu.matches("\told.example\t").count(), 100);
+        assert!(!old_menu.contains("new.example"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn end_to_end() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0 .. 10 {
+            std::fs::write(dir.path().join(format!("file{i}.txt")), format!("contents {i}\n"))
+                .unwrap();
+        }
+        let config = test_config(dir.path());
+        let mut incoming = RequestStream::new(Limits::from(&*config));
+        incoming.listen("127.0.0.1:0").await.unwrap();
+        let addr = incoming.local_addrs()[0];
+        let access_log = AccessLog::start(Some(&dir.path().join("access.log"))).await.unwrap();
+        let mut handlers = Handlers::new(Arc::new(ArcSwap::new(config)), access_log,
+            MenuCache::new(10, Duration::ZERO));
+        tokio::spawn(async move {
+            accept_loop(&mut incoming, &mut handlers, future::pending()).await.unwrap();
+        });
+
+        let clients = (0 .. 10).map(|i| tokio::spawn(async move {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client.write_all(format!("/file{i}.txt\r\n").as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert_eq!(response, format!("contents {i}\n.\r\n"));
+        }));
+        for client in clients {
+            client.await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn graceful_shutdown() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpStream;
+
+        let len = 32 * 1024 * 1024;
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("big.bin"), vec![b'x'; len]).unwrap();
+        let config = test_config(dir.path());
+        let mut incoming = RequestStream::new(Limits::from(&*config));
+        incoming.listen("127.0.0.1:0").await.unwrap();
+        let addr = incoming.local_addrs()[0];
+        let access_log = AccessLog::start(Some(&dir.path().join("access.log"))).await.unwrap();
+        let mut handlers = Handlers::new(Arc::new(ArcSwap::new(config)), access_log,
+            MenuCache::new(0, Duration::ZERO));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            accept_loop(&mut incoming, &mut handlers, async { stopped.await.unwrap() }).await
+                .unwrap();
+            drain(incoming, &mut handlers).await;
+        });
+
+        // Start a transfer, but only take a little of it before shutting down.
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"/big.bin\r\n").await.unwrap();
+        let mut start = vec![0; 1024];
+        client.read_exact(&mut start).await.unwrap();
+        stop.send(()).unwrap();
+
+        // New connections get refused once the listener is closed...
+        let mut refused = false;
+        for _ in 0 .. 100 {
+            if TcpStream::connect(addr).await.is_err() {
+                refused = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(refused, "still accepting connections");
+
+        // ...but the transfer in progress gets to finish, and the server waits for it.
+        assert!(!server.is_finished());
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(start.len() + rest.len(), len);
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_response_limit() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpStream;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("big.bin"), vec![b'x'; 32 * 1024 * 1024]).unwrap();
+        std::fs::write(dir.path().join("small.txt"), "small\n").unwrap();
+
+        for when_busy in [BusyPolicy::Reject, BusyPolicy::Wait] {
+            let mut config = (*test_config(dir.path())).clone();
+            config.max_concurrent_responses = Some(1);
+            config.when_busy = when_busy;
+            let mut incoming = RequestStream::new(Limits::from(&config));
+            incoming.listen("127.0.0.1:0").await.unwrap();
+            let addr = incoming.local_addrs()[0];
+            let access_log = AccessLog::start(Some(&dir.path().join("access.log"))).await.unwrap();
+            let mut handlers = Handlers::new(Arc::new(ArcSwap::from_pointee(config)), access_log,
+                MenuCache::new(0, Duration::ZERO));
+            let server = tokio::spawn(async move {
+                accept_loop(&mut incoming, &mut handlers, future::pending()).await.unwrap();
+            });
+
+            // A slow client takes up the only slot, by not reading most of its response.
+            let mut slow = TcpStream::connect(addr).await.unwrap();
+            slow.write_all(b"/big.bin\r\n").await.unwrap();
+            let mut start = vec![0; 1024];
+            slow.read_exact(&mut start).await.unwrap();
+
+            let mut other = TcpStream::connect(addr).await.unwrap();
+            other.write_all(b"/small.txt\r\n").await.unwrap();
+            let mut response = String::new();
+            match when_busy {
+                BusyPolicy::Reject => {
+                    other.read_to_string(&mut response).await.unwrap();
+                    assert_eq!(response,
+                        "3server busy, try again\terror\terror.host\t1\r\n.\r\n");
+                }
+                BusyPolicy::Wait => {
+                    let waiting = tokio::time::timeout(Duration::from_millis(200),
+                        other.read_to_string(&mut response)).await;
+                    assert!(waiting.is_err(), "got a response: {response:?}");
+                    // Once the slow client goes away, the other one gets its turn.
+                    drop(slow);
+                    other.read_to_string(&mut response).await.unwrap();
+                    assert_eq!(response, "small\n.\r\n");
+                }
+            }
+            server.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_released_before_draining() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpStream;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("small.txt"), "small\n").unwrap();
+        let mut config = (*test_config(dir.path())).clone();
+        config.max_concurrent_responses = Some(1);
+        config.when_busy = BusyPolicy::Reject;
+        config.max_connections_per_ip = Some(1);
+        let mut incoming = RequestStream::new(Limits::from(&config));
+        incoming.listen("127.0.0.1:0").await.unwrap();
+        let addr = incoming.local_addrs()[0];
+        let access_log = AccessLog::start(Some(&dir.path().join("access.log"))).await.unwrap();
+        let mut handlers = Handlers::new(Arc::new(ArcSwap::from_pointee(config)), access_log,
+            MenuCache::new(0, Duration::ZERO));
+        let server = tokio::spawn(async move {
+            accept_loop(&mut incoming, &mut handlers, future::pending()).await.unwrap();
+        });
+
+        // Clients that have their whole response, but haven't hung up yet, while the server
+        // waits for them to, don't hold up anyone else.
+        let mut lingering = vec![];
+        for _ in 0 .. 3 {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(b"/small.txt\r\n").await.unwrap();
+            let mut response = String::new();
+            tokio::time::timeout(DRAIN_TIMEOUT / 2, client.read_to_string(&mut response))
+                .await.expect("timed out waiting for a response").unwrap();
+            assert_eq!(response, "small\n.\r\n");
+            lingering.push(client);
+        }
+        server.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn slow_reader_gets_whole_response() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpStream;
+
+        let dir = tempfile::tempdir().unwrap();
+        let data = (0 .. 8 * 1024 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        std::fs::write(dir.path().join("big.bin"), &data).unwrap();
+        let config = test_config(dir.path());
+        let mut incoming = RequestStream::new(Limits::from(&*config));
+        incoming.listen("127.0.0.1:0").await.unwrap();
+        let addr = incoming.local_addrs()[0];
+        let access_log = AccessLog::start(Some(&dir.path().join("access.log"))).await.unwrap();
+        let mut handlers = Handlers::new(Arc::new(ArcSwap::new(config)), access_log,
+            MenuCache::new(0, Duration::ZERO));
+        let server = tokio::spawn(async move {
+            accept_loop(&mut incoming, &mut handlers, future::pending()).await.unwrap();
+        });
+
+        // Extra bytes sent after the request has been read are left unread by the server, and the
+        // client doesn't get around to reading most of the response until it's all been written.
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"/big.bin\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client.write_all(b"some trailing junk\r\n").await.unwrap();
+        let mut received = vec![];
+        let mut buf = vec![0; 256 * 1024];
+        loop {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            let n = client.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert!(received == data, "got {} of {} bytes", received.len(), data.len());
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn tls() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::rustls::{self, ClientConfig, RootCertStore};
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("secret.txt"), "").unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, format!(r#"
+            server_address = "127.0.0.1:0"
+            document_root = {root:?}
+            hostname = "localhost"
+            [tls]
+            address = "127.0.0.1:0"
+            cert_path = {cert_path:?}
+            key_path = {key_path:?}
+            "#)).unwrap();
+        let config = ArcSwap::from_pointee(config::load(&path).unwrap());
+        let mut incoming = listen(&config.load()).await.unwrap();
+        let tls_addr = incoming.local_addrs()[1];
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let client_config = ClientConfig::builder_with_provider(
+                Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let client = async {
+            let tcp = tokio::net::TcpStream::connect(tls_addr).await.unwrap();
+            let mut tls = tokio_rustls::TlsConnector::from(Arc::new(client_config))
+                .connect("localhost".try_into().unwrap(), tcp).await.unwrap();
+            tls.write_all(b"\r\n").await.unwrap();
+            let mut response = String::new();
+            tls.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let server = async {
+            let (req, conn) = incoming.next_request().await.unwrap();
+            assert!(req.is_ok());
+            let access_log = AccessLog::start(Some(&dir.path().join("access.log"))).await.unwrap();
+            serve(&config, &access_log, &MenuCache::new(0, Duration::ZERO), &FileCache::default(),
+                &DirCache::default(), req, conn).await;
+        };
+        let (response, ()) = tokio::join!(client, server);
+        assert!(response.contains("0secret.txt\t/secret.txt\t"), "{response}");
+        assert!(response.ends_with(".\r\n"));
+    }
+}
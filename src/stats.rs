@@ -0,0 +1,180 @@
+//! Server metrics, exposed in Prometheus format over HTTP on a separate port.
+
+use anyhow::{Context, Result};
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+/// How long a metrics client gets to send its request.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest HTTP request from a metrics client to bother reading.
+const MAX_HTTP_REQUEST: usize = 8192;
+
+const DURATION_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1., 5., 10., 60.];
+
+/// Start recording metrics, and serve them at `http://{addr}/metrics`.
+pub async fn start(addr: &str) -> Result<()> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full("gofer_request_duration_seconds".into()),
+            DURATION_BUCKETS)?
+        .install_recorder()?;
+    let local_addr = serve(addr, handle).await
+        .with_context(|| format!("failed to bind metrics server to {addr}"))?;
+    info!("serving metrics at http://{local_addr}/metrics");
+    Ok(())
+}
+
+/// Serve the metrics in the background, returning the address they're served from.
+async fn serve(addr: &str, handle: PrometheusHandle) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((conn, _)) => {
+                    let handle = handle.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = respond(conn, &handle).await {
+                            debug!("error serving metrics: {e}");
+                        }
+                    });
+                }
+                Err(e) => warn!("error accepting metrics connection: {e}"),
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+/// Answer one HTTP request. Only `GET /metrics` is supported.
+async fn respond(mut conn: TcpStream, handle: &PrometheusHandle) -> std::io::Result<()> {
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    let read_headers = async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_HTTP_REQUEST {
+            match conn.read(&mut buf).await? {
+                0 => break,
+                n => request.extend_from_slice(&buf[.. n]),
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    tokio::time::timeout(HTTP_TIMEOUT, read_headers).await??;
+
+    let response = if request.starts_with(b"GET /metrics ") {
+        handle.run_upkeep();
+        let body = handle.render();
+        format!("HTTP/1.1 200 OK\r\n\
+            Content-Type: text/plain; version=0.0.4\r\n\
+            Content-Length: {}\r\n\
+            Connection: close\r\n\
+            \r\n\
+            {body}", body.len())
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+    };
+    conn.write_all(response.as_bytes()).await?;
+    conn.shutdown().await
+}
+
+/// Counts a connection as active for as long as it's alive, which for [`Connection`]s is until
+/// their responses are done, not counting the wait for the client to hang up after that.
+///
+/// [`Connection`]: crate::request_stream::Connection
+pub struct ActiveConnection(());
+
+impl ActiveConnection {
+    pub fn new() -> Self {
+        counter!("gofer_connections_total").increment(1);
+        gauge!("gofer_active_connections").increment(1);
+        Self(())
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        gauge!("gofer_active_connections").decrement(1);
+    }
+}
+
+/// Counts a response as in progress for as long as it's alive.
+pub struct ResponseInFlight(());
+
+impl ResponseInFlight {
+    pub fn new() -> Self {
+        gauge!("gofer_responses_in_flight").increment(1);
+        Self(())
+    }
+}
+
+impl Drop for ResponseInFlight {
+    fn drop(&mut self) {
+        gauge!("gofer_responses_in_flight").decrement(1);
+    }
+}
+
+/// Record a finished request.
+pub fn request_finished(ok: bool, duration: Duration) {
+    let status = if ok { "ok" } else { "error" };
+    counter!("gofer_requests_total", "status" => status).increment(1);
+    histogram!("gofer_request_duration_seconds").record(duration.as_secs_f64());
+}
+
+pub fn bytes_sent(bytes: u64) {
+    counter!("gofer_bytes_sent_total").increment(bytes);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        conn.write_all(format!("GET {path} HTTP/1.1\r\nHost: x\r\n\r\n").as_bytes()).await.unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn exposition() {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(Matcher::Full("gofer_request_duration_seconds".into()),
+                DURATION_BUCKETS)
+            .unwrap()
+            .build_recorder();
+        let handle = recorder.handle();
+        let _still_active = metrics::with_local_recorder(&recorder, || {
+            drop(ActiveConnection::new());
+            let _in_flight = ResponseInFlight::new();
+            request_finished(true, Duration::from_millis(20));
+            request_finished(false, Duration::from_millis(2));
+            bytes_sent(1234);
+            ActiveConnection::new()
+        });
+
+        let addr = serve("127.0.0.1:0", handle).await.unwrap();
+        let response = get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        for line in [
+            "gofer_connections_total 2",
+            "gofer_active_connections 1",
+            "gofer_responses_in_flight 0",
+            "gofer_requests_total{status=\"ok\"} 1",
+            "gofer_requests_total{status=\"error\"} 1",
+            "gofer_bytes_sent_total 1234",
+            "gofer_request_duration_seconds_bucket{le=\"0.005\"} 1",
+            "gofer_request_duration_seconds_count 2",
+        ] {
+            assert!(body.lines().any(|l| l == line), "missing {line:?} in:\n{body}");
+        }
+
+        assert!(get(addr, "/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,72 @@
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+use thiserror::Error;
+
+/// The first file descriptor systemd passes; the rest follow on consecutively.
+const LISTEN_FDS_START: RawFd = 3;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ActivationError {
+    #[error("LISTEN_PID is {0}, not our PID {1}; the sockets are meant for some other process")]
+    WrongPid(u32, u32),
+
+    #[error("invalid {0} {1:?}")]
+    Invalid(&'static str, String),
+}
+
+/// How many sockets systemd passed us, given the values of the `LISTEN_PID` and `LISTEN_FDS`
+/// environment variables and our PID. Zero if we weren't socket-activated.
+pub fn fd_count(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32)
+    -> Result<usize, ActivationError>
+{
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(0);
+    };
+    let listen_pid = listen_pid.parse::<u32>()
+        .map_err(|_| ActivationError::Invalid("LISTEN_PID", listen_pid.to_owned()))?;
+    if listen_pid != pid {
+        return Err(ActivationError::WrongPid(listen_pid, pid));
+    }
+    listen_fds.parse::<usize>()
+        .ok()
+        .filter(|&count| count <= (RawFd::MAX - LISTEN_FDS_START) as usize)
+        .ok_or_else(|| ActivationError::Invalid("LISTEN_FDS", listen_fds.to_owned()))
+}
+
+/// Take ownership of the sockets systemd passed us, if any. The environment variables are
+/// removed, so this only returns them once.
+pub fn listen_fds() -> Result<Vec<OwnedFd>, ActivationError> {
+    let listen_pid = std::env::var("LISTEN_PID").ok();
+    let listen_fds = std::env::var("LISTEN_FDS").ok();
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    let count = fd_count(listen_pid.as_deref(), listen_fds.as_deref(), std::process::id())?;
+    Ok((0 .. count as RawFd)
+        .map(|i| {
+            // SAFETY: systemd passed us these, open, and nothing else in the process knows about
+            // them; the environment variables are gone, so they can't be taken twice.
+            unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START + i) }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn count() {
+        assert_eq!(fd_count(None, None, 100), Ok(0));
+        assert_eq!(fd_count(Some("100"), None, 100), Ok(0));
+        assert_eq!(fd_count(None, Some("2"), 100), Ok(0));
+        assert_eq!(fd_count(Some("100"), Some("2"), 100), Ok(2));
+        assert_eq!(fd_count(Some("100"), Some("0"), 100), Ok(0));
+        assert_eq!(fd_count(Some("99"), Some("2"), 100), Err(ActivationError::WrongPid(99, 100)));
+        assert_eq!(fd_count(Some("x"), Some("2"), 100),
+            Err(ActivationError::Invalid("LISTEN_PID", "x".to_owned())));
+        assert_eq!(fd_count(Some("100"), Some("-1"), 100),
+            Err(ActivationError::Invalid("LISTEN_FDS", "-1".to_owned())));
+        assert_eq!(fd_count(Some("100"), Some("99999999999"), 100),
+            Err(ActivationError::Invalid("LISTEN_FDS", "99999999999".to_owned())));
+    }
+}
@@ -0,0 +1,126 @@
+//! Traces of requests, sent to an OpenTelemetry collector over OTLP/HTTP. Where the collector is
+//! comes from the standard `OTEL_EXPORTER_OTLP_ENDPOINT` environment variable, and is
+//! `http://localhost:4318` if that isn't set.
+//!
+//! Every tracing span becomes a trace span, so a request's span is a child of its connection's.
+//! Gopher has no way for a client to pass on a trace it's part of, so there's never a parent from
+//! outside the server.
+
+use crate::request_stream::Peer;
+use crate::response::Response;
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use std::future::Future;
+use std::sync::OnceLock;
+use tracing::field::Empty;
+use tracing::{info_span, warn, Instrument, Subscriber};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Kept so it can be flushed by `shutdown`.
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// A layer that exports spans. They're sent in batches from a background thread.
+pub fn layer<S>() -> Result<OpenTelemetryLayer<S, SdkTracer>>
+    where S: Subscriber + for<'a> LookupSpan<'a>
+{
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+        .context("failed to set up the OpenTelemetry exporter")?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("gofer").build())
+        .build();
+    let tracer = provider.tracer("gofer");
+    PROVIDER.set(provider).map_err(|_| anyhow::anyhow!("OpenTelemetry already set up"))?;
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Send any spans that haven't been yet, before exiting.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            warn!("error shutting down OpenTelemetry: {e}");
+        }
+    }
+}
+
+/// Handle a request for `selector` from `peer` with `respond`, in a span for it. The span is
+/// marked as an error if the response is one.
+pub async fn traced(selector: &str, peer: Option<&Peer>, respond: impl Future<Output = Response>)
+    -> Response
+{
+    let span = info_span!("request",
+        otel.kind = "server",
+        otel.status_code = Empty,
+        gopher.selector = selector,
+        gopher.remote_addr = Empty,
+        gopher.response_type = Empty,
+    );
+    if let Some(peer) = peer {
+        span.record("gopher.remote_addr", peer.to_string());
+    }
+    let response = respond.instrument(span.clone()).await;
+    span.record("gopher.response_type", response.kind());
+    if matches!(response, Response::Error(_) | Response::NotFound) {
+        span.record("otel.status_code", "ERROR");
+    }
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use opentelemetry::trace::{SpanKind, Status};
+    use opentelemetry_sdk::error::OTelSdkResult;
+    use opentelemetry_sdk::trace::{SpanData, SpanExporter};
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::prelude::*;
+
+    /// Keeps the spans it's given.
+    #[derive(Debug, Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Collector {
+        async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+            self.0.lock().unwrap().extend(batch);
+            Ok(())
+        }
+    }
+
+    fn attribute(span: &SpanData, key: &str) -> Option<String> {
+        span.attributes.iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| kv.value.to_string())
+    }
+
+    #[tokio::test]
+    async fn request_spans() {
+        let collector = Collector::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(collector.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let peer = Peer::Tcp("192.0.2.1:1234".parse().unwrap());
+        traced("/file", Some(&peer), async { Response::Raw(vec![]) }).await;
+        traced("/missing", None, async { Response::NotFound }).await;
+
+        let spans = collector.0.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].span_kind, SpanKind::Server);
+        assert_eq!(attribute(&spans[0], "gopher.selector").as_deref(), Some("/file"));
+        assert_eq!(attribute(&spans[0], "gopher.remote_addr").as_deref(), Some("192.0.2.1:1234"));
+        assert_eq!(attribute(&spans[0], "gopher.response_type").as_deref(), Some("raw"));
+        assert_eq!(spans[0].status, Status::Unset);
+
+        assert_eq!(attribute(&spans[1], "gopher.remote_addr"), None);
+        assert_eq!(attribute(&spans[1], "gopher.response_type").as_deref(), Some("not_found"));
+        assert!(matches!(spans[1].status, Status::Error { .. }), "{:?}", spans[1].status);
+    }
+}
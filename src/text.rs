@@ -0,0 +1,137 @@
+use bytes::{Bytes, BytesMut};
+use tokio::io;
+use tokio_util::codec::Encoder;
+
+/// Encodes the contents of a text file for sending as a type 0 response: any lines starting with
+/// '.' get an extra '.' added, so that the client doesn't mistake them for the end of the file,
+/// and `finish` adds the terminating '.' line.
+///
+/// Optionally, bare LF line endings are converted to CRLF too.
+pub struct TextEncoder {
+    at_line_start: bool,
+    /// Whether the last byte seen was a CR, in case it's followed by LF in the next chunk.
+    after_cr: bool,
+    crlf: bool,
+}
+
+impl Default for TextEncoder {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
+
+impl TextEncoder {
+    pub fn new(crlf: bool) -> Self {
+        Self { at_line_start: true, after_cr: false, crlf }
+    }
+
+    /// Write the end of the response.
+    pub fn finish(&mut self, dst: &mut BytesMut) {
+        if !self.at_line_start {
+            dst.extend_from_slice(b"\r\n");
+        }
+        dst.extend_from_slice(b".\r\n");
+        self.at_line_start = true;
+    }
+}
+
+impl Encoder<Bytes> for TextEncoder {
+    type Error = io::Error;
+
+    fn encode(&mut self, chunk: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.reserve(chunk.len());
+        let mut rest = &chunk[..];
+        while !rest.is_empty() {
+            if self.at_line_start && rest[0] == b'.' {
+                dst.extend_from_slice(b".");
+            }
+            match rest.iter().position(|c| *c == b'\n') {
+                Some(idx) => {
+                    let has_cr = match idx {
+                        0 => self.after_cr,
+                        _ => rest[idx - 1] == b'\r',
+                    };
+                    dst.extend_from_slice(&rest[..idx]);
+                    if self.crlf && !has_cr {
+                        dst.extend_from_slice(b"\r");
+                    }
+                    dst.extend_from_slice(b"\n");
+                    self.at_line_start = true;
+                    self.after_cr = false;
+                    rest = &rest[idx + 1..];
+                }
+                None => {
+                    dst.extend_from_slice(rest);
+                    self.at_line_start = false;
+                    self.after_cr = rest.last() == Some(&b'\r');
+                    rest = &[];
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(chunks: &[&str]) -> String {
+        encode_with(TextEncoder::default(), chunks)
+    }
+
+    fn encode_with(mut encoder: TextEncoder, chunks: &[&str]) -> String {
+        let mut out = BytesMut::new();
+        for chunk in chunks {
+            encoder.encode(Bytes::copy_from_slice(chunk.as_bytes()), &mut out).unwrap();
+        }
+        encoder.finish(&mut out);
+        String::from_utf8(out.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(encode(&["hello\r\nworld\r\n"]), "hello\r\nworld\r\n.\r\n");
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(encode(&[]), ".\r\n");
+    }
+
+    #[test]
+    fn no_final_newline() {
+        assert_eq!(encode(&["hello"]), "hello\r\n.\r\n");
+    }
+
+    #[test]
+    fn dot_stuffing() {
+        assert_eq!(encode(&[".\r\n..two\r\nnot.this\r\n.end"]),
+            "..\r\n...two\r\nnot.this\r\n..end\r\n.\r\n");
+    }
+
+    #[test]
+    fn dot_stuffing_across_chunks() {
+        assert_eq!(encode(&["one\r\n", ".two", ".\r", "\n", ".", "three\r\n"]),
+            "one\r\n..two.\r\n..three\r\n.\r\n");
+    }
+
+    #[test]
+    fn lf_untouched_without_crlf() {
+        assert_eq!(encode(&["one\ntwo\r\n"]), "one\ntwo\r\n.\r\n");
+    }
+
+    #[test]
+    fn crlf_conversion() {
+        assert_eq!(encode_with(TextEncoder::new(true), &["one\ntwo\r\n\nthree\rfour\n"]),
+            "one\r\ntwo\r\n\r\nthree\rfour\r\n.\r\n");
+    }
+
+    #[test]
+    fn crlf_conversion_across_chunks() {
+        assert_eq!(encode_with(TextEncoder::new(true), &["one\r", "\ntwo", "\n", "\r", "\n"]),
+            "one\r\ntwo\r\n\r\n.\r\n");
+        assert_eq!(encode_with(TextEncoder::new(true), &["one\n", "\n", ".two\r", "x\n"]),
+            "one\r\n\r\n..two\rx\r\n.\r\n");
+    }
+}
@@ -0,0 +1,118 @@
+use pin_project_lite::pin_project;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::time::{Instant, Sleep};
+
+/// Smallest write to wait for budget for, when the caller has more than this to write. Without
+/// it, a writer that's used up its budget would go on to write a byte or two at a time.
+const MIN_WRITE: f64 = 1024.;
+
+pin_project! {
+    /// A writer which limits how fast data can be written through it, to a number of bytes per
+    /// second on average. Up to one second's worth can be written in a burst, after it's been idle.
+    pub struct Throttle<W> {
+        #[pin]
+        inner: W,
+
+        // Boxed so the throttle is Unpin if the writer is, like the client writers it goes on.
+        sleep: Pin<Box<Sleep>>,
+
+        rate: f64,
+
+        // How many bytes can be written right away, and when that was last worked out.
+        budget: f64,
+        updated: Instant,
+    }
+}
+
+impl<W> Throttle<W> {
+    pub fn new(inner: W, bytes_per_sec: usize) -> Self {
+        let now = Instant::now();
+        let rate = bytes_per_sec as f64;
+        Self {
+            inner,
+            sleep: Box::pin(tokio::time::sleep_until(now)),
+            rate,
+            budget: rate,
+            updated: now,
+        }
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for Throttle<W> {
+    fn poll_write(self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        let this = self.project();
+        if buf.is_empty() {
+            return this.inner.poll_write(ctx, buf);
+        }
+        let wanted = (buf.len() as f64).min(MIN_WRITE).min(*this.rate);
+        loop {
+            let now = Instant::now();
+            let elapsed = now.duration_since(*this.updated).as_secs_f64();
+            *this.budget = (*this.budget + elapsed * *this.rate).min(*this.rate);
+            *this.updated = now;
+            if *this.budget >= wanted {
+                break;
+            }
+            let wait = (wanted - *this.budget) / *this.rate;
+            this.sleep.as_mut().reset(now + Duration::from_secs_f64(wait));
+            ready!(this.sleep.as_mut().poll(ctx));
+        }
+
+        let allowed = buf.len().min(*this.budget as usize);
+        let n = ready!(this.inner.poll_write(ctx, &buf[.. allowed]))?;
+        *this.budget -= n as f64;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(ctx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(ctx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test(start_paused = true)]
+    async fn rate() {
+        let mut w = Throttle::new(tokio::io::sink(), 1000);
+        let start = Instant::now();
+        w.write_all(&[0; 5000]).await.unwrap();
+        // The first second's worth goes straight away, and the rest at the rate.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(3990), "{elapsed:?}");
+        assert!(elapsed <= Duration::from_millis(4010), "{elapsed:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst() {
+        let mut w = Throttle::new(tokio::io::sink(), 1000);
+        let start = Instant::now();
+        w.write_all(&[0; 1000]).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // Once that's used up, writes have to wait.
+        w.write_all(&[0; 500]).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(490), "{:?}", start.elapsed());
+
+        // An idle writer builds its budget back up, but no more than one second's worth.
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let start = Instant::now();
+        w.write_all(&[0; 1000]).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        w.write_all(&[0; 1000]).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(990), "{:?}", start.elapsed());
+    }
+}
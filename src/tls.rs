@@ -0,0 +1,123 @@
+use anyhow::{Context as _, Result};
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::{Accept, TlsAcceptor};
+
+/// Load a PEM certificate chain and private key, and make an acceptor that serves them.
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("failed to read certificates from {cert_path:?}"))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {cert_path:?}");
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("failed to read private key from {key_path:?}"))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .with_context(|| format!("certificate {cert_path:?} doesn't go with key {key_path:?}"))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// A TLS connection which does its handshake on first use, so a slow client holds up only its
+/// own request and not the accept loop.
+pub struct TlsConnection {
+    state: State,
+}
+
+enum State {
+    Handshaking(Accept<TcpStream>),
+    Ready(TlsStream<TcpStream>),
+    Failed,
+}
+
+impl TlsConnection {
+    pub fn new(acceptor: &TlsAcceptor, stream: TcpStream) -> Self {
+        Self { state: State::Handshaking(acceptor.accept(stream)) }
+    }
+
+    fn poll_stream(&mut self, ctx: &mut Context<'_>)
+        -> Poll<io::Result<Pin<&mut TlsStream<TcpStream>>>>
+    {
+        if let State::Handshaking(accept) = &mut self.state {
+            match ready!(Pin::new(accept).poll(ctx)) {
+                Ok(stream) => self.state = State::Ready(stream),
+                Err(e) => {
+                    self.state = State::Failed;
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+        Poll::Ready(match &mut self.state {
+            State::Ready(stream) => Ok(Pin::new(stream)),
+            _ => Err(io::Error::new(io::ErrorKind::NotConnected, "TLS handshake failed")),
+        })
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(mut self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &mut ReadBuf<'_>)
+        -> Poll<io::Result<()>>
+    {
+        ready!(self.poll_stream(ctx))?.poll_read(ctx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(mut self: Pin<&mut Self>, ctx: &mut Context<'_>, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        ready!(self.poll_stream(ctx))?.poll_write(ctx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_stream(ctx))?.poll_flush(ctx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_stream(ctx))?.poll_shutdown(ctx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn load_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        let other_key_path = dir.path().join("other.pem");
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        std::fs::write(&other_key_path, other.key_pair.serialize_pem()).unwrap();
+
+        acceptor(&cert_path, &key_path).unwrap();
+
+        let missing = dir.path().join("missing.pem");
+        let e = acceptor(&missing, &key_path).err().unwrap();
+        assert!(e.to_string().starts_with("failed to read certificates"), "{e}");
+        let e = acceptor(&key_path, &key_path).err().unwrap();
+        assert!(e.to_string().starts_with("no certificates found"), "{e}");
+        let e = acceptor(&cert_path, &cert_path).err().unwrap();
+        assert!(e.to_string().starts_with("failed to read private key"), "{e}");
+        let e = acceptor(&cert_path, &other_key_path).err().unwrap();
+        assert!(e.to_string().contains("doesn't go with key"), "{e}");
+    }
+}
@@ -1,4 +1,11 @@
-#[derive(Debug, Copy, Clone, PartialEq)]
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+/// The type of a menu item. Two types are equal if they have the same wire representation, so
+/// e.g. `Reserved(b'0')` is the same as `File`.
+#[derive(Debug, Copy, Clone)]
 pub enum ItemType {
     // RFC 1436:
     File,
@@ -86,4 +93,241 @@ impl ItemType {
             Self::Reserved(c) | Self::Other(c) => c,
         }
     }
+
+    /// Guess the type of a (non-directory) file from its extension. Files with no extension or an
+    /// unrecognized one are assumed to be text.
+    pub fn for_file(path: &Path) -> Self {
+        Self::for_extension(path).unwrap_or(Self::File)
+    }
+
+    /// The type of a (non-directory) file going by its extension, or `None` if it has no
+    /// extension or an unrecognized one.
+    pub fn for_extension(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_string_lossy().to_ascii_lowercase();
+        let typ = match ext.as_str() {
+            "gif" => Self::Gif,
+            "jpg" | "jpeg" | "png" | "bmp" | "webp" | "tif" | "tiff" | "ico" | "svg" => Self::Image,
+            "html" | "htm" | "xhtml" => Self::Html,
+            "wav" | "mp3" | "ogg" | "flac" | "m4a" | "opus" | "mid" | "midi" => Self::Audio,
+            "pdf" | "doc" | "docx" | "odt" | "rtf" | "ps" => Self::Document,
+            "hqx" => Self::BinHex,
+            "uu" | "uue" => Self::Uuencoded,
+            "exe" | "com" => Self::DosBinary,
+            "zip" | "gz" | "tgz" | "bz2" | "xz" | "zst" | "tar" | "7z" | "rar" | "iso" | "bin"
+                | "img" | "dmg" | "deb" | "rpm" | "mp4" | "mkv" | "avi" | "mov" | "webm" => {
+                Self::Binary
+            }
+            "txt" | "text" | "md" | "log" | "csv" | "conf" | "ini" | "toml" | "json" | "xml"
+                | "c" | "h" | "rs" | "py" | "sh" => Self::File,
+            _ => return None,
+        };
+        Some(typ)
+    }
+
+    /// Tell text files from binary ones by their first few bytes, for when the extension doesn't
+    /// say. Anything with a NUL byte, or that isn't UTF-8, is binary.
+    pub fn for_contents(start: &[u8]) -> Self {
+        let utf8 = match std::str::from_utf8(start) {
+            Ok(_) => true,
+            // Only the start was read, so the last character might be cut off.
+            Err(e) => e.error_len().is_none(),
+        };
+        if utf8 && !start.contains(&0) {
+            Self::File
+        } else {
+            Self::Binary
+        }
+    }
+
+    /// Recognize an image file from its first few bytes, for when its extension can't be trusted.
+    /// Only GIF, PNG, and JPEG are recognized.
+    pub fn for_image(start: &[u8]) -> Option<Self> {
+        if start.starts_with(b"GIF8") {
+            Some(Self::Gif)
+        } else if start.starts_with(b"\x89PNG") || start.starts_with(b"\xff\xd8\xff") {
+            Some(Self::Image)
+        } else {
+            None
+        }
+    }
+
+    /// Whether responses for items of this type are text, terminated by a line with a single '.'
+    /// on it, as opposed to being sent byte-for-byte.
+    pub fn is_text(self) -> bool {
+        matches!(self, Self::File | Self::Directory | Self::Error | Self::IndexSearch | Self::Info)
+    }
+}
+
+/// The type's name, from RFC 1436 where it has one.
+impl Display for ItemType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // Normalize first, so this is consistent with equality.
+        let name = match Self::from_u8(self.into_u8()) {
+            // RFC 1436:
+            Self::File => "Text File",
+            Self::Directory => "Directory",
+            Self::Cso => "CSO Phone Book",
+            Self::Error => "Error",
+            Self::BinHex => "BinHex File",
+            Self::DosBinary => "DOS Binary File",
+            Self::Uuencoded => "UUEncoded File",
+            Self::IndexSearch => "Index-Search Server",
+            Self::Telnet => "Telnet Session",
+            Self::Binary => "Binary File",
+            Self::RedundantServer => "Redundant Server",
+            Self::Tn3270 => "TN3270 Session",
+            Self::Gif => "GIF Image",
+            Self::Image => "Image",
+
+            // Unofficial:
+            Self::Document => "Document",
+            Self::Html => "HTML",
+            Self::Info => "Info",
+            Self::Audio => "Audio",
+
+            Self::Reserved(c) | Self::Other(c) => return write!(f, "Unknown({c:#04x})"),
+        };
+        f.write_str(name)
+    }
+}
+
+impl PartialEq for ItemType {
+    fn eq(&self, other: &Self) -> bool {
+        self.into_u8() == other.into_u8()
+    }
+}
+
+impl Eq for ItemType {}
+
+impl Hash for ItemType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.into_u8().hash(state);
+    }
+}
+
+impl PartialOrd for ItemType {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Ordered by wire representation.
+impl Ord for ItemType {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.into_u8().cmp(&other.into_u8())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Every type with a name, rather than `Reserved` or `Other`.
+    const NAMED: [ItemType; 18] = [
+        ItemType::File, ItemType::Directory, ItemType::Cso, ItemType::Error, ItemType::BinHex,
+        ItemType::DosBinary, ItemType::Uuencoded, ItemType::IndexSearch, ItemType::Telnet,
+        ItemType::Binary, ItemType::RedundantServer, ItemType::Tn3270, ItemType::Gif,
+        ItemType::Image, ItemType::Document, ItemType::Html, ItemType::Info, ItemType::Audio,
+    ];
+
+    fn any_type() -> impl Strategy<Value = ItemType> {
+        prop_oneof![
+            proptest::sample::select(&NAMED[..]),
+            any::<u8>().prop_map(ItemType::Reserved),
+            any::<u8>().prop_map(ItemType::Other),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn byte_round_trip(b in 0x21 ..= 0xffu8) {
+            prop_assert_eq!(ItemType::from_u8(b).into_u8(), b);
+        }
+
+        #[test]
+        fn type_round_trip(typ in any_type()) {
+            prop_assert_eq!(ItemType::from_u8(typ.into_u8()).into_u8(), typ.into_u8());
+        }
+
+        #[test]
+        fn named_bytes_are_named(typ in proptest::sample::select(&NAMED[..])) {
+            let parsed = ItemType::from_u8(typ.into_u8());
+            prop_assert!(!matches!(parsed, ItemType::Reserved(_) | ItemType::Other(_)),
+                "{:?} parsed as {:?}", typ, parsed);
+        }
+    }
+
+    #[test]
+    fn for_image() {
+        assert_eq!(Some(ItemType::Gif), ItemType::for_image(b"GIF89a\x01\x00"));
+        assert_eq!(Some(ItemType::Image), ItemType::for_image(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(Some(ItemType::Image), ItemType::for_image(b"\xff\xd8\xff\xe0\x00\x10JF"));
+        assert_eq!(None, ItemType::for_image(b"not an image"));
+        assert_eq!(None, ItemType::for_image(b"GIF"));
+        assert_eq!(None, ItemType::for_image(b""));
+    }
+
+    #[test]
+    fn for_file() {
+        assert_eq!(ItemType::File, ItemType::for_file(Path::new("README")));
+        assert_eq!(ItemType::File, ItemType::for_file(Path::new("notes.txt")));
+        assert_eq!(ItemType::File, ItemType::for_file(Path::new(".hidden")));
+        assert_eq!(ItemType::Gif, ItemType::for_file(Path::new("a.GIF")));
+        assert_eq!(ItemType::Image, ItemType::for_file(Path::new("a.jpeg")));
+        assert_eq!(ItemType::Html, ItemType::for_file(Path::new("dir.d/index.html")));
+        assert_eq!(ItemType::Binary, ItemType::for_file(Path::new("archive.tar.gz")));
+        assert_eq!(Some(ItemType::File), ItemType::for_extension(Path::new("notes.TXT")));
+        assert_eq!(None, ItemType::for_extension(Path::new("README")));
+        assert_eq!(None, ItemType::for_extension(Path::new("data.xyz")));
+    }
+
+    #[test]
+    fn for_contents() {
+        assert_eq!(ItemType::File, ItemType::for_contents(b"plain text\r\n"));
+        assert_eq!(ItemType::File, ItemType::for_contents("caf\u{e9}".as_bytes()));
+        assert_eq!(ItemType::File, ItemType::for_contents(b""));
+        // A character cut off at the end is fine, but not one in the middle.
+        assert_eq!(ItemType::File, ItemType::for_contents(&"caf\u{e9}".as_bytes()[.. 4]));
+        assert_eq!(ItemType::Binary, ItemType::for_contents(b"caf\xe9!"));
+        assert_eq!(ItemType::Binary, ItemType::for_contents(b"text\0with a NUL"));
+        assert_eq!(ItemType::Binary, ItemType::for_contents(b"\x7fELF\x02\x01\x01\0"));
+    }
+
+    #[test]
+    fn equality() {
+        use std::collections::HashMap;
+        assert_eq!(ItemType::File, ItemType::Reserved(b'0'));
+        assert_eq!(ItemType::Info, ItemType::Other(b'i'));
+        assert_eq!(ItemType::Reserved(b'Q'), ItemType::Other(b'Q'));
+        assert_ne!(ItemType::File, ItemType::Directory);
+        assert_ne!(ItemType::Reserved(b'Q'), ItemType::Reserved(b'R'));
+        assert!(ItemType::File < ItemType::Directory);
+
+        let mut counts = HashMap::new();
+        for typ in [ItemType::File, ItemType::Reserved(b'0'), ItemType::Directory] {
+            *counts.entry(typ).or_insert(0) += 1;
+        }
+        assert_eq!(counts[&ItemType::File], 2);
+        assert_eq!(counts[&ItemType::Directory], 1);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(ItemType::File.to_string(), "Text File");
+        assert_eq!(ItemType::Directory.to_string(), "Directory");
+        assert_eq!(ItemType::Binary.to_string(), "Binary File");
+        assert_eq!(ItemType::Reserved(b'Q').to_string(), "Unknown(0x51)");
+        assert_eq!(ItemType::Other(0x7f).to_string(), "Unknown(0x7f)");
+        assert_eq!(ItemType::Reserved(b'1').to_string(), "Directory");
+    }
+
+    #[test]
+    fn is_text() {
+        assert!(ItemType::File.is_text());
+        assert!(ItemType::Directory.is_text());
+        assert!(!ItemType::Binary.is_text());
+        assert!(!ItemType::Gif.is_text());
+        assert!(!ItemType::Html.is_text());
+    }
 }
@@ -0,0 +1,194 @@
+//! Tests that run the server and talk to it over real TCP connections.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use tempfile::TempDir;
+
+/// A server running on an ephemeral port, serving a temporary document root. It's killed when
+/// this is dropped.
+struct Server {
+    child: Child,
+    addr: SocketAddr,
+    _dir: TempDir,
+}
+
+impl Server {
+    /// Start a server, after `setup` has put whatever it needs in the document root.
+    fn start(setup: impl FnOnce(&Path)) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        setup(&root);
+        let config_path = write_config(dir.path(), &root, "");
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_gofer"))
+            .arg(&config_path)
+            .env("RUST_LOG", "info")
+            .env("NO_COLOR", "1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // The port is only known once the server says where it's listening.
+        let mut stderr = BufReader::new(child.stderr.take().unwrap());
+        let mut line = String::new();
+        let addr = loop {
+            line.clear();
+            if stderr.read_line(&mut line).unwrap() == 0 {
+                let _ = child.kill();
+                panic!("server exited without listening");
+            }
+            if let Some((_, addr)) = line.split_once("listening for connections at ") {
+                break addr.trim().parse().unwrap();
+            }
+        };
+        // Keep reading the log, so the server never blocks writing to it.
+        std::thread::spawn(move || std::io::copy(&mut stderr, &mut std::io::sink()));
+        Self { child, addr, _dir: dir }
+    }
+
+    /// Send `request` and read the whole response.
+    fn fetch(&self, request: &str) -> String {
+        let mut stream = TcpStream::connect(self.addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        String::from_utf8(response).unwrap()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Write a config file in `dir` for serving `root`, with `extra` settings, and return its path.
+fn write_config(dir: &Path, root: &Path, extra: &str) -> PathBuf {
+    let config_path = dir.join("config.toml");
+    std::fs::write(&config_path, format!(r#"
+        server_address = "127.0.0.1:0"
+        document_root = {root:?}
+        hostname = "localhost"
+        port = 7070
+        {extra}
+        "#)).unwrap();
+    config_path
+}
+
+/// Run `gofer check` on a document root set up by `setup`, and return whether it passed, and
+/// what it printed, with the root's path replaced by `$root`.
+fn check(setup: impl FnOnce(&Path), extra: &str) -> (bool, String) {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("root");
+    std::fs::create_dir(&root).unwrap();
+    setup(&root);
+    let config_path = write_config(dir.path(), &root, extra);
+    let output = Command::new(env!("CARGO_BIN_EXE_gofer"))
+        .arg("check")
+        .arg(&config_path)
+        .env("NO_COLOR", "1")
+        .output()
+        .unwrap();
+    let root = root.canonicalize().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap()
+        .replace(root.to_str().unwrap(), "$root");
+    (output.status.success(), stdout)
+}
+
+fn fixtures(root: &Path) {
+    std::fs::write(root.join("hello.txt"), "hello, world\r\n").unwrap();
+    std::fs::create_dir(root.join("docs")).unwrap();
+}
+
+#[test]
+fn root_listing() {
+    let server = Server::start(fixtures);
+    let menu = server.fetch("\r\n");
+    let body = menu.strip_suffix(".\r\n").expect("menu isn't terminated");
+    for line in body.split_terminator("\r\n") {
+        assert_eq!(line.split('\t').count(), 4, "bad menu line {line:?}");
+    }
+    assert!(menu.contains("0hello.txt\t/hello.txt\tlocalhost\t7070\r\n"), "{menu}");
+    assert!(menu.contains("1docs\t/docs\tlocalhost\t7070\r\n"), "{menu}");
+}
+
+#[test]
+fn url_redirect() {
+    let server = Server::start(fixtures);
+    let page = server.fetch("URL:https://example.com\r\n");
+    assert!(page.contains("<meta http-equiv=\"refresh\" content=\"5;URL=https://example.com\""),
+        "{page}");
+}
+
+#[test]
+fn http_request() {
+    let server = Server::start(fixtures);
+    let response = server.fetch("GET / HTTP/1.1\r\n");
+    assert!(response.starts_with("HTTP/1.0 400 Bad Request\r\n"), "{response}");
+}
+
+#[test]
+fn directory_traversal() {
+    let server = Server::start(fixtures);
+    let response = server.fetch("/docs/../../secret\r\n");
+    assert!(response.starts_with("3directory traversal denied\t"), "{response}");
+}
+
+#[test]
+fn file_contents() {
+    let server = Server::start(fixtures);
+    assert_eq!(server.fetch("/hello.txt\r\n"), "hello, world\r\n.\r\n");
+}
+
+#[test]
+fn dump_truncated() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("root");
+    std::fs::create_dir(&root).unwrap();
+    std::fs::write(root.join("big.txt"), "line\n".repeat(2000)).unwrap();
+    let config_path = write_config(dir.path(), &root, "");
+    let output = Command::new(env!("CARGO_BIN_EXE_gofer"))
+        .arg(&config_path)
+        .args(["--dump-menu", "/big.txt"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout.len(), 4096);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("[file truncated: showed 4096 of 10000 bytes]"), "{stderr}");
+}
+
+#[test]
+fn check_menus() {
+    let (ok, stdout) = check(fixtures, "");
+    assert!(ok, "{stdout}");
+    assert_eq!(stdout, "Menus OK\n");
+
+    let (ok, stdout) = check(|root| {
+        fixtures(root);
+        std::fs::write(root.join("!menu"), "# Comments count as lines too.\n\
+            0Hello\t/hello.txt\n\
+            0Missing\t/missing.txt\n\
+            xStrange\t/hello.txt\n\
+            !include nothere\n\
+            iThis line is much too long to fit in the forty characters allowed\n\
+            1Elsewhere\t/missing\texample.com\t70\n").unwrap();
+        std::fs::write(root.join("docs/!menu"), "0Gone\tgone.txt\n=glob [\n").unwrap();
+    }, "max_item_text = 40");
+    assert!(!ok, "{stdout}");
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(lines, [
+        "$root/!menu:3: selector \"/missing.txt\" doesn't lead to anything",
+        "$root/!menu:4: unknown item type 'x'",
+        "$root/!menu:5: can't include \"nothere\": not found",
+        "$root/!menu:6: text is 65 characters, more than 40",
+        "$root/docs/!menu:1: selector \"/docs/gone.txt\" doesn't lead to anything",
+        "$root/docs/!menu:2: glob \"[\": Pattern syntax error near position 0: invalid range \
+            pattern",
+    ], "{stdout}");
+}